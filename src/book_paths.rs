@@ -0,0 +1,269 @@
+use std::collections::HashSet;
+use std::fs::{File, canonicalize, read_dir, remove_file};
+use std::io::{ErrorKind, IsTerminal, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use zip::result::ZipResult;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::book_type::EPUB_MIMETYPE;
+use crate::log;
+
+pub const STDIN_PATH: &str = "-";
+
+fn has_glob_metacharacters(text: &str) -> bool {
+    text.contains(['*', '?', '['])
+}
+
+pub fn is_epub_path(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("epub"))
+}
+
+pub fn is_html_path(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("html") || extension.eq_ignore_ascii_case("htm"))
+}
+
+pub fn is_markdown_path(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("md") || extension.eq_ignore_ascii_case("markdown"))
+}
+
+fn is_book_path(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+    is_epub_path(path) || [".cbz", ".fb2", ".fb2.zip"].iter().any(|extension| name.ends_with(extension))
+}
+
+pub fn is_unpacked_epub(path: &Path) -> bool {
+    path.join("META-INF").join("container.xml").is_file()
+}
+
+fn glob_component_matches(pattern: &[char], name: &[char]) -> bool {
+    // Supports `*`, `?`, and `[...]` character classes (with `!` or `^` negation and `a-z` ranges).
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| glob_component_matches(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && glob_component_matches(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let Some(class_length) = pattern.iter().skip(2).position(|character| *character == ']').map(|position| position + 2) else {
+                return name.first() == Some(&'[') && glob_component_matches(&pattern[1..], &name[1..])
+            };
+            let Some(name_character) = name.first() else {
+                return false
+            };
+            let (negated, class) = match pattern[1] {
+                '!' | '^' => (true, &pattern[2..class_length]),
+                _ => (false, &pattern[1..class_length]),
+            };
+            let mut class_matches = false;
+            let mut class_index = 0;
+            while class_index < class.len() {
+                if class_index + 2 < class.len() && class[class_index + 1] == '-' {
+                    class_matches |= (class[class_index]..=class[class_index + 2]).contains(name_character);
+                    class_index += 3;
+                } else {
+                    class_matches |= class[class_index] == *name_character;
+                    class_index += 1;
+                }
+            }
+            class_matches != negated && glob_component_matches(&pattern[class_length + 1..], &name[1..])
+        },
+        Some(pattern_character) => name.first() == Some(pattern_character) && glob_component_matches(&pattern[1..], &name[1..]),
+    }
+}
+
+fn expand_glob(pattern: &str) -> Vec<PathBuf> {
+    let mut candidates = vec![PathBuf::new()];
+    for component in Path::new(pattern).components() {
+        let component_text = component.as_os_str().to_string_lossy();
+        if !matches!(component, Component::Normal(_)) || !has_glob_metacharacters(&component_text) {
+            candidates = candidates.into_iter().map(|candidate| candidate.join(component)).filter(|candidate| candidate.exists()).collect();
+            continue
+        }
+
+        let component_pattern = component_text.chars().collect::<Vec<char>>();
+        let mut next_candidates = Vec::new();
+        for candidate in candidates {
+            let dir = if candidate.as_os_str().is_empty() { Path::new(".") } else { candidate.as_path() };
+            let Ok(entries) = read_dir(dir) else {
+                continue
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().chars().collect::<Vec<char>>();
+                // As in shells, wildcards don't match hidden files unless the pattern itself starts with a dot.
+                if name.first() == Some(&'.') && component_pattern.first() != Some(&'.') {
+                    continue
+                }
+                if glob_component_matches(&component_pattern, &name) {
+                    next_candidates.push(candidate.join(entry.file_name()));
+                }
+            }
+        }
+        candidates = next_candidates;
+    }
+    candidates.sort();
+    candidates
+}
+
+fn scan_dir(dir: &Path, recursive: bool, strict: bool) -> Result<Vec<PathBuf>, String> {
+    let entries = read_dir(dir).map_err(|e| format!("Couldn't read directory {}: {}.", dir.display(), e))?;
+    let mut entry_paths = entries.flatten().map(|entry| entry.path()).collect::<Vec<PathBuf>>();
+    entry_paths.sort();
+
+    let mut epub_paths = Vec::new();
+    for entry_path in entry_paths {
+        if is_unpacked_epub(&entry_path) {
+            epub_paths.push(entry_path);
+        } else if entry_path.is_dir() {
+            if recursive {
+                epub_paths.append(&mut scan_dir(&entry_path, recursive, strict)?);
+            }
+        } else if is_book_path(&entry_path) {
+            epub_paths.push(entry_path);
+        } else if strict {
+            return Err(format!("Found non-book file {} while scanning {}.", entry_path.display(), dir.display()))
+        } else {
+            log::info!("Skipping non-book file {}.", entry_path.display());
+        }
+    }
+    Ok(epub_paths)
+}
+
+pub fn expand_book_paths(path_args: &[String], recursive: bool, strict: bool) -> Result<Vec<PathBuf>, String> {
+    let mut seen_paths = HashSet::new();
+    let mut book_paths = Vec::new();
+
+    for path_arg in path_args {
+        let path = Path::new(path_arg);
+        let expanded_paths = if path_arg == STDIN_PATH {
+            if book_paths.iter().any(|book_path| book_path == Path::new(STDIN_PATH)) {
+                return Err(String::from("'-' can only be given once, since stdin holds a single epub."))
+            }
+            book_paths.push(path.to_path_buf());
+            continue
+        } else if is_unpacked_epub(path) {
+            vec![path.to_path_buf()]
+        } else if path.is_dir() {
+            scan_dir(path, recursive, strict)?
+        } else if !path.exists() && has_glob_metacharacters(path_arg) {
+            let mut matched_paths = Vec::new();
+            for matched_path in expand_glob(path_arg) {
+                if matched_path.is_dir() && !is_unpacked_epub(&matched_path) {
+                    matched_paths.append(&mut scan_dir(&matched_path, recursive, strict)?);
+                } else {
+                    matched_paths.push(matched_path);
+                }
+            }
+            if matched_paths.is_empty() {
+                return Err(format!("Pattern '{}' didn't match any files.", path_arg))
+            }
+            matched_paths
+        } else {
+            vec![path.to_path_buf()]
+        };
+
+        for expanded_path in expanded_paths {
+            let identity = canonicalize(&expanded_path).unwrap_or_else(|_| expanded_path.clone());
+            if seen_paths.insert(identity) {
+                book_paths.push(expanded_path);
+            }
+        }
+    }
+
+    Ok(book_paths)
+}
+
+pub fn describe_book_path(path: &Path) -> String {
+    match path == Path::new(STDIN_PATH) {
+        true => String::from("<stdin>"),
+        false => path.display().to_string(),
+    }
+}
+
+// Removes its file once dropped, so a temporary book lasts exactly as long as the import that needs it.
+pub struct TemporaryBook {
+    path: PathBuf,
+}
+
+impl TemporaryBook {
+    fn new(kind: &str) -> Self {
+        // Books are converted several at once, possibly within one tick of a coarse clock, so a counter keeps their files apart.
+        static COUNT: AtomicU64 = AtomicU64::new(0);
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|duration| duration.as_nanos()).unwrap_or_default();
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        Self {
+            path: std::env::temp_dir().join(format!("rib-{}-{}-{}-{}.epub", kind, std::process::id(), nanos, count)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TemporaryBook {
+    fn drop(&mut self) {
+        match remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => log::debug!("Couldn't remove temporary book {}: {}", self.path.display(), e),
+            _ => (),
+        }
+    }
+}
+
+pub fn buffer_stdin() -> Result<TemporaryBook, String> {
+    // Epubs are zips, which are read from the end, so stdin has to be saved somewhere seekable first.
+    let mut stdin = std::io::stdin().lock();
+    if stdin.is_terminal() {
+        return Err(String::from("'-' reads an epub from stdin, but stdin is a terminal rather than a pipe or file."))
+    }
+    let buffer = TemporaryBook::new("stdin");
+    let mut buffer_file = File::create(buffer.path()).map_err(|e| format!("Couldn't create temporary file {}: {}", buffer.path().display(), e))?;
+    std::io::copy(&mut stdin, &mut buffer_file).map_err(|e| format!("Couldn't read stdin: {}", e))?;
+    Ok(buffer)
+}
+
+pub fn write_epub(path: &Path, add_contents: impl FnOnce(&mut ZipWriter<File>, SimpleFileOptions) -> ZipResult<()>) -> ZipResult<()> {
+    // Storing rather than compressing keeps the mimetype readable at its fixed offset, and the rest is rarely worth the time.
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut zip = ZipWriter::new(File::create(path)?);
+    zip.start_file("mimetype", options)?;
+    zip.write_all(EPUB_MIMETYPE.as_bytes())?;
+    add_contents(&mut zip, options)?;
+    zip.finish()?;
+    Ok(())
+}
+
+pub fn write_temporary_epub(kind: &str, add_contents: impl FnOnce(&mut ZipWriter<File>, SimpleFileOptions) -> ZipResult<()>) -> ZipResult<TemporaryBook> {
+    // Temporary epubs are read once and then deleted, so they're written as fast as they can be.
+    let epub = TemporaryBook::new(kind);
+    write_epub(epub.path(), add_contents)?;
+    Ok(epub)
+}
+
+fn add_dir_to_zip(zip: &mut ZipWriter<File>, root: &Path, dir: &Path, options: SimpleFileOptions) -> ZipResult<()> {
+    let mut entry_paths = read_dir(dir)?.map(|entry| entry.map(|entry| entry.path())).collect::<std::io::Result<Vec<PathBuf>>>()?;
+    entry_paths.sort();
+    for entry_path in entry_paths {
+        // Following a link could pack up (and then serve) files from anywhere on the system, or loop forever.
+        if entry_path.symlink_metadata()?.file_type().is_symlink() {
+            log::warning!("Skipping symlink {} in unpacked epub.", entry_path.display());
+            continue
+        }
+        if entry_path.is_dir() {
+            add_dir_to_zip(zip, root, &entry_path, options)?;
+            continue
+        }
+        // Zip entry names always use forward slashes, whatever the platform's separator.
+        let name = entry_path.strip_prefix(root).unwrap_or(&entry_path).components().map(|component| component.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+        if name != "mimetype" {
+            zip.start_file(name, options)?;
+            std::io::copy(&mut File::open(&entry_path)?, zip)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn pack_unpacked_epub(dir: &Path) -> Result<TemporaryBook, String> {
+    // EpubDoc only reads packaged epubs, so an unpacked one is zipped up first.
+    write_temporary_epub("unpacked", |zip, options| add_dir_to_zip(zip, dir, dir, options)).map_err(|e| format!("Couldn't package {}: {}", dir.display(), e))
+}
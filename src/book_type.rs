@@ -0,0 +1,88 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use crate::cbz::image_media_type;
+
+pub const EPUB_MIMETYPE: &str = "application/epub+zip";
+// Mobipocket's type and creator codes sit at a fixed offset in its PalmDB header.
+const MOBI_SIGNATURE_OFFSET: usize = 60;
+const MOBI_SIGNATURE: &[u8] = b"BOOKMOBI";
+// FB2's root element usually follows an XML declaration and sometimes a comment, so it's looked for a little way in.
+const HEADER_LENGTH: u64 = 512;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BookType {
+    Epub,
+    Cbz,
+    Fb2,
+    Fb2Zip,
+    Zip,
+    Pdf,
+    Mobi,
+    Html,
+    Unknown,
+}
+
+impl BookType {
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Epub => "an epub",
+            Self::Cbz => "a comic archive",
+            Self::Fb2 => "a FictionBook",
+            Self::Fb2Zip => "a zipped FictionBook",
+            Self::Zip => "a zip archive that isn't an epub",
+            Self::Pdf => "a PDF",
+            Self::Mobi => "a Mobipocket or Kindle book",
+            Self::Html => "an HTML page",
+            Self::Unknown => "an unrecognized file type",
+        }
+    }
+}
+
+fn detect_zip_type(path: &Path) -> BookType {
+    let Ok(mut archive) = File::open(path).map_err(zip::result::ZipError::from).and_then(|file| zip::ZipArchive::new(BufReader::new(file))) else {
+        return BookType::Zip
+    };
+    let mut mimetype = String::new();
+    let is_epub = match archive.by_name("mimetype").map(|mut entry| entry.read_to_string(&mut mimetype)) {
+        Ok(Ok(_)) => mimetype.trim() == EPUB_MIMETYPE,
+        // Some converters leave out the mimetype entry; the container is what actually makes an epub readable.
+        Err(_) => archive.by_name("META-INF/container.xml").is_ok(),
+        Ok(Err(_)) => false,
+    };
+    if is_epub {
+        BookType::Epub
+    } else if archive.file_names().any(|name| name.to_lowercase().ends_with(".fb2")) {
+        BookType::Fb2Zip
+    } else if archive.file_names().any(|name| image_media_type(name).is_some()) {
+        BookType::Cbz
+    } else {
+        BookType::Zip
+    }
+}
+
+pub fn detect_book_type(path: &Path) -> Result<BookType, String> {
+    let mut header = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(HEADER_LENGTH).read_to_end(&mut header))
+        .map_err(|e| format!("Couldn't read file: {}", e))?;
+
+    if header.starts_with(b"PK\x03\x04") {
+        return Ok(detect_zip_type(path))
+    }
+    if header.starts_with(b"%PDF-") {
+        return Ok(BookType::Pdf)
+    }
+    if header.get(MOBI_SIGNATURE_OFFSET..MOBI_SIGNATURE_OFFSET + MOBI_SIGNATURE.len()) == Some(MOBI_SIGNATURE) {
+        return Ok(BookType::Mobi)
+    }
+    let text_start = String::from_utf8_lossy(&header).trim_start_matches('\u{feff}').trim_start().to_lowercase();
+    if text_start.starts_with("<!doctype html") || text_start.starts_with("<html") {
+        return Ok(BookType::Html)
+    }
+    if text_start.starts_with("<?xml") && text_start.contains("<fictionbook") {
+        return Ok(BookType::Fb2)
+    }
+    Ok(BookType::Unknown)
+}
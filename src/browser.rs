@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::fs::{File, canonicalize, read_to_string, remove_file};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::helpers::{closest_match, executables_on_path, file_url, find_executable};
+use crate::log;
+
+const PATH_PLACEHOLDER: &str = "{path}";
+const URL_PLACEHOLDER: &str = "{url}";
+pub const DEFAULT_OPENER: &str = "xdg-open";
+const NO_BROWSER_MESSAGE: &str = "no browser is configured. Set default_browser in config.toml or pass --browser";
+// How to open a browser as a bare reading window, without tabs or toolbars, by program name.
+const CHROMIUM_APP_ARGUMENTS: &[&str] = &["--app={url}"];
+const FIREFOX_KIOSK_ARGUMENTS: &[&str] = &["--kiosk", "{url}"];
+const READER_WINDOW_ARGUMENTS: [(&str, &[&str]); 10] = [
+    ("chromium", CHROMIUM_APP_ARGUMENTS),
+    ("chromium-browser", CHROMIUM_APP_ARGUMENTS),
+    ("chrome", CHROMIUM_APP_ARGUMENTS),
+    ("google-chrome", CHROMIUM_APP_ARGUMENTS),
+    ("google-chrome-stable", CHROMIUM_APP_ARGUMENTS),
+    ("brave-browser", CHROMIUM_APP_ARGUMENTS),
+    ("msedge", CHROMIUM_APP_ARGUMENTS),
+    ("microsoft-edge", CHROMIUM_APP_ARGUMENTS),
+    ("vivaldi", CHROMIUM_APP_ARGUMENTS),
+    ("firefox", FIREFOX_KIOSK_ARGUMENTS),
+];
+const STARTUP_WAIT: Duration = Duration::from_secs(2);
+const STARTUP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Names are random, so colliding with an existing file even once is vanishingly unlikely.
+const STDERR_FILE_ATTEMPTS: usize = 4;
+
+pub fn split_command(command: &str) -> Result<Vec<String>, String> {
+    // Quotes group words as in a shell, but a backslash only escapes quotes and whitespace, so Windows paths survive unquoted.
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            },
+            (Some('\''), c) => current.push(c),
+            (_, '\\') => match chars.peek() {
+                Some(&next) if next == '"' || (quote.is_none() && (next == '\'' || next.is_whitespace())) => {
+                    current.push(next);
+                    chars.next();
+                    in_word = true;
+                },
+                _ => {
+                    current.push('\\');
+                    in_word = true;
+                },
+            },
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            },
+            (_, c) => {
+                current.push(c);
+                in_word = true;
+            },
+        }
+    }
+    if let Some(open) = quote {
+        return Err(format!("unterminated {} quote in browser command '{}'", open, command))
+    }
+    if in_word {
+        words.push(current);
+    }
+    Ok(words)
+}
+
+pub fn parse_browser_list(list: &str) -> Result<Vec<Vec<String>>, String> {
+    // The BROWSER convention: commands to try in order, separated like PATH entries, with %s standing for the URL and %% for a percent sign.
+    let separator = if cfg!(windows) { ';' } else { ':' };
+    list.split(separator).filter(|entry| !entry.trim().is_empty()).map(|entry| {
+        let mut command = String::new();
+        let mut chars = entry.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('%', Some('s')) => {
+                    command.push_str(URL_PLACEHOLDER);
+                    chars.next();
+                },
+                ('%', Some('%')) => {
+                    command.push('%');
+                    chars.next();
+                },
+                (c, _) => command.push(c),
+            }
+        }
+        split_command(&command)
+    }).collect()
+}
+
+pub fn open_with_first_working(commands: &[Vec<String>], mut open: impl FnMut(&[String]) -> Result<(), String>) -> Result<(), String> {
+    let mut last_error = String::from(NO_BROWSER_MESSAGE);
+    for command in commands {
+        match open(command) {
+            Ok(()) => {
+                if commands.len() > 1 {
+                    log::debug!("Opened with {}.", command.join(" "));
+                }
+                return Ok(())
+            },
+            Err(e) => {
+                log::debug!("Couldn't open with {}: {}", command.join(" "), e);
+                last_error = e;
+            },
+        }
+    }
+    Err(last_error)
+}
+
+pub fn reader_window_command(command: &[String], overrides: &HashMap<String, Vec<String>>) -> Option<Vec<String>> {
+    // Programs are recognized by file name, so a full path or a Windows .exe still matches.
+    let (program, _arguments) = command.split_first()?;
+    let program_name = Path::new(program).file_stem()?.to_string_lossy().to_lowercase();
+    let arguments = match overrides.get(&program_name) {
+        Some(arguments) => arguments.clone(),
+        None => READER_WINDOW_ARGUMENTS.iter().find(|(name, _arguments)| *name == program_name)?.1.iter().map(|argument| argument.to_string()).collect(),
+    };
+    Some(command.iter().cloned().chain(arguments).collect())
+}
+
+pub fn resolve_command(browser: &str, browsers: &HashMap<String, Vec<String>>) -> Result<Vec<String>, String> {
+    // Configured names win over commands, so a profile can share its name with the program it wraps.
+    match browsers.get(browser) {
+        Some(command) => Ok(command.clone()),
+        None => split_command(browser),
+    }
+}
+
+// Something to open: a book's file, or its URL when it's being served.
+#[derive(Clone, Debug)]
+pub enum Target {
+    File(PathBuf, Option<String>),
+    Url(String),
+}
+
+impl Target {
+    pub fn describe(&self) -> String {
+        match self {
+            Self::File(path, Some(fragment)) => format!("file {}", file_url(path, Some(fragment))),
+            Self::File(path, None) => format!("file {}", path.display()),
+            Self::Url(url) => format!("URL {}", url),
+        }
+    }
+}
+
+fn has_placeholder(words: &[String]) -> bool {
+    words.iter().any(|word| word.contains(PATH_PLACEHOLDER) || word.contains(URL_PLACEHOLDER))
+}
+
+fn build_arguments(words: &[String], path: &str, url: &str) -> Vec<String> {
+    // Without a placeholder, the target goes last, which is where nearly every browser expects it.
+    match has_placeholder(words) {
+        true => words.iter().map(|word| word.replace(PATH_PLACEHOLDER, path).replace(URL_PLACEHOLDER, url)).collect(),
+        false => words.iter().cloned().chain([path.to_string()]).collect(),
+    }
+}
+
+fn describe_missing_command(browser: &str) -> String {
+    // Only bare command names are worth suggesting for; a mistyped path is better reported as-is.
+    if browser.contains(['/', std::path::MAIN_SEPARATOR]) {
+        return format!("command not found: {}", browser)
+    }
+    let executables = executables_on_path();
+    match closest_match(browser, executables.iter().map(String::as_str)) {
+        Some(suggestion) => format!("command not found: {} (did you mean {}?)", browser, suggestion),
+        None => format!("command not found: {}", browser),
+    }
+}
+
+fn wait_for_startup(child: &mut Child, program: &str, stderr_path: Option<&Path>) -> Result<(), String> {
+    // A browser that stays in the foreground would hold rib up until it's closed, so it's only given long enough to fail at startup.
+    let start = Instant::now();
+    while start.elapsed() < STARTUP_WAIT {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return Ok(()),
+            Ok(Some(status)) => {
+                let stderr = stderr_path.and_then(|stderr_path| read_to_string(stderr_path).ok()).unwrap_or_default();
+                return match stderr.lines().find(|line| !line.trim().is_empty()) {
+                    Some(first_line) => Err(format!("browser {} exited with {}: {}", program, status, first_line.trim())),
+                    None => Err(format!("browser {} exited with {}", program, status)),
+                }
+            },
+            Ok(None) => sleep(STARTUP_POLL_INTERVAL),
+            Err(e) => return Err(format!("couldn't check on browser {}: {}", program, e)),
+        }
+    }
+    log::debug!("Browser {} is still running after {:?}, so taking it to have opened.", program, STARTUP_WAIT);
+    Ok(())
+}
+
+// The temp dir is shared with every other user, so the file is only ever one rib has just created under a name no one
+// could guess, never one planted there in advance or a link to somewhere else.
+fn create_stderr_file() -> Option<(File, PathBuf)> {
+    for _attempt in 0..STDERR_FILE_ATTEMPTS {
+        // Each RandomState is keyed from the system's random source, which is all the randomness a name needs.
+        let random = RandomState::new().build_hasher().finish();
+        let stderr_path = std::env::temp_dir().join(format!("rib-browser-stderr-{}-{:016x}", std::process::id(), random));
+        match File::options().write(true).create_new(true).open(&stderr_path) {
+            Ok(file) => return Some((file, stderr_path)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                log::debug!("Couldn't create {} for the browser's errors: {}", stderr_path.display(), e);
+                return None
+            },
+        }
+    }
+    None
+}
+
+fn run(program: &str, arguments: &[String], wait: bool) -> Result<(), String> {
+    // Stderr goes to a file rather than a pipe, since a pipe would break once rib exits and take a browser still writing to it down too.
+    let stderr_file = wait.then(create_stderr_file).flatten();
+    let (stderr, stderr_path) = match stderr_file {
+        Some((file, stderr_path)) => (Stdio::from(file), Some(stderr_path)),
+        None => (Stdio::null(), None),
+    };
+    let mut child = match Command::new(program).args(arguments).stdin(Stdio::null()).stdout(Stdio::null()).stderr(stderr).spawn() {
+        Ok(child) => child,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(describe_missing_command(program)),
+        Err(e) => return Err(format!("couldn't run browser {}: {}", program, e)),
+    };
+    if !wait {
+        return Ok(())
+    }
+    let started = wait_for_startup(&mut child, program, stderr_path.as_deref());
+    // Removing the file fails on Windows while the browser still has it open, which only leaves a small file in the temp dir.
+    if let Some(stderr_path) = stderr_path {
+        let _ = remove_file(stderr_path);
+    }
+    started
+}
+
+pub fn resolve_path(path: &Path) -> PathBuf {
+    // Resolving is only a nicety; a path that can't be resolved right now (on a slow network mount, say) may still open fine as it is.
+    let path = canonicalize(path).unwrap_or_else(|e| {
+        log::debug!("Couldn't resolve {}: {}. Using it unresolved.", path.display(), e);
+        std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+    });
+    // Windows' canonical paths carry a verbatim \\?\ prefix that some browsers mishandle; without it they name the same file.
+    let path_text = path.to_string_lossy();
+    if let Some(share_path) = path_text.strip_prefix(r"\\?\UNC\") {
+        return PathBuf::from(format!(r"\\{}", share_path))
+    }
+    match path_text.strip_prefix(r"\\?\") {
+        Some(plain_path) => PathBuf::from(plain_path),
+        None => path,
+    }
+}
+
+fn is_wsl() -> bool {
+    cfg!(target_os = "linux") && (std::env::var_os("WSL_DISTRO_NAME").is_some() || read_to_string("/proc/sys/kernel/osrelease").is_ok_and(|release| release.to_lowercase().contains("microsoft")))
+}
+
+fn open_from_wsl(path: &Path, fragment: Option<&str>, wait: bool) -> Result<(), String> {
+    // Windows browsers can't see Linux paths directly, only through the \\wsl$ share that wslpath translates them to.
+    let path = resolve_path(path);
+    let output = Command::new("wslpath").arg("-w").arg(&path).output().map_err(|e| format!("couldn't run wslpath: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("wslpath couldn't translate {}", path.display()))
+    }
+    let windows_path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let target = match fragment {
+        Some(fragment) => file_url(Path::new(&windows_path), Some(fragment)),
+        None => windows_path,
+    };
+
+    open_in_windows(target, fragment.is_some(), wait)
+}
+
+fn open_in_windows(target: String, is_url: bool, wait: bool) -> Result<(), String> {
+    if find_executable("wslview").is_some() {
+        return run("wslview", &[target], wait)
+    }
+    match is_url {
+        // explorer.exe exits nonzero even when it succeeds, so only failing to start it counts.
+        false => match Command::new("explorer.exe").arg(&target).status() {
+            Ok(_status) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(describe_missing_command("explorer.exe")),
+            Err(e) => Err(format!("couldn't run explorer.exe: {}", e)),
+        },
+        // explorer.exe drops URL fragments, so URLs go through start instead.
+        true => run("cmd.exe", &[String::from("/c"), String::from("start"), String::new(), target], wait),
+    }
+}
+
+fn platform_command(os: &str, command: &[String], is_on_path: impl Fn(&str) -> bool) -> Vec<String> {
+    match (os, command) {
+        // The default config names xdg-open, which only exists on Linux and the BSDs; elsewhere the system's own opener stands in.
+        ("macos", [program]) if program == DEFAULT_OPENER => vec![String::from("open")],
+        ("windows", [program]) if program == DEFAULT_OPENER => vec![String::from("cmd"), String::from("/c"), String::from("start"), String::new()],
+        // macOS applications like "Safari" aren't on PATH, but open -a can launch them by name.
+        ("macos", [program]) if !program.contains('/') && !is_on_path(program) => vec![String::from("open"), String::from("-a"), program.clone()],
+        _ => command.to_vec(),
+    }
+}
+
+fn launch(command: &[String], path: &str, url: &str, wait: bool) -> Result<(), String> {
+    let command = platform_command(std::env::consts::OS, command, |program| find_executable(program).is_some());
+    let (program, arguments) = command.split_first().expect("Platform command is empty. (This shouldn't happen.)");
+    run(program, &build_arguments(arguments, path, url), wait)
+}
+
+fn uses_windows_from_wsl(command: &[String], force_linux_browser: bool) -> bool {
+    // Only the generic opener is redirected; a browser named explicitly is assumed to be one that works here.
+    command == [DEFAULT_OPENER] && !force_linux_browser && is_wsl()
+}
+
+fn path_argument(path: &Path, fragment: Option<&str>) -> (String, String) {
+    let path = resolve_path(path);
+    let url = file_url(&path, fragment);
+    // Fragments only survive as part of a URL; plain paths are left as-is for browsers that handle them better.
+    let path = match fragment {
+        Some(_) => url.clone(),
+        None => path.display().to_string(),
+    };
+    (path, url)
+}
+
+pub fn open(command: &[String], path: &Path, fragment: Option<&str>, force_linux_browser: bool, wait: bool) -> Result<(), String> {
+    if command.is_empty() {
+        return Err(String::from(NO_BROWSER_MESSAGE))
+    }
+    if uses_windows_from_wsl(command, force_linux_browser) {
+        return open_from_wsl(path, fragment, wait)
+    }
+
+    let (path, url) = path_argument(path, fragment);
+    launch(command, &path, &url, wait)
+}
+
+pub fn open_url(command: &[String], url: &str, force_linux_browser: bool, wait: bool) -> Result<(), String> {
+    if command.is_empty() {
+        return Err(String::from(NO_BROWSER_MESSAGE))
+    }
+    // WSL forwards localhost to Windows, so a served URL works there untranslated.
+    if uses_windows_from_wsl(command, force_linux_browser) {
+        return open_in_windows(url.to_string(), true, wait)
+    }
+    launch(command, url, url, wait)
+}
+
+pub fn can_open_together(command: &[String], force_linux_browser: bool) -> bool {
+    // Browsers open each of several targets in a tab, but the system openers take just one, as do commands that place their target with a placeholder.
+    let platform_command = platform_command(std::env::consts::OS, command, |program| find_executable(program).is_some());
+    let is_single_target_opener = match platform_command.first().map(String::as_str) {
+        Some(DEFAULT_OPENER | "cmd") => true,
+        Some(_) => false,
+        None => true,
+    };
+    !is_single_target_opener && !has_placeholder(command) && !uses_windows_from_wsl(command, force_linux_browser)
+}
+
+pub fn open_all(command: &[String], targets: &[Target], force_linux_browser: bool, wait: bool) -> Result<(), String> {
+    // Several targets are passed to one command, which only works for commands that can_open_together allows.
+    match targets {
+        [] => Ok(()),
+        [Target::File(path, fragment)] => open(command, path, fragment.as_deref(), force_linux_browser, wait),
+        [Target::Url(url)] => open_url(command, url, force_linux_browser, wait),
+        targets => {
+            let command = platform_command(std::env::consts::OS, command, |program| find_executable(program).is_some());
+            let Some((program, words)) = command.split_first() else {
+                return Err(String::from(NO_BROWSER_MESSAGE))
+            };
+            let arguments = targets.iter().map(|target| match target {
+                Target::File(path, fragment) => path_argument(path, fragment.as_deref()).0,
+                Target::Url(url) => url.clone(),
+            });
+            run(program, &words.iter().cloned().chain(arguments).collect::<Vec<String>>(), wait)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(command: &[&str]) -> Vec<String> {
+        command.iter().map(|word| String::from(*word)).collect()
+    }
+
+    fn command_for(os: &str, command: &[&str]) -> Vec<String> {
+        platform_command(os, &words(command), |program| matches!(program, "firefox" | "open"))
+    }
+
+    #[test]
+    fn default_opener_is_the_systems_own() {
+        assert_eq!(command_for("linux", &[DEFAULT_OPENER]), [DEFAULT_OPENER]);
+        assert_eq!(command_for("macos", &[DEFAULT_OPENER]), ["open"]);
+        assert_eq!(command_for("windows", &[DEFAULT_OPENER]), ["cmd", "/c", "start", ""]);
+    }
+
+    #[test]
+    fn macos_opens_application_names_with_open_a() {
+        assert_eq!(command_for("macos", &["Safari"]), ["open", "-a", "Safari"]);
+        assert_eq!(command_for("macos", &["Google Chrome"]), ["open", "-a", "Google Chrome"]);
+    }
+
+    #[test]
+    fn commands_and_paths_run_directly() {
+        assert_eq!(command_for("macos", &["firefox"]), ["firefox"]);
+        assert_eq!(command_for("macos", &["/Applications/Firefox.app/Contents/MacOS/firefox"]), ["/Applications/Firefox.app/Contents/MacOS/firefox"]);
+        assert_eq!(command_for("macos", &["Safari", "--new-window"]), ["Safari", "--new-window"]);
+        assert_eq!(command_for("linux", &["Safari"]), ["Safari"]);
+        assert_eq!(command_for("windows", &["firefox", "-private"]), ["firefox", "-private"]);
+    }
+
+    #[test]
+    fn splits_commands_like_a_shell() {
+        assert_eq!(split_command("firefox --new-window").unwrap(), ["firefox", "--new-window"]);
+        assert_eq!(split_command(r#""C:\Program Files\Mozilla Firefox\firefox.exe" -private"#).unwrap(), [r"C:\Program Files\Mozilla Firefox\firefox.exe", "-private"]);
+        assert_eq!(split_command(r"open -a Google\ Chrome").unwrap(), ["open", "-a", "Google Chrome"]);
+        assert!(split_command("firefox 'unterminated").is_err());
+    }
+
+    #[test]
+    fn parses_browser_lists() {
+        let separator = if cfg!(windows) { ";" } else { ":" };
+        let list = ["firefox %s", "", "chromium --app=%s", "lynx -dump %%s"].join(separator);
+        assert_eq!(parse_browser_list(&list).unwrap(), [words(&["firefox", "{url}"]), words(&["chromium", "--app={url}"]), words(&["lynx", "-dump", "%s"])]);
+        assert_eq!(parse_browser_list("").unwrap(), Vec::<Vec<String>>::new());
+    }
+
+    #[test]
+    fn browser_list_placeholders_take_the_url() {
+        let commands = parse_browser_list("firefox --url=%s").unwrap();
+        let (_program, arguments) = commands[0].split_first().unwrap();
+        assert_eq!(build_arguments(arguments, "/book/index.html", "file:///book/index.html"), ["--url=file:///book/index.html"]);
+    }
+
+    #[test]
+    fn tries_commands_until_one_works() {
+        let commands = [words(&["missing"]), words(&["broken"]), words(&["working"]), words(&["unused"])];
+        let mut tried = Vec::new();
+        let result = open_with_first_working(&commands, |command| {
+            tried.push(command[0].clone());
+            match command[0].as_str() {
+                "working" => Ok(()),
+                program => Err(format!("{} failed", program)),
+            }
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(tried, ["missing", "broken", "working"]);
+        assert_eq!(open_with_first_working(&commands[..2], |command| Err(format!("{} failed", command[0]))), Err(String::from("broken failed")));
+        assert_eq!(open_with_first_working(&[], |_command| Ok(())), Err(String::from(NO_BROWSER_MESSAGE)));
+    }
+
+    #[test]
+    fn targets_go_last_without_a_placeholder() {
+        assert_eq!(build_arguments(&words(&["-a", "Safari"]), "/book/index.html", "file:///book/index.html"), ["-a", "Safari", "/book/index.html"]);
+        assert_eq!(build_arguments(&words(&["--url={url}", "--kiosk"]), "/book/index.html", "file:///book/index.html"), ["--url=file:///book/index.html", "--kiosk"]);
+    }
+}
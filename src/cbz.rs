@@ -0,0 +1,61 @@
+use std::fs::read;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use quick_xml::escape::escape;
+
+use crate::book_paths::TemporaryBook;
+use crate::helpers::{content_hash, natural_cmp};
+use crate::synthetic_epub::SyntheticEpub;
+
+pub fn image_media_type(name: &str) -> Option<&'static str> {
+    let extension = Path::new(name).extension()?.to_string_lossy().to_lowercase();
+    match extension.as_str() {
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "png" => Some("image/png"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        "avif" => Some("image/avif"),
+        _ => None,
+    }
+}
+
+fn is_page_image(name: &str) -> bool {
+    // Archivers add hidden files and macOS resource forks that only look like images.
+    let is_hidden = name.split('/').any(|component| component.starts_with('.') || component == "__MACOSX");
+    !is_hidden && image_media_type(name).is_some()
+}
+
+pub fn convert_cbz(cbz_path: &Path, title: &str) -> Result<TemporaryBook, String> {
+    let cbz = read(cbz_path).map_err(|e| format!("Couldn't read comic archive: {}", e))?;
+    let mut archive = zip::ZipArchive::new(Cursor::new(&cbz)).map_err(|e| format!("Couldn't open comic archive: {}", e))?;
+    let mut image_names = archive.file_names().filter(|name| is_page_image(name)).map(String::from).collect::<Vec<String>>();
+    image_names.sort_by(|first, second| natural_cmp(first, second));
+    if image_names.is_empty() {
+        return Err(String::from("Comic archive doesn't contain any images."))
+    }
+
+    // Comic archives carry no identifier, so the contents stand in for one.
+    let mut epub = SyntheticEpub::new(format!("urn:rib:cbz:{}", content_hash(&cbz)), title.to_string());
+    for (index, image_name) in image_names.iter().enumerate() {
+        let mut image = Vec::new();
+        archive.by_name(image_name)
+            .map_err(|e| e.to_string())
+            .and_then(|mut entry| entry.read_to_end(&mut image).map_err(|e| e.to_string()))
+            .map_err(|e| format!("Couldn't read {} from comic archive: {}", image_name, e))?;
+        // Pages are renamed by position, which keeps their order and avoids carrying over awkward archive paths.
+        let page_number = index + 1;
+        let extension = Path::new(image_name).extension().unwrap_or_default().to_string_lossy().to_lowercase();
+        let image_path = format!("images/{:04}.{}", page_number, extension);
+        epub.add_page(
+            format!("pages/{:04}.xhtml", page_number),
+            format!("Page {}", page_number),
+            format!("<img src=\"../{}\" alt=\"{}\"/>", image_path, escape(format!("Page {}", page_number).as_str())),
+        );
+        if index == 0 {
+            epub.set_cover(image_path.clone());
+        }
+        epub.add_resource(image_path, image_media_type(image_name).unwrap_or("application/octet-stream"), image);
+    }
+    epub.write("cbz")
+}
@@ -0,0 +1,373 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use epub::doc::EpubDoc;
+use quick_xml::events::Event;
+use serde::Serialize;
+
+use crate::cli::{CheckArgs, OutputFormat};
+use crate::output;
+use crate::{SpineIndex, SpineItem, SpineItemFormat, TocItem, flatten_toc_items, get_book_cache_id, get_spine_linearity, localize_toc_item_format, new_strict_xhtml_reader, resource_path_is_contained, sniff_spine_item_format, spine_media_type_is_supported, toc_item_spine_index};
+
+// Algorithms used for font obfuscation rather than DRM; books using only these open fine.
+const FONT_OBFUSCATION_ALGORITHMS: [&str; 2] = ["http://www.idpf.org/2008/embedding", "http://ns.adobe.com/pdf/enc#RC"];
+// Unix file type bits, as zip tools store them in an entry's external attributes.
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+
+///////////////
+//   Types   //
+///////////////
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    fn label(&self) -> &'static str {
+        match self {
+            Severity::Ok => "OK",
+            Severity::Warning => "WARNING",
+            Severity::Error => "ERROR",
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            Severity::Ok => 0,
+            Severity::Warning => 1,
+            Severity::Error => 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Finding {
+    check: &'static str,
+    severity: Severity,
+    message: String,
+}
+
+impl Finding {
+    pub fn new(check: &'static str, severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            check,
+            severity,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Report<'a> {
+    path: &'a str,
+    worst: Severity,
+    findings: &'a [Finding],
+}
+
+///////////////////
+//   Container   //
+///////////////////
+
+fn check_container(path: &Path, findings: &mut Vec<Finding>) -> bool {
+    let mut archive = match File::open(path).map_err(zip::result::ZipError::from).and_then(|file| zip::ZipArchive::new(BufReader::new(file))) {
+        Ok(archive) => archive,
+        Err(e) => {
+            findings.push(Finding::new("container", Severity::Error, format!("Couldn't open file as a zip archive: {}.", e)));
+            return false
+        },
+    };
+
+    let mut non_utf8_names = Vec::new();
+    let mut escaping_names = Vec::new();
+    let mut symlink_names = Vec::new();
+    for entry_index in 0..archive.len() {
+        let entry = match archive.by_index_raw(entry_index) {
+            Ok(entry) => entry,
+            Err(e) => {
+                findings.push(Finding::new("container", Severity::Error, format!("Couldn't read zip entry {}: {}.", entry_index, e)));
+                return false
+            },
+        };
+        if std::str::from_utf8(entry.name_raw()).is_err() {
+            non_utf8_names.push(entry.name().to_string());
+        }
+        if entry.enclosed_name().is_none() {
+            escaping_names.push(entry.name().to_string());
+        }
+        if entry.unix_mode().is_some_and(|mode| mode & S_IFMT == S_IFLNK) {
+            symlink_names.push(entry.name().to_string());
+        }
+    }
+
+    findings.push(match non_utf8_names.is_empty() {
+        true => Finding::new("entry_names_utf8", Severity::Ok, "All zip entry names are UTF-8."),
+        false => Finding::new("entry_names_utf8", Severity::Error, format!("Zip entry names aren't valid UTF-8: {}.", non_utf8_names.join(", "))),
+    });
+    findings.push(match escaping_names.is_empty() {
+        true => Finding::new("zip_slip", Severity::Ok, "No zip entries point outside the archive."),
+        false => Finding::new("zip_slip", Severity::Error, format!("Zip entries point outside the archive: {}.", escaping_names.join(", "))),
+    });
+    findings.push(match symlink_names.is_empty() {
+        true => Finding::new("symlinks", Severity::Ok, "No zip entries are symlinks."),
+        false => Finding::new("symlinks", Severity::Warning, format!("Zip entries are symlinks, which are extracted as regular files holding the link target: {}.", symlink_names.join(", "))),
+    });
+
+    if archive.by_name("META-INF/rights.xml").is_ok() {
+        findings.push(Finding::new("drm", Severity::Error, "Book contains META-INF/rights.xml, indicating DRM."));
+    } else {
+        let mut encryption = Vec::new();
+        let encryption_read = archive.by_name("META-INF/encryption.xml").map(|mut entry| entry.read_to_end(&mut encryption));
+        findings.push(match encryption_read {
+            Ok(Ok(_)) => {
+                let algorithms = get_encryption_algorithms(&encryption);
+                match algorithms.iter().find(|algorithm| !FONT_OBFUSCATION_ALGORITHMS.contains(&algorithm.as_str())) {
+                    Some(algorithm) => Finding::new("drm", Severity::Error, format!("Book is encrypted with {}, indicating DRM.", algorithm)),
+                    None => Finding::new("drm", Severity::Ok, "Book's encryption.xml only covers font obfuscation."),
+                }
+            },
+            Ok(Err(e)) => Finding::new("drm", Severity::Error, format!("Couldn't read META-INF/encryption.xml: {}.", e)),
+            Err(_) => Finding::new("drm", Severity::Ok, "Book isn't encrypted."),
+        });
+    }
+
+    true
+}
+
+fn get_encryption_algorithms(encryption: &[u8]) -> Vec<String> {
+    let mut reader = quick_xml::Reader::from_reader(encryption);
+    let mut algorithms = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e) | Event::Empty(e)) if e.local_name().as_ref() == b"EncryptionMethod" => {
+                if let Ok(Some(algorithm)) = e.try_get_attribute("Algorithm") {
+                    algorithms.push(String::from_utf8_lossy(&algorithm.value).into_owned());
+                }
+            },
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            // An unreadable encryption.xml can't be trusted not to be DRM.
+            Err(_) => return vec![String::from("an unreadable encryption scheme")],
+        }
+    }
+    algorithms
+}
+
+/////////////////
+//   Package   //
+/////////////////
+
+fn check_package(book: &mut EpubDoc<BufReader<File>>, findings: &mut Vec<Finding>) {
+    findings.push(match get_book_cache_id(book) {
+        Some(id) => Finding::new("unique_identifier", Severity::Ok, format!("Book has identifier {}.", id)),
+        None => Finding::new("unique_identifier", Severity::Error, "Book has no unique identifier."),
+    });
+
+    findings.push(match book.mdata("title") {
+        Some(title) => Finding::new("title", Severity::Ok, format!("Book has title {}.", title)),
+        None => Finding::new("title", Severity::Error, "Book has no title metadata."),
+    });
+
+    let mut unsafe_resource_paths = book.resources.values()
+        .filter(|(path, _media_type)| !resource_path_is_contained(path))
+        .map(|(path, _media_type)| path.display().to_string())
+        .collect::<Vec<String>>();
+    unsafe_resource_paths.sort();
+    findings.push(match unsafe_resource_paths.is_empty() {
+        true => Finding::new("resource_paths", Severity::Ok, "All manifest paths stay inside the book."),
+        false => Finding::new("resource_paths", Severity::Error, format!("Manifest paths point outside the book: {}.", unsafe_resource_paths.join(", "))),
+    });
+
+    let spine_linearity = match get_spine_linearity(book) {
+        Ok(spine_linearity) => spine_linearity,
+        Err(e) => {
+            findings.push(Finding::new("package", Severity::Error, e));
+            return
+        },
+    };
+    let mut spine = Vec::new();
+    let mut missing_spine_ids = Vec::new();
+    let mut mislabeled_spine_items = Vec::new();
+    for (spine_position, spine_item_id) in book.spine.iter().enumerate() {
+        match book.resources.get(spine_item_id) {
+            Some((path, media_type)) => {
+                if !spine_media_type_is_supported(media_type) {
+                    mislabeled_spine_items.push((spine_item_id.clone(), path.clone(), media_type.clone()));
+                }
+                spine.push(SpineItem {
+                    id: spine_item_id.clone(),
+                    path: path.clone(),
+                    linear: spine_linearity.get(spine_position).copied().unwrap_or(true),
+                    has_navigation: true,
+                    word_count: None,
+                    is_cover: false,
+                });
+            },
+            None => missing_spine_ids.push(spine_item_id.clone()),
+        }
+    }
+
+    if !missing_spine_ids.is_empty() {
+        findings.push(Finding::new("spine", Severity::Error, format!("Spine references ids missing from the manifest: {}.", missing_spine_ids.join(", "))));
+    }
+    findings.push(match spine.iter().filter(|spine_item| spine_item.linear).count() {
+        0 => Finding::new("linear_spine", Severity::Error, "Spine has no linear items."),
+        linear_count => Finding::new("linear_spine", Severity::Ok, format!("Spine has {} linear items out of {}.", linear_count, spine.len())),
+    });
+    let mut seen_spine_paths = HashSet::new();
+    let mut duplicate_spine_paths = Vec::new();
+    for spine_item in &spine {
+        let path = spine_item.path.display().to_string();
+        if !seen_spine_paths.insert(&spine_item.path) && !duplicate_spine_paths.contains(&path) {
+            duplicate_spine_paths.push(path);
+        }
+    }
+    findings.push(match duplicate_spine_paths.is_empty() {
+        true => Finding::new("spine_duplicates", Severity::Ok, "No spine item is listed twice."),
+        false => Finding::new("spine_duplicates", Severity::Warning, format!("Spine lists items more than once, and opening keeps only the first place of each: {}.", duplicate_spine_paths.join(", "))),
+    });
+    // Opening works out what mislabeled items really are, so only --strict refuses them.
+    let mislabeled_spine_items = mislabeled_spine_items.into_iter().map(|(spine_item_id, path, media_type)| {
+        match book.get_resource(&spine_item_id).map(|(content, _media_type)| sniff_spine_item_format(&media_type, &content)).unwrap_or(SpineItemFormat::Unsupported) {
+            SpineItemFormat::Unsupported => format!("{} ({}, left as is)", path.display(), media_type),
+            format => format!("{} ({}, but is {})", path.display(), media_type, format.media_type()),
+        }
+    }).collect::<Vec<String>>();
+    findings.push(match mislabeled_spine_items.is_empty() {
+        true => Finding::new("spine_media_types", Severity::Ok, "All spine items are XHTML or SVG."),
+        false => Finding::new("spine_media_types", Severity::Warning, format!("Spine items are declared with media types other than XHTML or SVG: {}.", mislabeled_spine_items.join(", "))),
+    });
+
+    check_toc(book, &spine, findings);
+    check_content_documents(book, findings);
+    check_cover(book, findings);
+}
+
+fn check_toc(book: &EpubDoc<BufReader<File>>, spine: &[SpineItem], findings: &mut Vec<Finding>) {
+    let toc = book.toc.iter().map(|nav_point| localize_toc_item_format(nav_point.clone(), 0)).collect::<Vec<TocItem>>();
+    let manifest_paths = book.resources.values().map(|(path, _media_type)| path).collect::<Vec<&PathBuf>>();
+    let spine_index = SpineIndex::new(spine);
+
+    let mut nonspine_targets = Vec::new();
+    let mut unresolvable_targets = Vec::new();
+    for toc_item in flatten_toc_items(&toc) {
+        if toc_item_spine_index(toc_item, &spine_index).is_some() {
+            continue
+        }
+        match manifest_paths.contains(&&toc_item.path) {
+            true => nonspine_targets.push(toc_item.iri.display().to_string()),
+            false => unresolvable_targets.push(toc_item.iri.display().to_string()),
+        }
+    }
+
+    findings.push(if !unresolvable_targets.is_empty() {
+        Finding::new("toc_targets", Severity::Error, format!("TOC entries point at files missing from the manifest: {}.", unresolvable_targets.join(", ")))
+    } else if !nonspine_targets.is_empty() {
+        Finding::new("toc_targets", Severity::Warning, format!("TOC entries point at files outside the spine, so the index will list the TOC separately: {}.", nonspine_targets.join(", ")))
+    } else {
+        Finding::new("toc_targets", Severity::Ok, format!("All {} TOC entries point into the spine.", flatten_toc_items(&toc).len()))
+    });
+}
+
+fn check_content_documents(book: &mut EpubDoc<BufReader<File>>, findings: &mut Vec<Finding>) {
+    let xhtml_spine_items = book.spine.iter()
+        .filter_map(|spine_item_id| book.resources.get(spine_item_id))
+        .filter(|(_path, media_type)| media_type == "application/xhtml+xml")
+        .map(|(path, _media_type)| path.clone())
+        .collect::<Vec<PathBuf>>();
+
+    let mut problems = Vec::new();
+    for path in &xhtml_spine_items {
+        let Some(xhtml) = book.get_resource_by_path(path) else {
+            problems.push(format!("{} (missing from archive)", path.display()));
+            continue
+        };
+        let mut reader = new_strict_xhtml_reader(&xhtml);
+        loop {
+            match reader.read_event() {
+                Ok(Event::Eof) => break,
+                Ok(_) => (),
+                Err(e) => {
+                    problems.push(format!("{} (at byte {}: {})", path.display(), reader.error_position(), e));
+                    break
+                },
+            }
+        }
+    }
+
+    findings.push(match problems.is_empty() {
+        true => Finding::new("content_documents", Severity::Ok, format!("All {} XHTML spine items parse.", xhtml_spine_items.len())),
+        false => Finding::new("content_documents", Severity::Error, format!("XHTML spine items fail to parse: {}.", problems.join("; "))),
+    });
+}
+
+fn check_cover(book: &mut EpubDoc<BufReader<File>>, findings: &mut Vec<Finding>) {
+    let Some(cover_id) = book.get_cover_id() else {
+        findings.push(Finding::new("cover", Severity::Ok, "Book doesn't declare a cover."));
+        return
+    };
+    let cover_path = book.resources.get(&cover_id).map(|(path, _media_type)| path.clone());
+    findings.push(match cover_path {
+        None => Finding::new("cover", Severity::Error, format!("Cover id {} is missing from the manifest.", cover_id)),
+        Some(path) => match book.get_resource_by_path(&path) {
+            Some(_) => Finding::new("cover", Severity::Ok, format!("Cover {} exists.", path.display())),
+            None => Finding::new("cover", Severity::Error, format!("Cover {} is missing from the archive.", path.display())),
+        },
+    });
+}
+
+//////////////
+//   Main   //
+//////////////
+
+pub fn check_epub(path: &Path) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    if !check_container(path, &mut findings) {
+        return findings
+    }
+    match EpubDoc::new(path) {
+        Ok(mut book) => check_package(&mut book, &mut findings),
+        Err(e) => findings.push(Finding::new("package", Severity::Error, format!("Couldn't parse book as epub: {}.", e))),
+    }
+    findings
+}
+
+pub fn report_findings(path: &str, findings: &[Finding], json: bool) -> i32 {
+    let worst = findings.iter().map(|finding| finding.severity).max().unwrap_or(Severity::Ok);
+
+    if json {
+        let report = Report {
+            path,
+            worst,
+            findings,
+        };
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        for finding in findings {
+            println!("[{}] {}: {}", finding.severity.label(), finding.check, finding.message);
+        }
+    }
+
+    worst.exit_code()
+}
+
+pub fn run(args: &CheckArgs, output_format: OutputFormat) -> i32 {
+    let findings = check_epub(Path::new(&args.epub));
+    if output_format == OutputFormat::Json {
+        // The check itself succeeded however bad the book is; the findings and exit status say how bad.
+        let report = Report {
+            path: &args.epub,
+            worst: findings.iter().map(|finding| finding.severity).max().unwrap_or(Severity::Ok),
+            findings: &findings,
+        };
+        output::print_envelope(&serde_json::to_value(&report).unwrap(), &[], None);
+        return report.worst.exit_code()
+    }
+    report_findings(&args.epub, &findings, args.json)
+}
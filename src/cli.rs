@@ -0,0 +1,682 @@
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::PathBuf;
+
+use argh::{FromArgValue, FromArgs};
+
+use crate::book_paths::STDIN_PATH;
+
+#[derive(Clone, Debug, FromArgs)]
+/// Minimalist EPUB reader.
+pub struct Args {
+    #[argh(subcommand)]
+    pub subcommand: Option<Subcommand>,
+    #[argh(positional)]
+    /// epub, cbz, fb2, html, or markdown paths, unpacked epub directories, directories to scan, or glob patterns to open, or '-' to read a book from stdin
+    pub epubs: Vec<String>,
+    #[argh(option, short = 'b')]
+    /// browser name (in config.toml) or command to open output with, optionally with arguments and a {{path}} or {{url}} placeholder
+    pub browser: Option<String>,
+    #[argh(switch, short = 'B')]
+    /// don't open output in browser
+    pub browser_skip: bool,
+    #[argh(switch)]
+    /// open books in a bare reading window, without tabs or toolbars, in browsers rib knows how to (Chromium-based browsers and Firefox)
+    pub reader_window: bool,
+    #[argh(switch)]
+    /// open each book with its own browser command, rather than passing them all to one
+    pub separate_windows: bool,
+    #[argh(switch)]
+    /// don't wait the couple of seconds it takes to tell whether the browser failed to start
+    pub no_wait: bool,
+    #[argh(switch)]
+    /// open each book in the browser as soon as it's ready, rather than all of them once every book is open
+    pub open_as_ready: bool,
+    #[argh(option, short = 's')]
+    /// stylesheet name (in config.toml) to apply to output
+    pub stylesheet: Option<String>,
+    #[argh(option)]
+    /// how many levels of the table of contents the index lists, with deeper entries counted rather than listed; 0 lists only the spine
+    pub toc_depth: Option<usize>,
+    #[argh(switch)]
+    /// use the default stylesheet rather than the ones chosen for individual books with 'library style set'
+    pub ignore_preferred: bool,
+    #[argh(switch)]
+    /// print each stylesheet property the books open with and where it came from; without books, explain the stylesheet used when none is chosen for a book
+    pub explain_style: bool,
+    #[argh(switch)]
+    /// switch off the books' own stylesheets, leaving only rib's styles and the browser's defaults; browsers with a page style menu can switch them back on as "Book styles"
+    pub no_book_css: bool,
+    #[argh(switch)]
+    /// drop the style attributes on the books' elements as well
+    pub strip_inline_styles: bool,
+    #[argh(switch)]
+    /// drop the properties the stylesheet overrides from the style attributes on the books' elements, which beat even overriding styles
+    pub force_styles: bool,
+    #[argh(option)]
+    /// cap how tall images are shown, in px, over the stylesheet and default_max_image_height in config
+    pub max_image_height: Option<NonZeroU32>,
+    #[argh(option)]
+    /// cap how wide images are shown, in px, over the stylesheet and default_max_image_width in config
+    pub max_image_width: Option<NonZeroU32>,
+    #[argh(option)]
+    /// where to open the book: 'spine:N' for the Nth linear spine item, a content path, or a table of contents label substring
+    pub at: Option<String>,
+    #[argh(switch)]
+    /// open the books from a temporary dir without adding them to the library
+    pub ephemeral: bool,
+    #[argh(switch)]
+    /// add the books to the library without counting them toward max_cache_books and max_cache_bytes, so they're neither purged to meet the limits nor purge other books
+    pub no_count: bool,
+    #[argh(switch)]
+    /// serve the books over HTTP on 127.0.0.1 instead of opening them as files, until interrupted
+    pub serve: bool,
+    #[argh(option)]
+    /// with --serve, the port to listen on instead of a free one
+    pub port: Option<u16>,
+    #[argh(option)]
+    /// with --serve, stop after this many seconds without a request
+    pub idle_timeout: Option<u64>,
+    #[argh(switch)]
+    /// keep running after opening a book, extracting it again whenever its source file changes; with --serve, its open pages reload themselves as well
+    pub watch: bool,
+    #[argh(switch)]
+    /// render and record the books but print the path of each one's entry point instead of opening a browser
+    pub print_path: bool,
+    #[argh(switch)]
+    /// with --print-path, print file:// URLs instead of paths
+    pub url: bool,
+    #[argh(switch)]
+    /// with --print-path, print a JSON object with each book's id, title, and path
+    pub json: bool,
+    #[argh(switch)]
+    /// extract books already in the library again even if their source files haven't changed
+    pub force_reimport: bool,
+    #[argh(switch)]
+    /// open books already in the library from their existing copy, even if their source files have changed
+    pub no_reimport: bool,
+    #[argh(option)]
+    /// what to do when a book has the same text as one already in the library under another id: 'replace' it, 'keep-both', or 'skip' importing and open the library's copy; asks if not given
+    pub dedupe: Option<DedupeMode>,
+    #[argh(option)]
+    /// whether to unwrap the koboSpan wrappers Kobo adds around every sentence of a kepub: 'auto' (the default) for books that have them, 'always', or 'never'
+    pub strip_kobo_spans: Option<KoboSpanMode>,
+    #[argh(switch)]
+    /// leave links whose case doesn't match any file's as they are, rather than pointing them at the file they match apart from case
+    pub strict_paths: bool,
+    #[argh(switch)]
+    /// add navigation to every spine item, including nonlinear ones that neither the table of contents nor any reachable page links to
+    pub all_wrappers: bool,
+    #[argh(switch)]
+    /// report how long each stage of opening each book took, on stderr, or in the JSON with --print-path --json
+    pub timings: bool,
+    #[argh(switch, short = 'r')]
+    /// scan directory arguments recursively for epubs
+    pub recursive: bool,
+    #[argh(option, short = 'j')]
+    /// how many books to open at once, by default as many as there are CPUs
+    pub jobs: Option<NonZeroUsize>,
+    #[argh(switch)]
+    /// treat non-book files found while scanning directories as errors, and refuse books whose spine items have the wrong media type rather than working out what they are
+    pub strict: bool,
+    #[argh(option)]
+    /// directory to keep the library in, overriding library_dir in config.toml
+    pub library_dir: Option<PathBuf>,
+    #[argh(option)]
+    /// how to print results: 'plain' (the default), or 'json' for one JSON object with the result, warnings, and a coded error on failure; goes before any subcommand
+    pub format: Option<OutputFormat>,
+    #[argh(switch, short = 'v')]
+    /// also log each extraction step and how long it took
+    pub verbose: bool,
+    #[argh(switch, short = 'q')]
+    /// only log errors
+    pub quiet: bool,
+    // To add: single-book overrides for individual styles
+}
+
+impl Args {
+    pub fn output_format(&self) -> OutputFormat {
+        self.format.unwrap_or_default()
+    }
+
+    // Running rib with nothing to do shows this rather than a bare error. argh only renders help as the early exit of
+    // parsing --help, so that's how it's got.
+    pub fn usage() -> String {
+        Args::from_args(&["rib"], &["--help"]).err().map(|early_exit| early_exit.output).unwrap_or_default()
+    }
+
+    pub fn has_nothing_to_do(&self) -> bool {
+        // --explain-style on its own explains the stylesheet books open with by default.
+        self.subcommand.is_none() && self.epubs.is_empty() && !self.explain_style
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        // Reject option combinations where one option would silently do nothing.
+        if self.subcommand.is_some() {
+            let open_options = [
+                (!self.epubs.is_empty(), "epub paths"),
+                (self.browser.is_some(), "--browser"),
+                (self.browser_skip, "--browser-skip"),
+                (self.no_wait, "--no-wait"),
+                (self.open_as_ready, "--open-as-ready"),
+                (self.separate_windows, "--separate-windows"),
+                (self.reader_window, "--reader-window"),
+                (self.stylesheet.is_some(), "--stylesheet"),
+                (self.ignore_preferred, "--ignore-preferred"),
+                (self.explain_style, "--explain-style"),
+                (self.no_book_css, "--no-book-css"),
+                (self.strip_inline_styles, "--strip-inline-styles"),
+                (self.force_styles, "--force-styles"),
+                (self.max_image_height.is_some(), "--max-image-height"),
+                (self.max_image_width.is_some(), "--max-image-width"),
+                (self.toc_depth.is_some(), "--toc-depth"),
+                (self.at.is_some(), "--at"),
+                (self.ephemeral, "--ephemeral"),
+                (self.no_count, "--no-count"),
+                (self.serve, "--serve"),
+                (self.port.is_some(), "--port"),
+                (self.idle_timeout.is_some(), "--idle-timeout"),
+                (self.watch, "--watch"),
+                (self.print_path, "--print-path"),
+                (self.url, "--url"),
+                (self.json, "--json"),
+                (self.force_reimport, "--force-reimport"),
+                (self.no_reimport, "--no-reimport"),
+                (self.dedupe.is_some(), "--dedupe"),
+                (self.strip_kobo_spans.is_some(), "--strip-kobo-spans"),
+                (self.strict_paths, "--strict-paths"),
+                (self.all_wrappers, "--all-wrappers"),
+                (self.timings, "--timings"),
+                (self.recursive, "--recursive"),
+                (self.jobs.is_some(), "--jobs"),
+                (self.strict, "--strict"),
+            ];
+            let given_open_options = open_options.iter().filter(|(given, _name)| *given).map(|(_given, name)| *name).collect::<Vec<&str>>();
+            if !given_open_options.is_empty() {
+                return Err(format!("These options only apply when opening books, not to subcommands: {}.", given_open_options.join(", ")))
+            }
+        }
+        if self.force_reimport && self.no_reimport {
+            return Err(String::from("--force-reimport and --no-reimport conflict."))
+        }
+        if self.ephemeral && (self.force_reimport || self.no_reimport) {
+            return Err(String::from("--force-reimport and --no-reimport only apply to books in the library, not with --ephemeral."))
+        }
+        if self.ephemeral && self.ignore_preferred {
+            return Err(String::from("--ignore-preferred only applies to books in the library, not with --ephemeral."))
+        }
+        if self.stylesheet.is_some() && self.ignore_preferred {
+            return Err(String::from("--ignore-preferred has no effect with --stylesheet, which already takes precedence over the stylesheets chosen for individual books."))
+        }
+        if self.ephemeral && self.no_count {
+            return Err(String::from("--no-count only applies to books in the library, not with --ephemeral."))
+        }
+        if self.ephemeral && self.dedupe.is_some() {
+            return Err(String::from("--dedupe only applies to books in the library, not with --ephemeral."))
+        }
+        if self.verbose && self.quiet {
+            return Err(String::from("--verbose and --quiet conflict."))
+        }
+        if self.browser_skip && self.browser.is_some() {
+            return Err(String::from("--browser and --browser-skip conflict: --browser-skip means no browser is opened."))
+        }
+        if self.json && self.output_format() == OutputFormat::Json {
+            return Err(String::from("--json and --format json conflict: with --format json, each book is reported in the one JSON object."))
+        }
+        if self.explain_style && self.json {
+            return Err(String::from("--explain-style and --json conflict: use --format json to get the explanation as JSON along with each book."))
+        }
+        if !self.print_path && (self.url || self.json) {
+            return Err(String::from("--url and --json only apply with --print-path."))
+        }
+        if !self.serve && (self.port.is_some() || self.idle_timeout.is_some()) {
+            return Err(String::from("--port and --idle-timeout only apply with --serve."))
+        }
+        if self.serve && self.print_path {
+            return Err(String::from("--serve and --print-path conflict: --print-path exits after rendering, but --serve keeps running to serve the books."))
+        }
+        if self.watch && (self.print_path || self.output_format() == OutputFormat::Json) {
+            return Err(String::from("--watch conflicts with --print-path and --format json, which exit once the books are rendered."))
+        }
+        if self.watch && (self.ephemeral || self.no_reimport) {
+            return Err(String::from("--watch conflicts with --ephemeral and --no-reimport: it works by extracting the library's copy of the book again."))
+        }
+        if self.watch && self.epubs.iter().any(|epub| epub == STDIN_PATH) {
+            return Err(String::from("--watch needs a file to watch, so it can't read the book from stdin."))
+        }
+        if self.print_path && (self.browser.is_some() || self.browser_skip) {
+            return Err(String::from("--print-path conflicts with --browser and --browser-skip: --print-path means no browser is opened."))
+        }
+        if self.open_as_ready && (self.browser_skip || self.print_path) {
+            return Err(String::from("--open-as-ready conflicts with --browser-skip and --print-path, which mean no browser is opened."))
+        }
+        if (self.no_wait || self.separate_windows || self.reader_window) && (self.browser_skip || self.print_path) {
+            return Err(String::from("--no-wait, --separate-windows, and --reader-window only apply when a browser is opened, not with --browser-skip or --print-path."))
+        }
+        if self.browser_skip && self.at.is_some() {
+            return Err(String::from("--at and --browser-skip conflict: --at chooses where the browser opens, and --browser-skip means no browser is opened."))
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Plain,
+    Json,
+}
+
+impl FromArgValue for OutputFormat {
+    fn from_arg_value(value: &str) -> Result<Self, String> {
+        match value {
+            "plain" => Ok(Self::Plain),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("expected 'plain' or 'json', not '{}'", value)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KoboSpanMode {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromArgValue for KoboSpanMode {
+    fn from_arg_value(value: &str) -> Result<Self, String> {
+        match value {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!("expected 'auto', 'always', or 'never', not '{}'", value)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DedupeMode {
+    Replace,
+    KeepBoth,
+    Skip,
+}
+
+impl FromArgValue for DedupeMode {
+    fn from_arg_value(value: &str) -> Result<Self, String> {
+        match value {
+            "replace" => Ok(Self::Replace),
+            "keep-both" => Ok(Self::KeepBoth),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!("expected 'replace', 'keep-both', or 'skip', not '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand)]
+pub enum Subcommand {
+    Check(CheckArgs),
+    Config(ConfigArgs),
+    Library(LibraryArgs),
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "check")]
+/// Check whether an epub can be opened, without adding it to the cache.
+pub struct CheckArgs {
+    #[argh(positional)]
+    /// epub path to check
+    pub epub: String,
+    #[argh(switch)]
+    /// output the report as JSON
+    pub json: bool,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "config")]
+/// Inspect the config file.
+pub struct ConfigArgs {
+    #[argh(subcommand)]
+    pub subcommand: ConfigSubcommand,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand)]
+pub enum ConfigSubcommand {
+    Check(ConfigCheckArgs),
+    Effective(ConfigEffectiveArgs),
+    Path(ConfigPathArgs),
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "check")]
+/// Validate config.toml and report any problems. Exits 0 if it's clean, 1 on warnings, and 2 on errors.
+pub struct ConfigCheckArgs {
+    #[argh(switch)]
+    /// print the report as JSON
+    pub json: bool,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "effective")]
+/// Print the fully-resolved configuration, noting where each value came from.
+pub struct ConfigEffectiveArgs {}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "path")]
+/// Print the path to config.toml. Exits nonzero if it doesn't exist.
+pub struct ConfigPathArgs {
+    #[argh(switch)]
+    /// write the default config first if none exists
+    pub create: bool,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "library")]
+/// Inspect or manage the library of cached books.
+pub struct LibraryArgs {
+    #[argh(subcommand)]
+    pub subcommand: LibrarySubcommand,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand)]
+pub enum LibrarySubcommand {
+    Clear(LibraryClearArgs),
+    Count(LibraryCountArgs),
+    Export(LibraryExportArgs),
+    Extraction(LibraryExtractionArgs),
+    History(LibraryHistoryArgs),
+    Import(LibraryImportArgs),
+    Info(LibraryInfoArgs),
+    List(LibraryListArgs),
+    Locate(LibraryLocateArgs),
+    NoCount(LibraryNoCountArgs),
+    Path(LibraryPathArgs),
+    Prune(LibraryPruneArgs),
+    Search(LibrarySearchArgs),
+    Shelf(LibraryShelfArgs),
+    Style(LibraryStyleArgs),
+    Verify(LibraryVerifyArgs),
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "clear")]
+/// Remove books from the library.
+pub struct LibraryClearArgs {
+    #[argh(positional)]
+    /// ids of books to remove, unique starts of ids, or 'title:' followed by part of their titles
+    pub ids: Vec<String>,
+    #[argh(switch)]
+    /// remove every book
+    pub all: bool,
+    #[argh(switch, short = 'y')]
+    /// remove the books without asking for confirmation
+    pub yes: bool,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "list")]
+/// List the books in the library, least recently opened first.
+pub struct LibraryListArgs {
+    #[argh(switch)]
+    /// also show the file each book was imported from
+    pub verbose: bool,
+    #[argh(switch)]
+    /// only show books with the same text as another book in the library, grouped together
+    pub duplicates: bool,
+    #[argh(switch)]
+    /// also show whether each book's source file is still where it was imported from, and whether it has changed since, by its size and modified time
+    pub sources: bool,
+    #[argh(switch)]
+    /// like --sources, but compare the text of each source that's still there with the library's copy, which means reading every source in full
+    pub verify: bool,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "history")]
+/// Show when books in the library were recently opened, with how many were opened this month, the most opened, and reading streaks.
+pub struct LibraryHistoryArgs {
+    #[argh(option)]
+    /// only show this book: its id, a unique start of it, or 'title:' followed by part of its title
+    pub book: Option<String>,
+    #[argh(option)]
+    /// how many days back to list openings for (default 30)
+    pub days: Option<u32>,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "info")]
+/// Show everything recorded about a book in the library.
+pub struct LibraryInfoArgs {
+    #[argh(positional)]
+    /// id of the book, a unique start of it, or 'title:' followed by part of its title
+    pub id: String,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "extraction")]
+/// Show what a book in the library was last extracted with, and the CSS rib wrote for it.
+pub struct LibraryExtractionArgs {
+    #[argh(positional)]
+    /// id of the book, a unique start of it, or 'title:' followed by part of its title
+    pub id: String,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "locate")]
+/// Print the path of the file a book was imported from. Exits nonzero if none is recorded.
+pub struct LibraryLocateArgs {
+    #[argh(positional)]
+    /// id of the book, a unique start of it, or 'title:' followed by part of its title
+    pub id: String,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "path")]
+/// Print the path to the library directory. Exits nonzero if it doesn't exist.
+pub struct LibraryPathArgs {
+    #[argh(switch)]
+    /// create the directory first if it doesn't exist
+    pub create: bool,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "prune")]
+/// Remove library entries whose book directories are missing, e.g. because a synced library hasn't finished syncing.
+pub struct LibraryPruneArgs {}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "search")]
+/// Search the text of the books in the library, printing each match with its chapter and some context. Exits nonzero if nothing matches.
+pub struct LibrarySearchArgs {
+    #[argh(positional)]
+    /// text to search for, ignoring case and differences in whitespace
+    pub query: String,
+    #[argh(option)]
+    /// only search this book: its id, a unique start of it, or 'title:' followed by part of its title
+    pub book: Option<String>,
+    #[argh(switch)]
+    /// open the chapter with the most matches in a browser, at the first of them
+    pub open: bool,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "style")]
+/// Choose the stylesheet a book opens with when none is passed with --stylesheet.
+pub struct LibraryStyleArgs {
+    #[argh(subcommand)]
+    pub subcommand: LibraryStyleSubcommand,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand)]
+pub enum LibraryStyleSubcommand {
+    Set(LibraryStyleSetArgs),
+    Unset(LibraryStyleUnsetArgs),
+    Refresh(LibraryStyleRefreshArgs),
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "set")]
+/// Open a book with the given stylesheet from now on, in place of the default one.
+pub struct LibraryStyleSetArgs {
+    #[argh(positional)]
+    /// id of the book, a unique start of it, or 'title:' followed by part of its title
+    pub id: String,
+    #[argh(positional)]
+    /// stylesheet name (in config.toml)
+    pub stylesheet: String,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "unset")]
+/// Open a book with the default stylesheet again.
+pub struct LibraryStyleUnsetArgs {
+    #[argh(positional)]
+    /// id of the book, a unique start of it, or 'title:' followed by part of its title
+    pub id: String,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "refresh")]
+/// Restyle books already in the library with their stylesheets as config now defines them, without extracting them again.
+pub struct LibraryStyleRefreshArgs {
+    #[argh(positional)]
+    /// only restyle books extracted with these stylesheets (in config.toml)
+    pub stylesheets: Vec<String>,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "count")]
+/// Count a book toward max_cache_books and max_cache_bytes again, after 'library no-count' or --no-count.
+pub struct LibraryCountArgs {
+    #[argh(positional)]
+    /// id of the book, a unique start of it, or 'title:' followed by part of its title
+    pub id: String,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "no-count")]
+/// Leave a book out of max_cache_books and max_cache_bytes, so it's never purged to meet them.
+pub struct LibraryNoCountArgs {
+    #[argh(positional)]
+    /// id of the book, a unique start of it, or 'title:' followed by part of its title
+    pub id: String,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "export")]
+/// Back up books in the library to a zip archive, with their library entries, for 'library import' to restore here or on another machine.
+pub struct LibraryExportArgs {
+    #[argh(option)]
+    /// path to write the zip archive to, e.g. backup.zip; tar and zstd archives aren't supported
+    pub out: PathBuf,
+    #[argh(option)]
+    /// only back up this book, by id, a unique start of it, or 'title:' followed by part of its title; can be given more than once
+    pub ids: Vec<String>,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "import")]
+/// Restore books from an archive made by 'library export'. A book already in the library is only replaced if the archived copy was opened more recently.
+pub struct LibraryImportArgs {
+    #[argh(positional)]
+    /// path to the archive
+    pub archive: PathBuf,
+    #[argh(option)]
+    /// only restore the book with this id; can be given more than once
+    pub ids: Vec<String>,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "verify")]
+/// Check that books' copies in the library still have every file their entries and indexes point to, e.g. after a bad sync. Exits nonzero if any don't.
+pub struct LibraryVerifyArgs {
+    #[argh(option)]
+    /// only check this book, by id, a unique start of it, or 'title:' followed by part of its title; can be given more than once
+    pub ids: Vec<String>,
+    #[argh(switch)]
+    /// also check that every page the index links to parses as XML, and that each book's files add up to the size recorded for it
+    pub deep: bool,
+    #[argh(switch)]
+    /// remove books found broken from the library, so they're extracted afresh the next time they're opened; books whose source files are gone are kept
+    pub repair: bool,
+}
+
+#[derive(Clone, Debug, FromArgs)]
+#[argh(subcommand, name = "shelf")]
+/// Open a page showing every book in the library by its cover, most recently opened first.
+pub struct LibraryShelfArgs {
+    #[argh(switch)]
+    /// print the page's path instead of opening it in a browser
+    pub print_path: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::from_args(&["rib"], args).map_err(|early_exit| early_exit.output)
+    }
+
+    #[test]
+    fn opens_positional_paths_with_style_flags_by_default() {
+        let args = parse(&["-s", "dark", "--no-book-css", "--max-image-height", "800", "a.epub", "b.fb2"]).unwrap();
+        assert!(args.subcommand.is_none());
+        assert_eq!(args.epubs, ["a.epub", "b.fb2"]);
+        assert_eq!(args.stylesheet.as_deref(), Some("dark"));
+        assert!(args.no_book_css);
+        assert_eq!(args.max_image_height.map(NonZeroU32::get), Some(800));
+        assert!(args.validate().is_ok());
+    }
+
+    #[test]
+    fn parses_without_any_arguments() {
+        // With nothing to open, main prints the help instead of parsing failing.
+        let args = parse(&[]).unwrap();
+        assert!(args.subcommand.is_none() && args.epubs.is_empty());
+    }
+
+    #[test]
+    fn has_usage_for_nothing_to_do() {
+        assert!(parse(&[]).unwrap().has_nothing_to_do());
+        assert!(!parse(&["--explain-style"]).unwrap().has_nothing_to_do());
+        assert!(!parse(&["library", "list"]).unwrap().has_nothing_to_do());
+        assert!(Args::usage().starts_with("Usage: rib"));
+    }
+
+    #[test]
+    fn routes_subcommands() {
+        assert!(matches!(parse(&["config", "path", "--create"]).unwrap().subcommand, Some(Subcommand::Config(ConfigArgs { subcommand: ConfigSubcommand::Path(ConfigPathArgs { create: true }) }))));
+        assert!(matches!(parse(&["library", "list", "--verbose"]).unwrap().subcommand, Some(Subcommand::Library(LibraryArgs { subcommand: LibrarySubcommand::List(LibraryListArgs { verbose: true, .. }) }))));
+        assert!(matches!(parse(&["library", "path"]).unwrap().subcommand, Some(Subcommand::Library(LibraryArgs { subcommand: LibrarySubcommand::Path(_) }))));
+        let Some(Subcommand::Library(LibraryArgs { subcommand: LibrarySubcommand::Clear(clear_args) })) = parse(&["library", "clear", "-y", "urn:a", "title:Dune"]).unwrap().subcommand else {
+            panic!("library clear wasn't parsed as such");
+        };
+        assert!(clear_args.yes && !clear_args.all);
+        assert_eq!(clear_args.ids, ["urn:a", "title:Dune"]);
+    }
+
+    #[test]
+    fn rejects_open_options_with_subcommands() {
+        let args = parse(&["-s", "dark", "--toc-depth", "2", "library", "list"]).unwrap();
+        assert_eq!(args.validate(), Err(String::from("These options only apply when opening books, not to subcommands: --stylesheet, --toc-depth.")));
+    }
+
+    #[test]
+    fn parses_how_books_open_together() {
+        let args = parse(&["-j", "2", "--open-as-ready", "a.epub", "b.epub"]).unwrap();
+        assert_eq!(args.jobs.map(NonZeroUsize::get), Some(2));
+        assert!(args.open_as_ready && args.validate().is_ok());
+        assert!(parse(&["--jobs", "0", "a.epub"]).is_err());
+        assert!(parse(&["--open-as-ready", "-B", "a.epub"]).unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_flags_and_subcommands() {
+        assert!(parse(&["--no-such-flag", "a.epub"]).is_err());
+        assert!(parse(&["library", "no-such-subcommand"]).is_err());
+    }
+}
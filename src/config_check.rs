@@ -0,0 +1,166 @@
+use std::fs::read_to_string;
+use std::path::Path;
+
+use toml::{Table, Value};
+
+use crate::browser;
+use crate::check::{Finding, Severity, report_findings};
+use crate::cli::ConfigCheckArgs;
+use crate::helpers::{closest_match, find_executable};
+use crate::{Config, Stylesheet};
+
+pub const CONFIG_KEYS: [&str; 19] = ["default_browser", "max_cache_books", "max_cache_bytes", "truncation_policy", "default_stylesheet", "stylesheets", "default_max_image_height", "default_max_image_width", "cover_navigation", "show_page_numbers", "toc_depth", "words_per_minute", "creator_serial_comma", "max_history_records", "browsers", "wsl_linux_browser", "single_book_browsers", "reader_window_arguments", "library_dir"];
+pub const STYLESHEET_KEYS: [&str; 18] = ["font", "font_size", "text_color", "link_color", "background_color", "line_spacing", "indentation", "margin_size", "max_width", "limit_image_size_to_viewport_size", "max_image_height", "max_image_width", "freeform_css_no_override", "freeform_css_override", "strip_book_css", "strip_inline_styles", "force_styles", "csp"];
+const STYLE_KEYS: [&str; 2] = ["value", "override_book"];
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+fn describe_unknown_key(location: &str, key: &str, candidates: &[&str]) -> String {
+    match closest_match(key, candidates.iter().copied()) {
+        Some(suggestion) => format!("Unknown key '{}' in {}. Did you mean '{}'?", key, location, suggestion),
+        None => format!("Unknown key '{}' in {}.", key, location),
+    }
+}
+
+fn line_and_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line, column)
+}
+
+fn stylesheet_problems(name: &str, value: &Value) -> Vec<String> {
+    let location = format!("stylesheet '{}'", name);
+    let Some(table) = value.as_table() else {
+        return vec![format!("{} isn't a table.", location)]
+    };
+
+    let mut problems = Vec::new();
+    for (key, style) in table {
+        if !STYLESHEET_KEYS.contains(&key.as_str()) {
+            problems.push(describe_unknown_key(&location, key, &STYLESHEET_KEYS));
+        } else if let Some(style_table) = style.as_table() {
+            for style_key in style_table.keys().filter(|style_key| !STYLE_KEYS.contains(&style_key.as_str())) {
+                problems.push(describe_unknown_key(&format!("{}.{}", location, key), style_key, &STYLE_KEYS));
+            }
+        }
+    }
+    if !problems.is_empty() {
+        return problems
+    }
+
+    match value.clone().try_into::<Stylesheet>() {
+        Ok(stylesheet) => stylesheet.validate().into_iter().map(|problem| format!("In {}: {}", location, problem)).collect(),
+        Err(e) => vec![format!("In {}: {}.", location, e.message())],
+    }
+}
+
+//////////////////
+//   Checking   //
+//////////////////
+
+pub fn load_config_leniently(text: &str) -> (Config, Vec<String>) {
+    // Starts from the defaults and overlays each entry of the file that deserializes on its own, so one bad
+    // entry costs only itself.
+    let mut problems = Vec::new();
+    let mut accepted_keys = Vec::new();
+    let mut table = include_str!("default_config.toml").parse::<Table>().expect("Default config is invalid. (This shouldn't happen.)");
+    let file_table = match text.parse::<Table>() {
+        Ok(file_table) => file_table,
+        Err(e) => {
+            let location = match e.span() {
+                Some(span) => {
+                    let (line, column) = line_and_column(text, span.start);
+                    format!(" at line {}, column {}", line, column)
+                },
+                None => String::new(),
+            };
+            problems.push(format!("Config file isn't valid TOML{}: {}.", location, e.message().trim().replace('\n', "; ")));
+            return (Config::default(), problems)
+        },
+    };
+
+    for (key, value) in file_table {
+        if !CONFIG_KEYS.contains(&key.as_str()) {
+            problems.push(describe_unknown_key("config", &key, &CONFIG_KEYS));
+            continue
+        }
+        if key == "stylesheets" {
+            let Some(stylesheets) = value.as_table() else {
+                problems.push(String::from("'stylesheets' isn't a table."));
+                continue
+            };
+            let mut valid_stylesheets = Table::new();
+            for (name, stylesheet) in stylesheets {
+                let stylesheet_problems = stylesheet_problems(name, stylesheet);
+                if stylesheet_problems.is_empty() {
+                    valid_stylesheets.insert(name.clone(), stylesheet.clone());
+                } else {
+                    problems.extend(stylesheet_problems);
+                }
+            }
+            table.insert(key.clone(), Value::Table(valid_stylesheets));
+            accepted_keys.push(key);
+            continue
+        }
+
+        let mut candidate = table.clone();
+        candidate.insert(key.clone(), value);
+        match Value::Table(candidate.clone()).try_into::<Config>() {
+            Ok(_) => {
+                table = candidate;
+                accepted_keys.push(key);
+            },
+            Err(e) => problems.push(format!("Invalid value for '{}': {}.", key, e.message())),
+        }
+    }
+
+    let mut config = Value::Table(table).try_into::<Config>().expect("Leniently-loaded config is invalid. (This shouldn't happen.)");
+    for key in accepted_keys {
+        config.mark_from_file(&key);
+    }
+    if let Err(e) = config.get_stylesheet(None) {
+        problems.push(format!("Invalid default_stylesheet: {}", e));
+    }
+    (config, problems)
+}
+
+fn check_browser_command(findings: &mut Vec<Finding>, description: &str, command: &[String]) {
+    match command.first() {
+        Some(program) => match find_executable(program) {
+            Some(browser_path) => findings.push(Finding::new("browser", Severity::Ok, format!("{} found at {}.", description, browser_path.display()))),
+            None => findings.push(Finding::new("browser", Severity::Warning, format!("{} runs '{}', which wasn't found on PATH.", description, program))),
+        },
+        None => findings.push(Finding::new("browser", Severity::Error, format!("{} has an empty command.", description))),
+    }
+}
+
+pub fn check_config(path: &Path) -> Vec<Finding> {
+    let text = match read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => return vec![Finding::new("file", Severity::Warning, format!("Couldn't read config file ({}). The defaults will be written the next time a book is opened, or by 'rib config path --create'.", e))],
+    };
+
+    let (config, problems) = load_config_leniently(&text);
+    let mut findings = vec![Finding::new("file", Severity::Ok, "Config file was read.")];
+    match problems.is_empty() {
+        true => findings.push(Finding::new("entries", Severity::Ok, "All entries are valid.")),
+        false => findings.extend(problems.into_iter().map(|problem| Finding::new("entries", Severity::Error, problem))),
+    }
+    match browser::resolve_command(&config.default_browser, &config.browsers) {
+        Ok(command) => check_browser_command(&mut findings, &format!("Default browser '{}'", config.default_browser), &command),
+        Err(e) => findings.push(Finding::new("browser", Severity::Error, format!("Invalid default_browser: {}.", e))),
+    }
+    let mut browser_names = config.browsers.keys().collect::<Vec<&String>>();
+    browser_names.sort();
+    for name in browser_names {
+        check_browser_command(&mut findings, &format!("Browser '{}'", name), &config.browsers[name]);
+    }
+    findings
+}
+
+pub fn run(args: &ConfigCheckArgs, config_path: &Path) -> i32 {
+    report_findings(&config_path.to_string_lossy(), &check_config(config_path), args.json)
+}
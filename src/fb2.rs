@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::fs::{File, read};
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+
+use crate::book_paths::TemporaryBook;
+use crate::helpers::content_hash;
+use crate::log;
+use crate::synthetic_epub::SyntheticEpub;
+use crate::xml_encoding::{declared_encoding, decode_codepage};
+
+const PAGE_SLUG_LENGTH: usize = 40;
+const HEADINGS: [&str; 6] = ["h1", "h2", "h3", "h4", "h5", "h6"];
+
+//////////////////
+//   Decoding   //
+//////////////////
+
+fn decode_fb2(fb2: &[u8]) -> Result<String, String> {
+    // XML parsers only need to honor UTF-8, but much FB2 in the wild is Cyrillic-codepage, so the declaration has to be applied here.
+    match declared_encoding(fb2).as_deref() {
+        None | Some("utf-8" | "utf8") => String::from_utf8(fb2.strip_prefix("\u{feff}".as_bytes()).unwrap_or(fb2).to_vec()).map_err(|e| format!("FB2 file isn't valid UTF-8: {}", e)),
+        Some(encoding) => decode_codepage(fb2, encoding).ok_or_else(|| format!("FB2 file uses the {} encoding, which isn't supported.", encoding)),
+    }
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer = 0u32;
+    let mut buffered_bits = 0;
+    for character in text.chars().filter(|character| !character.is_whitespace() && *character != '=') {
+        let value = match character {
+            'A'..='Z' => character as u32 - 'A' as u32,
+            'a'..='z' => character as u32 - 'a' as u32 + 26,
+            '0'..='9' => character as u32 - '0' as u32 + 52,
+            '+' => 62,
+            '/' => 63,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | value;
+        buffered_bits += 6;
+        if buffered_bits >= 8 {
+            buffered_bits -= 8;
+            bytes.push((buffer >> buffered_bits) as u8);
+            buffer &= (1 << buffered_bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+////////////////////
+//   Conversion   //
+////////////////////
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+    // Matching local names lets l:href and xlink:href both through, whichever prefix the file binds.
+    element.attributes().flatten()
+        .find(|attribute| attribute.key.local_name().as_ref() == name)
+        .and_then(|attribute| attribute.unescape_value().ok().map(|value| value.into_owned()))
+}
+
+fn image_path(binary_id: &str) -> String {
+    format!("images/{}", sanitize_filename::sanitize(binary_id))
+}
+
+fn page_path(page_index: usize, title: Option<&str>) -> String {
+    // Named after their titles, pages are recognizable in browser history; the number keeps them in order and apart when titles repeat.
+    let slug = title.unwrap_or_default().chars()
+        .map(|character| if character.is_alphanumeric() { character } else { ' ' })
+        .flat_map(char::to_lowercase)
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join("-");
+    let slug = slug.chars().take(PAGE_SLUG_LENGTH).collect::<String>();
+    match slug.trim_end_matches('-') {
+        "" => format!("text/{:04}.xhtml", page_index + 1),
+        slug => format!("text/{:04}-{}.xhtml", page_index + 1, slug),
+    }
+}
+
+fn xhtml_element(fb2_name: &str) -> (&'static str, Option<&'static str>) {
+    match fb2_name {
+        "p" => ("p", None),
+        "v" => ("p", Some("v")),
+        "stanza" => ("div", Some("stanza")),
+        "poem" => ("div", Some("poem")),
+        "epigraph" => ("blockquote", Some("epigraph")),
+        "cite" => ("blockquote", Some("cite")),
+        "annotation" => ("div", Some("annotation")),
+        "text-author" => ("p", Some("text-author")),
+        "subtitle" => ("p", Some("subtitle")),
+        "date" => ("p", Some("date")),
+        "emphasis" => ("em", None),
+        "strong" => ("strong", None),
+        "strikethrough" => ("del", None),
+        "sub" => ("sub", None),
+        "sup" => ("sup", None),
+        "code" => ("code", None),
+        "table" => ("table", None),
+        "tr" => ("tr", None),
+        "td" => ("td", None),
+        "th" => ("th", None),
+        "a" => ("a", None),
+        _ => ("span", None),
+    }
+}
+
+#[derive(Default)]
+struct PageContent {
+    title: Option<String>,
+    body: String,
+}
+
+#[derive(Default)]
+struct Converter {
+    book_title: String,
+    authors: Vec<String>,
+    author_parts: Vec<String>,
+    language: Option<String>,
+    series: Option<(String, Option<String>)>,
+    cover_binary: Option<String>,
+    document_id: Option<String>,
+    pages: Vec<PageContent>,
+    current_page: Option<usize>,
+    // Where each element id ended up, so links can be pointed at the right page afterwards.
+    id_pages: HashMap<String, usize>,
+    // Label, fragment, level, and page, which is only named once its title is known.
+    toc: Vec<(String, Option<String>, usize, usize)>,
+    section_count: usize,
+    in_main_body: bool,
+    body_count: usize,
+    open_elements: Vec<(String, &'static str)>,
+    title_text: Option<String>,
+    binary: Option<(String, String, String)>,
+    binaries: Vec<(String, String, Vec<u8>)>,
+}
+
+impl Converter {
+    fn page(&mut self) -> &mut PageContent {
+        let page_index = match self.current_page {
+            Some(page_index) => page_index,
+            None => {
+                self.pages.push(PageContent::default());
+                self.pages.len() - 1
+            },
+        };
+        self.current_page = Some(page_index);
+        &mut self.pages[page_index]
+    }
+
+    fn section_depth(&self) -> usize {
+        self.open_elements.iter().filter(|(name, _tag)| name == "section").count()
+    }
+
+    fn in_element(&self, name: &str) -> bool {
+        self.open_elements.iter().any(|(open_name, _tag)| open_name == name)
+    }
+
+    fn record_id(&mut self, id: &str) {
+        let page_index = self.current_page.unwrap_or_default();
+        self.id_pages.entry(id.to_string()).or_insert(page_index);
+    }
+
+    fn start_body_element(&mut self, name: &str, element: &BytesStart) {
+        match name {
+            "body" => {
+                self.body_count += 1;
+                // The first body is the book itself; later ones hold notes and comments, which each stay on one page.
+                self.in_main_body = self.body_count == 1 && attribute(element, b"name").is_none();
+                self.current_page = None;
+                if !self.in_main_body {
+                    let label = attribute(element, b"name").map(|name| {
+                        let mut characters = name.chars();
+                        characters.next().map(|first| first.to_uppercase().chain(characters).collect()).unwrap_or_default()
+                    }).unwrap_or_else(|| String::from("Notes"));
+                    let page_index = self.pages.len();
+                    self.page().title = Some(label.clone());
+                    self.toc.push((label, None, 0, page_index));
+                }
+                self.open_elements.push((name.to_string(), ""));
+            },
+            "section" => {
+                if self.in_main_body && self.section_depth() == 0 {
+                    self.current_page = None;
+                }
+                self.section_count += 1;
+                let id = attribute(element, b"id").unwrap_or_else(|| format!("section-{}", self.section_count));
+                self.page().body.push_str(&format!("<div class=\"section\" id=\"{}\">", escape(id.as_str())));
+                self.record_id(&id);
+                self.open_elements.push((name.to_string(), "div"));
+                if self.in_main_body {
+                    let page_index = self.current_page.unwrap_or_default();
+                    self.toc.push((String::new(), Some(id), self.section_depth(), page_index));
+                }
+            },
+            "title" => {
+                self.title_text = Some(String::new());
+                self.page().body.push_str("<div class=\"title\">");
+                self.open_elements.push((name.to_string(), "div"));
+            },
+            "p" if self.open_elements.last().is_some_and(|(name, _tag)| name == "title") => {
+                let heading = HEADINGS[self.section_depth().min(HEADINGS.len() - 1)];
+                if let Some(title_text) = self.title_text.as_mut().filter(|title_text| !title_text.is_empty()) {
+                    title_text.push(' ');
+                }
+                self.page().body.push_str(&format!("<{}>", heading));
+                self.open_elements.push((name.to_string(), heading));
+            },
+            "image" => {
+                let source = attribute(element, b"href").map(|href| image_path(href.trim_start_matches('#'))).unwrap_or_default();
+                let alt = attribute(element, b"alt").unwrap_or_default();
+                self.page().body.push_str(&format!("<img src=\"../{}\" alt=\"{}\"/>", escape(source.as_str()), escape(alt.as_str())));
+                self.open_elements.push((name.to_string(), ""));
+            },
+            "empty-line" => {
+                self.page().body.push_str("<br/>");
+                self.open_elements.push((name.to_string(), ""));
+            },
+            _ => {
+                let (tag, class) = xhtml_element(name);
+                let mut start = format!("<{}", tag);
+                if let Some(class) = class {
+                    start.push_str(&format!(" class=\"{}\"", class));
+                }
+                if let Some(id) = attribute(element, b"id") {
+                    self.page();
+                    self.record_id(&id);
+                    start.push_str(&format!(" id=\"{}\"", escape(id.as_str())));
+                }
+                if tag == "a" {
+                    if let Some(href) = attribute(element, b"href") {
+                        start.push_str(&format!(" href=\"{}\"", escape(href.as_str())));
+                    }
+                }
+                start.push('>');
+                self.page().body.push_str(&start);
+                self.open_elements.push((name.to_string(), tag));
+            },
+        }
+    }
+
+    fn end_body_element(&mut self) {
+        let Some((name, tag)) = self.open_elements.pop() else {
+            return
+        };
+        if !tag.is_empty() {
+            self.page().body.push_str(&format!("</{}>", tag));
+        }
+        match name.as_str() {
+            "title" => {
+                let title_text = self.title_text.take().unwrap_or_default();
+                // A section's title labels the TOC entry the section opened.
+                let section_depth = self.section_depth();
+                if section_depth > 0 && self.in_main_body {
+                    if let Some(entry) = self.toc.iter_mut().rev().find(|entry| entry.2 == section_depth) {
+                        if entry.0.is_empty() {
+                            entry.0 = title_text.clone();
+                        }
+                    }
+                }
+                let page = self.page();
+                if page.title.is_none() && !title_text.is_empty() {
+                    page.title = Some(title_text);
+                }
+            },
+            "section" if self.in_main_body && self.section_depth() == 0 => self.current_page = None,
+            "body" => self.current_page = None,
+            _ => (),
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        let path = self.open_elements.iter().map(|(name, _tag)| name.as_str()).collect::<Vec<&str>>();
+        match path.as_slice() {
+            [.., "title-info", "book-title"] => self.book_title.push_str(text),
+            [.., "title-info", "author", _part] => self.author_parts.push(text.trim().to_string()),
+            [.., "title-info", "lang"] => self.language = Some(text.trim().to_string()),
+            [.., "document-info", "id"] => self.document_id = Some(text.trim().to_string()),
+            [.., "binary"] => {
+                if let Some((_id, _media_type, data)) = self.binary.as_mut() {
+                    data.push_str(text);
+                }
+            },
+            // Whitespace between top-level sections would otherwise start a page of its own.
+            _ if self.current_page.is_none() && text.trim().is_empty() => (),
+            _ if self.in_element("body") => {
+                if let Some(title_text) = self.title_text.as_mut() {
+                    title_text.push_str(text.trim());
+                }
+                self.page().body.push_str(&escape(text));
+            },
+            _ => (),
+        }
+    }
+
+    fn start(&mut self, element: &BytesStart, is_empty: bool) {
+        let name = String::from_utf8_lossy(element.local_name().as_ref()).into_owned();
+        let in_title_info = self.in_element("title-info");
+        match name.as_str() {
+            "author" if in_title_info => self.author_parts.clear(),
+            "sequence" if in_title_info && self.series.is_none() => {
+                if let Some(series_name) = attribute(element, b"name") {
+                    self.series = Some((series_name, attribute(element, b"number")));
+                }
+            },
+            "image" if self.in_element("coverpage") => {
+                self.cover_binary = attribute(element, b"href").map(|href| href.trim_start_matches('#').to_string());
+            },
+            "binary" => {
+                if let (Some(id), Some(media_type)) = (attribute(element, b"id"), attribute(element, b"content-type")) {
+                    self.binary = Some((id, media_type, String::new()));
+                }
+            },
+            _ => (),
+        }
+        if name == "body" || self.in_element("body") {
+            self.start_body_element(&name, element);
+        } else {
+            self.open_elements.push((name, ""));
+        }
+        if is_empty {
+            self.end();
+        }
+    }
+
+    fn end(&mut self) {
+        let in_body = self.in_element("body");
+        let Some((name, _tag)) = self.open_elements.last().cloned() else {
+            return
+        };
+        if in_body {
+            self.end_body_element();
+            return
+        }
+        self.open_elements.pop();
+        match name.as_str() {
+            "author" if self.in_element("title-info") => {
+                let author = self.author_parts.iter().filter(|part| !part.is_empty()).cloned().collect::<Vec<String>>().join(" ");
+                if !author.is_empty() {
+                    self.authors.push(author);
+                }
+                self.author_parts.clear();
+            },
+            "binary" => {
+                if let Some((id, media_type, data)) = self.binary.take() {
+                    match decode_base64(&data) {
+                        Some(data) => self.binaries.push((id, media_type, data)),
+                        None => log::warning!("Skipping FB2 image {}, which isn't valid base64.", id),
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+fn link_across_pages(pages: &mut [PageContent], page_paths: &[String], id_pages: &HashMap<String, usize>) {
+    // Links in FB2 are all to ids within the one document, but split into pages they have to name the page too.
+    for (page_index, page) in pages.iter_mut().enumerate() {
+        for (id, target_page_index) in id_pages {
+            if *target_page_index != page_index {
+                let escaped_id = escape(id.as_str());
+                let local_link = format!("href=\"#{}\"", escaped_id);
+                if page.body.contains(&local_link) {
+                    let target_file = page_paths[*target_page_index].trim_start_matches("text/");
+                    page.body = page.body.replace(&local_link, &format!("href=\"{}#{}\"", target_file, escaped_id));
+                }
+            }
+        }
+    }
+}
+
+fn read_fb2_zip(path: &Path) -> Result<Vec<u8>, String> {
+    let mut archive = File::open(path).map_err(zip::result::ZipError::from).and_then(|file| zip::ZipArchive::new(BufReader::new(file))).map_err(|e| format!("Couldn't open zipped FB2: {}", e))?;
+    let fb2_name = archive.file_names().find(|name| name.to_lowercase().ends_with(".fb2")).map(String::from).ok_or("Zip archive doesn't contain an FB2 file.")?;
+    let mut fb2 = Vec::new();
+    archive.by_name(&fb2_name)
+        .map_err(|e| e.to_string())
+        .and_then(|mut entry| entry.read_to_end(&mut fb2).map_err(|e| e.to_string()))
+        .map_err(|e| format!("Couldn't read {} from zipped FB2: {}", fb2_name, e))?;
+    Ok(fb2)
+}
+
+pub fn convert_fb2(path: &Path, zipped: bool) -> Result<TemporaryBook, String> {
+    let fb2 = match zipped {
+        true => read_fb2_zip(path)?,
+        false => read(path).map_err(|e| format!("Couldn't read FB2 file: {}", e))?,
+    };
+    let text = decode_fb2(&fb2)?;
+    let mut reader = quick_xml::Reader::from_str(&text);
+    let mut converter = Converter::default();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => converter.start(&e, false),
+            Ok(Event::Empty(e)) => converter.start(&e, true),
+            Ok(Event::End(_)) => converter.end(),
+            Ok(Event::Text(e)) => converter.text(&e.unescape().map(|text| text.into_owned()).unwrap_or_else(|_| String::from_utf8_lossy(&e).into_owned())),
+            Ok(Event::CData(e)) => converter.text(&String::from_utf8_lossy(&e)),
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(e) => return Err(format!("Couldn't parse FB2 at byte {}: {}", reader.error_position(), e)),
+        }
+    }
+    if converter.pages.is_empty() {
+        return Err(String::from("FB2 file has no body text."))
+    }
+
+    let title = match converter.book_title.trim() {
+        "" => String::from("Untitled"),
+        title => title.to_string(),
+    };
+    // FB2's document id is meant to be unique, but files without one fall back on their contents.
+    let identifier = converter.document_id.clone().filter(|id| !id.is_empty()).unwrap_or_else(|| format!("urn:rib:fb2:{}", content_hash(&fb2)));
+    let mut epub = SyntheticEpub::new(identifier, title.clone());
+    for author in converter.authors.drain(..) {
+        epub.add_creator(author);
+    }
+    if let Some(language) = converter.language.take().filter(|language| !language.is_empty()) {
+        epub.set_language(language);
+    }
+    if let Some((name, number)) = converter.series.take() {
+        epub.set_series(name, number);
+    }
+    let page_paths = converter.pages.iter().enumerate().map(|(page_index, page)| page_path(page_index, page.title.as_deref())).collect::<Vec<String>>();
+    for (label, fragment, level, page_index) in converter.toc.drain(..) {
+        // Untitled sections still get entries, so the TOC's nesting stays faithful to the book's.
+        let label = if label.is_empty() { String::from("Untitled section") } else { label };
+        let href = match fragment {
+            Some(fragment) => format!("{}#{}", page_paths[page_index], fragment),
+            None => page_paths[page_index].clone(),
+        };
+        epub.add_toc_entry(label, href, level.saturating_sub(1));
+    }
+    link_across_pages(&mut converter.pages, &page_paths, &converter.id_pages);
+    for (page, page_path) in converter.pages.drain(..).zip(page_paths) {
+        epub.add_page(page_path, page.title.unwrap_or_else(|| title.clone()), page.body);
+    }
+    for (id, media_type, data) in converter.binaries.drain(..) {
+        if converter.cover_binary.as_deref() == Some(id.as_str()) {
+            epub.set_cover(image_path(&id));
+        }
+        epub.add_resource(image_path(&id), &media_type, data);
+    }
+    epub.write("fb2")
+}
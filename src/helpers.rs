@@ -0,0 +1,301 @@
+use std::cmp::Ordering;
+use std::env;
+use std::path::{Component, Path, PathBuf};
+
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+
+use crate::portable_path::encode_href_segment;
+
+pub fn edit_distance(first: &str, second: &str) -> usize {
+    let second_chars = second.chars().collect::<Vec<char>>();
+    let mut previous_row = (0..=second_chars.len()).collect::<Vec<usize>>();
+    for (first_index, first_char) in first.chars().enumerate() {
+        let mut current_row = vec![first_index + 1];
+        for (second_index, second_char) in second_chars.iter().enumerate() {
+            let substitution_cost = if first_char == *second_char { 0 } else { 1 };
+            current_row.push((previous_row[second_index] + substitution_cost).min(previous_row[second_index + 1] + 1).min(current_row[second_index] + 1));
+        }
+        previous_row = current_row;
+    }
+    previous_row[second_chars.len()]
+}
+
+pub fn closest_match<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    // Only suggest candidates close enough to plausibly be typos.
+    let max_distance = (name.chars().count() / 3).max(2);
+    candidates.into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _candidate)| *distance <= max_distance)
+        .min_by_key(|(distance, _candidate)| *distance)
+        .map(|(_distance, candidate)| candidate)
+}
+
+pub fn natural_cmp(first: &str, second: &str) -> Ordering {
+    // Compares runs of digits by value, so "page2" sorts before "page10".
+    let (mut first_chars, mut second_chars) = (first.chars().peekable(), second.chars().peekable());
+    loop {
+        match (first_chars.peek().copied(), second_chars.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(first_char), Some(second_char)) if first_char.is_ascii_digit() && second_char.is_ascii_digit() => {
+                let first_digits = std::iter::from_fn(|| first_chars.next_if(char::is_ascii_digit)).collect::<String>();
+                let second_digits = std::iter::from_fn(|| second_chars.next_if(char::is_ascii_digit)).collect::<String>();
+                let (first_trimmed, second_trimmed) = (first_digits.trim_start_matches('0'), second_digits.trim_start_matches('0'));
+                let ordering = first_trimmed.len().cmp(&second_trimmed.len()).then_with(|| first_trimmed.cmp(second_trimmed));
+                if ordering != Ordering::Equal {
+                    return ordering
+                }
+            },
+            (Some(first_char), Some(second_char)) => {
+                let ordering = first_char.to_lowercase().cmp(second_char.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering
+                }
+                first_chars.next();
+                second_chars.next();
+            },
+        }
+    }
+}
+
+// The basic CSS colors, plus the dark ones most often picked for reading backgrounds.
+const NAMED_COLORS: [(&str, (u8, u8, u8)); 24] = [
+    ("black", (0, 0, 0)), ("silver", (192, 192, 192)), ("gray", (128, 128, 128)), ("grey", (128, 128, 128)),
+    ("white", (255, 255, 255)), ("maroon", (128, 0, 0)), ("red", (255, 0, 0)), ("purple", (128, 0, 128)),
+    ("fuchsia", (255, 0, 255)), ("green", (0, 128, 0)), ("lime", (0, 255, 0)), ("olive", (128, 128, 0)),
+    ("yellow", (255, 255, 0)), ("navy", (0, 0, 128)), ("blue", (0, 0, 255)), ("teal", (0, 128, 128)),
+    ("aqua", (0, 255, 255)), ("darkslategray", (47, 79, 79)), ("darkslategrey", (47, 79, 79)), ("dimgray", (105, 105, 105)),
+    ("dimgrey", (105, 105, 105)), ("midnightblue", (25, 25, 112)), ("ivory", (255, 255, 240)), ("wheat", (245, 222, 179)),
+];
+
+pub fn parse_css_color(color: &str) -> Option<(u8, u8, u8)> {
+    // Only what's needed to tell light from dark: hex, rgb()/rgba(), and common names. Anything else is left alone.
+    let color = color.trim().to_lowercase();
+    if let Some(hex) = color.strip_prefix('#') {
+        let digits = hex.chars().map(|digit| digit.to_digit(16).map(|digit| digit as u8)).collect::<Option<Vec<u8>>>()?;
+        return match digits.as_slice() {
+            [red, green, blue] | [red, green, blue, _] => Some((red * 17, green * 17, blue * 17)),
+            [red_high, red_low, green_high, green_low, blue_high, blue_low] | [red_high, red_low, green_high, green_low, blue_high, blue_low, _, _] => Some((red_high * 16 + red_low, green_high * 16 + green_low, blue_high * 16 + blue_low)),
+            _ => None,
+        }
+    }
+    if let Some(arguments) = color.strip_prefix("rgba(").or_else(|| color.strip_prefix("rgb(")).and_then(|rest| rest.strip_suffix(')')) {
+        let channels = arguments.split([',', ' ', '/']).filter(|channel| !channel.is_empty()).take(3).map(|channel| match channel.strip_suffix('%') {
+            Some(percentage) => percentage.parse::<f64>().ok().map(|percentage| (percentage.clamp(0.0, 100.0) / 100.0 * 255.0).round() as u8),
+            None => channel.parse::<f64>().ok().map(|value| value.clamp(0.0, 255.0).round() as u8),
+        }).collect::<Option<Vec<u8>>>()?;
+        return match channels.as_slice() {
+            [red, green, blue] => Some((*red, *green, *blue)),
+            _ => None,
+        }
+    }
+    NAMED_COLORS.iter().find(|(name, _rgb)| *name == color).map(|(_name, rgb)| *rgb)
+}
+
+pub fn relative_luminance((red, green, blue): (u8, u8, u8)) -> f64 {
+    // As defined for WCAG contrast ratios.
+    let linear = |channel: u8| {
+        let channel = channel as f64 / 255.0;
+        if channel <= 0.04045 { channel / 12.92 } else { ((channel + 0.055) / 1.055).powf(2.4) }
+    };
+    0.2126 * linear(red) + 0.7152 * linear(green) + 0.0722 * linear(blue)
+}
+
+pub fn content_hash(bytes: &[u8]) -> String {
+    // FNV-1a, which unlike std's hashers is stable across Rust versions, so ids derived from it stay put.
+    let hash = bytes.iter().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3));
+    format!("{:016x}", hash)
+}
+
+pub fn find_executable(name: &str) -> Option<PathBuf> {
+    // Mirrors how Command resolves programs: names with a separator are paths, anything else is looked up on PATH.
+    if name.contains(['/', std::path::MAIN_SEPARATOR]) {
+        let path = PathBuf::from(name);
+        return is_executable(&path).then_some(path)
+    }
+    let search_path = env::var_os("PATH")?;
+    for dir in env::split_paths(&search_path) {
+        let candidate = dir.join(name);
+        if is_executable(&candidate) {
+            return Some(candidate)
+        }
+        if cfg!(windows) {
+            let extensions = env::var("PATHEXT").unwrap_or_else(|_| String::from(".EXE;.BAT;.CMD;.COM"));
+            for extension in extensions.split(';').filter(|extension| !extension.is_empty()) {
+                let candidate = dir.join(format!("{}{}", name, extension));
+                if is_executable(&candidate) {
+                    return Some(candidate)
+                }
+            }
+        }
+    }
+    None
+}
+
+pub fn executables_on_path() -> Vec<String> {
+    let Some(search_path) = env::var_os("PATH") else {
+        return Vec::new()
+    };
+    let mut names = env::split_paths(&search_path)
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten())
+        .filter(|entry| is_executable(&entry.path()))
+        .map(|entry| match cfg!(windows) {
+            true => entry.path().file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+            false => entry.file_name().to_string_lossy().into_owned(),
+        })
+        .collect::<Vec<String>>();
+    names.sort();
+    names.dedup();
+    names
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+pub fn file_url(path: &Path, fragment: Option<&str>) -> String {
+    const PATH_ESCAPES: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}');
+    // Windows paths are handled as text, since they also arrive here on Linux from wslpath. Canonicalized ones carry a verbatim `\\?\` prefix that has no place in a URL.
+    let path = path.to_string_lossy().replace('\\', "/");
+    let path = match path.strip_prefix("//?/") {
+        Some(verbatim_path) => match verbatim_path.strip_prefix("UNC/") {
+            Some(share_path) => format!("//{}", share_path),
+            None => verbatim_path.to_string(),
+        },
+        None => path,
+    };
+    let encoded_path = utf8_percent_encode(&path, PATH_ESCAPES).to_string();
+    // A UNC path's server becomes the URL's host (file://server/share/...); other paths get an empty one (file:///C:/... or file:///home/...).
+    let mut url = match encoded_path.strip_prefix("//") {
+        Some(share_path) => format!("file://{}", share_path),
+        None if encoded_path.starts_with('/') => format!("file://{}", encoded_path),
+        None => format!("file:///{}", encoded_path),
+    };
+    if let Some(fragment) = fragment {
+        url.push('#');
+        url.push_str(fragment);
+    }
+    url
+}
+
+pub fn relative_or_absolute_url(from_dir: &Path, target: &Path, fragment: Option<&str>) -> String {
+    // Relative links survive the library moving or being served over HTTP, and avoid Windows paths, whose drive letters browsers read as URL schemes.
+    let from_components = from_dir.components().collect::<Vec<Component>>();
+    let target_components = target.components().collect::<Vec<Component>>();
+    let common_length = from_components.iter().zip(&target_components).take_while(|(from_component, target_component)| from_component == target_component).count();
+    let is_plain = |components: &[Component]| components.iter().all(|component| matches!(component, Component::Normal(_)));
+    // Paths on different drives or shares have no relative path between them, so those links have to be absolute.
+    if common_length == 0 || !is_plain(&from_components[common_length..]) || !is_plain(&target_components[common_length..]) {
+        return file_url(target, fragment)
+    }
+    let mut href = std::iter::repeat_n(String::from(".."), from_components.len() - common_length)
+        .chain(target_components[common_length..].iter().map(|component| encode_href_segment(&component.as_os_str().to_string_lossy())))
+        .collect::<Vec<String>>()
+        .join("/");
+    if let Some(fragment) = fragment {
+        href.push('#');
+        href.push_str(fragment);
+    }
+    href
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_colors() {
+        assert_eq!(parse_css_color("#1e1e1e"), Some((30, 30, 30)));
+        assert_eq!(parse_css_color("#FFF"), Some((255, 255, 255)));
+        assert_eq!(parse_css_color("#0008"), Some((0, 0, 0)));
+        assert_eq!(parse_css_color("#12345678"), Some((18, 52, 86)));
+        assert_eq!(parse_css_color("#12345"), None);
+        assert_eq!(parse_css_color("#ggg"), None);
+    }
+
+    #[test]
+    fn parses_rgb_colors() {
+        assert_eq!(parse_css_color("rgb(20, 30, 40)"), Some((20, 30, 40)));
+        assert_eq!(parse_css_color("rgba(20,30,40,0.5)"), Some((20, 30, 40)));
+        assert_eq!(parse_css_color("rgb(20 30 40 / 50%)"), Some((20, 30, 40)));
+        assert_eq!(parse_css_color("rgb(100%, 0%, 50%)"), Some((255, 0, 128)));
+        assert_eq!(parse_css_color("rgb(300, -5, 0)"), Some((255, 0, 0)));
+        assert_eq!(parse_css_color("rgb(20, 30)"), None);
+    }
+
+    #[test]
+    fn parses_named_colors() {
+        assert_eq!(parse_css_color("Black"), Some((0, 0, 0)));
+        assert_eq!(parse_css_color(" midnightblue "), Some((25, 25, 112)));
+        assert_eq!(parse_css_color("rebeccapurple"), None);
+        assert_eq!(parse_css_color("var(--page)"), None);
+    }
+
+    #[test]
+    fn dark_backgrounds_have_low_luminance() {
+        assert!(relative_luminance((30, 30, 30)) < 0.179);
+        assert!(relative_luminance((245, 222, 179)) > 0.179);
+    }
+
+    #[test]
+    fn file_urls_for_unix_paths() {
+        assert_eq!(file_url(Path::new("/home/reader/rib/index.html"), None), "file:///home/reader/rib/index.html");
+        assert_eq!(file_url(Path::new("/home/reader/My Books/50% #1?.html"), Some("ch1")), "file:///home/reader/My%20Books/50%25%20%231%3F.html#ch1");
+        assert_eq!(file_url(Path::new("/tmp/caf\u{e9}.html"), None), "file:///tmp/caf%C3%A9.html");
+    }
+
+    // Windows paths reach file_url as text even on Linux, from wslpath, so these run everywhere.
+    #[test]
+    fn file_urls_for_drive_letter_paths() {
+        assert_eq!(file_url(Path::new(r"C:\Users\Reader\rib\index.html"), None), "file:///C:/Users/Reader/rib/index.html");
+        assert_eq!(file_url(Path::new(r"D:\My Books\a.html"), Some("top")), "file:///D:/My%20Books/a.html#top");
+        assert_eq!(file_url(Path::new(r"\\?\C:\Users\Reader\index.html"), None), "file:///C:/Users/Reader/index.html");
+    }
+
+    #[test]
+    fn file_urls_for_unc_paths() {
+        assert_eq!(file_url(Path::new(r"\\server\books\rib\index.html"), None), "file://server/books/rib/index.html");
+        assert_eq!(file_url(Path::new(r"\\?\UNC\server\books\index.html"), None), "file://server/books/index.html");
+        assert_eq!(file_url(Path::new(r"\\wsl$\Ubuntu\home\reader\a b.html"), None), "file://wsl$/Ubuntu/home/reader/a%20b.html");
+    }
+
+    #[test]
+    fn relative_urls_between_paths_in_one_tree() {
+        assert_eq!(relative_or_absolute_url(Path::new("/library/book/epub/text"), Path::new("/library/book/epub/text/ch2.xhtml"), None), "ch2.xhtml");
+        assert_eq!(relative_or_absolute_url(Path::new("/library/book/epub/text"), Path::new("/library/book/index.html"), Some("toc")), "../../index.html#toc");
+        assert_eq!(relative_or_absolute_url(Path::new("/library/book"), Path::new("/library/book/epub/My Chapter #1.xhtml"), None), "epub/My%20Chapter%20%231.xhtml");
+    }
+
+    #[test]
+    fn absolute_urls_without_a_plain_relative_path() {
+        // Parent components would be resolved through whatever they link to, so they aren't walked lexically.
+        assert_eq!(relative_or_absolute_url(Path::new("/library/book/../other"), Path::new("/library/book/a.html"), None), "file:///library/book/a.html");
+    }
+}
+
+#[cfg(all(test, windows))]
+mod windows_tests {
+    use super::*;
+
+    #[test]
+    fn absolute_urls_across_drives_and_shares() {
+        assert_eq!(relative_or_absolute_url(Path::new(r"C:\library\book"), Path::new(r"D:\exports\a.html"), None), "file:///D:/exports/a.html");
+        assert_eq!(relative_or_absolute_url(Path::new(r"C:\library\book"), Path::new(r"\\server\books\a.html"), Some("top")), "file://server/books/a.html#top");
+        assert_eq!(relative_or_absolute_url(Path::new(r"C:\library\book"), Path::new(r"C:\library\book\epub\a.html"), None), "epub/a.html");
+    }
+
+    #[test]
+    fn file_urls_for_windows_paths() {
+        assert_eq!(file_url(Path::new(r"C:\Users\Reader\rib\index.html"), Some("ch2")), "file:///C:/Users/Reader/rib/index.html#ch2");
+        assert_eq!(file_url(&PathBuf::from(r"\\server\books").join("a.html"), None), "file://server/books/a.html");
+    }
+}
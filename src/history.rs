@@ -0,0 +1,69 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{read_to_string, write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::timestamp::Timestamp;
+
+// When books in the library were opened, kept beside the library index for 'library history'. It's only ever read by
+// rib itself, and never leaves the library directory.
+
+pub const HISTORY_FILENAME: &str = "history.json";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HistoryRecord {
+    pub time: Timestamp,
+    pub id: String,
+    // The content path the book was opened at, if not its index.
+    #[serde(default)]
+    pub section: Option<String>,
+}
+
+pub fn read(path: &Path) -> Result<Vec<HistoryRecord>, String> {
+    match read_to_string(path) {
+        Ok(file) => serde_json::from_str(&file).map_err(|e| format!("Library history {} is invalid: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("Couldn't read library history {}: {}", path.display(), e)),
+    }
+}
+
+pub fn append(path: &Path, record: HistoryRecord, max_records: usize) -> Result<(), String> {
+    let mut records = read(path)?;
+    records.push(record);
+    // Past the limit, the oldest records go first.
+    let excess_count = records.len().saturating_sub(max_records);
+    records.drain(..excess_count);
+    write(path, serde_json::to_string_pretty(&records).unwrap()).map_err(|e| format!("Couldn't update library history {}: {}", path.display(), e))
+}
+
+pub fn open_counts<'a>(records: &[&'a HistoryRecord]) -> Vec<(&'a str, usize)> {
+    let mut counts = HashMap::new();
+    for record in records {
+        *counts.entry(record.id.as_str()).or_insert(0) += 1;
+    }
+    let mut counts = counts.into_iter().collect::<Vec<(&str, usize)>>();
+    counts.sort_by(|(first_id, first_count), (second_id, second_count)| second_count.cmp(first_count).then_with(|| first_id.cmp(second_id)));
+    counts
+}
+
+pub fn streaks(records: &[&HistoryRecord], today: i64) -> (usize, usize) {
+    // The current streak is still alive until a day passes without reading, so it can end today or yesterday.
+    let days = records.iter().map(|record| record.time.day()).collect::<BTreeSet<i64>>();
+    let mut longest_streak = 0;
+    let mut streak = 0;
+    let mut previous_day = None;
+    for &day in &days {
+        streak = match previous_day {
+            Some(previous_day) if day == previous_day + 1 => streak + 1,
+            _ => 1,
+        };
+        longest_streak = longest_streak.max(streak);
+        previous_day = Some(day);
+    }
+    let current_streak = match previous_day {
+        Some(last_day) if last_day >= today - 1 => streak,
+        _ => 0,
+    };
+    (current_streak, longest_streak)
+}
@@ -0,0 +1,351 @@
+use std::fs::read;
+use std::path::{Component, Path};
+
+use percent_encoding::percent_decode_str;
+use quick_xml::escape::{escape, resolve_html5_entity, unescape_with};
+use quick_xml::events::{BytesStart, Event};
+
+use crate::book_paths::TemporaryBook;
+use crate::cbz::image_media_type;
+use crate::helpers::content_hash;
+use crate::log;
+use crate::synthetic_epub::SyntheticEpub;
+
+const VOID_ELEMENTS: [&str; 14] = ["area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source", "track", "wbr"];
+// Opening one of these ends a paragraph, as HTML parsers do when a `</p>` is left out.
+const PARAGRAPH_CLOSERS: [&str; 27] = ["address", "article", "aside", "blockquote", "details", "div", "dl", "fieldset", "figure", "footer", "form", "h1", "h2", "h3", "h4", "h5", "h6", "header", "hr", "main", "nav", "ol", "p", "pre", "section", "table", "ul"];
+const RESOURCE_ATTRIBUTES: [&str; 3] = ["src", "poster", "data"];
+const RAW_TEXT_ELEMENTS: [&str; 3] = ["script", "style", "title"];
+const HEAD_ELEMENTS: [&str; 7] = ["title", "style", "link", "meta", "base", "script", "noscript"];
+// The longest named references are around thirty characters; a `;` further on than that isn't ending one.
+const MAX_REFERENCE_LENGTH: usize = 40;
+
+#[derive(Default)]
+struct HtmlDocument {
+    title: Option<String>,
+    head: String,
+    body: String,
+    headings: Vec<(usize, String, String)>,
+    resources: Vec<String>,
+}
+
+fn is_xml_name(name: &str) -> bool {
+    let mut characters = name.chars();
+    characters.next().is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && characters.all(|character| character.is_ascii_alphanumeric() || matches!(character, '-' | '_' | '.' | ':'))
+}
+
+fn unescape_html(text: &str) -> String {
+    // Unlike XML, HTML lets a bare `&` through as itself, so references are resolved one at a time and anything unrecognized is kept.
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(ampersand) = rest.find('&') {
+        unescaped.push_str(&rest[..ampersand]);
+        rest = &rest[ampersand..];
+        let reference = rest.find(';').filter(|end| *end <= MAX_REFERENCE_LENGTH).map(|end| &rest[..=end]);
+        match reference.and_then(|reference| unescape_with(reference, resolve_html5_entity).ok()) {
+            Some(resolved) => {
+                unescaped.push_str(&resolved);
+                rest = &rest[reference.unwrap_or_default().len()..];
+            },
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+fn local_resource_path(reference: &str) -> Option<String> {
+    // Only relative references can be resources sitting next to the document; anything with a scheme or absolute path is left to the browser.
+    let reference = reference.split(['?', '#']).next().unwrap_or_default();
+    if reference.is_empty() || reference.starts_with('/') || reference.split('/').next().is_some_and(|first| first.contains(':')) {
+        return None
+    }
+    percent_decode_str(reference).decode_utf8().ok().map(|reference| reference.into_owned())
+}
+
+fn heading_level(name: &str) -> Option<usize> {
+    match name.as_bytes() {
+        [b'h', level @ b'1'..=b'6'] => Some(usize::from(level - b'0')),
+        _ => None,
+    }
+}
+
+struct Normalizer {
+    document: HtmlDocument,
+    in_head: bool,
+    open_elements: Vec<String>,
+    heading_text: Option<String>,
+}
+
+impl Normalizer {
+    fn output(&mut self) -> &mut String {
+        match self.in_head {
+            true => &mut self.document.head,
+            false => &mut self.document.body,
+        }
+    }
+
+    fn close_until(&mut self, name: &str) {
+        while let Some(open_name) = self.open_elements.pop() {
+            self.end_element(&open_name);
+            if open_name == name {
+                break
+            }
+        }
+    }
+
+    fn close_implied(&mut self, name: &str) {
+        // A handful of elements end implicitly in HTML; XHTML needs those ends written out.
+        let implied: &[&str] = match name {
+            "li" => &["li"],
+            "dt" | "dd" => &["dt", "dd"],
+            "td" | "th" => &["td", "th"],
+            "tr" => &["td", "th", "tr"],
+            "option" => &["option"],
+            _ if PARAGRAPH_CLOSERS.contains(&name) => &["p"],
+            _ => &[],
+        };
+        while let Some(open_name) = self.open_elements.last().cloned() {
+            if !implied.contains(&open_name.as_str()) {
+                break
+            }
+            self.close_until(&open_name);
+        }
+    }
+
+    fn end_element(&mut self, name: &str) {
+        self.output().push_str(&format!("</{}>", name));
+        if heading_level(name).is_some() {
+            if let Some(text) = self.heading_text.take() {
+                if let Some(heading) = self.document.headings.last_mut() {
+                    heading.2 = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+                }
+            }
+        }
+    }
+
+    fn start_element(&mut self, name: &str, element: &BytesStart, is_empty: bool) {
+        self.close_implied(name);
+        let mut start = format!("<{}", name);
+        let mut seen_keys = Vec::new();
+        let mut id = None;
+        for attribute in element.html_attributes().with_checks(false).flatten() {
+            let key = String::from_utf8_lossy(attribute.key.as_ref()).to_lowercase();
+            // Scripts are dropped, so their handlers would only point at nothing; namespaces come from the page wrapper.
+            if !is_xml_name(&key) || key.starts_with("on") || key == "xmlns" || key.starts_with("xmlns:") || seen_keys.contains(&key) {
+                continue
+            }
+            let value = unescape_html(&String::from_utf8_lossy(&attribute.value));
+            let is_resource = RESOURCE_ATTRIBUTES.contains(&key.as_str()) || (name == "link" && key == "href");
+            if let Some(resource_path) = Some(&value).filter(|_| is_resource).and_then(|value| local_resource_path(value)) {
+                self.document.resources.push(resource_path);
+            }
+            if key == "id" {
+                id = Some(value.clone());
+            }
+            start.push_str(&format!(" {}=\"{}\"", key, escape(value.as_str())));
+            seen_keys.push(key);
+        }
+        if let Some(level) = heading_level(name) {
+            // Headings make up the TOC, so each needs an id to link to.
+            let id = id.unwrap_or_else(|| {
+                let generated_id = format!("heading-{}", self.document.headings.len() + 1);
+                start.push_str(&format!(" id=\"{}\"", generated_id));
+                generated_id
+            });
+            self.document.headings.push((level, id, String::new()));
+            self.heading_text = Some(String::new());
+        }
+        if is_empty || VOID_ELEMENTS.contains(&name) {
+            start.push_str("/>");
+            self.output().push_str(&start);
+        } else {
+            start.push('>');
+            self.output().push_str(&start);
+            self.open_elements.push(name.to_string());
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if let Some(heading_text) = self.heading_text.as_mut() {
+            heading_text.push_str(text);
+        }
+        if !self.in_head {
+            self.document.body.push_str(&escape(text));
+        }
+    }
+}
+
+fn escape_raw_text(html: &str) -> String {
+    // Scripts, styles, and titles hold text that can include a bare `<`, which would read as markup, so their contents are escaped before parsing.
+    let lowercase_html = html.to_ascii_lowercase();
+    let mut escaped = String::with_capacity(html.len());
+    let mut position = 0;
+    while let Some((start, name)) = RAW_TEXT_ELEMENTS.iter().filter_map(|name| lowercase_html[position..].find(&format!("<{}", name)).map(|start| (position + start, *name))).min() {
+        let Some(content_start) = lowercase_html[start..].find('>').map(|end| start + end + 1) else {
+            break
+        };
+        let content_end = lowercase_html[content_start..].find(&format!("</{}", name)).map_or(html.len(), |end| content_start + end);
+        escaped.push_str(&html[position..content_start]);
+        // Titles still decode references, unlike scripts and styles, so their ampersands are left for that.
+        let content = match name {
+            "title" => html[content_start..content_end].to_string(),
+            _ => html[content_start..content_end].replace('&', "&amp;"),
+        };
+        escaped.push_str(&content.replace('<', "&lt;"));
+        position = content_end;
+        if position == html.len() {
+            break
+        }
+        // Step past the end tag's `<`, so the same element isn't found again.
+        escaped.push('<');
+        position += 1;
+    }
+    escaped.push_str(&html[position..]);
+    escaped
+}
+
+fn normalize_html(html: &str) -> Result<HtmlDocument, String> {
+    // HTML leaves out end tags, quotes, and slashes that XHTML needs, so it's read leniently and written back out balanced.
+    let html = escape_raw_text(html);
+    let mut reader = quick_xml::Reader::from_str(&html);
+    let reader_config = reader.config_mut();
+    reader_config.check_end_names = false;
+    reader_config.allow_unmatched_ends = true;
+    reader_config.check_comments = false;
+    let mut normalizer = Normalizer {
+        document: HtmlDocument::default(),
+        in_head: false,
+        open_elements: Vec::new(),
+        heading_text: None,
+    };
+    loop {
+        let event = reader.read_event().map_err(|e| format!("Couldn't parse HTML at byte {}: {}", reader.error_position(), e))?;
+        match event {
+            Event::Start(ref element) | Event::Empty(ref element) => {
+                let name = String::from_utf8_lossy(element.local_name().as_ref()).to_lowercase();
+                let is_empty = matches!(event, Event::Empty(_));
+                // Like `</head>`, the body's start tag is optional, so the first body element ends the head too.
+                if normalizer.in_head && !HEAD_ELEMENTS.contains(&name.as_str()) && name != "head" {
+                    normalizer.in_head = false;
+                }
+                match name.as_str() {
+                    "html" => (),
+                    "head" => normalizer.in_head = !is_empty,
+                    "body" => normalizer.in_head = false,
+                    "script" | "style" | "title" => {
+                        let text = match is_empty {
+                            true => String::new(),
+                            false => reader.read_text(element.name()).map_err(|e| format!("Couldn't parse HTML <{}>: {}", name, e))?.into_owned(),
+                        };
+                        match name.as_str() {
+                            "style" => normalizer.document.head.push_str(&format!("<style>{}</style>", text)),
+                            "title" => normalizer.document.title = Some(unescape_html(text.trim())).filter(|title| !title.is_empty()),
+                            _ => (),
+                        }
+                    },
+                    "meta" | "base" => (),
+                    "link" if !normalizer.in_head => (),
+                    _ if !is_xml_name(&name) => (),
+                    _ => normalizer.start_element(&name, element, is_empty),
+                }
+            },
+            Event::End(element) => {
+                let name = String::from_utf8_lossy(element.local_name().as_ref()).to_lowercase();
+                match name.as_str() {
+                    "head" => normalizer.in_head = false,
+                    _ if normalizer.open_elements.contains(&name) => normalizer.close_until(&name),
+                    // Stray end tags are ignored, as browsers do.
+                    _ => (),
+                }
+            },
+            Event::Text(text) => normalizer.text(&unescape_html(&String::from_utf8_lossy(&text))),
+            Event::CData(text) => normalizer.text(&String::from_utf8_lossy(&text)),
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    while let Some(open_name) = normalizer.open_elements.pop() {
+        normalizer.end_element(&open_name);
+    }
+    Ok(normalizer.document)
+}
+
+fn resource_media_type(path: &str) -> &'static str {
+    let extension = Path::new(path).extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "css" => "text/css",
+        "svg" => "image/svg+xml",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        _ => image_media_type(path).unwrap_or("application/octet-stream"),
+    }
+}
+
+fn build_epub(document: HtmlDocument, original_path: Option<&Path>, kind: &str, identifier_hash: String) -> Result<TemporaryBook, String> {
+    let stem = original_path.map_or_else(|| String::from("Untitled document"), |path| path.file_stem().unwrap_or_default().to_string_lossy().into_owned());
+    // A document read from stdin has no directory of its own to find resources in.
+    let resource_dir = original_path.map(|path| path.parent().unwrap_or(Path::new(".")));
+    let first_heading = document.headings.first().map(|(_level, _id, text)| text.clone()).filter(|text| !text.is_empty());
+    let title = document.title.or(first_heading).unwrap_or_else(|| stem.clone());
+    let page_path = match sanitize_filename::sanitize(&stem).trim_start_matches('.') {
+        "" => String::from("document.xhtml"),
+        page_stem => format!("{}.xhtml", page_stem),
+    };
+
+    let mut epub = SyntheticEpub::new(format!("urn:rib:{}:{}", kind, identifier_hash), title.clone());
+    let top_level = document.headings.iter().map(|(level, _id, _text)| *level).min().unwrap_or(1);
+    for (level, id, text) in &document.headings {
+        let label = if text.is_empty() { String::from("Untitled section") } else { text.clone() };
+        epub.add_toc_entry(label, format!("{}#{}", page_path, id), level - top_level);
+    }
+    epub.add_page_with_head(page_path, title, document.head, document.body);
+
+    let Some(resource_dir) = resource_dir else {
+        return epub.write(kind)
+    };
+    // The page sits at the epub's root, so relative references keep pointing at the same files once they're copied in beside it.
+    let mut added_resources = Vec::new();
+    for resource_path in &document.resources {
+        if added_resources.contains(resource_path) {
+            continue
+        }
+        if !Path::new(resource_path).components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+            log::warning!("Skipping resource {}, which points outside the document's directory.", resource_path);
+            continue
+        }
+        match read(resource_dir.join(resource_path)) {
+            Ok(data) => {
+                epub.add_resource(resource_path.trim_start_matches("./").to_string(), resource_media_type(resource_path), data);
+                added_resources.push(resource_path.clone());
+            },
+            Err(e) => log::warning!("Skipping resource {}: {}", resource_path, e),
+        }
+    }
+    epub.write(kind)
+}
+
+pub fn convert_html(html_path: &Path, original_path: Option<&Path>) -> Result<TemporaryBook, String> {
+    let html = read(html_path).map_err(|e| format!("Couldn't read HTML file: {}", e))?;
+    let document = normalize_html(&String::from_utf8_lossy(&html))?;
+    build_epub(document, original_path, "html", content_hash(&html))
+}
+
+pub fn convert_markdown(markdown_path: &Path, original_path: Option<&Path>) -> Result<TemporaryBook, String> {
+    let markdown = read(markdown_path).map_err(|e| format!("Couldn't read Markdown file: {}", e))?;
+    let markdown_text = String::from_utf8(markdown.clone()).map_err(|e| format!("Markdown file isn't valid UTF-8: {}", e))?;
+    let options = pulldown_cmark::Options::ENABLE_TABLES | pulldown_cmark::Options::ENABLE_FOOTNOTES | pulldown_cmark::Options::ENABLE_STRIKETHROUGH | pulldown_cmark::Options::ENABLE_HEADING_ATTRIBUTES;
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, pulldown_cmark::Parser::new_ext(&markdown_text, options));
+    // Markdown can embed raw HTML, so its output gets the same lenient treatment as an HTML file.
+    let document = normalize_html(&html)?;
+    build_epub(document, original_path, "markdown", content_hash(&markdown))
+}
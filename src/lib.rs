@@ -0,0 +1,5282 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{File, canonicalize, create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file, rename, write};
+use std::io::{BufReader, BufWriter, IsTerminal, Write};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::{Component, Path, PathBuf};
+use std::process::exit;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::sync::atomic::AtomicUsize;
+use std::time::{Duration, Instant, SystemTime};
+
+use directories::ProjectDirs;
+use epub::doc::EpubDoc;
+use maud::{DOCTYPE, html};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use quick_xml::events::{BytesDecl, BytesStart, BytesText, Event};
+
+mod book_paths;
+mod book_type;
+mod browser;
+mod cbz;
+mod check;
+mod cli;
+mod config_check;
+mod fb2;
+mod helpers;
+mod history;
+mod html_document;
+mod library_archive;
+mod library_verify;
+mod log;
+mod output;
+mod portable_path;
+mod search;
+mod serve;
+mod style;
+mod style_explanation;
+mod synthetic_epub;
+mod timestamp;
+mod timings;
+mod watch;
+mod xml_encoding;
+
+use book_paths::{STDIN_PATH, buffer_stdin, describe_book_path, expand_book_paths, is_epub_path, is_html_path, is_markdown_path, is_unpacked_epub, pack_unpacked_epub};
+use book_type::{BookType, detect_book_type};
+use cbz::convert_cbz;
+use fb2::convert_fb2;
+use html_document::{convert_html, convert_markdown};
+use helpers::{file_url, relative_or_absolute_url};
+use output::{CodedError, ErrorCode};
+use portable_path::{encode_href_segment, portable_href, portable_resource_path};
+use style::{ResolvedStyle, resolve_style};
+use timings::Timings;
+use cli::{Args, ConfigArgs, ConfigSubcommand, DedupeMode, KoboSpanMode, OutputFormat, LibraryArgs, LibraryCountArgs, LibraryNoCountArgs, LibraryStyleSubcommand, LibrarySubcommand, LibraryVerifyArgs, Subcommand};
+use timestamp::Timestamp;
+use xml_encoding::{declared_encoding, decode_codepage};
+
+// The integration tests build their books with this; it isn't part of the library's API.
+#[cfg(feature = "test-support")]
+#[doc(hidden)]
+pub use synthetic_epub::SyntheticEpub;
+
+////////////////
+//   Config   //
+////////////////
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StyleFont {
+    value: String,
+    override_book: bool,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StyleFontSize {
+    value: i64,
+    override_book: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StyleTextColor {
+    value: String,
+    override_book: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StyleLinkColor {
+    value: String,
+    override_book: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StyleBackgroundColor {
+    value: String,
+    override_book: bool,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StyleLineSpacing {
+    value: f64,
+    override_book: bool,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StyleIndentation {
+    value: i64,
+    override_book: bool,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StyleMarginSize {
+    value: i64,
+    override_book: bool,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StyleMaxWidth {
+    value: i64,
+    override_book: bool,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StyleLimitImageSizeToViewportSize {
+    value: bool,
+    override_book: bool,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StyleMaxImageHeight {
+    value: i64,
+    override_book: bool,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StyleMaxImageWidth {
+    value: i64,
+    override_book: bool,
+}
+
+// How far a book's pages are kept from reaching beyond the book, since they open in an everyday browser profile.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ContentSecurityPolicy {
+    // No scripts and nothing remote.
+    Strict,
+    // The book's own scripts run, but still nothing remote.
+    Relaxed,
+    // A policy only goes into pages when a stylesheet asks for one, since it can break books that worked before.
+    #[default]
+    Off,
+}
+
+impl ContentSecurityPolicy {
+    // Pages can be opened as files or served from localhost, so both their own origin and file: count as the book's. Images and fonts are often embedded as data: URIs.
+    fn directives(self) -> Option<&'static str> {
+        match self {
+            Self::Strict => Some("default-src 'self' file: data:; script-src 'none'; style-src 'self' file: data: 'unsafe-inline'; connect-src 'none'; object-src 'none'; form-action 'none'; base-uri 'none'"),
+            Self::Relaxed => Some("default-src 'self' file: data: blob:; script-src 'self' file: 'unsafe-inline'; style-src 'self' file: data: 'unsafe-inline'; connect-src 'self' file: data: blob:; object-src 'none'; form-action 'none'; base-uri 'none'"),
+            Self::Off => None,
+        }
+    }
+}
+
+/// How books are styled: one of the tables under `[stylesheets]` in config.toml.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Stylesheet {
+    font: Option<StyleFont>,
+    font_size: Option<StyleFontSize>,
+    text_color: Option<StyleTextColor>,
+    link_color: Option<StyleLinkColor>,
+    background_color: Option<StyleBackgroundColor>,
+    line_spacing: Option<StyleLineSpacing>,
+    indentation: Option<StyleIndentation>,
+    margin_size: Option<StyleMarginSize>,
+    max_width: Option<StyleMaxWidth>,
+    limit_image_size_to_viewport_size: Option<StyleLimitImageSizeToViewportSize>,
+    max_image_height: Option<StyleMaxImageHeight>,
+    max_image_width: Option<StyleMaxImageWidth>,
+    freeform_css_no_override: Option<String>,
+    freeform_css_override: Option<String>,
+    // For books whose own CSS is beyond overriding: their stylesheets are dropped, and with them, optionally, their style attributes.
+    #[serde(default)]
+    strip_book_css: bool,
+    #[serde(default)]
+    strip_inline_styles: bool,
+    // For books whose style attributes beat even overriding styles: the properties those styles set are dropped from them.
+    #[serde(default)]
+    force_styles: bool,
+    #[serde(default)]
+    csp: ContentSecurityPolicy,
+}
+
+impl Stylesheet {
+    /// Reads a stylesheet written as it would be in config.toml, e.g. `font = { value = "Georgia", override_book = false }`.
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        let stylesheet = toml::from_str::<Self>(text).map_err(|e| format!("Invalid stylesheet: {}", e.message().trim()))?;
+        match stylesheet.validate().as_slice() {
+            [] => Ok(stylesheet),
+            problems => Err(format!("Invalid stylesheet: {}", problems.join(" "))),
+        }
+    }
+
+    /// A stylesheet that styles nothing, leaving books as they are.
+    pub fn empty() -> Self {
+        Self {
+            font: None,
+            font_size: None,
+            text_color: None,
+            link_color: None,
+            background_color: None,
+            line_spacing: None,
+            indentation: None,
+            margin_size: None,
+            max_width: None,
+            limit_image_size_to_viewport_size: None,
+            max_image_height: None,
+            max_image_width: None,
+            freeform_css_no_override: None,
+            freeform_css_override: None,
+            strip_book_css: false,
+            strip_inline_styles: false,
+            force_styles: false,
+            csp: ContentSecurityPolicy::default(),
+        }
+    }
+
+    fn has_no_override_styles (&self) -> bool {
+        (self.font.is_some() && !self.font.as_ref().unwrap().override_book)
+        || (self.font_size.is_some() && !self.font_size.as_ref().unwrap().override_book)
+        || (self.text_color.is_some() && !self.text_color.as_ref().unwrap().override_book)
+        || (self.link_color.is_some() && !self.link_color.as_ref().unwrap().override_book)
+        || (self.background_color.is_some() && !self.background_color.as_ref().unwrap().override_book)
+        || (self.line_spacing.is_some() && !self.line_spacing.as_ref().unwrap().override_book)
+        || (self.indentation.is_some() && !self.indentation.as_ref().unwrap().override_book)
+        || (self.margin_size.is_some() && !self.margin_size.as_ref().unwrap().override_book)
+        || (self.max_width.is_some() && !self.max_width.as_ref().unwrap().override_book)
+        || (self.limit_image_size_to_viewport_size.is_some() && !self.limit_image_size_to_viewport_size.as_ref().unwrap().override_book)
+        || (self.max_image_height.is_some() && !self.max_image_height.as_ref().unwrap().override_book)
+        || (self.max_image_width.is_some() && !self.max_image_width.as_ref().unwrap().override_book)
+        || self.freeform_css_no_override.is_some()
+    }
+
+    fn has_override_styles (&self) -> bool {
+        (self.font.is_some() && self.font.as_ref().unwrap().override_book)
+        || (self.font_size.is_some() && self.font_size.as_ref().unwrap().override_book)
+        || (self.text_color.is_some() && self.text_color.as_ref().unwrap().override_book)
+        || (self.link_color.is_some() && self.link_color.as_ref().unwrap().override_book)
+        || (self.background_color.is_some() && self.background_color.as_ref().unwrap().override_book)
+        || (self.line_spacing.is_some() && self.line_spacing.as_ref().unwrap().override_book)
+        || (self.indentation.is_some() && self.indentation.as_ref().unwrap().override_book)
+        || (self.margin_size.is_some() && self.margin_size.as_ref().unwrap().override_book)
+        || (self.max_width.is_some() && self.max_width.as_ref().unwrap().override_book)
+        || (self.limit_image_size_to_viewport_size.is_some() && self.limit_image_size_to_viewport_size.as_ref().unwrap().override_book)
+        || (self.max_image_height.is_some() && self.max_image_height.as_ref().unwrap().override_book)
+        || (self.max_image_width.is_some() && self.max_image_width.as_ref().unwrap().override_book)
+        || self.freeform_css_override.is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.has_no_override_styles() && !self.has_override_styles() && !self.strip_book_css && !self.strip_inline_styles && !self.force_styles
+    }
+
+    fn validate(&self) -> Vec<String> {
+        // Catches values that deserialize fine but can't produce sensible CSS.
+        let mut problems = Vec::new();
+        for (key, value) in [("font", &self.font.as_ref().map(|font| &font.value)), ("text_color", &self.text_color.as_ref().map(|color| &color.value)), ("link_color", &self.link_color.as_ref().map(|color| &color.value)), ("background_color", &self.background_color.as_ref().map(|color| &color.value))] {
+            if value.is_some_and(|value| value.trim().is_empty()) {
+                problems.push(format!("{} is empty.", key));
+            }
+        }
+        for (key, value) in [("font_size", self.font_size.map(|font_size| font_size.value)), ("max_width", self.max_width.map(|max_width| max_width.value)), ("max_image_height", self.max_image_height.map(|max_image_height| max_image_height.value)), ("max_image_width", self.max_image_width.map(|max_image_width| max_image_width.value))] {
+            if value.is_some_and(|value| value <= 0) {
+                problems.push(format!("{} must be positive.", key));
+            }
+        }
+        if self.margin_size.is_some_and(|margin_size| margin_size.value < 0) {
+            problems.push(String::from("margin_size can't be negative."));
+        }
+        if self.line_spacing.is_some_and(|line_spacing| line_spacing.value <= 0.0 || line_spacing.value.is_nan()) {
+            problems.push(String::from("line_spacing must be positive."));
+        }
+        if self.force_styles && forced_properties(self).is_empty() {
+            problems.push(String::from("force_styles has nothing to force, since no style sets override_book."));
+        }
+        problems
+    }
+}
+
+// Layered over the config file, and in turn overridden by command-line flags.
+const CONFIG_PATH_VAR: &str = "RIB_CONFIG";
+const LIBRARY_DIR_VAR: &str = "RIB_LIBRARY_DIR";
+const BROWSER_VAR: &str = "RIB_BROWSER";
+const SYSTEM_BROWSER_VAR: &str = "BROWSER";
+const MAX_LIBRARY_BYTES_VAR: &str = "RIB_MAX_LIBRARY_BYTES";
+// Where the config and library go when there's no home directory to find the platform's locations from, as in a minimal container.
+const FALLBACK_CONFIG_PATH: &str = "rib-config.toml";
+const FALLBACK_LIBRARY_DIR: &str = "rib-library";
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+enum ConfigSource {
+    #[default]
+    Default,
+    File,
+    Environment(&'static str),
+    CommandLine(&'static str),
+}
+
+impl ConfigSource {
+    fn describe(self) -> String {
+        match self {
+            Self::Default => String::from("default"),
+            Self::File => String::from("config file"),
+            Self::Environment(var) => format!("environment ({})", var),
+            Self::CommandLine(flag) => format!("command line ({})", flag),
+        }
+    }
+}
+
+// Which books go first when the library outgrows its limits.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum TruncationPolicy {
+    #[default]
+    Lru,
+    LargestFirst,
+    Score,
+}
+
+impl TruncationPolicy {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Lru => "lru",
+            Self::LargestFirst => "largest-first",
+            Self::Score => "score",
+        }
+    }
+}
+
+// What navigation a book's cover page gets, where a Previous/Index/Next bar would sit over the artwork.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum CoverNavigation {
+    #[default]
+    Full,
+    Start,
+    None,
+}
+
+impl CoverNavigation {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::Start => "start",
+            Self::None => "none",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default = "default_browser")]
+    default_browser: String,
+    max_cache_books: usize,
+    max_cache_bytes: usize,
+    #[serde(default)]
+    truncation_policy: TruncationPolicy,
+    #[serde(default)]
+    default_stylesheet: Option<String>,
+    #[serde(default)]
+    stylesheets: HashMap<String, Stylesheet>,
+    #[serde(default)]
+    cover_navigation: CoverNavigation,
+    // Image size caps, in px, for every book, for stylesheets that don't set their own.
+    #[serde(default)]
+    default_max_image_height: Option<NonZeroU32>,
+    #[serde(default)]
+    default_max_image_width: Option<NonZeroU32>,
+    // Whether print page numbers marked in books are shown in the margin, with the book's page list in its index.
+    #[serde(default)]
+    show_page_numbers: bool,
+    // How many levels of the table of contents the index lists, when --toc-depth isn't passed.
+    #[serde(default)]
+    toc_depth: Option<usize>,
+    // How many openings the library's history keeps, or 0 to keep none.
+    #[serde(default = "default_max_history_records")]
+    max_history_records: usize,
+    // The reading speed the index's reading times are estimated at, or 0 to leave them out.
+    #[serde(default = "default_words_per_minute")]
+    words_per_minute: usize,
+    // Whether three or more authors are joined as "A, B, & C" rather than "A, B & C".
+    #[serde(default)]
+    creator_serial_comma: bool,
+    #[serde(default)]
+    browsers: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    wsl_linux_browser: bool,
+    // Browsers, by name or command, that can only be given one book at a time.
+    #[serde(default)]
+    single_book_browsers: Vec<String>,
+    // Arguments that open a browser as a bare reading window, by program name, adding to or replacing rib's own.
+    #[serde(default)]
+    reader_window_arguments: HashMap<String, Vec<String>>,
+    // Commands from BROWSER to try in turn, when nothing rib-specific names a browser.
+    #[serde(skip)]
+    system_browsers: Vec<Vec<String>>,
+    #[serde(default)]
+    library_dir: Option<PathBuf>,
+    #[serde(skip)]
+    sources: HashMap<&'static str, ConfigSource>,
+}
+
+impl Config {
+    fn open(path: &Path) -> Self {
+        match read_to_string(path) {
+            Ok(file) => match toml::from_str::<Self>(&file) {
+                Ok(mut config) => {
+                    for key in file.parse::<toml::Table>().map(|table| table.keys().cloned().collect::<Vec<String>>()).unwrap_or_default() {
+                        config.mark_from_file(&key);
+                    }
+                    config
+                },
+                Err(_) => {
+                    // Salvage what's usable rather than discarding the whole file over one bad entry.
+                    let (config, problems) = config_check::load_config_leniently(&file);
+                    for problem in problems {
+                        log::warning!("{}", problem);
+                    }
+                    log::warning!("Ignored the config entries above. Run 'rib config check' for details.");
+                    config
+                },
+            },
+            Err(_) => Self::default(),
+        }
+    }
+
+    fn create_if_missing(path: &Path) {
+        if !path.exists() {
+            log::info!("No preexisting config file found. Attempting to create new config file with default settings at {}.", path.display());
+            Self::write_default(path).expect("Failed to create config file.");
+        }
+    }
+
+    fn load(path: &Path, library_dir_flag: Option<&Path>) -> Result<Self, String> {
+        let mut config = Self::open(path);
+        // A relative library_dir in the file is relative to the file, so a synced config and library can move together.
+        if let (Some(library_dir), Some(config_dir)) = (&config.library_dir, path.parent()) {
+            config.library_dir = Some(config_dir.join(library_dir));
+        }
+        config.apply_environment()?;
+        if let Some(library_dir) = library_dir_flag {
+            config.library_dir = Some(library_dir.to_path_buf());
+            config.sources.insert("library_dir", ConfigSource::CommandLine("--library-dir"));
+        }
+        Ok(config)
+    }
+
+    fn library_dir(&self, default: &Path) -> PathBuf {
+        self.library_dir.clone().unwrap_or_else(|| default.to_path_buf())
+    }
+
+    fn mark_from_file(&mut self, key: &str) {
+        if let Some(key) = config_check::CONFIG_KEYS.iter().find(|config_key| **config_key == key) {
+            self.sources.insert(key, ConfigSource::File);
+        }
+    }
+
+    fn source(&self, key: &str) -> ConfigSource {
+        self.sources.get(key).copied().unwrap_or_default()
+    }
+
+    fn apply_environment(&mut self) -> Result<(), String> {
+        if let Some(browser) = env_var(BROWSER_VAR)? {
+            self.default_browser = browser;
+            self.sources.insert("default_browser", ConfigSource::Environment(BROWSER_VAR));
+        }
+        if self.source("default_browser") == ConfigSource::Default {
+            if let Some(browsers) = env_var(SYSTEM_BROWSER_VAR)? {
+                self.system_browsers = browser::parse_browser_list(&browsers).map_err(|e| format!("{} is invalid: {}.", SYSTEM_BROWSER_VAR, e))?;
+            }
+        }
+        if let Some(library_dir) = env_var(LIBRARY_DIR_VAR)? {
+            self.library_dir = Some(PathBuf::from(library_dir));
+            self.sources.insert("library_dir", ConfigSource::Environment(LIBRARY_DIR_VAR));
+        }
+        if let Some(max_bytes) = env_var(MAX_LIBRARY_BYTES_VAR)? {
+            self.max_cache_bytes = max_bytes.trim().parse().map_err(|_| format!("{} must be a whole number of bytes, not '{}'.", MAX_LIBRARY_BYTES_VAR, max_bytes))?;
+            self.sources.insert("max_cache_bytes", ConfigSource::Environment(MAX_LIBRARY_BYTES_VAR));
+        }
+        Ok(())
+    }
+
+    fn default() -> Self {
+        toml::from_str(include_str!("default_config.toml")).expect("Default config is invalid. (This shouldn't happen.)")
+    }
+
+    fn get_stylesheet(&self, name: Option<&str>) -> Result<Stylesheet, String> {
+        let mut stylesheet = match name.or(self.default_stylesheet.as_deref()) {
+            Some(name) => self.named_stylesheet(name)?,
+            // With neither a requested nor a default stylesheet, books are shown unstyled, but for the image caps below.
+            None => Stylesheet::empty(),
+        };
+        // The caps are there to rein in the sizes books give their images, so they override them.
+        stylesheet.max_image_height = stylesheet.max_image_height.or(self.default_max_image_height.map(|value| StyleMaxImageHeight {
+            value: i64::from(value.get()),
+            override_book: true,
+        }));
+        stylesheet.max_image_width = stylesheet.max_image_width.or(self.default_max_image_width.map(|value| StyleMaxImageWidth {
+            value: i64::from(value.get()),
+            override_book: true,
+        }));
+        Ok(stylesheet)
+    }
+
+    fn named_stylesheet(&self, name: &str) -> Result<Stylesheet, String> {
+        match self.stylesheets.get(name) {
+            Some(stylesheet) => Ok(stylesheet.clone()),
+            None => {
+                let mut names = self.stylesheets.keys().map(String::as_str).collect::<Vec<&str>>();
+                names.sort();
+                match helpers::closest_match(name, names.iter().copied()) {
+                    Some(suggestion) => Err(format!("Stylesheet '{}' isn't defined in config. Did you mean '{}'?", name, suggestion)),
+                    None => Err(format!("Stylesheet '{}' isn't defined in config. Defined stylesheets: {}.", name, names.join(", "))),
+                }
+            },
+        }
+    }
+
+    fn warn_if_empty_stylesheet(&self, name: &str) {
+        // An empty stylesheet is how config spells unstyled, so one is only suspicious when its name is a near miss for one that styles something.
+        if !self.stylesheets.get(name).is_some_and(Stylesheet::is_empty) {
+            return
+        }
+        let mut names = self.stylesheets.iter().filter(|(other_name, stylesheet)| *other_name != name && !stylesheet.is_empty()).map(|(other_name, _stylesheet)| other_name.as_str()).collect::<Vec<&str>>();
+        names.sort();
+        match helpers::closest_match(name, names.iter().copied()) {
+            Some(suggestion) => log::warning!("Stylesheet '{}' sets no properties, so books are shown unstyled. Did you mean '{}'?", name, suggestion),
+            None => log::debug!("Stylesheet '{}' sets no properties, so books are shown unstyled.", name),
+        }
+    }
+
+    fn write_default(path: &Path) -> std::io::Result<()> {
+        create_dir_all(path.parent().unwrap())?;
+        write(path, include_str!("default_config.toml"))
+    }
+}
+
+fn default_browser() -> String {
+    String::from(browser::DEFAULT_OPENER)
+}
+
+fn default_max_history_records() -> usize {
+    1000
+}
+
+fn default_words_per_minute() -> usize {
+    250
+}
+
+fn env_var(name: &str) -> Result<Option<String>, String> {
+    // Empty variables count as unset, so `RIB_BROWSER= rib ...` falls back to the config file.
+    match std::env::var(name) {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(std::env::VarError::NotUnicode(_)) => Err(format!("{} isn't valid Unicode.", name)),
+    }
+}
+
+fn env_path(name: &'static str, default: PathBuf) -> (PathBuf, ConfigSource) {
+    match std::env::var_os(name) {
+        Some(path) if !path.is_empty() => (PathBuf::from(path), ConfigSource::Environment(name)),
+        _ => (default, ConfigSource::Default),
+    }
+}
+
+///////////////
+//   Cache   //
+///////////////
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct CachedBook {
+    id: String,
+    path: PathBuf,
+    bytes: usize,
+    #[serde(default)]
+    added_time: Timestamp,
+    #[serde(default)]
+    last_opened_time: Timestamp,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    creator: Option<String>,
+    // Relative to the book's directory.
+    #[serde(default)]
+    cover: Option<PathBuf>,
+    // Where the book was imported from, if it came from a file rather than stdin.
+    #[serde(default)]
+    source: Option<BookSource>,
+    // When the book was last extracted again because its source changed.
+    #[serde(default)]
+    updated_time: Option<Timestamp>,
+    // Hash of the book's text alone, so different releases of the same book can be recognized despite their different ids.
+    #[serde(default)]
+    content_digest: Option<String>,
+    // Name of the stylesheet in config to open the book with when none is passed with --stylesheet.
+    #[serde(default)]
+    preferred_stylesheet: Option<String>,
+    // Left out of the book and byte counts max_cache_books and max_cache_bytes limit, and so never purged to meet them.
+    #[serde(default)]
+    exclude_from_limits: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct BookSource {
+    path: PathBuf,
+    bytes: u64,
+    modified_time: Timestamp,
+}
+
+impl BookSource {
+    fn read(path: &Path) -> Option<Self> {
+        let path = canonicalize(path).ok()?;
+        let metadata = path.metadata().ok()?;
+        Some(Self {
+            bytes: metadata.len(),
+            modified_time: metadata.modified().map(Timestamp::from).unwrap_or_default(),
+            path,
+        })
+    }
+}
+
+// Recorded when a book is added, since the epub itself isn't kept.
+#[derive(Clone, Debug, Default)]
+struct BookDetails {
+    title: Option<String>,
+    creator: Option<String>,
+    cover: Option<PathBuf>,
+    source: Option<BookSource>,
+    content_digest: Option<String>,
+    reimported: bool,
+    // Set by --no-count; a book already excluded stays excluded when it's opened without it.
+    exclude_from_limits: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct CacheIndex {
+    // Bumped on every change to the books, so generated pages like the shelf can tell when they're stale.
+    #[serde(default)]
+    generation: u64,
+    #[serde(default)]
+    shelf_generation: Option<u64>,
+    books: VecDeque<CachedBook>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredCacheIndex {
+    Current(CacheIndex),
+    // Indexes used to be a bare list of books.
+    Legacy(VecDeque<CachedBook>),
+}
+
+// Books are extracted into `<dirname>.tmp-<pid>` before being moved into place.
+const TEMPORARY_DIR_INFIX: &str = ".tmp-";
+const STALE_TEMPORARY_DIR_AGE: Duration = Duration::from_secs(60 * 60);
+
+const TITLE_SELECTOR_PREFIX: &str = "title:";
+
+#[derive(Clone, Debug)]
+struct Cache {
+    path: PathBuf,
+    contents: VecDeque<CachedBook>,
+    generation: u64,
+    shelf_generation: Option<u64>,
+    max_books: Option<usize>,
+    max_bytes: Option<usize>,
+    truncation_policy: TruncationPolicy,
+}
+
+impl Cache {
+    fn open(path: PathBuf, config: &Config) -> Self {
+        if !path.exists() {
+            create_dir_all(path.parent().unwrap()).expect("Failed to create cache dir.");
+            write(&path, serde_json::to_string_pretty(&CacheIndex::default()).unwrap()).expect("Failed to create cache index.");
+        }
+        Self::open_readonly(path, config)
+    }
+
+    fn open_readonly(path: PathBuf, config: &Config) -> Self {
+        // A library that doesn't exist yet reads as empty, without being created.
+        // Book paths in the index are relative to the library root, so the browser needs the root itself absolute.
+        let path = std::path::absolute(&path).unwrap_or(path);
+        let root = path.parent().unwrap().to_path_buf();
+        let index = match read_to_string(&path) {
+            Ok(file) => {
+                let mut index = match serde_json::from_str(&file).expect("Cache index is invalid or incorrectly-structured JSON.") {
+                    StoredCacheIndex::Current(index) => index,
+                    StoredCacheIndex::Legacy(books) => CacheIndex {
+                        books,
+                        ..CacheIndex::default()
+                    },
+                };
+                // Older indexes stored absolute paths; relativize those that point inside the library.
+                for cached_book in &mut index.books {
+                    if let Ok(relative_path) = cached_book.path.strip_prefix(&root) {
+                        // Some indexes placed book directories under the index file itself rather than beside it.
+                        cached_book.path = relative_path.strip_prefix("cache_index.json").unwrap_or(relative_path).to_path_buf();
+                    }
+                }
+                index
+            },
+            Err(_) => CacheIndex::default(),
+        };
+        Self {
+            path,
+            contents: index.books,
+            generation: index.generation,
+            shelf_generation: index.shelf_generation,
+            max_books: match config.max_cache_books {
+                0 => None,
+                _ => Some(config.max_cache_books),
+            },
+            max_bytes: match config.max_cache_bytes {
+                0 => None,
+                _ => Some(config.max_cache_bytes),
+            },
+            truncation_policy: config.truncation_policy,
+        }
+    }
+
+    fn save(&self) {
+        let index = CacheIndex {
+            generation: self.generation,
+            shelf_generation: self.shelf_generation,
+            books: self.contents.clone(),
+        };
+        let index_serialized = serde_json::to_string_pretty(&index).unwrap();
+        write(&self.path, index_serialized).expect("Failed to update cache index.");
+    }
+
+    fn write(&mut self) {
+        self.generation += 1;
+        self.save();
+    }
+
+    fn root(&self) -> &Path {
+        self.path.parent().unwrap()
+    }
+
+    fn history_path(&self) -> PathBuf {
+        self.root().join(history::HISTORY_FILENAME)
+    }
+
+    fn book_dir(&self, cached_book: &CachedBook) -> PathBuf {
+        self.root().join(&cached_book.path)
+    }
+
+    fn missing_book_dirs(&self) -> Vec<&CachedBook> {
+        self.contents.iter().filter(|cached_book| !self.book_dir(cached_book).is_dir()).collect()
+    }
+
+    fn remove_stale_temporary_dirs(&self) -> Vec<PathBuf> {
+        // Another rib may still be extracting into its own temporary dir, so only ones left untouched for a while count as abandoned.
+        let Ok(entries) = read_dir(self.root()) else {
+            return Vec::new()
+        };
+        entries.flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().contains(TEMPORARY_DIR_INFIX))
+            .filter(|entry| entry.metadata().and_then(|metadata| metadata.modified()).is_ok_and(|modified_time| modified_time.elapsed().is_ok_and(|age| age > STALE_TEMPORARY_DIR_AGE)))
+            .filter_map(|entry| remove_dir_all(entry.path()).ok().map(|()| entry.path()))
+            .collect()
+    }
+
+    fn prune(&mut self) -> Vec<CachedBook> {
+        let root = self.root().to_path_buf();
+        let (kept_books, pruned_books) = self.contents.drain(..).partition::<VecDeque<CachedBook>, _>(|cached_book| root.join(&cached_book.path).is_dir());
+        self.contents = kept_books;
+        self.write();
+        pruned_books.into_iter().collect()
+    }
+
+    fn count_books(&self) -> usize {
+        self.contents.len()
+    }
+
+    fn count_bytes(&self) -> usize {
+        self.contents.iter().map(|book| book.bytes).sum()
+    }
+
+    fn get(&self, id: &str) -> Option<&CachedBook> {
+        self.contents.iter().find(|cached_book| cached_book.id == id)
+    }
+
+    fn resolve_selector(&self, selector: &str) -> Result<Vec<String>, CodedError> {
+        // Ids are often long URNs nobody will type, so books can also be picked by a unique id prefix or by title.
+        if self.get(selector).is_some() {
+            return Ok(vec![selector.to_string()])
+        }
+        if let Some(title_substring) = selector.strip_prefix(TITLE_SELECTOR_PREFIX) {
+            if title_substring.is_empty() {
+                return Err(CodedError::new(ErrorCode::Usage, format!("'{}' needs some text to match titles against.", selector)))
+            }
+            let title_substring = title_substring.to_lowercase();
+            let matching_ids = self.contents.iter()
+                .filter(|cached_book| cached_book.title.as_ref().is_some_and(|title| title.to_lowercase().contains(&title_substring)))
+                .map(|cached_book| cached_book.id.clone())
+                .collect::<Vec<String>>();
+            return match matching_ids.is_empty() {
+                true => Err(CodedError::new(ErrorCode::NotFound, format!("No book in the library has a title containing '{}'.", &selector[TITLE_SELECTOR_PREFIX.len()..]))),
+                false => Ok(matching_ids),
+            }
+        }
+        match self.contents.iter().filter(|cached_book| cached_book.id.starts_with(selector)).collect::<Vec<&CachedBook>>().as_slice() {
+            [] => Err(CodedError::new(ErrorCode::NotFound, format!("No book with id {} is in the library.", selector))),
+            [cached_book] => Ok(vec![cached_book.id.clone()]),
+            cached_books => Err(CodedError::new(ErrorCode::Usage, format!("'{}' is the start of more than one book's id:\n{}", selector, describe_candidates(cached_books)))),
+        }
+    }
+
+    fn resolve_single_selector(&self, selector: &str) -> Result<&CachedBook, CodedError> {
+        let ids = self.resolve_selector(selector)?;
+        let cached_books = ids.iter().filter_map(|id| self.get(id)).collect::<Vec<&CachedBook>>();
+        match cached_books.as_slice() {
+            [cached_book] => Ok(cached_book),
+            cached_books => Err(CodedError::new(ErrorCode::Usage, format!("'{}' matches more than one book:\n{}", selector, describe_candidates(cached_books)))),
+        }
+    }
+
+    fn get_dirname(&self, id: &str) -> Option<String> {
+        self.get(id)
+            .and_then(|cached_book| cached_book.path.file_name())
+            .map(|dirname| dirname.to_string_lossy().into_owned())
+    }
+
+    fn find_duplicate(&self, id: &str, content_digest: &str) -> Option<&CachedBook> {
+        self.contents.iter().find(|cached_book| cached_book.id != id && cached_book.content_digest.as_deref() == Some(content_digest) && self.book_dir(cached_book).is_dir())
+    }
+
+    // Books sharing a content digest, in library order, both within each group and between groups.
+    // Books imported before digests were recorded get one the next time they're opened, and until then match nothing.
+    fn duplicate_groups(&self) -> Vec<Vec<&CachedBook>> {
+        let mut groups: Vec<Vec<&CachedBook>> = Vec::new();
+        for cached_book in &self.contents {
+            let Some(content_digest) = &cached_book.content_digest else {
+                continue
+            };
+            match groups.iter_mut().find(|group| group[0].content_digest.as_ref() == Some(content_digest)) {
+                Some(group) => group.push(cached_book),
+                None => groups.push(vec![cached_book]),
+            }
+        }
+        groups.retain(|group| group.len() > 1);
+        groups
+    }
+
+    fn contains_dirname(&self, dirname: &str) -> bool {
+        self.contents.iter().any(|cached_book| cached_book.path.file_name().is_some_and(|cached_dirname| cached_dirname == dirname))
+    }
+
+    fn remove(&mut self, id: &str) -> Option<CachedBook> {
+        let removed_book = self.remove_unwritten(id)?;
+        self.write();
+        Some(removed_book)
+    }
+
+    // Like remove, but leaves writing the index to the caller, for changes made together and written once.
+    fn remove_unwritten(&mut self, id: &str) -> Option<CachedBook> {
+        let position = self.contents.iter().position(|cached_book| cached_book.id == id)?;
+        let removed_book = self.contents.remove(position).unwrap();
+        let book_dir = self.book_dir(&removed_book);
+        match remove_dir_all(&book_dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => log::warning!("Removed {} from the library, but couldn't delete its directory {}: {}", removed_book.id, book_dir.display(), e),
+            _ => (),
+        }
+        Some(removed_book)
+    }
+
+    // Unlike the other changes, adding a book leaves writing the index to the caller, since books opened together are
+    // written once for all of them.
+    fn add(&mut self, id: String, dirname: String, bytes: usize, details: BookDetails) {
+        // Reopening a cached book moves it to the back of the queue rather than duplicating it.
+        let now = Timestamp::now();
+        let (added_time, source, updated_time, content_digest, preferred_stylesheet, exclude_from_limits) = match self.contents.iter().position(|cached_book| cached_book.id == id) {
+            Some(position) => {
+                let previous_book = self.contents.remove(position).unwrap();
+                let updated_time = if details.reimported { Some(now) } else { previous_book.updated_time };
+                let content_digest = details.content_digest.or(previous_book.content_digest);
+                match (previous_book.source, details.source) {
+                    (Some(previous_source), Some(source)) if previous_source.path != source.path => {
+                        log::info!("{} was imported from {} before; recording its new source {}.", id, previous_source.path.display(), source.path.display());
+                        (previous_book.added_time, Some(source), updated_time, content_digest, previous_book.preferred_stylesheet, previous_book.exclude_from_limits || details.exclude_from_limits)
+                    },
+                    // A book reopened from stdin is still the one from its old source.
+                    (previous_source, source) => (previous_book.added_time, source.or(previous_source), updated_time, content_digest, previous_book.preferred_stylesheet, previous_book.exclude_from_limits || details.exclude_from_limits),
+                }
+            },
+            None => (now, details.source, None, details.content_digest, None, details.exclude_from_limits),
+        };
+        self.contents.push_back(CachedBook {
+            id,
+            path: PathBuf::from(dirname),
+            bytes,
+            added_time,
+            last_opened_time: now,
+            title: details.title,
+            creator: details.creator,
+            cover: details.cover,
+            source,
+            updated_time,
+            content_digest,
+            preferred_stylesheet,
+            exclude_from_limits,
+        });
+    }
+
+    fn set_preferred_stylesheet(&mut self, id: &str, preferred_stylesheet: Option<String>) {
+        if let Some(cached_book) = self.contents.iter_mut().find(|cached_book| cached_book.id == id) {
+            cached_book.preferred_stylesheet = preferred_stylesheet;
+            self.write();
+        }
+    }
+
+    fn set_bytes(&mut self, id: &str, bytes: usize) {
+        if let Some(cached_book) = self.contents.iter_mut().find(|cached_book| cached_book.id == id && cached_book.bytes != bytes) {
+            cached_book.bytes = bytes;
+            self.write();
+        }
+    }
+
+    fn set_exclude_from_limits(&mut self, id: &str, exclude_from_limits: bool) {
+        if let Some(cached_book) = self.contents.iter_mut().find(|cached_book| cached_book.id == id) {
+            cached_book.exclude_from_limits = exclude_from_limits;
+            self.write();
+        }
+    }
+
+    fn restore(&mut self, restored_book: CachedBook) {
+        // Restored books keep their own timestamps, so they slot into the queue by when they were last opened rather than at its back.
+        self.contents.retain(|cached_book| cached_book.id != restored_book.id);
+        let position = self.contents.iter().position(|cached_book| cached_book.last_opened_time > restored_book.last_opened_time).unwrap_or(self.contents.len());
+        self.contents.insert(position, restored_book);
+        self.write();
+    }
+
+    fn truncate(&mut self, protected_ids: &[String]) {
+        // Entries from before timestamps were recorded default to the epoch; the stable sort keeps their queue order.
+        self.contents.make_contiguous().sort_by_key(|cached_book| cached_book.last_opened_time);
+        // A limit the books just opened break on their own can't be met by purging the rest, so it's set aside rather than emptying the library for nothing.
+        let protected_books = self.contents.iter().filter(|cached_book| protected_ids.contains(&cached_book.id) && !cached_book.exclude_from_limits).collect::<Vec<&CachedBook>>();
+        let protected_bytes = protected_books.iter().map(|cached_book| cached_book.bytes).sum::<usize>();
+        let max_books = match self.max_books {
+            Some(max_books) if protected_books.len() > max_books => {
+                log::warning!("{} books were just opened, more than max_cache_books ({}) on their own, so no other books were purged to make room for them.", protected_books.len(), max_books);
+                None
+            },
+            max_books => max_books,
+        };
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) if protected_bytes > max_bytes => {
+                log::warning!("The books just opened take up {} bytes, more than max_cache_bytes ({}) on their own, so no other books were purged to make room for them.", protected_bytes, max_bytes);
+                None
+            },
+            max_bytes => max_bytes,
+        };
+        let evicted_ids = select_evictions(self.contents.make_contiguous(), max_books, max_bytes, self.truncation_policy, protected_ids, Timestamp::now());
+        let root = self.root().to_path_buf();
+        self.contents.retain(|cached_book| {
+            let is_evicted = evicted_ids.contains(&cached_book.id);
+            if is_evicted {
+                let _ = remove_dir_all(root.join(&cached_book.path));
+            }
+            !is_evicted
+        });
+        self.write();
+    }
+}
+
+// How a library book's source file compares with what was imported from it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum SourceState {
+    // Imported from stdin, or before sources were recorded.
+    Unknown,
+    Missing,
+    Unchanged,
+    Changed,
+    // Only known with the text compared: the file changed, but not its text, so extracting it again would give the same book.
+    TextUnchanged,
+}
+
+impl SourceState {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Unknown => "source unknown",
+            Self::Missing => "source missing",
+            Self::Unchanged => "source unchanged",
+            Self::Changed => "source changed",
+            Self::TextUnchanged => "source changed, text unchanged",
+        }
+    }
+}
+
+fn source_state(cached_book: &CachedBook, verify: bool) -> SourceState {
+    let Some(recorded_source) = &cached_book.source else {
+        return SourceState::Unknown
+    };
+    let Some(source) = BookSource::read(&recorded_source.path) else {
+        return SourceState::Missing
+    };
+    let has_changed = source.bytes != recorded_source.bytes || source.modified_time != recorded_source.modified_time;
+    if !verify {
+        return match has_changed {
+            true => SourceState::Changed,
+            false => SourceState::Unchanged,
+        }
+    }
+    // A source that no longer opens as a book would certainly change things if imported again.
+    let content_digest = EpubDoc::new(&source.path).ok().map(|mut book| get_content_digest(&mut book));
+    match (content_digest, &cached_book.content_digest) {
+        (Some(content_digest), Some(recorded_digest)) if content_digest == *recorded_digest => match has_changed {
+            true => SourceState::TextUnchanged,
+            false => SourceState::Unchanged,
+        },
+        _ => SourceState::Changed,
+    }
+}
+
+fn describe_candidates(cached_books: &[&CachedBook]) -> String {
+    cached_books.iter().map(|cached_book| match &cached_book.title {
+        Some(title) => format!("  {} ({})", cached_book.id, title),
+        None => format!("  {}", cached_book.id),
+    }).collect::<Vec<String>>().join("\n")
+}
+
+fn eviction_score(cached_book: &CachedBook, now: Timestamp) -> f64 {
+    // Time since last opened multiplied by size, so a book twice as large goes as soon as one twice as stale.
+    let age = SystemTime::from(now).duration_since(SystemTime::from(cached_book.last_opened_time)).unwrap_or_default();
+    age.as_secs_f64() * cached_book.bytes as f64
+}
+
+fn select_evictions(books: &[CachedBook], max_books: Option<usize>, max_bytes: Option<usize>, policy: TruncationPolicy, protected_ids: &[String], now: Timestamp) -> Vec<String> {
+    // Returns the ids to evict, in order, to bring the library within its limits; books are expected least recently opened first, which breaks ties.
+    // Books excluded from the limits neither count toward them nor go to meet them.
+    let counted_books = books.iter().filter(|cached_book| !cached_book.exclude_from_limits).collect::<Vec<&CachedBook>>();
+    let mut candidates = counted_books.iter().copied().filter(|cached_book| !protected_ids.contains(&cached_book.id)).collect::<Vec<&CachedBook>>();
+    match policy {
+        TruncationPolicy::Lru => candidates.sort_by_key(|cached_book| cached_book.last_opened_time),
+        TruncationPolicy::LargestFirst => candidates.sort_by_key(|cached_book| Reverse(cached_book.bytes)),
+        TruncationPolicy::Score => candidates.sort_by(|first, second| eviction_score(second, now).total_cmp(&eviction_score(first, now))),
+    }
+
+    let mut book_count = counted_books.len();
+    let mut byte_count = counted_books.iter().map(|cached_book| cached_book.bytes).sum::<usize>();
+    let mut evicted_ids = Vec::new();
+    for candidate in candidates {
+        if !(max_books.is_some_and(|max_books| book_count > max_books) || max_bytes.is_some_and(|max_bytes| byte_count > max_bytes)) {
+            break
+        }
+        evicted_ids.push(candidate.id.clone());
+        book_count -= 1;
+        byte_count -= candidate.bytes;
+    }
+    evicted_ids
+}
+
+///////////////////
+//   Ephemeral   //
+///////////////////
+
+const EPHEMERAL_DIR_PREFIX: &str = "rib-ephemeral-";
+
+fn create_ephemeral_root() -> Result<PathBuf, String> {
+    let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|duration| duration.as_nanos()).unwrap_or_default();
+    let root = std::env::temp_dir().join(format!("{}{}-{}", EPHEMERAL_DIR_PREFIX, std::process::id(), nanos));
+    create_dir_all(&root).map_err(|e| format!("Couldn't create temporary dir {}: {}", root.display(), e))?;
+    Ok(root)
+}
+
+fn clean_up_ephemeral_dirs() {
+    // The browser may still be reading an ephemeral book when rib exits, so each run removes the ones left by earlier runs instead.
+    let own_prefix = format!("{}{}-", EPHEMERAL_DIR_PREFIX, std::process::id());
+    let Ok(entries) = std::fs::read_dir(std::env::temp_dir()) else {
+        return
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(EPHEMERAL_DIR_PREFIX) && !name.starts_with(&own_prefix) {
+            match remove_dir_all(entry.path()) {
+                Ok(()) => log::debug!("Removed ephemeral dir {}", entry.path().display()),
+                Err(e) => log::debug!("Couldn't remove ephemeral dir {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+}
+
+// Long enough to recognize a book by, and short enough to leave room for the paths inside its directory, even under Windows' 260-character limit.
+const MAX_DIRNAME_LENGTH: usize = 64;
+
+fn id_to_dirname(id: &str) -> String {
+    // Windows' rules apply everywhere, since libraries get synced between machines.
+    let sanitized_id = sanitize_filename::sanitize_with_options(id, sanitize_filename::Options {
+        windows: true,
+        truncate: false,
+        replacement: "",
+    });
+    let id_hash = helpers::content_hash(id.as_bytes());
+    // Ids made only of separators or reserved device names sanitize to nothing, which would put the book at the library root.
+    if sanitized_id.is_empty() || sanitized_id.chars().all(|character| character == '.') {
+        return format!("book-{}", id_hash)
+    }
+    if sanitized_id.len() <= MAX_DIRNAME_LENGTH {
+        return sanitized_id
+    }
+    // Long ids often share a prefix (DOI-style URLs from one publisher), so the hash keeps truncated ones apart.
+    let mut prefix_length = MAX_DIRNAME_LENGTH - id_hash.len() - 1;
+    while !sanitized_id.is_char_boundary(prefix_length) {
+        prefix_length -= 1;
+    }
+    format!("{}-{}", sanitized_id[..prefix_length].trim_end_matches(['.', ' ']), id_hash)
+}
+
+// Where opened books are dumped: into the library, or into a temporary dir that the library never hears about.
+enum Destination {
+    Library(Cache),
+    Ephemeral(PathBuf),
+}
+
+impl Destination {
+    fn root(&self) -> &Path {
+        match self {
+            Self::Library(cache) => cache.root(),
+            Self::Ephemeral(root) => root,
+        }
+    }
+
+    fn get_dirname(&self, id: &str, in_flight: &HashMap<String, String>) -> String {
+        if let Self::Library(cache) = self {
+            if let Some(dirname) = cache.get_dirname(id) {
+                return dirname
+            }
+        }
+        // Files the library keeps beside the books, like its index, count as taken too, as do the directories of books being opened alongside this one.
+        let is_taken = |dirname: &str| in_flight.values().any(|in_flight_dirname| in_flight_dirname == dirname) || match self {
+            Self::Library(cache) => cache.contains_dirname(dirname) || cache.root().join(dirname).exists(),
+            Self::Ephemeral(root) => root.join(dirname).exists(),
+        };
+        unique_dirname(id, is_taken)
+    }
+}
+
+fn unique_dirname(id: &str, is_taken: impl Fn(&str) -> bool) -> String {
+    let base_dirname = id_to_dirname(id);
+    let mut dirname = base_dirname.clone();
+    let mut numeric_extension = 2;
+    while is_taken(&dirname) {
+        dirname = format!("{}_{}", base_dirname, numeric_extension);
+        numeric_extension += 1;
+    }
+    dirname
+}
+
+/////////////////////////////
+//   Miscellaneous Types   //
+/////////////////////////////
+
+#[derive(Clone, Debug)]
+struct TocItem {
+    iri: PathBuf,
+    path: PathBuf,
+    label: String,
+    children: Vec<TocItem>,
+    nesting_level: usize,
+}
+
+#[derive(Clone, Debug)]
+struct SpineItem {
+    id: String,
+    path: PathBuf,
+    linear: bool,
+    // Nonlinear items nothing links to are left without navigation, and the navigation of the others passes over them.
+    has_navigation: bool,
+    // Counted as the item is extracted, for the reading times in the index.
+    word_count: Option<usize>,
+    // Covers can be given less navigation than other pages, though the navigation of other pages still reaches them.
+    is_cover: bool,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct BookLayout {
+    rtl: bool,
+    fixed_layout: bool,
+}
+
+// What the index credits people with, by MARC relator code. Anyone else listed as a creator counts as an author.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum CreatorRole {
+    Author,
+    Editor,
+    Translator,
+    Illustrator,
+}
+
+impl CreatorRole {
+    // The roles credited on lines of their own, below the authors, in the order they're listed.
+    const SECONDARY: [CreatorRole; 3] = [Self::Editor, Self::Translator, Self::Illustrator];
+
+    fn from_relator_code(code: &str) -> Option<Self> {
+        match code.trim().to_ascii_lowercase().as_str() {
+            "aut" => Some(Self::Author),
+            "edt" => Some(Self::Editor),
+            "trl" => Some(Self::Translator),
+            "ill" => Some(Self::Illustrator),
+            _ => None,
+        }
+    }
+
+    fn credit(self) -> &'static str {
+        match self {
+            Self::Author => "By",
+            Self::Editor => "Edited by",
+            Self::Translator => "Translated by",
+            Self::Illustrator => "Illustrated by",
+        }
+    }
+}
+
+struct BookCreator {
+    name: String,
+    role: CreatorRole,
+}
+
+// A book's titles, as EPUB 3 refinements tell them apart. Without refinements, the title is the first one, as the epub crate gives it.
+struct BookTitles {
+    main: String,
+    subtitle: Option<String>,
+    // The series or collection the book belongs to.
+    collection: Option<String>,
+}
+
+impl BookTitles {
+    fn main_only(main: String) -> Self {
+        Self {
+            main,
+            subtitle: None,
+            collection: None,
+        }
+    }
+
+    // The title with its subtitle, for where there's room for both.
+    fn full(&self) -> String {
+        match &self.subtitle {
+            Some(subtitle) => format!("{}: {}", self.main, subtitle),
+            None => self.main.clone(),
+        }
+    }
+}
+
+// Problems that don't stop a book from opening, collected so they can be reported together after it, or in its --json output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ImportWarning {
+    MisleadingExtension,
+    MissingTitle { fallback_title: String },
+    FixedLayout,
+    MissingCover { cover_id: String },
+    ResourceOutsideContents { path: PathBuf },
+    ResourceCollision { first_path: PathBuf, second_path: PathBuf, output_path: PathBuf },
+    SvgSpineItem { path: PathBuf },
+    MislabeledSpineItem { path: PathBuf, media_type: String, format: SpineItemFormat },
+    DuplicateSpineItem { path: PathBuf, occurrences: usize },
+    AmbiguousLinkCase { href: String, document_path: PathBuf, candidates: Vec<PathBuf> },
+}
+
+impl ImportWarning {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::MisleadingExtension => "misleading_extension",
+            Self::MissingTitle { .. } => "missing_title",
+            Self::FixedLayout => "fixed_layout",
+            Self::MissingCover { .. } => "missing_cover",
+            Self::ResourceOutsideContents { .. } => "resource_outside_contents",
+            Self::ResourceCollision { .. } => "resource_collision",
+            Self::SvgSpineItem { .. } => "svg_spine_item",
+            Self::MislabeledSpineItem { .. } => "mislabeled_spine_item",
+            Self::DuplicateSpineItem { .. } => "duplicate_spine_item",
+            Self::AmbiguousLinkCase { .. } => "ambiguous_link_case",
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "kind": self.kind(),
+            "message": self.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for ImportWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MisleadingExtension => write!(f, "It's an epub despite its extension, and was opened anyway."),
+            Self::MissingTitle { fallback_title } => write!(f, "It has no title metadata, so its index is titled {}.", fallback_title),
+            Self::FixedLayout => write!(f, "It has a fixed layout, so its pages are shown as designed, without stylesheets."),
+            Self::MissingCover { cover_id } => write!(f, "Its cover, {}, isn't among its resources, so it has no cover on the shelf.", cover_id),
+            Self::ResourceOutsideContents { path } => write!(f, "Skipped resource {}, which points outside the book's contents directory.", path.display()),
+            Self::ResourceCollision { first_path, second_path, output_path } => write!(f, "{} and {} are both extracted as {}, so only one of them was kept.", first_path.display(), second_path.display(), output_path.display()),
+            Self::SvgSpineItem { path } => write!(f, "Spine item {} is SVG, which currently lacks navigation and stylesheet support.", path.display()),
+            Self::MislabeledSpineItem { path, media_type, format } => match format {
+                SpineItemFormat::Unsupported => write!(f, "Spine item {} is of type {}, which rib can't add navigation or styles to, so it was left as is.", path.display(), media_type),
+                _ => write!(f, "Spine item {} is declared as {} but is {}, and was treated as such.", path.display(), media_type, format.media_type()),
+            },
+            Self::DuplicateSpineItem { path, occurrences } => write!(f, "Spine item {} is listed {} times, so only its first place in the reading order was kept.", path.display(), occurrences),
+            Self::AmbiguousLinkCase { href, document_path, candidates } => {
+                let candidates = candidates.iter().map(|candidate| candidate.display().to_string()).collect::<Vec<String>>();
+                write!(f, "{} in {} doesn't match any file's case exactly, and could be any of {}, so it was left as is.", href, document_path.display(), candidates.join(", "))
+            },
+        }
+    }
+}
+
+///////////////////
+//   Functions   //
+///////////////////
+
+// Where a book's pages are extracted to and the order they're read in, for linking between them.
+#[derive(Copy, Clone)]
+struct BookPages<'a> {
+    contents_dir: &'a Path,
+    index_path: &'a Path,
+    spine: &'a [SpineItem],
+    layout: BookLayout,
+}
+
+fn write_navigation_element<W: Write>(writer: &mut quick_xml::Writer<W>, pages: BookPages, spine_position: usize, start_only: bool) -> quick_xml::Result<()> {
+    let BookPages { contents_dir: book_contents_dir, index_path: book_index_path, spine, layout } = pages;
+    // This currently doesn't work if the spine items have '.xhtml' extensions, because apparently browser recognition of XHTML versus HTML is down to file extension. Figure out a fix, probably involving format-conversion.
+    use quick_xml::Error;
+
+    let page_dir = book_contents_dir.join(&spine[spine_position].path).parent().map(Path::to_path_buf).unwrap_or_default();
+    let previous_spine_path = spine[..spine_position].iter().rev().find(|spine_item| spine_item.has_navigation).map(|spine_item| &spine_item.path);
+    let next_spine_path = spine[spine_position + 1..].iter().find(|spine_item| spine_item.has_navigation).map(|spine_item| &spine_item.path);
+
+    // Fixed-layout pages position their contents absolutely, so there the navigation floats over the page instead of taking up room on it.
+    let wrapper = match layout.fixed_layout {
+        true => writer.create_element("div").with_attribute(("style", "position: fixed; bottom: 0; left: 0; right: 0; z-index: 2147483647;")),
+        false => writer.create_element("div"),
+    };
+    wrapper.write_inner_content::<_, Error>(|writer| {
+        writer.create_element("template").with_attribute(("shadowrootmode", "closed")).write_inner_content::<_, Error>(|writer| {
+            // In a right-to-left book, the direction alone puts Next on the left, where the following page is.
+            let direction = if layout.rtl { "rtl" } else { "ltr" };
+            writer.create_element("nav").with_attributes([("style", "text-align: center;"), ("dir", direction), ("aria-label", "Book navigation")]).write_inner_content::<_, Error>(|writer| {
+                // A cover only needs a way into the book.
+                if start_only {
+                    if let Some(path) = next_spine_path {
+                        writer.create_element("a").with_attribute(("href", relative_or_absolute_url(&page_dir, &book_contents_dir.join(path), None).as_str())).write_inner_content::<_, Error>(|writer| {
+                            writer.create_element("button").with_attributes([("type", "button"), ("tabindex", "-1")]).write_text_content(BytesText::new("Start reading"))?;
+                            Ok(())
+                        })?;
+                    }
+                    return Ok(())
+                }
+                // The links take focus in place of the buttons inside them, so each control is one stop when tabbing rather than two.
+                // Previous button
+                match previous_spine_path {
+                    Some(path) => writer.create_element("a").with_attribute(("href", relative_or_absolute_url(&page_dir, &book_contents_dir.join(path), None).as_str())).write_inner_content::<_, Error>(|writer| {
+                        writer.create_element("button").with_attributes([("type", "button"), ("tabindex", "-1")]).write_text_content(BytesText::new("Previous"))?;
+                        Ok(())
+                    })?,
+                    None => writer.create_element("button").with_attributes([("type", "button"), ("disabled", "disabled"), ("aria-disabled", "true")]).write_text_content(BytesText::new("Previous"))?,
+                };
+                // Index button
+                writer.create_element("a").with_attribute(("href", relative_or_absolute_url(&page_dir, book_index_path, None).as_str())).write_inner_content::<_, Error>(|writer| {
+                    writer.create_element("button").with_attributes([("type", "button"), ("tabindex", "-1")]).write_text_content(BytesText::new("Index"))?;
+                    Ok(())
+                })?;
+                // Next button
+                match next_spine_path {
+                    Some(path) => writer.create_element("a").with_attribute(("href", relative_or_absolute_url(&page_dir, &book_contents_dir.join(path), None).as_str())).write_inner_content::<_, Error>(|writer| {
+                        writer.create_element("button").with_attributes([("type", "button"), ("tabindex", "-1")]).write_text_content(BytesText::new("Next"))?;
+                        Ok(())
+                    })?,
+                    None => writer.create_element("button").with_attributes([("type", "button"), ("disabled", "disabled"), ("aria-disabled", "true")]).write_text_content(BytesText::new("Next"))?,
+                };
+                Ok(())
+            })?;
+            Ok(())
+        })?;
+        Ok(())
+    })?;
+    Ok(())
+}
+
+fn new_strict_xhtml_reader(xhtml: &[u8]) -> quick_xml::Reader<&[u8]> {
+    let mut reader = quick_xml::Reader::from_reader(xhtml);
+    let reader_config = reader.config_mut();
+    reader_config.enable_all_checks(true);
+    reader_config.expand_empty_elements = true;
+    reader
+}
+
+const KOBO_SPAN_CLASS: &str = "koboSpan";
+
+fn has_kobo_spans(xhtml: &[u8]) -> bool {
+    xhtml.windows(KOBO_SPAN_CLASS.len()).any(|window| window == KOBO_SPAN_CLASS.as_bytes())
+}
+
+fn is_kobo_injection(element: &BytesStart) -> bool {
+    // Kobo marks its style hacks with kobo-prefixed ids and loads its reader scripts from kobo-named files.
+    element.attributes().flatten().any(|attribute| match attribute.key.as_ref() {
+        b"id" => attribute.value.to_ascii_lowercase().starts_with(b"kobo"),
+        b"src" => String::from_utf8_lossy(&attribute.value).rsplit('/').next().is_some_and(|filename| filename.to_lowercase().starts_with("kobo")),
+        _ => false,
+    })
+}
+
+fn is_charset_meta(element: &BytesStart) -> bool {
+    element.name().as_ref() == b"meta" && element.attributes().flatten().any(|attribute| match attribute.key.as_ref() {
+        b"charset" => true,
+        b"http-equiv" => attribute.value.eq_ignore_ascii_case(b"content-type"),
+        _ => false,
+    })
+}
+
+fn is_kobo_span(element: &BytesStart) -> bool {
+    element.attributes().flatten().any(|attribute| attribute.key.as_ref() == b"class" && String::from_utf8_lossy(&attribute.value).split_whitespace().any(|class| class == KOBO_SPAN_CLASS))
+}
+
+// Where a book's resources were extracted to, for repairing hrefs whose case doesn't match.
+struct ResourceIndex {
+    paths: HashSet<PathBuf>,
+    paths_by_lowercase: HashMap<String, Vec<PathBuf>>,
+}
+
+impl ResourceIndex {
+    fn new(paths: impl IntoIterator<Item = PathBuf>) -> Self {
+        let paths = paths.into_iter().collect::<HashSet<PathBuf>>();
+        let mut paths_by_lowercase = HashMap::<String, Vec<PathBuf>>::new();
+        for path in &paths {
+            paths_by_lowercase.entry(path.to_string_lossy().to_lowercase()).or_default().push(path.clone());
+        }
+        for candidate_paths in paths_by_lowercase.values_mut() {
+            candidate_paths.sort();
+        }
+        Self {
+            paths,
+            paths_by_lowercase,
+        }
+    }
+
+    fn fix_href_case(&self, href: &str, document_path: &Path, warnings: &mut Vec<ImportWarning>) -> Option<String> {
+        // Books made on case-insensitive systems can get away with hrefs whose case differs from the file's; elsewhere those links break.
+        let first_segment = href.split(['/', '?', '#']).next().unwrap_or_default();
+        if href.starts_with('/') || first_segment.contains(':') {
+            return None
+        }
+        let (href_path, suffix) = href.split_at(href.find(['?', '#']).unwrap_or(href.len()));
+        if href_path.is_empty() {
+            return None
+        }
+        let target_path = contained_resource_path(&document_path.parent().unwrap_or(Path::new("")).join(percent_decode_str(href_path).decode_utf8_lossy().as_ref()))?;
+        if self.paths.contains(&target_path) {
+            return None
+        }
+        match self.paths_by_lowercase.get(&target_path.to_string_lossy().to_lowercase())?.as_slice() {
+            [real_path] => {
+                // Only the segments naming the target are recased; leading `..`s stay as they are.
+                let segments = href_path.split('/').collect::<Vec<&str>>();
+                let leading_length = segments.iter().take_while(|segment| matches!(**segment, "." | "..")).count();
+                let naming_segments = &segments[leading_length..];
+                let real_components = real_path.components().map(|component| component.as_os_str().to_string_lossy()).collect::<Vec<_>>();
+                if naming_segments.iter().any(|segment| matches!(*segment, "" | "." | "..")) || naming_segments.len() > real_components.len() {
+                    return None
+                }
+                let real_segments = real_components[real_components.len() - naming_segments.len()..].iter().map(|component| encode_href_segment(component));
+                let fixed_href = segments[..leading_length].iter().map(|segment| segment.to_string()).chain(real_segments).collect::<Vec<String>>().join("/") + suffix;
+                log::debug!("Fixing the case of {} in {} to {}.", href, document_path.display(), fixed_href);
+                Some(fixed_href)
+            },
+            real_paths => {
+                warnings.push(ImportWarning::AmbiguousLinkCase {
+                    href: href.to_string(),
+                    document_path: document_path.to_path_buf(),
+                    candidates: real_paths.to_vec(),
+                });
+                None
+            },
+        }
+    }
+}
+
+fn adjust_element_hrefs(element: &BytesStart, document_path: &Path, resource_index: Option<&ResourceIndex>, warnings: &mut Vec<ImportWarning>) -> BytesStart<'static> {
+    let mut adjusted_element = BytesStart::new(String::from_utf8_lossy(element.name().as_ref()).into_owned());
+    for attribute in element.attributes().flatten() {
+        match attribute.key.as_ref() {
+            b"href" | b"src" | b"xlink:href" | b"poster" | b"data" => {
+                let value = attribute.unescape_value().map(|value| value.into_owned()).unwrap_or_else(|_| String::from_utf8_lossy(&attribute.value).into_owned());
+                let portable_value = portable_href(&value);
+                let adjusted_value = resource_index.and_then(|resource_index| resource_index.fix_href_case(&portable_value, document_path, warnings)).unwrap_or(portable_value);
+                adjusted_element.push_attribute((attribute.key.as_ref(), adjusted_value.as_bytes()));
+            },
+            _ => adjusted_element.push_attribute(attribute),
+        }
+    }
+    adjusted_element
+}
+
+const PAGE_NUMBER_CLASS: &str = "rib-page-number";
+// The number floats at the end of the line the page began on, in the margin where print editions tend to put it.
+const PAGE_NUMBER_CSS: &str = ".rib-page-number::after {content: attr(data-rib-page); position: absolute; inset-inline-end: 0.5em; font-size: 0.75em; font-style: normal; font-weight: normal; opacity: 0.6;}";
+
+fn has_page_breaks(xhtml: &[u8]) -> bool {
+    xhtml.windows(b"pagebreak".len()).any(|window| window == b"pagebreak")
+}
+
+fn page_break_label(element: &BytesStart) -> Option<String> {
+    // EPUB 3 marks page breaks with epub:type, and DPUB-ARIA with a role; the page number is in a title or an aria-label.
+    let attribute_value = |key: &[u8]| element.attributes().flatten().find(|attribute| attribute.key.as_ref() == key).map(|attribute| attribute.unescape_value().map(|value| value.into_owned()).unwrap_or_else(|_| String::from_utf8_lossy(&attribute.value).into_owned()));
+    let is_page_break = attribute_value(b"epub:type").is_some_and(|types| types.split_whitespace().any(|epub_type| epub_type == "pagebreak"))
+        || attribute_value(b"role").is_some_and(|role| role.trim() == "doc-pagebreak");
+    if !is_page_break {
+        return None
+    }
+    attribute_value(b"title").or_else(|| attribute_value(b"aria-label")).map(|label| label.trim().to_string()).filter(|label| !label.is_empty())
+}
+
+fn mark_page_break(element: BytesStart<'static>) -> BytesStart<'static> {
+    let Some(label) = page_break_label(&element) else {
+        return element
+    };
+    let mut marked_element = BytesStart::new(String::from_utf8_lossy(element.name().as_ref()).into_owned());
+    let mut classes = vec![String::from(PAGE_NUMBER_CLASS)];
+    for attribute in element.attributes().flatten() {
+        match attribute.key.as_ref() {
+            b"class" => classes.insert(0, String::from_utf8_lossy(&attribute.value).into_owned()),
+            b"data-rib-page" => (),
+            _ => marked_element.push_attribute(attribute),
+        }
+    }
+    marked_element.push_attribute(("class", classes.join(" ").as_str()));
+    marked_element.push_attribute(("data-rib-page", label.as_str()));
+    marked_element
+}
+
+fn is_stylesheet_link(element: &BytesStart) -> bool {
+    // Alternate stylesheets are stylesheets too, and some books offer their broken styles as several.
+    element.name().as_ref() == b"link" && element.try_get_attribute("rel").ok().flatten().is_some_and(|rel| String::from_utf8_lossy(&rel.value).split_whitespace().any(|rel| rel.eq_ignore_ascii_case("stylesheet")))
+}
+
+fn strip_style_attribute(element: BytesStart<'static>) -> BytesStart<'static> {
+    if !element.attributes().flatten().any(|attribute| attribute.key.as_ref() == b"style") {
+        return element
+    }
+    let mut stripped_element = BytesStart::new(String::from_utf8_lossy(element.name().as_ref()).into_owned());
+    stripped_element.extend_attributes(element.attributes().flatten().filter(|attribute| attribute.key.as_ref() != b"style"));
+    stripped_element
+}
+
+// The title of the set the book's own stylesheets are kept in when they're stripped, for browsers that can switch to it.
+const BOOK_STYLES_TITLE: &str = "Book styles";
+
+fn demote_stylesheet_link(element: BytesStart<'static>) -> BytesStart<'static> {
+    // An alternate stylesheet isn't applied until it's picked from the browser's page style menu, such as Firefox's View > Page Style.
+    let mut demoted_element = BytesStart::new(String::from_utf8_lossy(element.name().as_ref()).into_owned());
+    demoted_element.extend_attributes(element.attributes().flatten().filter(|attribute| !matches!(attribute.key.as_ref(), b"rel" | b"title")));
+    demoted_element.push_attribute(("rel", "alternate stylesheet"));
+    demoted_element.push_attribute(("title", BOOK_STYLES_TITLE));
+    demoted_element
+}
+
+// Which elements' style attributes lose a forced property: those the stylesheet's overriding rule for it selects.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ForcedOn {
+    AllElements,
+    Elements(&'static [&'static str]),
+}
+
+impl ForcedOn {
+    fn includes(self, element_name: &str) -> bool {
+        match self {
+            Self::AllElements => true,
+            Self::Elements(element_names) => element_names.iter().any(|forced_element_name| forced_element_name.eq_ignore_ascii_case(element_name)),
+        }
+    }
+}
+
+// The CSS properties that, set in a style attribute, would beat the stylesheet's overriding styles, along with the
+// shorthands that set them too.
+fn forced_properties(stylesheet: &Stylesheet) -> Vec<(&'static str, ForcedOn)> {
+    let mut properties = Vec::new();
+    let mut force = |override_book: bool, forced_on: ForcedOn, names: &[&'static str]| {
+        if override_book {
+            properties.extend(names.iter().map(|name| (*name, forced_on)));
+        }
+    };
+    if let Some(font) = &stylesheet.font {
+        force(font.override_book, ForcedOn::AllElements, &["font", "font-family"]);
+    }
+    if let Some(font_size) = &stylesheet.font_size {
+        force(font_size.override_book, ForcedOn::Elements(&["body"]), &["font", "font-size"]);
+    }
+    if let Some(text_color) = &stylesheet.text_color {
+        force(text_color.override_book, ForcedOn::AllElements, &["color"]);
+    }
+    if let Some(link_color) = &stylesheet.link_color {
+        force(link_color.override_book, ForcedOn::Elements(&["a"]), &["color"]);
+    }
+    if let Some(background_color) = &stylesheet.background_color {
+        force(background_color.override_book, ForcedOn::Elements(&["html", "body"]), &["background", "background-color"]);
+    }
+    if let Some(line_spacing) = &stylesheet.line_spacing {
+        force(line_spacing.override_book, ForcedOn::AllElements, &["font", "line-height"]);
+    }
+    if let Some(indentation) = &stylesheet.indentation {
+        force(indentation.override_book, ForcedOn::Elements(&["p"]), &["text-indent"]);
+    }
+    if let Some(margin_size) = &stylesheet.margin_size {
+        force(margin_size.override_book, ForcedOn::Elements(&["body"]), &["margin", "margin-left", "margin-right"]);
+    }
+    if let Some(max_width) = &stylesheet.max_width {
+        force(max_width.override_book, ForcedOn::Elements(&["body"]), &["max-width"]);
+    }
+    if let Some(StyleLimitImageSizeToViewportSize { value: true, override_book }) = stylesheet.limit_image_size_to_viewport_size {
+        force(override_book, ForcedOn::Elements(&["img", "svg"]), &["max-width", "max-height"]);
+    }
+    if let Some(max_image_height) = &stylesheet.max_image_height {
+        force(max_image_height.override_book, ForcedOn::Elements(&["img", "svg"]), &["max-height"]);
+    }
+    if let Some(max_image_width) = &stylesheet.max_image_width {
+        force(max_image_width.override_book, ForcedOn::Elements(&["img", "svg"]), &["max-width"]);
+    }
+    properties
+}
+
+// Splits a style attribute into its declarations, minding semicolons inside quotes and url()s.
+fn style_declarations(style: &str) -> Vec<&str> {
+    let mut declarations = Vec::new();
+    let (mut start, mut depth, mut quote) = (0, 0usize, None);
+    for (position, character) in style.char_indices() {
+        match (quote, character) {
+            (Some(open_quote), _) if character == open_quote => quote = None,
+            (Some(_), _) => (),
+            (None, '"' | '\'') => quote = Some(character),
+            (None, '(') => depth += 1,
+            (None, ')') => depth = depth.saturating_sub(1),
+            (None, ';') if depth == 0 => {
+                declarations.push(&style[start..position]);
+                start = position + 1;
+            },
+            (None, _) => (),
+        }
+    }
+    declarations.push(&style[start..]);
+    declarations.into_iter().filter(|declaration| !declaration.trim().is_empty()).collect()
+}
+
+fn strip_style_properties(style: &str, properties: &[&str]) -> String {
+    style_declarations(style).into_iter()
+        .filter(|declaration| {
+            let property = declaration.split(':').next().unwrap_or_default().trim();
+            !properties.iter().any(|forced_property| forced_property.eq_ignore_ascii_case(property))
+        })
+        .map(str::trim)
+        .collect::<Vec<&str>>()
+        .join("; ")
+}
+
+fn strip_forced_properties(element: BytesStart<'static>, forced_properties: &[(&'static str, ForcedOn)]) -> BytesStart<'static> {
+    let element_name = String::from_utf8_lossy(element.local_name().as_ref()).into_owned();
+    let properties = forced_properties.iter().filter(|(_property, forced_on)| forced_on.includes(&element_name)).map(|(property, _forced_on)| *property).collect::<Vec<&str>>();
+    if properties.is_empty() {
+        return element
+    }
+    let Some(style) = element.try_get_attribute("style").ok().flatten() else {
+        return element
+    };
+    let style = style.unescape_value().map(|style| style.into_owned()).unwrap_or_else(|_| String::from_utf8_lossy(&style.value).into_owned());
+    let stripped_style = strip_style_properties(&style, &properties);
+    let mut stripped_element = BytesStart::new(String::from_utf8_lossy(element.name().as_ref()).into_owned());
+    for attribute in element.attributes().flatten() {
+        match attribute.key.as_ref() {
+            b"style" if stripped_style.is_empty() => (),
+            b"style" => stripped_element.push_attribute(("style", stripped_style.as_str())),
+            _ => stripped_element.push_attribute(attribute),
+        }
+    }
+    stripped_element
+}
+
+// What changes about a spine document's own markup as it's extracted, besides the navigation added to it.
+struct SpineXhtmlRewrite<'a> {
+    document_path: &'a Path,
+    strip_kobo_spans: bool,
+    // Links follow resources renamed on extraction, and, given an index to check against, get their case fixed.
+    adjust_hrefs: bool,
+    resource_index: Option<&'a ResourceIndex>,
+    cover_navigation: CoverNavigation,
+    show_page_numbers: bool,
+    strip_book_css: bool,
+    strip_inline_styles: bool,
+    // Empty unless the stylesheet forces its styles.
+    forced_properties: Vec<(&'static str, ForcedOn)>,
+    csp: ContentSecurityPolicy,
+    // Where the book's copies of rib's stylesheets are, for pages that link them.
+    styles_dir: Option<&'a Path>,
+}
+
+// The rewritten document goes straight to output as it's produced, so a chapter of any size only ever needs the one copy read from the book.
+fn rewrite_spine_xhtml<W: Write>(xhtml: &[u8], output: W, rewrite: &SpineXhtmlRewrite, pages: BookPages, spine_position: usize, warnings: &mut Vec<ImportWarning>) -> Result<W, String> {
+    let BookPages { contents_dir: book_contents_dir, spine, layout, .. } = pages;
+    // Every change is made in one pass, since chapters can be large and each pass means parsing the whole document again.
+    let cover_navigation = match spine[spine_position].is_cover {
+        true => rewrite.cover_navigation,
+        false => CoverNavigation::Full,
+    };
+    // A cover's Start reading button sits under the artwork, except on fixed-layout pages, where the one navigation overlay is all there is.
+    let has_start_navigation = spine[spine_position].has_navigation && match cover_navigation {
+        CoverNavigation::Full => true,
+        CoverNavigation::Start => layout.fixed_layout,
+        CoverNavigation::None => false,
+    };
+    let has_end_navigation = spine[spine_position].has_navigation && !layout.fixed_layout && cover_navigation != CoverNavigation::None;
+    let start_only = cover_navigation == CoverNavigation::Start;
+    let page_dir = book_contents_dir.join(&spine[spine_position].path).parent().map(Path::to_path_buf).unwrap_or_default();
+    let stylesheet_hrefs = rewrite.styles_dir.map(|styles_dir| {
+        (relative_or_absolute_url(&page_dir, &styles_dir.join(NO_OVERRIDE_STYLESHEET_FILENAME), None), relative_or_absolute_url(&page_dir, &styles_dir.join(OVERRIDE_STYLESHEET_FILENAME), None))
+    });
+    // Documents in codepages rib can read are written out as UTF-8 and declared as such, in the XML declaration for XHTML parsing and a meta charset for HTML parsing.
+    // Any other encoding is left as declared, since the bytes can't be reencoded.
+    let transcoded_xhtml;
+    let (xhtml, is_utf8) = match declared_encoding(xhtml).as_deref() {
+        None | Some("utf-8" | "utf8") => (xhtml, std::str::from_utf8(xhtml).is_ok()),
+        Some(encoding) => match decode_codepage(xhtml, encoding) {
+            Some(text) => {
+                transcoded_xhtml = text.into_bytes();
+                (transcoded_xhtml.as_slice(), true)
+            },
+            None => {
+                log::debug!("Leaving {} in its {} encoding, which rib can't convert to UTF-8.", rewrite.document_path.display(), encoding);
+                (xhtml, false)
+            },
+        },
+    };
+    let mut reader = new_strict_xhtml_reader(xhtml);
+    let mut writer = quick_xml::Writer::new(output);
+    if is_utf8 && !xhtml.trim_ascii_start().starts_with(b"<?xml") {
+        writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("utf-8"), None))).map_err(xhtml_writing_error)?;
+        writer.write_event(Event::Text(BytesText::from_escaped("\n"))).map_err(xhtml_writing_error)?;
+    }
+    let marks_page_breaks = rewrite.show_page_numbers && has_page_breaks(xhtml);
+    let mut adjust = |element: BytesStart| {
+        let element = match rewrite.adjust_hrefs {
+            true => adjust_element_hrefs(&element, rewrite.document_path, rewrite.resource_index, warnings),
+            false => element.into_owned(),
+        };
+        let element = match (rewrite.strip_inline_styles, rewrite.forced_properties.is_empty()) {
+            (true, _) => strip_style_attribute(element),
+            (false, false) => strip_forced_properties(element, &rewrite.forced_properties),
+            (false, true) => element,
+        };
+        match marks_page_breaks {
+            true => mark_page_break(element),
+            false => element,
+        }
+    };
+    // Whether each open span is a koboSpan, so its end tag can be dropped along with its start tag.
+    let mut open_spans = Vec::new();
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) if rewrite.strip_kobo_spans && e.name().as_ref() == b"span" => {
+                let is_kobo_span = is_kobo_span(&e);
+                if !is_kobo_span {
+                    writer.write_event(Event::Start(adjust(e))).map_err(xhtml_writing_error)?;
+                }
+                open_spans.push(is_kobo_span);
+            },
+            Ok(Event::End(e)) if rewrite.strip_kobo_spans && e.name().as_ref() == b"span" => {
+                if !open_spans.pop().unwrap_or_default() {
+                    writer.write_event(Event::End(e)).map_err(xhtml_writing_error)?;
+                }
+            },
+            Ok(Event::Start(e)) if rewrite.strip_kobo_spans && matches!(e.name().as_ref(), b"style" | b"script") && is_kobo_injection(&e) => {
+                reader.read_to_end(e.name()).map_err(|e| format!("XHTML reading error at byte {}: {}", reader.error_position(), e))?;
+            },
+            // rib's own styles are written straight to the output rather than read from the book, so only the book's are dropped.
+            // Linked stylesheets are kept, switched off, for a reader who wants to see what the book intended.
+            Ok(Event::Start(e)) if rewrite.strip_book_css && e.name().as_ref() == b"style" => {
+                reader.read_to_end(e.name()).map_err(|e| format!("XHTML reading error at byte {}: {}", reader.error_position(), e))?;
+            },
+            Ok(Event::Empty(e)) if rewrite.strip_book_css && e.name().as_ref() == b"style" => (),
+            Ok(Event::Start(e)) if rewrite.strip_book_css && is_stylesheet_link(&e) => writer.write_event(Event::Start(demote_stylesheet_link(adjust(e)))).map_err(xhtml_writing_error)?,
+            Ok(Event::Empty(e)) if rewrite.strip_book_css && is_stylesheet_link(&e) => writer.write_event(Event::Empty(demote_stylesheet_link(adjust(e)))).map_err(xhtml_writing_error)?,
+            Ok(Event::PI(e)) if rewrite.strip_book_css && e.target() == b"xml-stylesheet" => (),
+            Ok(Event::Decl(e)) if is_utf8 => {
+                let version = e.version().map(|version| String::from_utf8_lossy(&version).into_owned()).unwrap_or_else(|_| String::from("1.0"));
+                let standalone = e.standalone().and_then(Result::ok).map(|standalone| String::from_utf8_lossy(&standalone).into_owned());
+                writer.write_event(Event::Decl(BytesDecl::new(&version, Some("utf-8"), standalone.as_deref()))).map_err(xhtml_writing_error)?;
+            },
+            // Charset declarations are replaced by one naming UTF-8, placed first in the head so HTML parsing finds it early.
+            // The content security policy follows, ahead of anything it should apply to.
+            Ok(Event::Start(e)) if e.name().as_ref() == b"head" => {
+                writer.write_event(Event::Start(adjust(e))).map_err(xhtml_writing_error)?;
+                if is_utf8 {
+                    writer.write_event(Event::Empty(BytesStart::new("meta").with_attributes([("charset", "utf-8")]))).map_err(xhtml_writing_error)?;
+                }
+                if let Some(directives) = rewrite.csp.directives() {
+                    writer.write_event(Event::Empty(BytesStart::new("meta").with_attributes([("http-equiv", "Content-Security-Policy"), ("content", directives)]))).map_err(xhtml_writing_error)?;
+                }
+                // Ahead of the book's own styles, so they win wherever they disagree.
+                if let Some((no_override_href, _override_href)) = &stylesheet_hrefs {
+                    inject_styles(&mut writer, no_override_href).map_err(xhtml_writing_error)?;
+                }
+            },
+            Ok(Event::Empty(e)) if is_utf8 && is_charset_meta(&e) => (),
+            Ok(Event::Start(e)) if is_utf8 && is_charset_meta(&e) => {
+                reader.read_to_end(e.name()).map_err(|e| format!("XHTML reading error at byte {}: {}", reader.error_position(), e))?;
+            },
+            Ok(Event::End(e)) if e.name().as_ref() == b"head" => {
+                if marks_page_breaks {
+                    writer.create_element("style").write_text_content(BytesText::new(PAGE_NUMBER_CSS)).map_err(xhtml_writing_error)?;
+                }
+                // After the book's own styles, which its !important rules then override.
+                if let Some((_no_override_href, override_href)) = &stylesheet_hrefs {
+                    inject_styles(&mut writer, override_href).map_err(xhtml_writing_error)?;
+                }
+                writer.write_event(Event::End(e)).map_err(xhtml_writing_error)?;
+            },
+            Ok(Event::Start(e)) if e.name().as_ref() == b"body" => {
+                writer.write_event(Event::Start(adjust(e))).map_err(xhtml_writing_error)?;
+                if has_start_navigation {
+                    write_navigation_element(&mut writer, pages, spine_position, start_only).map_err(xhtml_writing_error)?;
+                }
+            },
+            // An overlay is the same wherever it sits in the page, so fixed-layout pages only need the one.
+            Ok(Event::End(e)) if e.name().as_ref() == b"body" && has_end_navigation => {
+                write_navigation_element(&mut writer, pages, spine_position, start_only).map_err(xhtml_writing_error)?;
+                writer.write_event(Event::End(e)).map_err(xhtml_writing_error)?;
+            },
+            Ok(Event::Start(e)) => writer.write_event(Event::Start(adjust(e))).map_err(xhtml_writing_error)?,
+            Ok(Event::Empty(e)) => writer.write_event(Event::Empty(adjust(e))).map_err(xhtml_writing_error)?,
+            Ok(Event::Eof) => break,
+            Ok(e) => writer.write_event(e.borrow()).map_err(xhtml_writing_error)?,
+            Err(e) => return Err(format!("XHTML reading error at byte {}: {}", reader.error_position(), e)),
+        }
+    }
+
+    Ok(writer.into_inner())
+}
+
+fn xhtml_writing_error(e: quick_xml::Error) -> String {
+    format!("XHTML writing error: {}", e)
+}
+
+fn inject_styles<W: Write>(writer: &mut quick_xml::Writer<W>, href: &str) -> quick_xml::Result<()> {
+    writer.write_event(Event::Empty(BytesStart::new("link").with_attributes([("rel", "stylesheet"), ("type", "text/css"), ("href", href)])))
+}
+
+fn css_rule(selector: &str, declarations: &[(&str, String)], important: bool) -> String {
+    let priority = if important { " !important" } else { "" };
+    let declarations = declarations.iter().map(|(property, value)| format!("{}: {}{};", property, value, priority)).collect::<Vec<String>>();
+    format!("{} {{{}}}\n", selector, declarations.join(" "))
+}
+
+// Without a color scheme to match, the overscroll area and scrollbars stay light around a dark page. None when the
+// background isn't a color rib can tell light from dark.
+fn color_scheme_declarations(stylesheet: &Stylesheet) -> Option<Vec<(&'static str, String)>> {
+    let background_color = &stylesheet.background_color.as_ref()?.value;
+    let background = helpers::parse_css_color(background_color)?;
+    // Below this luminance, white text contrasts better than black.
+    let color_scheme = if helpers::relative_luminance(background) < 0.179 { "dark" } else { "light" };
+    let mut declarations = vec![("color-scheme", String::from(color_scheme))];
+    if let Some(text_color) = &stylesheet.text_color {
+        declarations.push(("scrollbar-color", format!("{} {}", text_color.value, background_color)));
+    }
+    Some(declarations)
+}
+
+// The CSS for a book's own pages, as two stylesheets: one linked ahead of the book's styles, for the properties the book
+// can override, and one after them, for those it can't. Either is empty when the stylesheet sets nothing for it.
+fn create_book_css(stylesheet: &Stylesheet) -> (String, String) {
+    let mut no_override_css = String::new();
+    let mut override_css = String::new();
+    // Overriding styles also go on every element the book could style otherwise, rather than only on the body they'd be inherited from.
+    let mut add_rule = |override_book: bool, selector: &str, override_selector: &str, declarations: &[(&str, String)]| {
+        match override_book {
+            false => no_override_css.push_str(&css_rule(selector, declarations, false)),
+            true => override_css.push_str(&css_rule(override_selector, declarations, true)),
+        }
+    };
+    if let Some(font) = &stylesheet.font {
+        // Code keeps its monospace font.
+        add_rule(font.override_book, "body", "body, body *:not(pre, pre *, code, kbd, samp)", &[("font-family", font.value.clone())]);
+    }
+    if let Some(font_size) = &stylesheet.font_size {
+        // Only the base size, which the book's relative sizes for headings and the like scale from.
+        add_rule(font_size.override_book, "body", "body", &[("font-size", format!("{}px", font_size.value))]);
+    }
+    if let Some(text_color) = &stylesheet.text_color {
+        add_rule(text_color.override_book, "body", "body, body *", &[("color", text_color.value.clone())]);
+    }
+    if let Some(link_color) = &stylesheet.link_color {
+        add_rule(link_color.override_book, "a", "body a, body a *", &[("color", link_color.value.clone())]);
+    }
+    if let Some(background_color) = &stylesheet.background_color {
+        add_rule(background_color.override_book, "html, body", "html, body", &[("background", background_color.value.clone())]);
+        if let Some(root_declarations) = color_scheme_declarations(stylesheet) {
+            add_rule(background_color.override_book, ":root", ":root", &root_declarations);
+        }
+    }
+    if let Some(line_spacing) = &stylesheet.line_spacing {
+        add_rule(line_spacing.override_book, "body", "body, body *", &[("line-height", line_spacing.value.to_string())]);
+    }
+    if let Some(indentation) = &stylesheet.indentation {
+        add_rule(indentation.override_book, "p", "body p", &[("text-indent", format!("{}px", indentation.value))]);
+    }
+    if let Some(margin_size) = &stylesheet.margin_size {
+        add_rule(margin_size.override_book, "body", "body", &[("margin-left", format!("{}px", margin_size.value)), ("margin-right", format!("{}px", margin_size.value))]);
+    }
+    if let Some(max_width) = &stylesheet.max_width {
+        add_rule(max_width.override_book, "body", "body", &[("max-width", format!("{}px", max_width.value))]);
+    }
+    if let Some(StyleLimitImageSizeToViewportSize { value: true, override_book }) = stylesheet.limit_image_size_to_viewport_size {
+        add_rule(override_book, "img, svg", "body img, body svg", &[("max-width", String::from("100%")), ("max-height", String::from("100vh")), ("object-fit", String::from("contain"))]);
+    }
+    // Coming after the viewport limit, a cap in px takes its place.
+    if let Some(max_image_height) = &stylesheet.max_image_height {
+        add_rule(max_image_height.override_book, "img, svg", "body img, body svg", &[("max-height", format!("{}px", max_image_height.value)), ("object-fit", String::from("contain"))]);
+    }
+    if let Some(max_image_width) = &stylesheet.max_image_width {
+        add_rule(max_image_width.override_book, "img, svg", "body img, body svg", &[("max-width", format!("{}px", max_image_width.value)), ("object-fit", String::from("contain"))]);
+    }
+    if let Some(freeform_css_no_override) = &stylesheet.freeform_css_no_override {
+        no_override_css = format!("{}{}\n", no_override_css, freeform_css_no_override);
+    }
+    if let Some(freeform_css_override) = &stylesheet.freeform_css_override {
+        override_css = format!("{}{}\n", override_css, freeform_css_override);
+    }
+    (no_override_css, override_css)
+}
+
+fn create_index_css(stylesheet: &Stylesheet) -> Option<String> {
+    // Add non-inline styling for the index table (overriding even override styles, at least pending addition of an override-even-reader-UI style-category)
+    let mut css = String::new();
+
+    let mut body_styles = Vec::new();
+    if let Some(font) = &stylesheet.font {
+        body_styles.push(format!("font-family: {};", font.value));
+    }
+    if let Some(font_size) = &stylesheet.font_size {
+        body_styles.push(format!("font-size: {}px;", font_size.value));
+    }
+    if let Some(text_color) = &stylesheet.text_color {
+        body_styles.push(format!("color: {};", text_color.value));
+    }
+    if let Some(background_color) = &stylesheet.background_color {
+        body_styles.push(format!("background: {};", background_color.value));
+    }
+    if let Some(line_spacing) = &stylesheet.line_spacing {
+        body_styles.push(format!("line-height: {};", line_spacing.value));
+    }
+    if let Some(indentation) = &stylesheet.indentation {
+        body_styles.push(format!("text-indent: {}px;", indentation.value));
+    }
+    if let Some(max_width) = &stylesheet.max_width {
+        // Confirm this actually works
+        body_styles.push(format!("max-width: {}px;", max_width.value));
+    }
+    if let Some(margin_size) = &stylesheet.margin_size {
+        body_styles.push(format!("margin-left: {}px; margin-right: {}px;", margin_size.value, margin_size.value));
+    }
+
+    if !body_styles.is_empty() {
+        css = format!("body {{{}}}\n", body_styles.join(" "))
+    }
+    if let (Some(background_color), Some(mut root_declarations)) = (&stylesheet.background_color, color_scheme_declarations(stylesheet)) {
+        root_declarations.push(("background", background_color.value.clone()));
+        css.push_str(&css_rule(":root", &root_declarations, false));
+    }
+    if let Some(link_color) = &stylesheet.link_color {
+        css = format!("{}a {{color: {}}}\n", css, link_color.value);
+    }
+    if let Some(StyleLimitImageSizeToViewportSize {
+        value: true,
+        ..
+    }) = &stylesheet.limit_image_size_to_viewport_size {
+        // Make sure this plays well with margins
+        css = format!("{}img {{max-width: 100%}};\n", css);
+    }
+    if let Some(max_image_height) = &stylesheet.max_image_height {
+        css = format!("{}img {{max-height: {}px}}\n", css, max_image_height.value);
+    }
+    if let Some(max_image_width) = &stylesheet.max_image_width {
+        css = format!("{}img {{max-width: {}px}}\n", css, max_image_width.value);
+    }
+    if let Some(freeform_css_no_override) = &stylesheet.freeform_css_no_override {
+        css = format!("{}{}\n", css, freeform_css_no_override);
+    }
+    if let Some(freeform_css_override) = &stylesheet.freeform_css_override {
+        css = format!("{}{}\n", css, freeform_css_override);
+    }
+
+    if css.is_empty() {
+        None
+    } else {
+        Some(css)
+    }
+}
+
+const UNTITLED_TOC_LABEL: &str = "(untitled)";
+
+fn normalize_toc_label(label: &str) -> String {
+    // Some NCX files escape the markup of their labels, which would otherwise be shown as text, tags and all.
+    let mut text = String::new();
+    let mut rest = label;
+    while let Some(tag_start) = rest.find('<') {
+        text.push_str(&rest[..tag_start]);
+        let tag = &rest[tag_start..];
+        let looks_like_tag = tag[1..].trim_start_matches('/').chars().next().is_some_and(|character| character.is_ascii_alphabetic());
+        match (looks_like_tag, tag.find('>')) {
+            (true, Some(tag_end)) => rest = &tag[tag_end + 1..],
+            _ => {
+                text.push('<');
+                rest = &tag[1..];
+            },
+        }
+    }
+    text.push_str(rest);
+    // Line breaks and runs of spaces in labels would otherwise make the index ragged.
+    match text.split_whitespace().collect::<Vec<&str>>().join(" ") {
+        normalized_label if normalized_label.is_empty() => String::from(UNTITLED_TOC_LABEL),
+        normalized_label => normalized_label,
+    }
+}
+
+fn localize_toc_item_format(nav_point: epub::doc::NavPoint, nesting_level: usize) -> TocItem {
+
+    let content = nav_point.content.to_string_lossy();
+    let mut path_split = content.split("#").collect::<Vec<&str>>();
+    let path = match path_split.len() {
+        0 => PathBuf::new(), // This should be possible per the EPUB spec, even if the library is failing to expose it well.
+        1 => PathBuf::from(path_split.first().unwrap()),
+        _ => {
+            let _fragment = path_split.pop().unwrap();
+            PathBuf::from(path_split.join("#"))
+        }
+    };
+    TocItem {
+        iri: nav_point.content,
+        path,
+        label: normalize_toc_label(&nav_point.label),
+        children: nav_point.children.into_iter().map(|child| localize_toc_item_format(child, nesting_level + 1)).collect(),
+        nesting_level,
+    }
+}
+
+fn flatten_toc_items(toc_items: &[TocItem]) -> Vec<&TocItem> {
+    let mut flattened_toc_items = Vec::new();
+    for toc_item in toc_items {
+        flattened_toc_items.push(toc_item);
+        flattened_toc_items.append(&mut flatten_toc_items(&toc_item.children));
+    }
+    flattened_toc_items
+}
+
+fn normalize_book_path(path: &str) -> PathBuf {
+    // Book-internal hrefs vary in separators, percent-encoding, and `.` and `..` segments; reduce them to one comparable form.
+    let decoded = percent_decode_str(path).decode_utf8_lossy().replace('\\', "/");
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => (),
+            // A `..` past the top can't match any path in the book, so it's kept to keep the path from matching one.
+            ".." if segments.last().is_some_and(|last| *last != "..") => {
+                segments.pop();
+            },
+            segment => segments.push(segment),
+        }
+    }
+    segments.into_iter().collect()
+}
+
+fn split_fragment(href: &str) -> (&str, Option<&str>) {
+    match href.rsplit_once('#') {
+        Some((path, fragment)) => (path, Some(fragment)),
+        None => (href, None),
+    }
+}
+
+// Where each path sits in the spine, so placing every TOC entry doesn't mean searching the whole spine for each one.
+struct SpineIndex {
+    positions_by_path: HashMap<PathBuf, Vec<usize>>,
+}
+
+impl SpineIndex {
+    fn new(spine: &[SpineItem]) -> Self {
+        let mut positions_by_path = HashMap::<PathBuf, Vec<usize>>::new();
+        for (spine_position, spine_item) in spine.iter().enumerate() {
+            positions_by_path.entry(normalize_book_path(&spine_item.path.to_string_lossy())).or_default().push(spine_position);
+        }
+        Self {
+            positions_by_path,
+        }
+    }
+
+    fn positions(&self, path: &Path) -> &[usize] {
+        self.positions_by_path.get(&normalize_book_path(&path.to_string_lossy())).map(Vec::as_slice).unwrap_or_default()
+    }
+}
+
+fn toc_item_spine_index(toc_item: &TocItem, spine_index: &SpineIndex) -> Option<usize> {
+    spine_index.positions(&toc_item.path).first().copied()
+}
+
+fn resolve_open_target(spec: &str, toc: &[TocItem], spine: &[SpineItem]) -> Result<(PathBuf, Option<String>), String> {
+    // Resolves an `--at` spec to a spine path plus optional fragment, trying spine index, then content path, then TOC label.
+    if let Some(index) = spec.strip_prefix("spine:") {
+        let linear_spine = spine.iter().filter(|spine_item| spine_item.linear).collect::<Vec<&SpineItem>>();
+        let index = index.parse::<usize>().map_err(|_| format!("'{}' isn't a valid spine index.", index))?;
+        return match linear_spine.get(index) {
+            Some(spine_item) => Ok((spine_item.path.clone(), None)),
+            None => Err(format!("Spine index {} is out of range; this book's linear spine indices run from 0 to {}.", index, linear_spine.len().saturating_sub(1))),
+        }
+    }
+
+    let (spec_path, spec_fragment) = split_fragment(spec);
+    let spec_path = normalize_book_path(&portable_href(spec_path));
+    if !spec_path.as_os_str().is_empty() {
+        if let Some(spine_item) = spine.iter().find(|spine_item| normalize_book_path(&spine_item.path.to_string_lossy()).ends_with(&spec_path)) {
+            return Ok((spine_item.path.clone(), spec_fragment.map(String::from)))
+        }
+    }
+
+    let spec_lowercase = spec.to_lowercase();
+    let mut matching_toc_items = flatten_toc_items(toc).into_iter().filter(|toc_item| toc_item.label.to_lowercase().contains(&spec_lowercase)).collect::<Vec<&TocItem>>();
+    matching_toc_items.dedup_by(|first, second| first.iri == second.iri);
+    if matching_toc_items.len() > 1 {
+        // An exact label match beats substring matches, so 'Chapter 1' isn't ambiguous with 'Chapter 10'.
+        let exact_matches = matching_toc_items.iter().copied().filter(|toc_item| toc_item.label.to_lowercase() == spec_lowercase).collect::<Vec<&TocItem>>();
+        if exact_matches.len() == 1 {
+            matching_toc_items = exact_matches;
+        }
+    }
+    match matching_toc_items.as_slice() {
+        [] => Err(format!("'{}' doesn't match any spine index, content path, or table of contents entry.", spec)),
+        [toc_item] => {
+            let iri = toc_item.iri.to_string_lossy();
+            let (_path, fragment) = split_fragment(&iri);
+            Ok((toc_item.path.clone(), fragment.map(String::from)))
+        },
+        _ => Err(format!(
+            "'{}' matches multiple table of contents entries:\n{}",
+            spec,
+            matching_toc_items.iter().map(|toc_item| format!("  {} ({})", toc_item.label, toc_item.iri.display())).collect::<Vec<String>>().join("\n"),
+        )),
+    }
+}
+
+fn toc_is_linear_relative_to_spine(toc: &[TocItem], spine_index: &SpineIndex) -> bool {
+    // TOC items pointing outside the spine can't be slotted into the spine-aligned table, so treat them as making the TOC nonlinear.
+    let mut last_spine_index = 0;
+    for toc_item in flatten_toc_items(toc) {
+        match toc_item_spine_index(toc_item, spine_index) {
+            Some(toc_item_spine_index) if toc_item_spine_index >= last_spine_index => last_spine_index = toc_item_spine_index,
+            _ => return false,
+        }
+    }
+    true
+}
+
+fn map_toc_items_to_spine_items(toc: &[TocItem], spine: &[SpineItem], spine_index: &SpineIndex) -> Vec<(SpineItem, Vec<TocItem>)> {
+    let mut toc_items_by_spine_position = vec![Vec::new(); spine.len()];
+    for toc_item in flatten_toc_items(toc) {
+        for &spine_position in spine_index.positions(&toc_item.path) {
+            toc_items_by_spine_position[spine_position].push(toc_item.clone());
+        }
+    }
+    spine.iter().cloned().zip(toc_items_by_spine_position).collect()
+}
+
+fn toc_item_href(toc_item: &TocItem, book_contents_dir: &Path) -> String {
+    // The index sits just above the contents dir.
+    let index_dir = book_contents_dir.parent().unwrap_or(book_contents_dir);
+    let iri = toc_item.iri.to_string_lossy();
+    let (path, fragment) = split_fragment(&iri);
+    relative_or_absolute_url(index_dir, &book_contents_dir.join(percent_decode_str(path).decode_utf8_lossy().as_ref()), fragment)
+}
+
+fn more_toc_items_note(hidden_count: usize) -> maud::Markup {
+    html! {
+        @if hidden_count > 0 {
+            " (and " (hidden_count) " more)"
+        }
+    }
+}
+
+fn list_toc_items_for_linear_index_spine_entry(toc_items: &[TocItem], book_contents_dir: &Path, toc_depth: Option<usize>) -> maud::Markup {
+    fn list_toc_items_for_linear_index_spine_entry_recursive(book_contents_dir: &Path, nesting_level: usize, toc_items_iter: &mut std::iter::Peekable<std::slice::Iter<(&TocItem, usize)>>) -> maud::Markup {
+        let mut list_items = Vec::new();
+        while let Some((next_item, _hidden_count)) = toc_items_iter.peek() {
+            match nesting_level.cmp(&next_item.nesting_level) {
+                Ordering::Equal => {
+                    let (toc_item, hidden_count) = toc_items_iter.next().unwrap();
+                    list_items.push(html! {
+                        li {
+                            a href=(toc_item_href(toc_item, book_contents_dir)) { (toc_item.label) }
+                            (more_toc_items_note(*hidden_count))
+                        }
+                    });
+                },
+                Ordering::Less => list_items.push(html! {
+                    ul {
+                        (list_toc_items_for_linear_index_spine_entry_recursive(book_contents_dir, nesting_level + 1, toc_items_iter))
+                    }
+                }),
+                // The rest of the items belong to a shallower list, further up.
+                Ordering::Greater => break,
+            }
+        }
+        html! {
+            @for list_item in list_items {
+                (list_item)
+            }
+        }
+    }
+
+    // Entries deeper than the limit are counted against the nearest shown entry before them rather than listed.
+    let mut shown_toc_items: Vec<(&TocItem, usize)> = Vec::new();
+    let mut leading_hidden_count = 0;
+    for toc_item in toc_items {
+        match (toc_depth.is_some_and(|toc_depth| toc_item.nesting_level >= toc_depth), shown_toc_items.last_mut()) {
+            (false, _) => shown_toc_items.push((toc_item, 0)),
+            (true, Some((_shown_toc_item, hidden_count))) => *hidden_count += 1,
+            (true, None) => leading_hidden_count += 1,
+        }
+    }
+    html! {
+        @if leading_hidden_count > 0 {
+            li { "(" (leading_hidden_count) " more)" }
+        }
+        (list_toc_items_for_linear_index_spine_entry_recursive(book_contents_dir, 0, &mut shown_toc_items.iter().peekable()))
+    }
+}
+
+fn list_toc_items_for_nonlinear_index(toc_items: &[TocItem], book_contents_dir: &Path, toc_depth: Option<usize>) -> maud::Markup {
+    html! {
+        @for toc_item in toc_items {
+            @let shows_children = toc_depth.is_none_or(|toc_depth| toc_item.nesting_level + 1 < toc_depth);
+            li {
+                a href=(toc_item_href(toc_item, book_contents_dir)) { (toc_item.label) }
+                @if !shows_children {
+                    (more_toc_items_note(flatten_toc_items(&toc_item.children).len()))
+                }
+            }
+            @if shows_children && !toc_item.children.is_empty() {
+                ul {
+                    (list_toc_items_for_nonlinear_index(&toc_item.children, book_contents_dir, toc_depth))
+                }
+            }
+        }
+    }
+}
+
+fn format_reading_time(word_count: usize, words_per_minute: usize) -> Option<String> {
+    // Pages without text, like covers and illustrations, get no time rather than a misleading minute.
+    if word_count == 0 || words_per_minute == 0 {
+        return None
+    }
+    let minutes = word_count.div_ceil(words_per_minute);
+    match (minutes / 60, minutes % 60) {
+        (0, minutes) => Some(format!("{} min", minutes)),
+        (hours, 0) => Some(format!("{} h", hours)),
+        (hours, minutes) => Some(format!("{} h {} min", hours, minutes)),
+    }
+}
+
+// "A", "A & B", "A, B & C", or with a serial comma, "A, B, & C".
+fn join_names(names: &[&str], serial_comma: bool) -> Option<String> {
+    match names {
+        [] => None,
+        [name] => Some(name.to_string()),
+        [first, second] => Some(format!("{} & {}", first, second)),
+        [rest @ .., last] => Some(format!("{}{} & {}", rest.join(", "), if serial_comma { "," } else { "" }, last)),
+    }
+}
+
+fn join_creator_names(creators: &[BookCreator], role: CreatorRole, serial_comma: bool) -> Option<String> {
+    join_names(&creators.iter().filter(|creator| creator.role == role).map(|creator| creator.name.as_str()).collect::<Vec<&str>>(), serial_comma)
+}
+
+// What a book's index lists, besides its pages, as read from the book before it's extracted.
+#[derive(Copy, Clone)]
+struct IndexContents<'a> {
+    titles: &'a BookTitles,
+    creators: &'a [BookCreator],
+    toc: &'a [TocItem],
+    page_list: &'a [PageListItem],
+}
+
+// How a book's index lists it, from config and the command line.
+#[derive(Copy, Clone)]
+struct IndexOptions {
+    toc_depth: Option<usize>,
+    words_per_minute: usize,
+    creator_serial_comma: bool,
+}
+
+fn create_index(book: &EpubDoc<BufReader<File>>, contents: IndexContents, pages: BookPages, csp: ContentSecurityPolicy, options: IndexOptions) -> Result<String, String> {
+    let IndexContents { titles, creators, toc, page_list } = contents;
+    let BookPages { contents_dir: book_contents_dir, spine, layout, .. } = pages;
+    let IndexOptions { toc_depth, words_per_minute, creator_serial_comma: serial_comma } = options;
+    let (Some(first_spine_item), Some(last_spine_item)) = (spine.first(), spine.last()) else {
+        return Err(String::from("Ill-formed EPUB: spine is empty."))
+    };
+    let index_dir = book_contents_dir.parent().unwrap_or(book_contents_dir);
+    let title = titles.full();
+    let spine_index = SpineIndex::new(spine);
+    // A depth of 0 leaves only the spine. The depth only limits what's listed; linearity is judged on the whole table of contents.
+    let shows_toc = toc_depth != Some(0);
+    let reading_time = |word_count: Option<usize>| html! {
+        @if let Some(reading_time) = word_count.and_then(|word_count| format_reading_time(word_count, words_per_minute)) {
+            " (" (reading_time) ")"
+        }
+    };
+    // Nonlinear items like footnotes are read as they're reached, if at all, so the book's total leaves them out.
+    let total_word_count = spine.iter().filter(|spine_item| spine_item.linear).filter_map(|spine_item| spine_item.word_count).sum::<usize>();
+
+    Ok(html!{
+        (DOCTYPE)
+        html lang="en" dir=(if layout.rtl { "rtl" } else { "ltr" }) {
+            head {
+                meta charset="utf-8";
+                @if let Some(directives) = csp.directives() {
+                    meta http-equiv="Content-Security-Policy" content=(directives);
+                }
+                title {
+                    // Just the main title, to keep tabs short.
+                    "rib | " (titles.main) " | Index" // Rename
+                }
+                link rel="stylesheet" href=(INDEX_STYLESHEET_FILENAME);
+            }
+            body style="text-align: center;" {
+                h1 { (title) }
+                @if let Some(collection) = &titles.collection {
+                    p { "Part of " (collection) }
+                }
+                @if let Some(authors) = join_creator_names(creators, CreatorRole::Author, serial_comma).or_else(|| book.mdata("creator")) {
+                    h3 { (authors) }
+                }
+                @for role in CreatorRole::SECONDARY {
+                    @if let Some(names) = join_creator_names(creators, role, serial_comma) {
+                        p { (role.credit()) " " (names) }
+                    }
+                }
+                @if let Some(total_reading_time) = format_reading_time(total_word_count, words_per_minute) {
+                    p { "About " (total_reading_time) " to read" }
+                }
+                @if let Some(cover_path) = book.get_cover_id().and_then(|cover_id| book.resources.get(&cover_id)).and_then(|(cover_path, _cover_mimetype)| contained_resource_path(cover_path)) {
+                    img alt=(format!("Cover of {}", title)) src=(relative_or_absolute_url(index_dir, &book_contents_dir.join(portable_resource_path(&cover_path)), None));
+                }
+                p {
+                    a href=(relative_or_absolute_url(index_dir, &book_contents_dir.join(&first_spine_item.path), None)) { "Start" }
+                }
+                // Bodymatter, if there's a good way to get it within the limits of this epub crate
+                p {
+                    a href=(relative_or_absolute_url(index_dir, &book_contents_dir.join(&last_spine_item.path), None)) { "End" }
+                }
+                @if !page_list.is_empty() {
+                    details {
+                        summary { "Printed pages (" (page_list.len()) ")" }
+                        p {
+                            @for page_list_item in page_list {
+                                a href=(relative_or_absolute_url(index_dir, &book_contents_dir.join(&page_list_item.path), page_list_item.fragment.as_deref())) { (page_list_item.label) }
+                                " "
+                            }
+                        }
+                    }
+                }
+                table style="border-collapse: collapse; margin-left: auto; margin-right: auto;" {
+                    caption { "Contents" }
+                    // Factor styles out to the stylesheet probably (using the same techniques, in case of override, as are used for main book body)
+                    // Make margins more consistent for list-items
+                    @if toc_is_linear_relative_to_spine(toc, &spine_index) {
+                        tr {
+                            th scope="col" style="border: 1px solid black; vertical-align: top;" { "Spine" }
+                            @if shows_toc {
+                                th scope="col" style="border: 1px solid black; vertical-align: top;" { "Table of Contents" }
+                            }
+                        }
+                        @for (spine_item, toc_items) in map_toc_items_to_spine_items(toc, spine, &spine_index) {
+                            tr {
+                                td style="border: 1px solid black; vertical-align: top;" {
+                                    ul style="text-align: start;" {
+                                        li {
+                                            a href=(relative_or_absolute_url(index_dir, &book_contents_dir.join(&spine_item.path), None)) { (&spine_item.path.display()) }
+                                            @if !spine_item.linear {
+                                                " (nonlinear)"
+                                            }
+                                            (reading_time(spine_item.word_count))
+                                        }
+                                    }
+                                }
+                                @if shows_toc {
+                                    td style="border: 1px solid black; vertical-align: top;" {
+                                        @if !toc_items.is_empty() {
+                                            ul style="text-align: start;" {
+                                                (list_toc_items_for_linear_index_spine_entry(&toc_items, book_contents_dir, toc_depth))
+                                            }
+                                        } @else {
+                                            br;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    } @else {
+                        tr {
+                            th scope="col" style="border: 1px solid black; vertical-align: top;" { "Spine" }
+                            @if shows_toc {
+                                td { br; }
+                                th scope="col" style="border: 1px solid black; vertical-align: top;" { "Table of Contents" }
+                            }
+                        }
+                        tr {
+                            td style="border: 1px solid black; vertical-align: top;" {
+                                ul style="text-align: start;" {
+                                    @for spine_item in spine {
+                                        @if spine_item.linear {
+                                            li {
+                                                a href=(relative_or_absolute_url(index_dir, &book_contents_dir.join(&spine_item.path), None)) { (&spine_item.path.display()) }
+                                                (reading_time(spine_item.word_count))
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            @if shows_toc {
+                                td { br; }
+                                td style="border: 1px solid black; vertical-align: top;" {
+                                    ul style="text-align: start;" {
+                                        (list_toc_items_for_nonlinear_index(toc, book_contents_dir, toc_depth))
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }.into_string())
+}
+
+const SHELF_FILENAME: &str = "shelf.html";
+const SHELF_CSS: &str = "body {font-family: sans-serif; margin: 2em;}
+.shelf {display: grid; grid-template-columns: repeat(auto-fill, minmax(10em, 1fr)); gap: 1.5em;}
+.book {display: flex; flex-direction: column; color: inherit; text-decoration: none;}
+.cover {width: 100%; aspect-ratio: 2 / 3; object-fit: cover; border: 1px solid #ccc;}
+.placeholder {display: flex; align-items: center; justify-content: center; box-sizing: border-box; padding: 1em; background: #ddd; text-align: center; font-weight: bold; overflow: hidden;}
+.title {margin-top: 0.5em; font-weight: bold;}
+.creator {color: #666;}
+";
+
+fn create_shelf(cache: &Cache) -> String {
+    let mut cached_books = cache.contents.iter().filter(|cached_book| cache.book_dir(cached_book).is_dir()).collect::<Vec<&CachedBook>>();
+    cached_books.sort_by_key(|cached_book| Reverse(cached_book.last_opened_time));
+
+    // Links are relative to the library root, where the shelf lives, so they survive the library being moved or synced.
+    html!{
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                title { "rib | Library" }
+                style { (SHELF_CSS) }
+            }
+            body {
+                h1 { "Library" }
+                div class="shelf" {
+                    @for cached_book in cached_books {
+                        @let title = cached_book.title.as_deref().unwrap_or(&cached_book.id);
+                        a class="book" href=(relative_or_absolute_url(cache.root(), &cache.book_dir(cached_book).join("index.html"), None)) {
+                            @match cached_book.cover.as_ref().filter(|cover| cache.book_dir(cached_book).join(cover).is_file()) {
+                                Some(cover) => {
+                                    img class="cover" alt="" src=(relative_or_absolute_url(cache.root(), &cache.book_dir(cached_book).join(cover), None));
+                                },
+                                None => {
+                                    div class="cover placeholder" { (title) }
+                                },
+                            }
+                            div class="title" { (title) }
+                            @if let Some(creator) = &cached_book.creator {
+                                div class="creator" { (creator) }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }.into_string()
+}
+
+fn get_book_cache_id(book: &EpubDoc<BufReader<File>>) -> Option<String> {
+    match book.get_release_identifier() {
+        Some(release_id) => Some(release_id),
+        None => book.unique_identifier.clone(),
+    }
+}
+
+fn spine_media_type_is_supported(media_type: &str) -> bool {
+    matches!(media_type, "application/xhtml+xml" | "image/svg+xml")
+}
+
+// What a spine item is treated as, which for one whose manifest entry gives the wrong media type is what its content turns out to be.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum SpineItemFormat {
+    Xhtml,
+    Svg,
+    // Left as it is, without navigation or styles.
+    Unsupported,
+}
+
+impl SpineItemFormat {
+    fn media_type(self) -> &'static str {
+        match self {
+            Self::Xhtml => "application/xhtml+xml",
+            Self::Svg => "image/svg+xml",
+            Self::Unsupported => "unsupported",
+        }
+    }
+}
+
+fn sniff_spine_item_format(media_type: &str, content: &[u8]) -> SpineItemFormat {
+    match media_type {
+        "application/xhtml+xml" => return SpineItemFormat::Xhtml,
+        "image/svg+xml" => return SpineItemFormat::Svg,
+        _ => (),
+    }
+    // Converters label XHTML chapters text/html or application/octet-stream often enough that only content that isn't well-formed XML, or whose root isn't one rib can render, counts as something else.
+    let mut reader = quick_xml::Reader::from_reader(content);
+    let mut root_name = None;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e) | Event::Empty(e)) if root_name.is_none() => root_name = Some(e.local_name().as_ref().to_ascii_lowercase()),
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(_) => return SpineItemFormat::Unsupported,
+        }
+    }
+    match root_name.as_deref() {
+        Some(b"html") => SpineItemFormat::Xhtml,
+        Some(b"svg") => SpineItemFormat::Svg,
+        _ => SpineItemFormat::Unsupported,
+    }
+}
+
+fn contained_resource_path(path: &Path) -> Option<PathBuf> {
+    // Guards against zip-slip: a resource path must stay inside the directory it's extracted into.
+    // `.` and `..` are resolved here rather than by the file system, where `dir/..` would pass through whatever `dir` links to.
+    let mut normalized_path = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized_path.push(name),
+            Component::CurDir => (),
+            Component::ParentDir => if !normalized_path.pop() {
+                return None
+            },
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized_path)
+}
+
+fn resource_path_is_contained(path: &Path) -> bool {
+    contained_resource_path(path).is_some()
+}
+
+fn create_contained(root: &Path, relative_path: &Path) -> Result<File, String> {
+    // Lexical checks can't see symlinks, so the directory actually being written into is resolved and checked against the (canonical) root too.
+    let target_path = root.join(relative_path);
+    let target_dir = target_path.parent().unwrap_or(root);
+    create_dir_all(target_dir).map_err(|e| format!("Couldn't create cache subdir {}: {}", target_dir.display(), e))?;
+    let canonical_target_dir = canonicalize(target_dir).map_err(|e| format!("Couldn't resolve cache subdir {}: {}", target_dir.display(), e))?;
+    if !canonical_target_dir.starts_with(root) {
+        return Err(format!("Refusing to write {}, which resolves to {}, outside the book's directory.", relative_path.display(), canonical_target_dir.display()))
+    }
+    let Some(file_name) = target_path.file_name() else {
+        return Err(format!("Refusing to write {}, which has no file name.", relative_path.display()))
+    };
+    // Writing through a symlink would land wherever it points, so one in the way is replaced by a regular file.
+    let canonical_target_path = canonical_target_dir.join(file_name);
+    if canonical_target_path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        remove_file(&canonical_target_path).map_err(|e| format!("Couldn't remove symlink {}: {}", canonical_target_path.display(), e))?;
+    }
+    File::create(&canonical_target_path).map_err(|e| format!("Failed to write {} from book to disk: {}", relative_path.display(), e))
+}
+
+fn write_contained(root: &Path, relative_path: &Path, contents: impl AsRef<[u8]>) -> Result<(), String> {
+    create_contained(root, relative_path)?.write_all(contents.as_ref()).map_err(|e| format!("Failed to write {} from book to disk: {}", relative_path.display(), e))
+}
+
+fn get_spine_linearity(book: &mut EpubDoc<BufReader<File>>) -> Result<Vec<bool>, String> {
+    // The epub crate doesn't expose itemref linearity, so read it off the package document directly.
+    let root_file = book.root_file.clone();
+    let package_document = book.get_resource_by_path(&root_file).ok_or_else(|| format!("Couldn't read package document {}.", root_file.display()))?;
+    let mut reader = quick_xml::Reader::from_reader(package_document.as_ref());
+    let mut linearity = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e) | Event::Empty(e)) if e.local_name().as_ref() == b"itemref" => {
+                let linear = e.try_get_attribute("linear").ok().flatten().is_none_or(|attribute| attribute.value.as_ref() != b"no");
+                linearity.push(linear);
+            },
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(e) => return Err(format!("Couldn't parse package document {}: {}", root_file.display(), e)),
+        }
+    }
+    Ok(linearity)
+}
+
+// An entry in an EPUB 3 navigation document's list of print pages.
+struct PageListItem {
+    label: String,
+    path: PathBuf,
+    fragment: Option<String>,
+}
+
+fn get_page_list(book: &mut EpubDoc<BufReader<File>>) -> Result<Vec<PageListItem>, String> {
+    // The navigation document is the manifest item with the nav property, which the epub crate doesn't expose either.
+    let root_file = book.root_file.clone();
+    let package_document = book.get_resource_by_path(&root_file).ok_or_else(|| format!("Couldn't read package document {}.", root_file.display()))?;
+    let package_dir = root_file.parent().unwrap_or(Path::new(""));
+    let mut reader = quick_xml::Reader::from_reader(package_document.as_ref());
+    let mut nav_path = None;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e) | Event::Empty(e)) if e.local_name().as_ref() == b"item" && e.try_get_attribute("properties").ok().flatten().is_some_and(|attribute| String::from_utf8_lossy(&attribute.value).split_whitespace().any(|property| property == "nav")) => {
+                nav_path = e.try_get_attribute("href").ok().flatten().map(|href| normalize_book_path(&package_dir.join(String::from_utf8_lossy(&href.value).as_ref()).to_string_lossy()));
+                break
+            },
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(e) => return Err(format!("Couldn't parse package document {}: {}", root_file.display(), e)),
+        }
+    }
+    // EPUB 2 books have no navigation document, and a missing or broken one only costs the index its page list.
+    let Some(nav_document) = nav_path.as_ref().and_then(|nav_path| book.get_resource_by_path(nav_path)) else {
+        return Ok(Vec::new())
+    };
+    let nav_dir = nav_path.as_deref().and_then(Path::parent).unwrap_or(Path::new(""));
+
+    let mut reader = quick_xml::Reader::from_reader(nav_document.as_ref());
+    reader.config_mut().check_end_names = false;
+    let mut page_list = Vec::new();
+    let mut nav_depth = 0;
+    let mut page_list_depth = None;
+    let mut current_link: Option<(String, String)> = None;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) if e.local_name().as_ref() == b"nav" => {
+                nav_depth += 1;
+                if e.try_get_attribute("epub:type").ok().flatten().is_some_and(|attribute| String::from_utf8_lossy(&attribute.value).split_whitespace().any(|epub_type| epub_type == "page-list")) {
+                    page_list_depth = Some(nav_depth);
+                }
+            },
+            Ok(Event::End(e)) if e.local_name().as_ref() == b"nav" => {
+                if page_list_depth == Some(nav_depth) {
+                    page_list_depth = None;
+                }
+                nav_depth -= 1;
+            },
+            Ok(Event::Start(e)) if page_list_depth.is_some() && e.local_name().as_ref() == b"a" => {
+                current_link = e.try_get_attribute("href").ok().flatten().map(|href| (String::from_utf8_lossy(&href.value).into_owned(), String::new()));
+            },
+            Ok(Event::Text(e)) => if let Some((_href, label)) = &mut current_link {
+                label.push_str(&e.unescape().map(|text| text.into_owned()).unwrap_or_else(|_| String::from_utf8_lossy(&e).into_owned()));
+            },
+            Ok(Event::End(e)) if e.local_name().as_ref() == b"a" => if let Some((href, label)) = current_link.take() {
+                let (path, fragment) = split_fragment(&href);
+                page_list.push(PageListItem {
+                    label: normalize_toc_label(&label),
+                    path: portable_resource_path(&normalize_book_path(&nav_dir.join(path).to_string_lossy())),
+                    fragment: fragment.map(String::from),
+                });
+            },
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(_) => (),
+        }
+    }
+    Ok(page_list)
+}
+
+fn get_cover_page_paths(book: &mut EpubDoc<BufReader<File>>) -> Result<Vec<PathBuf>, String> {
+    // Nor does it expose the guide, where EPUB 2 books name their cover page.
+    let root_file = book.root_file.clone();
+    let package_document = book.get_resource_by_path(&root_file).ok_or_else(|| format!("Couldn't read package document {}.", root_file.display()))?;
+    let package_dir = root_file.parent().unwrap_or(Path::new(""));
+    let mut reader = quick_xml::Reader::from_reader(package_document.as_ref());
+    let mut cover_page_paths = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e) | Event::Empty(e)) if e.local_name().as_ref() == b"reference" && e.try_get_attribute("type").ok().flatten().is_some_and(|attribute| attribute.value.eq_ignore_ascii_case(b"cover")) => {
+                if let Some(href) = e.try_get_attribute("href").ok().flatten() {
+                    let href = String::from_utf8_lossy(&href.value).into_owned();
+                    let (path, _fragment) = split_fragment(&href);
+                    cover_page_paths.push(portable_resource_path(&normalize_book_path(&package_dir.join(path).to_string_lossy())));
+                }
+            },
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(e) => return Err(format!("Couldn't parse package document {}: {}", root_file.display(), e)),
+        }
+    }
+    Ok(cover_page_paths)
+}
+
+fn get_book_layout(book: &mut EpubDoc<BufReader<File>>) -> Result<BookLayout, String> {
+    // As with linearity, the epub crate doesn't expose page progression or rendition properties.
+    let root_file = book.root_file.clone();
+    let package_document = book.get_resource_by_path(&root_file).ok_or_else(|| format!("Couldn't read package document {}.", root_file.display()))?;
+    let mut reader = quick_xml::Reader::from_reader(package_document.as_ref());
+    let mut layout = BookLayout::default();
+    let mut in_layout_meta = false;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e) | Event::Empty(e)) if e.local_name().as_ref() == b"spine" => {
+                layout.rtl = e.try_get_attribute("page-progression-direction").ok().flatten().is_some_and(|attribute| attribute.value.as_ref() == b"rtl");
+            },
+            Ok(Event::Start(e)) if e.local_name().as_ref() == b"meta" => {
+                in_layout_meta = e.try_get_attribute("property").ok().flatten().is_some_and(|attribute| attribute.value.as_ref() == b"rendition:layout");
+            },
+            Ok(Event::Text(e)) if in_layout_meta => {
+                layout.fixed_layout = e.trim_ascii() == b"pre-paginated";
+            },
+            Ok(Event::End(e)) if e.local_name().as_ref() == b"meta" => in_layout_meta = false,
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(e) => return Err(format!("Couldn't parse package document {}: {}", root_file.display(), e)),
+        }
+    }
+    Ok(layout)
+}
+
+fn get_book_creators(book: &mut EpubDoc<BufReader<File>>) -> Result<Vec<BookCreator>, String> {
+    // The epub crate gives creators' names alone, without the roles EPUB 2 sets as attributes and EPUB 3 as refinements.
+    struct Credit {
+        id: Option<String>,
+        name: String,
+        relator_code: Option<String>,
+        is_contributor: bool,
+    }
+
+    let root_file = book.root_file.clone();
+    let package_document = book.get_resource_by_path(&root_file).ok_or_else(|| format!("Couldn't read package document {}.", root_file.display()))?;
+    let mut reader = quick_xml::Reader::from_reader(package_document.as_ref());
+    let mut credits = Vec::<Credit>::new();
+    let mut relator_codes_by_id = HashMap::<String, String>::new();
+    let mut in_credit = false;
+    let mut refined_id = None;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) if matches!(e.local_name().as_ref(), b"creator" | b"contributor") => {
+                credits.push(Credit {
+                    id: e.try_get_attribute("id").ok().flatten().map(|attribute| String::from_utf8_lossy(&attribute.value).into_owned()),
+                    name: String::new(),
+                    // EPUB 2's opf:role, whatever the prefix is bound as.
+                    relator_code: e.attributes().flatten().find(|attribute| attribute.key.local_name().as_ref() == b"role").map(|attribute| String::from_utf8_lossy(&attribute.value).into_owned()),
+                    is_contributor: e.local_name().as_ref() == b"contributor",
+                });
+                in_credit = true;
+            },
+            Ok(Event::End(e)) if matches!(e.local_name().as_ref(), b"creator" | b"contributor") => in_credit = false,
+            Ok(Event::Start(e)) if e.local_name().as_ref() == b"meta" && e.try_get_attribute("property").ok().flatten().is_some_and(|attribute| attribute.value.as_ref() == b"role") => {
+                refined_id = e.try_get_attribute("refines").ok().flatten().map(|attribute| String::from_utf8_lossy(&attribute.value).trim_start_matches('#').to_string());
+            },
+            Ok(Event::End(e)) if e.local_name().as_ref() == b"meta" => refined_id = None,
+            Ok(Event::Text(e)) => {
+                let text = e.unescape().map(|text| text.into_owned()).unwrap_or_default();
+                if in_credit {
+                    if let Some(credit) = credits.last_mut() {
+                        credit.name.push_str(&text);
+                    }
+                } else if let Some(refined_id) = &refined_id {
+                    relator_codes_by_id.entry(refined_id.clone()).or_default().push_str(&text);
+                }
+            },
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(e) => return Err(format!("Couldn't parse package document {}: {}", root_file.display(), e)),
+        }
+    }
+    // Contributors are often the tools that made the book, so they're only credited with a role the index knows.
+    Ok(credits.into_iter().filter_map(|credit| {
+        let relator_code = credit.id.as_ref().and_then(|id| relator_codes_by_id.get(id)).or(credit.relator_code.as_ref());
+        let role = match relator_code.and_then(|relator_code| CreatorRole::from_relator_code(relator_code)) {
+            Some(role) => role,
+            None if credit.is_contributor => return None,
+            None => CreatorRole::Author,
+        };
+        let name = credit.name.split_whitespace().collect::<Vec<&str>>().join(" ");
+        (!name.is_empty()).then_some(BookCreator {
+            name,
+            role,
+        })
+    }).collect())
+}
+
+fn get_book_titles(book: &mut EpubDoc<BufReader<File>>) -> Result<Option<BookTitles>, String> {
+    // Like creators' roles, which title is which is only told by refinements, which the epub crate doesn't read.
+    struct Title {
+        id: Option<String>,
+        text: String,
+    }
+
+    let root_file = book.root_file.clone();
+    let package_document = book.get_resource_by_path(&root_file).ok_or_else(|| format!("Couldn't read package document {}.", root_file.display()))?;
+    let mut reader = quick_xml::Reader::from_reader(package_document.as_ref());
+    let mut titles = Vec::<Title>::new();
+    // Refinements by the id of the title they refine: its title-type and display-seq.
+    let mut refinements = HashMap::<(String, &'static str), String>::new();
+    let mut in_title = false;
+    let mut refinement = None;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) if e.local_name().as_ref() == b"title" => {
+                titles.push(Title {
+                    id: e.try_get_attribute("id").ok().flatten().map(|attribute| String::from_utf8_lossy(&attribute.value).into_owned()),
+                    text: String::new(),
+                });
+                in_title = true;
+            },
+            Ok(Event::End(e)) if e.local_name().as_ref() == b"title" => in_title = false,
+            Ok(Event::Start(e)) if e.local_name().as_ref() == b"meta" => {
+                let property = e.try_get_attribute("property").ok().flatten().and_then(|attribute| match attribute.value.as_ref() {
+                    b"title-type" => Some("title-type"),
+                    b"display-seq" => Some("display-seq"),
+                    _ => None,
+                });
+                let refined_id = e.try_get_attribute("refines").ok().flatten().map(|attribute| String::from_utf8_lossy(&attribute.value).trim_start_matches('#').to_string());
+                refinement = refined_id.zip(property);
+            },
+            Ok(Event::End(e)) if e.local_name().as_ref() == b"meta" => refinement = None,
+            Ok(Event::Text(e)) => {
+                let text = e.unescape().map(|text| text.into_owned()).unwrap_or_default();
+                if in_title {
+                    if let Some(title) = titles.last_mut() {
+                        title.text.push_str(&text);
+                    }
+                } else if let Some(refinement) = &refinement {
+                    refinements.entry(refinement.clone()).or_default().push_str(text.trim());
+                }
+            },
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(e) => return Err(format!("Couldn't parse package document {}: {}", root_file.display(), e)),
+        }
+    }
+    let refinement = |title: &Title, property: &'static str| title.id.as_ref().and_then(|id| refinements.get(&(id.clone(), property)));
+    let mut titles = titles.iter().filter(|title| !title.text.trim().is_empty()).collect::<Vec<&Title>>();
+    // Books that don't say which title is which keep the one they always opened with.
+    if !titles.iter().any(|title| refinement(title, "title-type").is_some()) {
+        return Ok(book.mdata("title").map(BookTitles::main_only))
+    }
+    titles.sort_by_key(|title| refinement(title, "display-seq").and_then(|display_seq| display_seq.parse::<usize>().ok()).unwrap_or(usize::MAX));
+    let title_of_type = |title_type: Option<&str>| titles.iter().find(|title| refinement(title, "title-type").map(String::as_str) == title_type).map(|title| title.text.split_whitespace().collect::<Vec<&str>>().join(" "));
+    let Some(main) = title_of_type(Some("main")).or_else(|| title_of_type(None)).or_else(|| book.mdata("title")) else {
+        return Ok(None)
+    };
+    Ok(Some(BookTitles {
+        main,
+        subtitle: title_of_type(Some("subtitle")),
+        collection: title_of_type(Some("collection")),
+    }))
+}
+
+fn make_toc_item_portable(toc_item: TocItem) -> TocItem {
+    TocItem {
+        iri: PathBuf::from(portable_href(&toc_item.iri.to_string_lossy())),
+        path: PathBuf::from(portable_href(&toc_item.path.to_string_lossy())),
+        children: toc_item.children.into_iter().map(make_toc_item_portable).collect(),
+        ..toc_item
+    }
+}
+
+fn get_toc(book: &EpubDoc<BufReader<File>>) -> Vec<TocItem> {
+    // Paths are given as extracted, so they match the renamed files.
+    book.toc.iter().map(|nav_point| make_toc_item_portable(localize_toc_item_format(nav_point.clone(), 0))).collect()
+}
+
+fn get_spine(book: &mut EpubDoc<BufReader<File>>) -> Result<Vec<SpineItem>, String> {
+    let spine_linearity = get_spine_linearity(book)?;
+    book.spine.iter().enumerate().map(|(spine_position, spine_item_id)| match book.resources.get(spine_item_id) {
+        // Navigation and the index link to spine items by path, so one outside the contents dir would send them outside the book.
+        Some((path, _mimetype)) => Ok(SpineItem {
+            id: spine_item_id.clone(),
+            path: contained_resource_path(path).map(|contained_path| portable_resource_path(&contained_path)).ok_or_else(|| format!("Ill-formed EPUB: spine item {} points outside the book's contents directory.", path.display()))?,
+            linear: spine_linearity.get(spine_position).copied().unwrap_or(true),
+            has_navigation: true,
+            word_count: None,
+            is_cover: false,
+        }),
+        None => Err(format!("Ill-formed EPUB: spine references id {}, which isn't in the manifest.", spine_item_id)),
+    }).collect()
+}
+
+// A page listed twice in the spine is extracted once, so its navigation can only follow one place in the reading order.
+// Keeping the first means Previous and Next never send a reader back to a page they've passed.
+fn remove_duplicate_spine_items(spine: &mut Vec<SpineItem>, warnings: &mut Vec<ImportWarning>) {
+    let mut occurrences = HashMap::<PathBuf, usize>::new();
+    for spine_item in spine.iter() {
+        *occurrences.entry(spine_item.path.clone()).or_default() += 1;
+    }
+    let mut duplicate_paths = Vec::new();
+    let mut seen_paths = HashSet::new();
+    spine.retain(|spine_item| {
+        let is_first = seen_paths.insert(spine_item.path.clone());
+        if is_first && occurrences[&spine_item.path] > 1 {
+            duplicate_paths.push(spine_item.path.clone());
+        }
+        is_first
+    });
+    for path in duplicate_paths {
+        warnings.push(ImportWarning::DuplicateSpineItem {
+            occurrences: occurrences[&path],
+            path,
+        });
+    }
+}
+
+fn linked_spine_positions(xhtml: &[u8], document_path: &Path, spine_positions_by_lowercase_path: &HashMap<String, Vec<usize>>) -> Vec<usize> {
+    // Case is ignored because links whose case is off get fixed on extraction, and counting an extra page as linked costs nothing.
+    let document_dir = document_path.parent().unwrap_or(Path::new(""));
+    let mut reader = quick_xml::Reader::from_reader(xhtml);
+    let mut positions = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e) | Event::Empty(e)) => {
+                for attribute in e.attributes().flatten().filter(|attribute| matches!(attribute.key.as_ref(), b"href" | b"xlink:href")) {
+                    let href = portable_href(&attribute.unescape_value().map(|value| value.into_owned()).unwrap_or_default());
+                    let first_segment = href.split(['/', '?', '#']).next().unwrap_or_default();
+                    let href_path = href.split(['?', '#']).next().unwrap_or_default();
+                    if href.starts_with('/') || first_segment.contains(':') || href_path.is_empty() {
+                        continue;
+                    }
+                    if let Some(target_path) = contained_resource_path(&document_dir.join(percent_decode_str(href_path).decode_utf8_lossy().as_ref())) {
+                        positions.extend(spine_positions_by_lowercase_path.get(&target_path.to_string_lossy().to_lowercase()).into_iter().flatten());
+                    }
+                }
+            },
+            // A document that can't be read fails extraction anyway, so whatever it linked to before the error is enough.
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(_) => (),
+        }
+    }
+    positions
+}
+
+fn find_reachable_spine_items(book: &mut EpubDoc<BufReader<File>>, spine: &[SpineItem], toc: &[TocItem]) -> Vec<bool> {
+    // Linear items and anything in the TOC can be reached directly; nonlinear items are otherwise only reachable through links from reachable pages.
+    let spine_index = SpineIndex::new(spine);
+    let mut is_reachable = spine.iter().map(|spine_item| spine_item.linear).collect::<Vec<bool>>();
+    for toc_item in flatten_toc_items(toc) {
+        for &spine_position in spine_index.positions(&toc_item.path) {
+            is_reachable[spine_position] = true;
+        }
+    }
+    if is_reachable.iter().all(|is_reachable| *is_reachable) {
+        return is_reachable
+    }
+
+    let mut spine_positions_by_lowercase_path = HashMap::<String, Vec<usize>>::new();
+    for (spine_position, spine_item) in spine.iter().enumerate() {
+        spine_positions_by_lowercase_path.entry(spine_item.path.to_string_lossy().to_lowercase()).or_default().push(spine_position);
+    }
+    let links = spine.iter().map(|spine_item| match book.get_resource(&spine_item.id) {
+        Some((xhtml, mimetype)) if sniff_spine_item_format(&mimetype, &xhtml) == SpineItemFormat::Xhtml => linked_spine_positions(&xhtml, &spine_item.path, &spine_positions_by_lowercase_path),
+        _ => Vec::new(),
+    }).collect::<Vec<Vec<usize>>>();
+    let mut queue = (0..spine.len()).filter(|&spine_position| is_reachable[spine_position]).collect::<VecDeque<usize>>();
+    while let Some(spine_position) = queue.pop_front() {
+        for &linked_position in &links[spine_position] {
+            if !is_reachable[linked_position] {
+                is_reachable[linked_position] = true;
+                queue.push_back(linked_position);
+            }
+        }
+    }
+    is_reachable
+}
+
+fn get_content_digest(book: &mut EpubDoc<BufReader<File>>) -> String {
+    // Only the words are hashed, since markup, whitespace, and page titles vary between releases of the same text.
+    let mut text = String::new();
+    for spine_item_id in book.spine.clone() {
+        let Some((xhtml, _mimetype)) = book.get_resource(&spine_item_id) else {
+            continue
+        };
+        let mut reader = quick_xml::Reader::from_reader(xhtml.as_slice());
+        let mut in_head = false;
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(e)) if e.local_name().as_ref() == b"head" => in_head = true,
+                Ok(Event::End(e)) if e.local_name().as_ref() == b"head" => in_head = false,
+                Ok(Event::Text(e)) if !in_head => {
+                    let Ok(unescaped) = e.unescape() else {
+                        continue
+                    };
+                    for word in unescaped.split_whitespace() {
+                        text.push_str(word);
+                        text.push(' ');
+                    }
+                },
+                Ok(Event::Eof) | Err(_) => break,
+                Ok(_) => (),
+            }
+        }
+    }
+    helpers::content_hash(text.as_bytes())
+}
+
+// How a book is extracted, from its stylesheet, config, and the command line.
+struct ExtractionOptions<'a> {
+    stylesheet: &'a Stylesheet,
+    kobo_spans: KoboSpanMode,
+    strict_paths: bool,
+    strict_media_types: bool,
+    // Whether every spine item gets navigation, rather than only those something links to.
+    all_navigation: bool,
+    cover_navigation: CoverNavigation,
+    show_page_numbers: bool,
+    index: IndexOptions,
+}
+
+fn dump_book(book: &mut EpubDoc<BufReader<File>>, index_dir: &Path, options: &ExtractionOptions, timings: &mut Timings, warnings: &mut Vec<ImportWarning>) -> Result<usize, String> {
+    let ExtractionOptions { stylesheet, kobo_spans, strict_paths, strict_media_types, all_navigation, cover_navigation, show_page_numbers, index: index_options } = *options;
+    let contents_dir = index_dir.join("epub");
+    let styles_dir = index_dir.join("styles");
+    create_dir_all(index_dir).map_err(|e| format!("Couldn't create cache dir {}: {}", index_dir.display(), e))?;
+    create_dir_all(&contents_dir).map_err(|e| format!("Couldn't create epub subdir for cache dir {}: {}", index_dir.display(), e))?;
+    create_dir_all(&styles_dir).map_err(|e| format!("Couldn't create styles subdir for cache dir {}: {}", index_dir.display(), e))?;
+    let index_path = index_dir.join("index.html");
+    let canonical_contents_dir = canonicalize(&contents_dir).map_err(|e| format!("Couldn't resolve epub subdir for cache dir {}: {}", index_dir.display(), e))?;
+
+    let mut dumped_bytes = 0;
+
+    // Everything the index needs is read before extracting, so a book that can't be indexed fails before the work rather than after it, and missing metadata only degrades the index.
+    let metadata_start = Instant::now();
+    let toc = get_toc(book);
+    let mut spine = get_spine(book)?;
+    remove_duplicate_spine_items(&mut spine, warnings);
+    if spine.is_empty() {
+        return Err(String::from("Ill-formed EPUB: spine is empty."))
+    }
+    let titles = get_book_titles(book)?.unwrap_or_else(|| {
+        let fallback_title = get_book_cache_id(book).unwrap_or_else(|| String::from("Untitled"));
+        warnings.push(ImportWarning::MissingTitle {
+            fallback_title: fallback_title.clone(),
+        });
+        BookTitles::main_only(fallback_title)
+    });
+    if toc.is_empty() {
+        log::debug!("{} has no table of contents, or one that couldn't be read, so its index lists only the spine.", titles.main);
+    }
+    let layout = get_book_layout(book)?;
+    let creators = get_book_creators(book)?;
+    if layout.fixed_layout {
+        warnings.push(ImportWarning::FixedLayout);
+    }
+    // Every page links the same two stylesheets, even when they're empty, so 'library style refresh' can fill them in later.
+    // Margins and widths would move a fixed-layout page's contents out from under their declared viewport, so those pages get none.
+    if !layout.fixed_layout {
+        let (no_override_css, override_css) = create_book_css(stylesheet);
+        for (filename, css) in [(NO_OVERRIDE_STYLESHEET_FILENAME, no_override_css), (OVERRIDE_STYLESHEET_FILENAME, override_css)] {
+            write(styles_dir.join(filename), &css).map_err(|e| format!("Failed to write {}: {}", filename, e))?;
+            dumped_bytes += css.len();
+        }
+    }
+    // Pages ahead of the first linear one are front matter like covers, besides any page the guide names as the cover.
+    let cover_page_paths = get_cover_page_paths(book)?;
+    let page_list = match show_page_numbers {
+        true => get_page_list(book)?,
+        false => Vec::new(),
+    };
+    let first_linear_position = spine.iter().position(|spine_item| spine_item.linear).unwrap_or(0);
+    for (spine_position, spine_item) in spine.iter_mut().enumerate() {
+        spine_item.is_cover = spine_position < first_linear_position || cover_page_paths.contains(&spine_item.path);
+    }
+    timings.add("read metadata and TOC", metadata_start.elapsed());
+    if !all_navigation {
+        let is_reachable = timings.time("find linked spine items", || find_reachable_spine_items(book, &spine, &toc));
+        for (spine_item, is_reachable) in spine.iter_mut().zip(is_reachable) {
+            spine_item.has_navigation = is_reachable;
+        }
+        let unreachable_count = spine.iter().filter(|spine_item| !spine_item.has_navigation).count();
+        if unreachable_count > 0 {
+            log::debug!("Leaving navigation off {} nonlinear spine items nothing links to.", unreachable_count);
+        }
+    }
+    let mut book_ids_and_paths = book.resources.iter().map(|(id, (path, _mimetype))| {
+        (id.clone(), path.clone())
+    }).collect::<Vec<(String, PathBuf)>>();
+    // The manifest comes out of a hash map, so it's put in a fixed order to make the same book extract the same way every time, down to which of two colliding files wins.
+    book_ids_and_paths.sort_by(|(first_id, first_path), (second_id, second_path)| first_path.cmp(second_path).then_with(|| first_id.cmp(second_id)));
+    // The spine is looked up once per resource, which adds up on books with thousands of chapters.
+    // It's looked up by path, so that manifest items sharing a spine item's file are extracted the same way it is.
+    let spine_positions_by_path = spine.iter().enumerate().map(|(spine_position, spine_item)| (spine_item.path.clone(), spine_position)).collect::<HashMap<PathBuf, usize>>();
+    let has_renamed_resources = book_ids_and_paths.iter().any(|(_id, path)| portable_resource_path(path) != *path);
+    let resource_index = (!strict_paths).then(|| ResourceIndex::new(book_ids_and_paths.iter().filter_map(|(_id, path)| contained_resource_path(path)).map(|path| portable_resource_path(&path))));
+    let mut output_paths = HashMap::new();
+    for (id, path) in book_ids_and_paths {
+        let Some(output_path) = contained_resource_path(&path).map(|contained_path| portable_resource_path(&contained_path)) else {
+            warnings.push(ImportWarning::ResourceOutsideContents {
+                path,
+            });
+            continue;
+        };
+        if let Some(colliding_path) = output_paths.insert(output_path.clone(), path.clone()) {
+            warnings.push(ImportWarning::ResourceCollision {
+                first_path: colliding_path,
+                second_path: path.clone(),
+                output_path: output_path.clone(),
+            });
+        }
+        match output_path != path {
+            true => log::debug!("Extracting {} as {}, a name every system accepts", path.display(), output_path.display()),
+            false => log::debug!("Extracting {}", path.display()),
+        }
+        let read_start = Instant::now();
+        let (resource, resource_type) = book.get_resource(&id).ok_or_else(|| format!("Couldn't read resource {} from book.", path.display()))?;
+        timings.add_bytes("read resources", read_start.elapsed(), resource.len());
+        if let Some(&resource_spine_position) = spine_positions_by_path.get(&output_path) {
+            let format = sniff_spine_item_format(&resource_type, &resource);
+            if format.media_type() != resource_type {
+                if strict_media_types {
+                    return Err(format!("Spine contains item {} of type {}, rather than application/xhtml+xml or image/svg+xml.", path.display(), resource_type))
+                }
+                warnings.push(ImportWarning::MislabeledSpineItem {
+                    path: path.clone(),
+                    media_type: resource_type.clone(),
+                    format,
+                });
+            }
+            match format {
+                SpineItemFormat::Xhtml => {
+                    let rewrite = SpineXhtmlRewrite {
+                        document_path: &output_path,
+                        strip_kobo_spans: match kobo_spans {
+                            KoboSpanMode::Auto => has_kobo_spans(&resource),
+                            KoboSpanMode::Always => true,
+                            KoboSpanMode::Never => false,
+                        },
+                        adjust_hrefs: has_renamed_resources || resource_index.is_some(),
+                        resource_index: resource_index.as_ref(),
+                        cover_navigation,
+                        show_page_numbers,
+                        strip_book_css: stylesheet.strip_book_css,
+                        strip_inline_styles: stylesheet.strip_inline_styles,
+                        forced_properties: match stylesheet.force_styles {
+                            true => forced_properties(stylesheet),
+                            false => Vec::new(),
+                        },
+                        csp: stylesheet.csp,
+                        styles_dir: (!layout.fixed_layout).then_some(styles_dir.as_path()),
+                    };
+                    // Counted from the book's own text, which the rewrite only adds markup to.
+                    let word_count = timings.time("count words", || search::count_words(&resource));
+                    let process_start = Instant::now();
+                    let output = BufWriter::new(create_contained(&canonical_contents_dir, &output_path)?);
+                    let pages = BookPages {
+                        contents_dir: &contents_dir,
+                        index_path: &index_path,
+                        spine: &spine,
+                        layout,
+                    };
+                    let output = rewrite_spine_xhtml(&resource, output, &rewrite, pages, resource_spine_position, warnings)
+                        .map_err(|e| format!("Couldn't process {}: {}", path.display(), e))?;
+                    let written_bytes = output.into_inner().map_err(|e| e.into_error()).and_then(|file| file.metadata()).map_err(|e| format!("Failed to write {} from book to disk: {}", output_path.display(), e))?.len();
+                    // Writing happens as the document is rewritten, so it's timed along with processing.
+                    timings.add_spine_document(&path, process_start.elapsed());
+                    dumped_bytes += written_bytes as usize;
+                    spine[resource_spine_position].word_count = Some(word_count);
+                    continue
+                },
+                SpineItemFormat::Svg => warnings.push(ImportWarning::SvgSpineItem {
+                    path: path.clone(),
+                }),
+                SpineItemFormat::Unsupported => (),
+            }
+        }
+        dumped_bytes += resource.len();
+        let write_start = Instant::now();
+        write_contained(&canonical_contents_dir, &output_path, &resource)?;
+        timings.add_bytes("write files", write_start.elapsed(), resource.len());
+    }
+
+    let index_start = Instant::now();
+    // The index links its stylesheet even when it's empty, so 'library style refresh' can fill it in later.
+    let index_css = create_index_css(stylesheet).unwrap_or_default();
+    write(index_dir.join(INDEX_STYLESHEET_FILENAME), index_css).map_err(|e| format!("Failed to write index stylesheet: {}", e))?;
+
+    let pages = BookPages {
+        contents_dir: &contents_dir,
+        index_path: &index_path,
+        spine: &spine,
+        layout,
+    };
+    let index_contents = IndexContents {
+        titles: &titles,
+        creators: &creators,
+        toc: &toc,
+        page_list: &page_list,
+    };
+    let index = create_index(book, index_contents, pages, stylesheet.csp, index_options)?;
+    write(&index_path, index).map_err(|e| format!("Failed to write index: {}", e))?;
+    timings.add("generate index", index_start.elapsed());
+
+    Ok(dumped_bytes)
+}
+
+/// A book extracted by [`extract_book`].
+pub struct ExtractedBook {
+    /// The book's index, which links to every page.
+    pub index_path: PathBuf,
+    /// What the book's files and the styles written for them add up to.
+    pub bytes: usize,
+    /// Problems with the book that were worked around, as `rib` reports them when opening it.
+    pub warnings: Vec<String>,
+}
+
+/// Extracts the epub at `epub_path` into `output_dir` as pages styled by `stylesheet`, with navigation between them and
+/// an index. Nothing is printed; what had to be worked around comes back in [`ExtractedBook::warnings`].
+pub fn extract_book(epub_path: &Path, output_dir: &Path, stylesheet: &Stylesheet) -> Result<ExtractedBook, String> {
+    let mut book = EpubDoc::new(epub_path).map_err(|e| format!("Failed to open as epub: {}", e))?;
+    let options = ExtractionOptions {
+        stylesheet,
+        kobo_spans: KoboSpanMode::Auto,
+        strict_paths: false,
+        strict_media_types: false,
+        all_navigation: false,
+        cover_navigation: CoverNavigation::default(),
+        show_page_numbers: false,
+        index: IndexOptions {
+            toc_depth: None,
+            words_per_minute: default_words_per_minute(),
+            creator_serial_comma: false,
+        },
+    };
+    let mut warnings = Vec::new();
+    let bytes = dump_book(&mut book, output_dir, &options, &mut Timings::default(), &mut warnings)?;
+    Ok(ExtractedBook {
+        index_path: output_dir.join("index.html"),
+        bytes,
+        warnings: warnings.iter().map(ToString::to_string).collect(),
+    })
+}
+
+fn browser_commands(browser: Option<&str>, config: &Config) -> Result<Vec<Vec<String>>, String> {
+    // BROWSER's commands are tried in order, but a browser named on the command line or for rib alone is the only choice.
+    match browser {
+        Some(browser) => Ok(vec![browser::resolve_command(browser, &config.browsers)?]),
+        None if !config.system_browsers.is_empty() => Ok(config.system_browsers.clone()),
+        None => Ok(vec![browser::resolve_command(&config.default_browser, &config.browsers)?]),
+    }
+}
+
+fn publish_book_dir(temporary_dir_path: &Path, book_dir_path: &Path) -> Result<(), String> {
+    // Replacing the whole directory, rather than writing over it, also sheds any files a changed book no longer has.
+    if book_dir_path.exists() {
+        remove_dir_all(book_dir_path).map_err(|e| format!("Couldn't clear {} to replace it: {}", book_dir_path.display(), e))?;
+    }
+    rename(temporary_dir_path, book_dir_path).map_err(|e| {
+        let _ = remove_dir_all(temporary_dir_path);
+        format!("Couldn't move {} into place at {}: {}", temporary_dir_path.display(), book_dir_path.display(), e)
+    })
+}
+
+// Written into each book's directory, so an odd-looking book can be traced back to what it was extracted with.
+#[derive(Serialize)]
+struct ExtractionRecord<'a> {
+    rib_version: &'static str,
+    extracted_time: Timestamp,
+    stylesheet_name: Option<&'a str>,
+    stylesheet: &'a Stylesheet,
+    toc_depth: Option<usize>,
+    words_per_minute: usize,
+    creator_serial_comma: bool,
+    show_page_numbers: bool,
+    source: Option<&'a BookSource>,
+    content_digest: Option<&'a str>,
+}
+
+const EXTRACTION_RECORD_FILENAME: &str = "rib_extraction.json";
+const INDEX_STYLESHEET_FILENAME: &str = "index_stylesheet.css";
+// In the book's styles directory, and linked from each of its pages.
+const NO_OVERRIDE_STYLESHEET_FILENAME: &str = "rib_no_override.css";
+const OVERRIDE_STYLESHEET_FILENAME: &str = "rib_override.css";
+
+// Properties that decided how the book was extracted rather than what CSS it's shown with, which a refresh leaves as they were.
+const EXTRACTION_STYLESHEET_KEYS: [&str; 4] = ["strip_book_css", "strip_inline_styles", "force_styles", "csp"];
+
+enum StyleRefresh {
+    Refreshed {
+        stylesheet_name: String,
+        // Whether the book's pages were restyled along with its index. Fixed-layout pages, and pages extracted before they
+        // linked rib's stylesheets, have none to restyle.
+        pages_restyled: bool,
+        // The bytes of the book's files now, which its stylesheets count toward.
+        bytes: usize,
+    },
+    // Extracted with no stylesheet, or one not being refreshed, or before records were kept.
+    Skipped,
+    // Extracted before indexes always linked their stylesheets, so there's nothing to refresh short of extracting it again.
+    NeedsReopening,
+}
+
+// Everything generated from a stylesheet for a book: the index's CSS, and the CSS its pages link before and after their own.
+struct StylesheetCss {
+    index: String,
+    no_override: String,
+    override_book: String,
+}
+
+// Rewrites a library book's stylesheets, for its index and for its pages, from the stylesheet it was extracted with, as
+// config now defines it, for 'library style refresh'. Only books extracted with one of the named stylesheets are touched,
+// or with any, if none are named.
+fn refresh_stylesheets(cache: &Cache, cached_book: &CachedBook, names: &[String], config: &Config, css_by_name: &mut HashMap<String, StylesheetCss>) -> Result<StyleRefresh, String> {
+    let book_dir = cache.book_dir(cached_book);
+    let record_path = book_dir.join(EXTRACTION_RECORD_FILENAME);
+    let mut record = match read_to_string(&record_path) {
+        Ok(record) => serde_json::from_str::<serde_json::Value>(&record).map_err(|e| format!("Couldn't parse {}: {}", record_path.display(), e))?,
+        // Books extracted before records were kept can't say what they were styled with.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(StyleRefresh::Skipped),
+        Err(e) => return Err(format!("Couldn't read {}: {}", record_path.display(), e)),
+    };
+    let Some(name) = record["stylesheet_name"].as_str().map(str::to_string).filter(|name| names.is_empty() || names.contains(name)) else {
+        return Ok(StyleRefresh::Skipped)
+    };
+    let index_path = book_dir.join("index.html");
+    let index = read_to_string(&index_path).map_err(|e| format!("Couldn't read {}: {}", index_path.display(), e))?;
+    if !index.contains(INDEX_STYLESHEET_FILENAME) {
+        return Ok(StyleRefresh::NeedsReopening)
+    }
+    let stylesheet = config.get_stylesheet(Some(&name))?;
+    // Each stylesheet's CSS is generated once, however many books use it.
+    let css = css_by_name.entry(name.clone()).or_insert_with(|| {
+        let (no_override, override_book) = create_book_css(&stylesheet);
+        StylesheetCss {
+            index: create_index_css(&stylesheet).unwrap_or_default(),
+            no_override,
+            override_book,
+        }
+    });
+    write(book_dir.join(INDEX_STYLESHEET_FILENAME), &css.index).map_err(|e| format!("Failed to write index stylesheet: {}", e))?;
+    // Pages without rib's stylesheets don't link them either, so there they're left unwritten.
+    let styles_dir = book_dir.join("styles");
+    let page_stylesheets = [(NO_OVERRIDE_STYLESHEET_FILENAME, &css.no_override), (OVERRIDE_STYLESHEET_FILENAME, &css.override_book)];
+    let pages_restyled = page_stylesheets.iter().all(|(filename, _css)| styles_dir.join(filename).is_file());
+    let mut bytes = cached_book.bytes;
+    if pages_restyled {
+        for (filename, css) in page_stylesheets {
+            let path = styles_dir.join(filename);
+            let previous_bytes = path.metadata().map(|metadata| metadata.len() as usize).unwrap_or_default();
+            write(&path, css).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+            bytes = (bytes + css.len()).saturating_sub(previous_bytes);
+        }
+    }
+
+    let mut refreshed_stylesheet = serde_json::to_value(&stylesheet).unwrap();
+    for key in EXTRACTION_STYLESHEET_KEYS {
+        refreshed_stylesheet[key] = record["stylesheet"][key].clone();
+    }
+    record["stylesheet"] = refreshed_stylesheet;
+    record["stylesheet_refreshed_time"] = serde_json::to_value(Timestamp::now()).unwrap();
+    if let Err(e) = write(&record_path, serde_json::to_string_pretty(&record).unwrap()) {
+        log::warning!("Couldn't record the refreshed stylesheet in {}: {}", record_path.display(), e);
+    }
+    Ok(StyleRefresh::Refreshed {
+        stylesheet_name: name,
+        pages_restyled,
+        bytes,
+    })
+}
+
+struct BookOrigin<'a> {
+    name: &'a str,
+    source: Option<BookSource>,
+}
+
+fn ask_dedupe_mode(name: &str, duplicate: &CachedBook) -> DedupeMode {
+    // Without a terminal there's nobody to ask (and stdin may be the book itself), so the book is imported as it would have been anyway.
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        log::warning!("{} has the same text as {}, already in the library. Keeping both; pass --dedupe to choose otherwise.", name, duplicate.id);
+        return DedupeMode::KeepBoth
+    }
+    let duplicate_name = match &duplicate.title {
+        Some(title) => format!("{} ({})", duplicate.id, title),
+        None => duplicate.id.clone(),
+    };
+    loop {
+        eprint!("{} has the same text as {}, already in the library. [r]eplace it, [k]eep both, or [s]kip importing and open the library's copy? ", name, duplicate_name);
+        let _ = std::io::stderr().flush();
+        let mut answer = String::new();
+        match stdin.read_line(&mut answer) {
+            Ok(0) | Err(_) => return DedupeMode::KeepBoth,
+            Ok(_) => match answer.trim().to_lowercase().as_str() {
+                "r" | "replace" => return DedupeMode::Replace,
+                "k" | "keep-both" => return DedupeMode::KeepBoth,
+                "s" | "skip" => return DedupeMode::Skip,
+                _ => continue,
+            },
+        }
+    }
+}
+
+fn confirm(question: &str) -> bool {
+    // Anything but yes is no, including end of input.
+    eprint!("{} [y/N] ", question);
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    match std::io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+// The library book with the same text as one being imported, if there is one.
+fn find_import_duplicate<'a>(cache: &'a Cache, book_cache_id: &str, content_digest: &str) -> Option<&'a CachedBook> {
+    // Reopening a book already in the library isn't importing a duplicate of it, whatever else shares its text.
+    if cache.get(book_cache_id).is_some() {
+        return None
+    }
+    cache.find_duplicate(book_cache_id, content_digest)
+}
+
+// What becomes of a library book with the same text as one being imported.
+enum DuplicateResolution {
+    // Removed once the new book is in place, so a failed import leaves the library as it was.
+    Replace(String),
+    // Opened instead of importing the new book.
+    Reuse(String),
+}
+
+// The mode is settled before the lock is taken, since asking for it waits on the reader; a duplicate that only turned up
+// since then is kept alongside the new book, as when there's nobody to ask.
+fn resolve_duplicate(cache: &Cache, book_cache_id: &str, content_digest: &str, origin: &BookOrigin, dedupe: Option<DedupeMode>) -> Option<DuplicateResolution> {
+    let duplicate = find_import_duplicate(cache, book_cache_id, content_digest)?;
+    match dedupe.unwrap_or(DedupeMode::KeepBoth) {
+        DedupeMode::Replace => {
+            log::info!("Replacing {} in the library with {}, which has the same text.", duplicate.id, origin.name);
+            Some(DuplicateResolution::Replace(duplicate.id.clone()))
+        },
+        DedupeMode::KeepBoth => {
+            log::debug!("Importing {} alongside {}, which has the same text.", origin.name, duplicate.id);
+            None
+        },
+        DedupeMode::Skip => {
+            log::info!("{} has the same text as {}, so opening the library's copy instead.", origin.name, duplicate.id);
+            Some(DuplicateResolution::Reuse(duplicate.id.clone()))
+        },
+    }
+}
+
+// What the books being opened at once share. Each takes the lock only to consult or update the library, so their extraction overlaps.
+struct OpeningBooks<'a> {
+    destination: &'a mut Destination,
+    server: Option<&'a mut serve::Server>,
+    // Directories of books being opened, by id: those still being extracted, which the library may not list yet, and
+    // library copies being opened instead of a book with the same text.
+    in_flight: HashMap<String, String>,
+}
+
+// The books being opened at once, behind the one lock they share, and how they're all opened.
+struct Opening<'a> {
+    args: &'a Args,
+    config: &'a Config,
+    books: Mutex<OpeningBooks<'a>>,
+    // Notified as each book's extraction ends, however it ends, for books waiting to open the same one or reuse it.
+    extraction_ended: Condvar,
+    // Held for the whole of a question about a duplicate, so that questions about several books aren't asked at once.
+    prompt: Mutex<()>,
+}
+
+impl<'a> Opening<'a> {
+    fn new(args: &'a Args, config: &'a Config, destination: &'a mut Destination, server: Option<&'a mut serve::Server>) -> Self {
+        Self {
+            args,
+            config,
+            books: Mutex::new(OpeningBooks {
+                destination,
+                server,
+                in_flight: HashMap::new(),
+            }),
+            extraction_ended: Condvar::new(),
+            prompt: Mutex::new(()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, OpeningBooks<'a>> {
+        self.books.lock().unwrap()
+    }
+
+    fn end_extraction(&self, book_id: &str) {
+        self.lock().in_flight.remove(book_id);
+        self.extraction_ended.notify_all();
+    }
+}
+
+// Where a book is going, settled under the lock before it's extracted.
+struct BookClaim {
+    id: String,
+    dirname: String,
+    dir_path: PathBuf,
+    root: PathBuf,
+    // Opening the library's copy of a book with the same text instead, which writes nothing. The copy is still claimed, so
+    // it isn't extracted over while it's being opened.
+    reuse_existing: bool,
+    // A library book with the same text, to remove once every book being opened is in place.
+    replaced_id: Option<String>,
+    previous_entry: Option<CachedBook>,
+    preferred_stylesheet: Option<String>,
+}
+
+fn claim_book(opening: &Opening, book_id: &str, content_digest: Option<&str>, origin: &BookOrigin) -> BookClaim {
+    // Other books carry on extracting while the reader decides what to do about a duplicate. Only one book is asked about at
+    // a time, so each answer is read by the book whose question it answers.
+    let dedupe = opening.args.dedupe.or_else(|| {
+        let _prompt = opening.prompt.lock().unwrap();
+        let duplicate = match (&*opening.lock().destination, content_digest) {
+            (Destination::Library(cache), Some(content_digest)) => find_import_duplicate(cache, book_id, content_digest).cloned(),
+            _ => None,
+        };
+        duplicate.map(|duplicate| ask_dedupe_mode(origin.name, &duplicate))
+    });
+    // The same book opened twice at once waits for the first to finish, then opens the copy it left in the library. So does
+    // a book that's to reuse the library's copy of one being opened.
+    let is_waiting = |books: &OpeningBooks| books.in_flight.contains_key(book_id) || dedupe == Some(DedupeMode::Skip) && match (&*books.destination, content_digest) {
+        (Destination::Library(cache), Some(content_digest)) => find_import_duplicate(cache, book_id, content_digest).is_some_and(|duplicate| books.in_flight.contains_key(&duplicate.id)),
+        _ => false,
+    };
+    let mut books = opening.lock();
+    while is_waiting(&books) {
+        books = opening.extraction_ended.wait(books).unwrap();
+    }
+    let OpeningBooks { destination, in_flight, .. } = &mut *books;
+    let mut id = book_id.to_string();
+    let mut reuse_existing = false;
+    let mut replaced_id = None;
+    if let (Destination::Library(cache), Some(content_digest)) = (&**destination, content_digest) {
+        match resolve_duplicate(cache, &id, content_digest, origin, dedupe) {
+            Some(DuplicateResolution::Replace(duplicate_id)) => replaced_id = Some(duplicate_id),
+            Some(DuplicateResolution::Reuse(existing_id)) => {
+                id = existing_id;
+                reuse_existing = true;
+            },
+            None => (),
+        }
+    }
+    let dirname = destination.get_dirname(&id, in_flight);
+    let root = destination.root().to_path_buf();
+    let dir_path = root.join(&dirname);
+    let (previous_entry, preferred_stylesheet) = match &**destination {
+        Destination::Library(cache) => (cache.get(&id).cloned().filter(|_| dir_path.is_dir()), cache.get(&id).and_then(|cached_book| cached_book.preferred_stylesheet.clone())),
+        Destination::Ephemeral(_) => (None, None),
+    };
+    in_flight.insert(id.clone(), dirname.clone());
+    BookClaim {
+        id,
+        dirname,
+        dir_path,
+        root,
+        reuse_existing,
+        replaced_id,
+        previous_entry,
+        preferred_stylesheet,
+    }
+}
+
+fn open_book(epub_path: &Path, origin: &BookOrigin, opening: &Opening, timings: &mut Timings, warnings: &mut Vec<ImportWarning>) -> Result<OpenedBook, CodedError> {
+    let mut book = timings.time("open container", || EpubDoc::new(epub_path)).map_err(|e| CodedError::new(ErrorCode::EpubParse, format!("Failed to open as epub: {}", e)))?;
+    let book_id = get_book_cache_id(&book).ok_or_else(|| CodedError::new(ErrorCode::EpubParse, "Ill-formed EPUB: doesn't have unique identifier."))?;
+    let is_library = matches!(opening.lock().destination, Destination::Library(_));
+    let content_digest = is_library.then(|| timings.time("content digest", || get_content_digest(&mut book)));
+    let claim = claim_book(opening, &book_id, content_digest.as_deref(), origin);
+    let opened = open_claimed_book(&mut book, &claim, content_digest, origin, opening, timings, warnings);
+    opening.end_extraction(&claim.id);
+    opened
+}
+
+fn open_claimed_book(book: &mut EpubDoc<BufReader<File>>, claim: &BookClaim, content_digest: Option<String>, origin: &BookOrigin, opening: &Opening, timings: &mut Timings, warnings: &mut Vec<ImportWarning>) -> Result<OpenedBook, CodedError> {
+    let Opening { args, config, .. } = opening;
+    let BookClaim { id: book_cache_id, dirname: book_cache_dirname, dir_path: book_cache_dir_path, reuse_existing, previous_entry, preferred_stylesheet, .. } = claim;
+    let reuse_existing = *reuse_existing;
+    let open_target = match &args.at {
+        Some(spec) => {
+            let (path, fragment) = resolve_open_target(spec, &get_toc(book), &get_spine(book)?)?;
+            Some((book_cache_dir_path.join("epub").join(path), fragment))
+        },
+        // With only one chapter, the index has nothing to choose between, so the book opens straight into it; the index stays linked from the chapter's navigation.
+        None => match get_spine(book)?.as_slice() {
+            [only_item] => Some((book_cache_dir_path.join("epub").join(&only_item.path), None)),
+            _ => None,
+        },
+    };
+
+    let source_changed = previous_entry.as_ref()
+        .and_then(|previous_entry| previous_entry.source.as_ref())
+        .zip(origin.source.as_ref())
+        .is_some_and(|(previous_source, source)| previous_source.bytes != source.bytes || previous_source.modified_time != source.modified_time);
+    let ResolvedStyle { chosen: chosen_stylesheet, stylesheet } = resolve_style(args, config, preferred_stylesheet.as_deref())?;
+    let style_explanation = args.explain_style.then(|| style_explanation::explain(config, chosen_stylesheet, &stylesheet, preferred_stylesheet.as_deref(), args));
+    if let Some(style_explanation) = &style_explanation {
+        if args.output_format() == OutputFormat::Plain {
+            // In one piece, so it isn't interleaved with other books' output.
+            println!("Style for {}:\n{}", origin.name, style_explanation);
+        }
+    }
+    let reimported = previous_entry.is_some() && !args.no_reimport && !reuse_existing && (args.force_reimport || source_changed);
+    let dumped_bytes = match previous_entry {
+        Some(previous_entry) if args.no_reimport || reuse_existing => {
+            log::debug!("Reusing the library's copy of {} without extracting it again.", origin.name);
+            previous_entry.bytes
+        },
+        _ => {
+            if reimported {
+                log::info!("{} has changed since it was last opened; extracting it afresh.", origin.name);
+            }
+            let index_options = IndexOptions {
+                toc_depth: args.toc_depth.or(config.toc_depth),
+                words_per_minute: config.words_per_minute,
+                creator_serial_comma: config.creator_serial_comma,
+            };
+            let extraction_options = ExtractionOptions {
+                stylesheet: &stylesheet,
+                kobo_spans: args.strip_kobo_spans.unwrap_or(KoboSpanMode::Auto),
+                strict_paths: args.strict_paths,
+                strict_media_types: args.strict,
+                all_navigation: args.all_wrappers,
+                cover_navigation: config.cover_navigation,
+                show_page_numbers: config.show_page_numbers,
+                index: index_options,
+            };
+            // Books are extracted beside their directory and only moved into place once complete, so a crash midway can't leave a half-written book that passes for a finished one.
+            let temporary_dir_path = claim.root.join(format!("{}{}{}", book_cache_dirname, TEMPORARY_DIR_INFIX, std::process::id()));
+            let _ = remove_dir_all(&temporary_dir_path);
+            let dump_start = Instant::now();
+            let dumped_bytes = dump_book(book, &temporary_dir_path, &extraction_options, timings, warnings).inspect_err(|_| {
+                let _ = remove_dir_all(&temporary_dir_path);
+            })?;
+            let extraction_record = ExtractionRecord {
+                rib_version: env!("CARGO_PKG_VERSION"),
+                extracted_time: Timestamp::now(),
+                stylesheet_name: chosen_stylesheet.map(|(name, _choice)| name),
+                stylesheet: &stylesheet,
+                toc_depth: index_options.toc_depth,
+                words_per_minute: index_options.words_per_minute,
+                creator_serial_comma: index_options.creator_serial_comma,
+                show_page_numbers: extraction_options.show_page_numbers,
+                source: origin.source.as_ref(),
+                content_digest: content_digest.as_deref(),
+            };
+            if let Err(e) = write(temporary_dir_path.join(EXTRACTION_RECORD_FILENAME), serde_json::to_string_pretty(&extraction_record).unwrap()) {
+                log::warning!("Couldn't record how {} was extracted: {}", origin.name, e);
+            }
+            timings.time("move into place", || publish_book_dir(&temporary_dir_path, book_cache_dir_path))?;
+            log::debug!("Dumped raw contents of {} to {} ({} bytes, {:.2?})", origin.name, book_cache_dir_path.display(), dumped_bytes, dump_start.elapsed());
+            dumped_bytes
+        },
+    };
+    if matches!(opening.lock().destination, Destination::Library(_)) {
+        let details = match previous_entry.clone().filter(|_| reuse_existing) {
+            // The library's copy stays as it was imported; only its last-opened time moves.
+            Some(previous_entry) => BookDetails {
+                title: previous_entry.title,
+                creator: previous_entry.creator,
+                cover: previous_entry.cover,
+                source: previous_entry.source,
+                content_digest: previous_entry.content_digest,
+                reimported: false,
+                exclude_from_limits: args.no_count,
+            },
+            None => BookDetails {
+                title: get_book_titles(book).ok().flatten().map(|titles| titles.full()).or_else(|| book.mdata("title")),
+                creator: get_book_creators(book).ok().and_then(|creators| join_creator_names(&creators, CreatorRole::Author, config.creator_serial_comma)).or_else(|| book.mdata("creator")),
+                cover: book.get_cover_id().and_then(|cover_id| {
+                    let cover_path = book.resources.get(&cover_id)
+                        .map(|(cover_path, _cover_mimetype)| cover_path)
+                        .filter(|cover_path| resource_path_is_contained(cover_path))
+                        .map(|cover_path| Path::new("epub").join(portable_resource_path(cover_path)));
+                    if cover_path.is_none() {
+                        warnings.push(ImportWarning::MissingCover {
+                            cover_id,
+                        });
+                    }
+                    cover_path
+                }),
+                source: origin.source.clone(),
+                content_digest,
+                reimported,
+                exclude_from_limits: args.no_count,
+            },
+        };
+        let history_record = (config.max_history_records > 0).then(|| history::HistoryRecord {
+            time: Timestamp::now(),
+            id: book_cache_id.clone(),
+            section: open_target.as_ref().and_then(|(path, _fragment)| path.strip_prefix(book_cache_dir_path.join("epub")).ok()).map(|path| path.to_string_lossy().into_owned()),
+        });
+        // The index is written once every book is in, rather than once for each.
+        let mut opening = opening.lock();
+        if let Destination::Library(cache) = &mut *opening.destination {
+            cache.add(book_cache_id.clone(), book_cache_dirname.clone(), dumped_bytes, details);
+            if let Some(record) = history_record {
+                if let Err(e) = timings.time("write library history", || history::append(&cache.history_path(), record, config.max_history_records)) {
+                    log::warning!("{}", e);
+                }
+            }
+        }
+    }
+    let output_format = args.output_format();
+    if args.timings && !(args.print_path && args.json) && output_format == OutputFormat::Plain {
+        eprintln!("Timings for {}:\n{}", origin.name, timings.table());
+    }
+
+    let (target_path, fragment) = open_target.unwrap_or_else(|| (book_cache_dir_path.join("index.html"), None));
+    let printed_path = browser::resolve_path(&target_path);
+    let mut target = match args.url {
+        true => file_url(&printed_path, fragment.as_deref()),
+        false => printed_path.display().to_string(),
+    };
+    // With --print-path, paths are printed once every book is open, in the order they were given.
+    let mut browser_target = None;
+    if !args.print_path {
+        match opening.lock().server.as_deref_mut() {
+            Some(server) => {
+                let relative_target = target_path.strip_prefix(book_cache_dir_path).unwrap_or(&target_path);
+                let url = server.mount(book_cache_dirname, book_cache_dir_path, relative_target, fragment.as_deref())?;
+                log::info!("Serving {} at {}", origin.name, url);
+                target = url.clone();
+                if !args.browser_skip {
+                    browser_target = Some(browser::Target::Url(url));
+                }
+            },
+            None if !args.browser_skip => browser_target = Some(browser::Target::File(target_path, fragment)),
+            None => (),
+        }
+    }
+
+    Ok(OpenedBook {
+        id: book_cache_id.clone(),
+        replaced_id: claim.replaced_id.clone(),
+        title: get_book_titles(book).ok().flatten().map(|titles| titles.full()).or_else(|| book.mdata("title")),
+        target,
+        browser_target,
+        timings: args.timings.then(|| timings.to_json()),
+        style: style_explanation.map(|style_explanation| style_explanation.to_json()),
+    })
+}
+
+fn watch_book(epub_path: &Path, args: &Args, config: &Config, destination: &mut Destination, server: Option<&serve::Server>, keep_watching: impl Fn() -> bool) {
+    // The browser was opened, and the book mounted, the first time round; later extractions land in the same place.
+    let refresh_args = Args {
+        browser_skip: true,
+        ..args.clone()
+    };
+    let book_name = describe_book_path(epub_path);
+    let mut watcher = watch::SourceWatcher::new(epub_path);
+    while watcher.wait_for_change(&keep_watching) {
+        let mut warnings = Vec::new();
+        let opening = Opening::new(&refresh_args, config, &mut *destination, None);
+        let imported = import_book(epub_path, &book_name, &opening, &mut warnings);
+        match imported {
+            Ok(opened_book) => {
+                println!("[{}] Refreshed {}", Timestamp::now(), book_name);
+                if !warnings.is_empty() {
+                    let warning_lines = warnings.iter().map(|warning| format!("\n  {}", warning)).collect::<String>();
+                    log::warning!("{}:{}", book_name, warning_lines);
+                }
+                if let Destination::Library(cache) = destination {
+                    if let Some(replaced_id) = &opened_book.replaced_id {
+                        cache.remove_unwritten(replaced_id);
+                    }
+                    cache.truncate(&[opened_book.id]);
+                }
+                if let Some(server) = server {
+                    server.bump_version();
+                }
+            },
+            // A book caught mid-write or broken by an edit is likely to be fixed by the next one, so watching goes on.
+            Err(e) => log::error!("Couldn't refresh {}: {}", book_name, e),
+        }
+    }
+}
+
+// What opening a book reports back for --format json.
+struct OpenedBook {
+    id: String,
+    // The library book with the same text it replaces, once every book is in.
+    replaced_id: Option<String>,
+    title: Option<String>,
+    // The path or URL it was opened at.
+    target: String,
+    browser_target: Option<browser::Target>,
+    timings: Option<serde_json::Value>,
+    style: Option<serde_json::Value>,
+}
+
+fn import_book(book_path: &Path, book_name: &str, opening: &Opening, warnings: &mut Vec<ImportWarning>) -> Result<OpenedBook, CodedError> {
+    // Stdin and unpacked epubs are first written out as single files, since that's all EpubDoc reads.
+    let mut timings = Timings::default();
+    let buffered = match book_path {
+        path if path == Path::new(STDIN_PATH) => Some(timings.time("read stdin", buffer_stdin)?),
+        path if is_unpacked_epub(path) => Some(timings.time("pack unpacked epub", || pack_unpacked_epub(path))?),
+        _ => None,
+    };
+    let source_path = buffered.as_ref().map_or(book_path, |buffered| buffered.path());
+    let original_path = Some(book_path).filter(|path| *path != Path::new(STDIN_PATH));
+
+    // Extensions are wrong often enough in both directions that the contents decide what a file is.
+    let convert_start = Instant::now();
+    let book_type = detect_book_type(source_path).map_err(|e| match source_path.exists() {
+        true => CodedError::new(ErrorCode::Io, e),
+        false => CodedError::new(ErrorCode::NotFound, e),
+    })?;
+    let converted = match book_type {
+        BookType::Epub => {
+            if buffered.is_none() && !is_epub_path(book_path) {
+                warnings.push(ImportWarning::MisleadingExtension);
+            }
+            None
+        },
+        BookType::Cbz => {
+            let title = match book_path == Path::new(STDIN_PATH) {
+                true => String::from("Untitled comic"),
+                false => book_path.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+            };
+            Some(convert_cbz(source_path, &title)?)
+        },
+        BookType::Fb2 => Some(convert_fb2(source_path, false)?),
+        BookType::Fb2Zip => Some(convert_fb2(source_path, true)?),
+        BookType::Html => Some(convert_html(source_path, original_path)?),
+        // Markdown has no signature to sniff, and HTML fragments may lack one too, so for those the extension decides.
+        BookType::Unknown if is_html_path(book_path) => Some(convert_html(source_path, original_path)?),
+        BookType::Unknown if is_markdown_path(book_path) => Some(convert_markdown(source_path, original_path)?),
+        book_type => return Err(CodedError::new(ErrorCode::EpubParse, format!("This looks like {}, which rib can't open.", book_type.describe()))),
+    };
+    if converted.is_some() {
+        timings.add("convert to epub", convert_start.elapsed());
+    }
+    let epub_path = converted.as_ref().map_or(source_path, |converted| converted.path());
+    let origin = BookOrigin {
+        name: book_name,
+        source: original_path.and_then(BookSource::read),
+    };
+    open_book(epub_path, &origin, opening, &mut timings, warnings)
+}
+
+fn open_in_browser(browser_targets: &[(String, browser::Target)], args: &Args, config: &Config) {
+    // By now the books are rendered and in the library, so a browser failure doesn't fail them.
+    let report_failure = |book_name: &str, target: &browser::Target, e: &str| log::error!("Couldn't open {} in a browser: {}. Open this {} manually.", book_name, e, target.describe());
+    let commands = match browser_commands(args.browser.as_deref(), config) {
+        Ok(commands) if args.reader_window => commands.into_iter().map(|command| match browser::reader_window_command(&command, &config.reader_window_arguments) {
+            Some(reader_window_command) => reader_window_command,
+            None => {
+                log::warning!("rib doesn't know how to open {} as a reading window, so it's opening books there normally. Add it to reader_window_arguments in config.toml to fix this.", command.first().map(String::as_str).unwrap_or_default());
+                command
+            },
+        }).collect(),
+        Ok(commands) => commands,
+        Err(e) => {
+            for (book_name, target) in browser_targets {
+                report_failure(book_name, target, &e);
+            }
+            return
+        },
+    };
+    // One command for every book opens them as tabs of one window, rather than racing a window per book.
+    let browser_name = args.browser.as_deref().unwrap_or(&config.default_browser);
+    let can_open_together = |command: &[String]| !args.separate_windows && !config.single_book_browsers.iter().any(|name| name == browser_name) && browser::can_open_together(command, config.wsl_linux_browser);
+    let batch_size = if commands.first().is_some_and(|command| can_open_together(command)) { browser_targets.len().max(1) } else { 1 };
+    for batch in browser_targets.chunks(batch_size) {
+        let targets = batch.iter().map(|(_book_name, target)| target.clone()).collect::<Vec<browser::Target>>();
+        // A fallback command that can't take several books at once is given them one by one.
+        let opened = browser::open_with_first_working(&commands, |command| match can_open_together(command) {
+            true => browser::open_all(command, &targets, config.wsl_linux_browser, !args.no_wait),
+            false => targets.chunks(1).try_for_each(|target| browser::open_all(command, target, config.wsl_linux_browser, !args.no_wait)),
+        });
+        if let Err(e) = opened {
+            for (book_name, target) in batch {
+                report_failure(book_name, target, &e);
+            }
+        }
+    }
+}
+
+fn print_opened_book(opened_book: &OpenedBook, warnings: &[ImportWarning], args: &Args) {
+    match args.json {
+        _ if args.output_format() == OutputFormat::Json => (),
+        true => {
+            let mut printed_book = serde_json::json!({
+                "id": opened_book.id,
+                "title": opened_book.title,
+                "path": opened_book.target,
+                "warnings": warnings.iter().map(ImportWarning::to_json).collect::<Vec<serde_json::Value>>(),
+            });
+            if let Some(timings) = &opened_book.timings {
+                printed_book["timings"] = timings.clone();
+            }
+            println!("{}", printed_book)
+        },
+        false => println!("{}", opened_book.target),
+    }
+}
+
+fn open_books(epub_paths: &[PathBuf], args: &Args, config: &Config, destination: &mut Destination, server: Option<&mut serve::Server>) -> i32 {
+    let output_format = args.output_format();
+    let opening = Opening::new(args, config, &mut *destination, server);
+    // Books are opened several at once, as many as --jobs or the CPUs allow, each by whichever worker is free, and reported on afterwards in the order they were given, however they finished.
+    let imports = Mutex::new(epub_paths.iter().map(|_| None).collect::<Vec<Option<(Result<OpenedBook, CodedError>, Vec<ImportWarning>)>>>());
+    let next_index = AtomicUsize::new(0);
+    let worker_count = args.jobs.or_else(|| std::thread::available_parallelism().ok()).map_or(1, NonZeroUsize::get).min(epub_paths.len());
+    std::thread::scope(|scope| {
+        for _ in 0..worker_count {
+            scope.spawn(|| loop {
+                let index = next_index.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let Some(epub_path) = epub_paths.get(index) else {
+                    break
+                };
+                let mut warnings = Vec::new();
+                let book_name = describe_book_path(epub_path);
+                let mut imported = import_book(epub_path, &book_name, &opening, &mut warnings);
+                // With --open-as-ready, each book goes to the browser from its worker as soon as it's in, rather than with the rest.
+                if let (true, Ok(opened_book)) = (args.open_as_ready, &mut imported) {
+                    if let Some(browser_target) = opened_book.browser_target.take() {
+                        open_in_browser(&[(book_name, browser_target)], args, config);
+                    }
+                }
+                imports.lock().unwrap()[index] = Some((imported, warnings));
+            });
+        }
+    });
+    drop(opening);
+
+    let mut opened_ids = Vec::new();
+    let mut replaced_ids = Vec::new();
+    let mut failures = Vec::new();
+    let mut book_warnings = Vec::new();
+    let mut browser_targets = Vec::new();
+    let mut book_results = Vec::new();
+    let mut json_warnings = Vec::new();
+    for (epub_path, import) in epub_paths.iter().zip(imports.into_inner().unwrap()) {
+        let book_name = describe_book_path(epub_path);
+        let (imported, warnings) = import.expect("Every book is imported before any is reported on.");
+        if output_format == OutputFormat::Json {
+            json_warnings.extend(warnings.iter().map(|warning| {
+                let mut warning_json = warning.to_json();
+                warning_json["book"] = serde_json::json!(book_name);
+                warning_json
+            }));
+            book_results.push(match &imported {
+                Ok(opened_book) => {
+                    let mut book_result = serde_json::json!({
+                        "book": book_name,
+                        "ok": true,
+                        "id": opened_book.id,
+                        "title": opened_book.title,
+                        "path": opened_book.target,
+                    });
+                    if let Some(timings) = &opened_book.timings {
+                        book_result["timings"] = timings.clone();
+                    }
+                    if let Some(style) = &opened_book.style {
+                        book_result["style"] = style.clone();
+                    }
+                    book_result
+                },
+                Err(e) => serde_json::json!({
+                    "book": book_name,
+                    "ok": false,
+                    "error": e,
+                }),
+            });
+        }
+        if let (Ok(opened_book), true) = (&imported, args.print_path) {
+            print_opened_book(opened_book, &warnings, args);
+        }
+        // Books printed as JSON carry their warnings in it.
+        let is_printed_as_json = (imported.is_ok() && args.print_path && args.json) || output_format == OutputFormat::Json;
+        if !warnings.is_empty() && !is_printed_as_json {
+            book_warnings.push((book_name.clone(), warnings));
+        }
+        match imported {
+            Ok(opened_book) => {
+                if let Some(browser_target) = opened_book.browser_target {
+                    browser_targets.push((book_name, browser_target));
+                }
+                opened_ids.push(opened_book.id);
+                replaced_ids.extend(opened_book.replaced_id);
+            },
+            Err(e) => failures.push((book_name, e)),
+        }
+    }
+    for (book_name, warnings) in &book_warnings {
+        let warning_lines = warnings.iter().map(|warning| format!("\n  {}", warning)).collect::<String>();
+        log::warning!("{}:{}", book_name, warning_lines);
+    }
+    open_in_browser(&browser_targets, args, config);
+
+    // Books were only added to the library in memory as they were opened, so truncating writes the index for all of them.
+    if let Destination::Library(cache) = destination {
+        for replaced_id in &replaced_ids {
+            // Another book may have opened the duplicate itself, or reused it, in which case its pages are still wanted.
+            match opened_ids.contains(replaced_id) {
+                true => log::warning!("Kept {} in the library instead of replacing it, since it was opened too.", replaced_id),
+                false => {
+                    cache.remove_unwritten(replaced_id);
+                },
+            }
+        }
+        cache.truncate(&opened_ids);
+    }
+
+    match output_format {
+        OutputFormat::Json => {
+            // Each book's own error is in its result; the overall one shares their code when they agree.
+            let error = failures.first().map(|(_book_name, first_error)| {
+                let code = match failures.iter().all(|(_book_name, e)| e.code == first_error.code) {
+                    true => first_error.code,
+                    false => ErrorCode::Other,
+                };
+                CodedError::new(code, format!("Failed to open {} of {} books.", failures.len(), epub_paths.len()))
+            });
+            output::print_envelope(&serde_json::Value::from(book_results), &json_warnings, error.as_ref());
+        },
+        OutputFormat::Plain if !failures.is_empty() => {
+            let failure_lines = failures.iter().map(|(book_name, e)| format!("\n  {}: {}", book_name, e)).collect::<String>();
+            log::error!("Failed to open {} of {} books:{}", failures.len(), epub_paths.len(), failure_lines);
+        },
+        OutputFormat::Plain => (),
+    }
+    if failures.is_empty() {
+        0
+    } else if opened_ids.is_empty() {
+        2
+    } else {
+        1
+    }
+}
+
+//////////////
+//   Main   //
+//////////////
+
+fn run_path_subcommand(path: &Path, create: impl FnOnce() -> std::io::Result<()>, should_create: bool, output_format: OutputFormat) -> i32 {
+    // Exit status reports whether the path exists, so scripts can tell a fresh install apart.
+    if should_create && !path.exists() {
+        if let Err(e) = create() {
+            exit_with_error(output_format, CodedError::new(ErrorCode::Io, format!("Couldn't create {}: {}", path.display(), e)));
+        }
+    }
+    match output_format {
+        OutputFormat::Plain => println!("{}", path.display()),
+        OutputFormat::Json => output::print_envelope(&serde_json::json!({
+            "path": path,
+            "exists": path.exists(),
+        }), &[], None),
+    }
+    if path.exists() { 0 } else { 1 }
+}
+
+fn print_effective_config(config: &Config, config_path: (&Path, ConfigSource), default_library_dir: &Path) -> i32 {
+    println!("# config file: {} (from {})", config_path.0.display(), config_path.1.describe());
+    println!("default_browser = {} # from {}", toml::Value::from(config.default_browser.as_str()), config.source("default_browser").describe());
+    if !config.system_browsers.is_empty() {
+        let commands = config.system_browsers.iter().map(|command| command.join(" ")).collect::<Vec<String>>();
+        println!("# default_browser is passed over for the commands in {}, tried in order: {}", SYSTEM_BROWSER_VAR, commands.join(", "));
+    }
+    println!("max_cache_books = {} # from {}", config.max_cache_books, config.source("max_cache_books").describe());
+    println!("max_cache_bytes = {} # from {}", config.max_cache_bytes, config.source("max_cache_bytes").describe());
+    println!("truncation_policy = {} # from {}", toml::Value::from(config.truncation_policy.name()), config.source("truncation_policy").describe());
+    println!("max_history_records = {} # from {}", config.max_history_records, config.source("max_history_records").describe());
+    match &config.default_stylesheet {
+        Some(default_stylesheet) => println!("default_stylesheet = {} # from {}", toml::Value::from(default_stylesheet.as_str()), config.source("default_stylesheet").describe()),
+        None => println!("# default_stylesheet is unset"),
+    }
+    let mut stylesheet_names = config.stylesheets.keys().map(|name| toml::Value::from(name.as_str())).collect::<Vec<toml::Value>>();
+    stylesheet_names.sort_by_key(|name| name.to_string());
+    println!("stylesheets = {} # from {}", toml::Value::Array(stylesheet_names), config.source("stylesheets").describe());
+    for (key, value) in [("default_max_image_height", config.default_max_image_height), ("default_max_image_width", config.default_max_image_width)] {
+        match value {
+            Some(value) => println!("{} = {} # from {}", key, value, config.source(key).describe()),
+            None => println!("# {} is unset", key),
+        }
+    }
+    println!("cover_navigation = {} # from {}", toml::Value::from(config.cover_navigation.name()), config.source("cover_navigation").describe());
+    println!("show_page_numbers = {} # from {}", config.show_page_numbers, config.source("show_page_numbers").describe());
+    match config.toc_depth {
+        Some(toc_depth) => println!("toc_depth = {} # from {}", toc_depth, config.source("toc_depth").describe()),
+        None => println!("# toc_depth is unset"),
+    }
+    println!("words_per_minute = {} # from {}", config.words_per_minute, config.source("words_per_minute").describe());
+    println!("creator_serial_comma = {} # from {}", config.creator_serial_comma, config.source("creator_serial_comma").describe());
+    let mut browser_names = config.browsers.keys().map(|name| toml::Value::from(name.as_str())).collect::<Vec<toml::Value>>();
+    browser_names.sort_by_key(|name| name.to_string());
+    println!("browsers = {} # from {}", toml::Value::Array(browser_names), config.source("browsers").describe());
+    println!("wsl_linux_browser = {} # from {}", config.wsl_linux_browser, config.source("wsl_linux_browser").describe());
+    let mut reader_window_programs = config.reader_window_arguments.keys().map(|name| toml::Value::from(name.as_str())).collect::<Vec<toml::Value>>();
+    reader_window_programs.sort_by_key(|name| name.to_string());
+    println!("reader_window_arguments = {} # from {}", toml::Value::Array(reader_window_programs), config.source("reader_window_arguments").describe());
+    println!("single_book_browsers = {} # from {}", toml::Value::Array(config.single_book_browsers.iter().map(|name| toml::Value::from(name.as_str())).collect()), config.source("single_book_browsers").describe());
+    println!("library_dir = {} # from {}", toml::Value::from(config.library_dir(default_library_dir).to_string_lossy().as_ref()), config.source("library_dir").describe());
+    0
+}
+
+fn run_config_subcommand(config_args: &ConfigArgs, config_path: (&Path, ConfigSource), library_dir_flag: Option<&Path>, default_library_dir: &Path) -> i32 {
+    match &config_args.subcommand {
+        ConfigSubcommand::Check(check_args) => config_check::run(check_args, config_path.0),
+        ConfigSubcommand::Effective(_) => match Config::load(config_path.0, library_dir_flag) {
+            Ok(config) => print_effective_config(&config, config_path, default_library_dir),
+            Err(e) => {
+                log::error!("{}", e);
+                1
+            },
+        },
+        ConfigSubcommand::Path(path_args) => run_path_subcommand(config_path.0, || Config::write_default(config_path.0), path_args.create, OutputFormat::Plain),
+    }
+}
+
+fn library_book_json(cache: &Cache, cached_book: &CachedBook) -> serde_json::Value {
+    let mut book_json = serde_json::to_value(cached_book).unwrap();
+    book_json["directory"] = serde_json::json!(cache.book_dir(cached_book));
+    book_json
+}
+
+fn run_library_subcommand_json(library_args: &LibraryArgs, cache: &Cache) -> i32 {
+    // Each subcommand's plain output is left exactly as it is; these build their results separately.
+    let result = match &library_args.subcommand {
+        LibrarySubcommand::List(list_args) if list_args.duplicates => {
+            let groups = cache.duplicate_groups();
+            Ok(serde_json::json!({
+                "groups": groups.iter().map(|group| group.iter().map(|cached_book| library_book_json(cache, cached_book)).collect::<Vec<serde_json::Value>>()).collect::<Vec<Vec<serde_json::Value>>>(),
+            }))
+        },
+        LibrarySubcommand::List(list_args) => Ok(serde_json::json!({
+            "books": cache.contents.iter().map(|cached_book| {
+                let mut book_json = library_book_json(cache, cached_book);
+                if list_args.sources || list_args.verify {
+                    book_json["source_state"] = serde_json::json!(source_state(cached_book, list_args.verify).name());
+                }
+                book_json
+            }).collect::<Vec<serde_json::Value>>(),
+            "book_count": cache.count_books(),
+            "bytes": cache.count_bytes(),
+        })),
+        LibrarySubcommand::Info(info_args) => cache.resolve_single_selector(&info_args.id).map(|cached_book| library_book_json(cache, cached_book)),
+        _ => Err(CodedError::new(ErrorCode::Usage, "Of the library subcommands, only 'list', 'info', and 'path' have JSON output.")),
+    };
+    match result {
+        Ok(result) => {
+            output::print_envelope(&result, &[], None);
+            0
+        },
+        Err(e) => exit_with_error(OutputFormat::Json, e),
+    }
+}
+
+fn run_library_subcommand(library_args: &LibraryArgs, cache: &mut Cache, config: &Config, output_format: OutputFormat) -> i32 {
+    if output_format == OutputFormat::Json {
+        return run_library_subcommand_json(library_args, cache)
+    }
+    match &library_args.subcommand {
+        LibrarySubcommand::List(list_args) => {
+            let shows_sources = list_args.sources || list_args.verify;
+            let mut source_state_counts = HashMap::new();
+            let mut print_book = |cached_book: &CachedBook| {
+                let bytes = match cached_book.exclude_from_limits {
+                    true => format!("{} bytes (not counted)", cached_book.bytes),
+                    false => format!("{} bytes", cached_book.bytes),
+                };
+                let source_state = shows_sources.then(|| source_state(cached_book, list_args.verify));
+                if let Some(source_state) = source_state {
+                    *source_state_counts.entry(source_state).or_insert(0) += 1;
+                }
+                let source_state = source_state.map(|source_state| format!("\t{}", source_state.name())).unwrap_or_default();
+                match (list_args.verbose, &cached_book.source) {
+                    (true, Some(source)) => println!("{}\t{}\tlast opened {}\t{}\tfrom {}{}", cached_book.id, bytes, cached_book.last_opened_time, cache.book_dir(cached_book).display(), source.path.display(), source_state),
+                    _ => println!("{}\t{}\tlast opened {}\t{}{}", cached_book.id, bytes, cached_book.last_opened_time, cache.book_dir(cached_book).display(), source_state),
+                }
+            };
+            if list_args.duplicates {
+                let groups = cache.duplicate_groups();
+                for (index, group) in groups.iter().enumerate() {
+                    if index > 0 {
+                        println!();
+                    }
+                    for cached_book in group {
+                        print_book(cached_book);
+                    }
+                }
+                println!("{} groups of books with the same text", groups.len());
+                return 0
+            }
+            for cached_book in &cache.contents {
+                print_book(cached_book);
+            }
+            println!("{} books, {} bytes", cache.count_books(), cache.count_bytes());
+            let source_state_summary = [SourceState::Missing, SourceState::Changed, SourceState::TextUnchanged].into_iter()
+                .filter_map(|source_state| source_state_counts.get(&source_state).map(|count| format!("{} {}", count, source_state.name())))
+                .collect::<Vec<String>>();
+            if !source_state_summary.is_empty() {
+                println!("{}", source_state_summary.join(", "));
+            }
+            0
+        },
+        LibrarySubcommand::Clear(clear_args) => {
+            if clear_args.all != clear_args.ids.is_empty() {
+                log::error!("Pass either book ids to remove or --all, but not both.");
+                return 1
+            }
+            let mut books_to_remove = Vec::new();
+            let mut unresolved_selectors = Vec::new();
+            match clear_args.all {
+                true => books_to_remove.extend(cache.contents.iter().cloned()),
+                false => for selector in &clear_args.ids {
+                    match cache.resolve_selector(selector) {
+                        Ok(ids) => for id in ids {
+                            if !books_to_remove.iter().any(|book_to_remove| book_to_remove.id == id) {
+                                books_to_remove.extend(cache.get(&id).cloned());
+                            }
+                        },
+                        Err(e) => unresolved_selectors.push(e),
+                    }
+                },
+            }
+            for e in &unresolved_selectors {
+                log::error!("{}", e);
+            }
+            let exit_code = if unresolved_selectors.is_empty() { 0 } else { 1 };
+            if books_to_remove.is_empty() {
+                if clear_args.all {
+                    println!("The library is already empty.");
+                }
+                return exit_code
+            }
+
+            eprintln!("About to remove:");
+            for book_to_remove in &books_to_remove {
+                eprintln!("  {} ({}, {} bytes)", book_to_remove.title.as_deref().unwrap_or("(untitled)"), book_to_remove.id, book_to_remove.bytes);
+            }
+            if !clear_args.yes {
+                // Scripts have nobody to ask, so they have to say so up front.
+                if !std::io::stdin().is_terminal() {
+                    log::error!("Nothing was removed. Pass --yes to remove books without confirming.");
+                    return 1
+                }
+                if !confirm(&format!("Remove these {} books?", books_to_remove.len())) {
+                    log::info!("Nothing was removed.");
+                    return 1
+                }
+            }
+
+            let removed_books = books_to_remove.iter().filter_map(|book_to_remove| cache.remove(&book_to_remove.id)).collect::<Vec<CachedBook>>();
+            println!("Removed {} books, freeing {} bytes.", removed_books.len(), removed_books.iter().map(|removed_book| removed_book.bytes).sum::<usize>());
+            exit_code
+        },
+        LibrarySubcommand::History(history_args) => {
+            let records = match history::read(&cache.history_path()) {
+                Ok(records) => records,
+                Err(e) => {
+                    log::error!("{}", e);
+                    return 1
+                },
+            };
+            let book_id = match &history_args.book {
+                Some(selector) => match cache.resolve_single_selector(selector) {
+                    Ok(cached_book) => Some(cached_book.id.clone()),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        return 1
+                    },
+                },
+                None => None,
+            };
+            let days = history_args.days.unwrap_or(30);
+            let now = Timestamp::now();
+            let records = records.iter().filter(|record| book_id.as_ref().is_none_or(|book_id| record.id == *book_id)).collect::<Vec<&history::HistoryRecord>>();
+            let recent_records = records.iter().copied().filter(|record| record.time.day() > now.day() - days as i64).collect::<Vec<&history::HistoryRecord>>();
+            // Books removed since are still in the history, under their ids alone.
+            let describe_book = |id: &str| match cache.get(id) {
+                Some(cached_book) => format!("{}\t{}", id, cached_book.title.as_deref().unwrap_or("(untitled)")),
+                None => format!("{}\t(no longer in the library)", id),
+            };
+            for record in recent_records.iter().rev() {
+                match &record.section {
+                    Some(section) => println!("{}\t{}\tat {}", record.time, describe_book(&record.id), section),
+                    None => println!("{}\t{}", record.time, describe_book(&record.id)),
+                }
+            }
+            println!("{} openings in the last {} days", recent_records.len(), days);
+            let this_month_records = records.iter().copied().filter(|record| record.time.year_and_month() == now.year_and_month()).collect::<Vec<&history::HistoryRecord>>();
+            println!("{} books opened this month", history::open_counts(&this_month_records).len());
+            if let Some((id, count)) = history::open_counts(&recent_records).first() {
+                println!("Most opened in the last {} days: {} ({} times)", days, describe_book(id).replace('\t', " "), count);
+            }
+            let (current_streak, longest_streak) = history::streaks(&records, now.day());
+            println!("Reading streak: {} days, longest {} days", current_streak, longest_streak);
+            0
+        },
+        LibrarySubcommand::Info(info_args) => {
+            let cached_book = match cache.resolve_single_selector(&info_args.id) {
+                Ok(cached_book) => cached_book,
+                Err(e) => {
+                    log::error!("{}", e);
+                    return 1
+                },
+            };
+            println!("id: {}", cached_book.id);
+            println!("title: {}", cached_book.title.as_deref().unwrap_or("(unknown)"));
+            println!("creator: {}", cached_book.creator.as_deref().unwrap_or("(unknown)"));
+            println!("directory: {}", cache.book_dir(cached_book).display());
+            println!("size: {} bytes", cached_book.bytes);
+            println!("added: {}", cached_book.added_time);
+            println!("last opened: {}", cached_book.last_opened_time);
+            if let Some(updated_time) = cached_book.updated_time {
+                println!("re-extracted: {}", updated_time);
+            }
+            match &cached_book.source {
+                Some(source) => println!("source: {} ({} bytes, modified {})", source.path.display(), source.bytes, source.modified_time),
+                None => println!("source: (unknown)"),
+            }
+            if let Some(content_digest) = &cached_book.content_digest {
+                println!("content digest: {}", content_digest);
+            }
+            if let Some(preferred_stylesheet) = &cached_book.preferred_stylesheet {
+                println!("stylesheet: {}", preferred_stylesheet);
+            }
+            if cached_book.exclude_from_limits {
+                println!("counted toward library limits: no");
+            }
+            0
+        },
+        LibrarySubcommand::Extraction(extraction_args) => {
+            let cached_book = match cache.resolve_single_selector(&extraction_args.id) {
+                Ok(cached_book) => cached_book,
+                Err(e) => {
+                    log::error!("{}", e);
+                    return 1
+                },
+            };
+            let book_dir = cache.book_dir(cached_book);
+            let extraction_record = match read_to_string(book_dir.join(EXTRACTION_RECORD_FILENAME)) {
+                Ok(extraction_record) => extraction_record,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    log::error!("{} was extracted before rib recorded how books were extracted. Open it with --force-reimport to record it.", cached_book.id);
+                    return 1
+                },
+                Err(e) => {
+                    log::error!("Couldn't read {}: {}", book_dir.join(EXTRACTION_RECORD_FILENAME).display(), e);
+                    return 1
+                },
+            };
+            println!("{}", extraction_record.trim_end());
+            let stylesheet_paths = [PathBuf::from(INDEX_STYLESHEET_FILENAME), Path::new("styles").join(NO_OVERRIDE_STYLESHEET_FILENAME), Path::new("styles").join(OVERRIDE_STYLESHEET_FILENAME)];
+            let mut wrote_css = false;
+            for stylesheet_path in stylesheet_paths {
+                if let Some(css) = read_to_string(book_dir.join(&stylesheet_path)).ok().map(|css| css.trim_end().to_string()).filter(|css| !css.trim().is_empty()) {
+                    println!();
+                    println!("{}:", stylesheet_path.display());
+                    println!("{}", css);
+                    wrote_css = true;
+                }
+            }
+            if !wrote_css {
+                println!("No CSS was written, since the stylesheet was empty.");
+            }
+            0
+        },
+        LibrarySubcommand::Locate(locate_args) => {
+            let cached_book = match cache.resolve_single_selector(&locate_args.id) {
+                Ok(cached_book) => cached_book,
+                Err(e) => {
+                    log::error!("{}", e);
+                    return 1
+                },
+            };
+            match &cached_book.source {
+                Some(source) => {
+                    println!("{}", source.path.display());
+                    0
+                },
+                None => {
+                    log::error!("{} has no recorded source, since it was read from stdin or imported before sources were recorded.", cached_book.id);
+                    1
+                },
+            }
+        },
+        LibrarySubcommand::Prune(_) => {
+            for pruned_book in cache.prune() {
+                println!("Removed {} (missing directory {})", pruned_book.id, pruned_book.path.display());
+            }
+            for temporary_dir_path in cache.remove_stale_temporary_dirs() {
+                println!("Removed leftover temporary dir {}", temporary_dir_path.display());
+            }
+            0
+        },
+        LibrarySubcommand::Search(search_args) => {
+            let query = search::normalize_query(&search_args.query);
+            if query.is_empty() {
+                log::error!("The search query is empty.");
+                return 1
+            }
+            // Most recently opened books first, as on the shelf, since those are likeliest to be the ones meant.
+            let cached_books = match &search_args.book {
+                Some(selector) => match cache.resolve_single_selector(selector) {
+                    Ok(cached_book) => vec![cached_book],
+                    Err(e) => {
+                        log::error!("{}", e);
+                        return 1
+                    },
+                },
+                None => cache.contents.iter().rev().collect(),
+            };
+            let mut match_count = 0;
+            let mut best_match: Option<(usize, PathBuf, Option<String>)> = None;
+            for cached_book in cached_books {
+                let contents_dir = cache.book_dir(cached_book).join("epub");
+                let mut printed_header = false;
+                for document_path in search::searchable_documents(&contents_dir) {
+                    let document_matches = match search::search_document(&document_path, &query) {
+                        Ok(document_matches) => document_matches,
+                        Err(e) => {
+                            log::warning!("{}", e);
+                            continue
+                        },
+                    };
+                    let Some(first_match) = document_matches.matches.first() else {
+                        continue
+                    };
+                    if !printed_header {
+                        println!("{} [{}]", cached_book.title.as_deref().unwrap_or("(untitled)"), cached_book.id);
+                        printed_header = true;
+                    }
+                    let relative_path = document_path.strip_prefix(&contents_dir).unwrap_or(&document_path);
+                    let chapter = match &document_matches.title {
+                        Some(title) => format!("{} ({})", relative_path.display(), title),
+                        None => relative_path.display().to_string(),
+                    };
+                    for document_match in &document_matches.matches {
+                        println!("  {}: {}", chapter, document_match.snippet);
+                    }
+                    match_count += document_matches.matches.len();
+                    if best_match.as_ref().is_none_or(|(best_count, _path, _anchor)| document_matches.matches.len() > *best_count) {
+                        best_match = Some((document_matches.matches.len(), document_path.clone(), first_match.anchor.clone()));
+                    }
+                }
+            }
+            let Some((_count, best_path, best_anchor)) = best_match else {
+                log::info!("No matches for '{}'.", search_args.query);
+                return 1
+            };
+            match match_count {
+                1 => log::info!("1 match."),
+                match_count => log::info!("{} matches.", match_count),
+            }
+            if search_args.open {
+                let opened = browser_commands(None, config).and_then(|commands| browser::open_with_first_working(&commands, |command| browser::open(command, &best_path, best_anchor.as_deref(), config.wsl_linux_browser, true)));
+                if let Err(e) = opened {
+                    log::error!("Couldn't open the best match in a browser: {}. Open this file manually: {}", e, file_url(&best_path, best_anchor.as_deref()));
+                    return 1
+                }
+            }
+            0
+        },
+        LibrarySubcommand::Shelf(shelf_args) => {
+            let shelf_path = cache.root().join(SHELF_FILENAME);
+            if cache.shelf_generation != Some(cache.generation) || !shelf_path.is_file() {
+                if let Err(e) = write(&shelf_path, create_shelf(cache)) {
+                    log::error!("Couldn't write shelf page {}: {}", shelf_path.display(), e);
+                    return 1
+                }
+                cache.shelf_generation = Some(cache.generation);
+                cache.save();
+            }
+            if shelf_args.print_path {
+                println!("{}", browser::resolve_path(&shelf_path).display());
+                return 0
+            }
+            let opened = browser_commands(None, config).and_then(|commands| browser::open_with_first_working(&commands, |command| browser::open(command, &shelf_path, None, config.wsl_linux_browser, true)));
+            match opened {
+                Ok(()) => 0,
+                Err(e) => {
+                    log::error!("Couldn't open the shelf in a browser: {}. Open this file manually: {}", e, shelf_path.display());
+                    1
+                },
+            }
+        },
+        LibrarySubcommand::Style(style_args) => {
+            if let LibraryStyleSubcommand::Refresh(refresh_args) = &style_args.subcommand {
+                for name in &refresh_args.stylesheets {
+                    if let Err(e) = config.get_stylesheet(Some(name)) {
+                        log::error!("{}", e);
+                        return 1
+                    }
+                }
+                let mut css_by_name = HashMap::new();
+                let mut refreshed_count = 0;
+                let mut index_only_count = 0;
+                let mut refreshed_bytes = Vec::new();
+                let mut failed = false;
+                for cached_book in &cache.contents {
+                    match refresh_stylesheets(cache, cached_book, &refresh_args.stylesheets, config, &mut css_by_name) {
+                        Ok(StyleRefresh::Refreshed { stylesheet_name, pages_restyled: true, bytes }) => {
+                            println!("Restyled {} with stylesheet '{}'", cached_book.id, stylesheet_name);
+                            refreshed_count += 1;
+                            refreshed_bytes.push((cached_book.id.clone(), bytes));
+                        },
+                        Ok(StyleRefresh::Refreshed { stylesheet_name, pages_restyled: false, bytes }) => {
+                            println!("Restyled the index of {} with stylesheet '{}'; its pages are fixed-layout or were extracted without rib's stylesheets, so they're as they were", cached_book.id, stylesheet_name);
+                            index_only_count += 1;
+                            refreshed_bytes.push((cached_book.id.clone(), bytes));
+                        },
+                        Ok(StyleRefresh::Skipped) => (),
+                        Ok(StyleRefresh::NeedsReopening) => log::warning!("{} was extracted before its index could be restyled in place; open it again to restyle it.", cached_book.id),
+                        Err(e) => {
+                            log::error!("Couldn't restyle {}: {}", cached_book.id, e);
+                            failed = true;
+                        },
+                    }
+                }
+                for (id, bytes) in refreshed_bytes {
+                    cache.set_bytes(&id, bytes);
+                }
+                // What a stylesheet strips from a book's pages was decided when it was extracted, so only CSS changes show.
+                match index_only_count {
+                    0 => println!("Restyled {} books.", refreshed_count),
+                    _ => println!("Restyled {} books, and the index alone of {} more.", refreshed_count, index_only_count),
+                }
+                println!("Changes to strip_book_css, strip_inline_styles, force_styles, or csp show once a book is opened again.");
+                return match failed {
+                    true => 1,
+                    false => 0,
+                }
+            }
+            let (selector, preferred_stylesheet) = match &style_args.subcommand {
+                LibraryStyleSubcommand::Set(set_args) => {
+                    if let Err(e) = config.get_stylesheet(Some(&set_args.stylesheet)) {
+                        log::error!("{}", e);
+                        return 1
+                    }
+                    config.warn_if_empty_stylesheet(&set_args.stylesheet);
+                    (&set_args.id, Some(set_args.stylesheet.clone()))
+                },
+                LibraryStyleSubcommand::Unset(unset_args) => (&unset_args.id, None),
+                LibraryStyleSubcommand::Refresh(_) => unreachable!("Refreshing styles is handled above."),
+            };
+            let id = match cache.resolve_single_selector(selector) {
+                Ok(cached_book) => cached_book.id.clone(),
+                Err(e) => {
+                    log::error!("{}", e);
+                    return 1
+                },
+            };
+            // Books are styled as they're extracted, so the change shows the next time the book is opened.
+            match &preferred_stylesheet {
+                Some(stylesheet) => println!("{} will open with stylesheet '{}'.", id, stylesheet),
+                None => println!("{} will open with the default stylesheet.", id),
+            }
+            cache.set_preferred_stylesheet(&id, preferred_stylesheet);
+            0
+        },
+        LibrarySubcommand::Count(LibraryCountArgs { id }) | LibrarySubcommand::NoCount(LibraryNoCountArgs { id }) => {
+            let exclude_from_limits = matches!(&library_args.subcommand, LibrarySubcommand::NoCount(_));
+            let id = match cache.resolve_single_selector(id) {
+                Ok(cached_book) => cached_book.id.clone(),
+                Err(e) => {
+                    log::error!("{}", e);
+                    return 1
+                },
+            };
+            // A book counted again can put the library over its limits, which are next enforced when a book is opened.
+            match exclude_from_limits {
+                true => println!("{} no longer counts toward the library's limits, and won't be purged to meet them.", id),
+                false => println!("{} counts toward the library's limits again.", id),
+            }
+            cache.set_exclude_from_limits(&id, exclude_from_limits);
+            0
+        },
+        LibrarySubcommand::Export(export_args) => {
+            let mut ids = Vec::new();
+            for selector in &export_args.ids {
+                match cache.resolve_selector(selector) {
+                    Ok(selected_ids) => ids.extend(selected_ids),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        return 1
+                    },
+                }
+            }
+            match library_archive::export(cache, &ids, &export_args.out) {
+                Ok(book_count) => {
+                    println!("Backed up {} books to {}", book_count, export_args.out.display());
+                    0
+                },
+                Err(e) => {
+                    log::error!("{}", e);
+                    1
+                },
+            }
+        },
+        LibrarySubcommand::Import(import_args) => {
+            let summary = match library_archive::import(cache, &import_args.archive, &import_args.ids) {
+                Ok(summary) => summary,
+                Err(e) => {
+                    log::error!("{}", e);
+                    return 1
+                },
+            };
+            for id in &summary.kept_ids {
+                println!("Kept the library's copy of {}, which was opened no earlier than the archived one.", id);
+            }
+            for (id, e) in &summary.failures {
+                log::error!("Couldn't restore {}: {}", id, e);
+            }
+            println!("Restored {} books from {}", summary.imported_ids.len(), import_args.archive.display());
+            // Restored books are held to the library's limits like any others, but aren't purged to make room for each other.
+            cache.truncate(&summary.imported_ids);
+            match summary.failures.is_empty() {
+                true => 0,
+                false => 1,
+            }
+        },
+        LibrarySubcommand::Verify(verify_args) => {
+            let mut ids = Vec::new();
+            for selector in &verify_args.ids {
+                match cache.resolve_selector(selector) {
+                    Ok(selected_ids) => ids.extend(selected_ids),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        return 1
+                    },
+                }
+            }
+            let mut checked_count = 0;
+            let mut broken_books = Vec::new();
+            for cached_book in cache.contents.iter().filter(|cached_book| ids.is_empty() || ids.contains(&cached_book.id)) {
+                checked_count += 1;
+                let problems = library_verify::verify_book(cache, cached_book, verify_args.deep);
+                if problems.is_empty() {
+                    println!("{}: OK", cached_book.id);
+                    continue
+                }
+                println!("{}: problems found", cached_book.id);
+                for problem in &problems {
+                    println!("  {}", problem);
+                }
+                broken_books.push(cached_book.clone());
+            }
+            println!("Checked {} books; found problems with {}.", checked_count, broken_books.len());
+            if verify_args.repair {
+                // A broken copy is dropped whole, and opening the book again imports it afresh. Without its source, though,
+                // a partly readable copy beats none, so it's left for 'library clear' to remove if that's what's wanted.
+                for broken_book in &broken_books {
+                    match &broken_book.source {
+                        Some(source) if source.path.is_file() => {
+                            cache.remove(&broken_book.id);
+                            println!("Removed {}; open {} to import it again.", broken_book.id, source.path.display());
+                        },
+                        Some(source) => println!("Kept {}, since its source {} is gone too; import it again from another copy, or remove it with 'library clear'.", broken_book.id, source.path.display()),
+                        None => println!("Kept {}, since its source isn't recorded; import it again from wherever it came from, or remove it with 'library clear'.", broken_book.id),
+                    }
+                }
+            }
+            match broken_books.is_empty() {
+                true => 0,
+                false => 1,
+            }
+        },
+        LibrarySubcommand::Path(_) => unreachable!("Library path subcommand is handled before the cache is opened."),
+    }
+}
+
+fn exit_with_error(output_format: OutputFormat, error: CodedError) -> ! {
+    match output_format {
+        OutputFormat::Plain => log::error!("{}", error),
+        OutputFormat::Json => output::print_envelope(&serde_json::Value::Null, &[], Some(&error)),
+    }
+    exit(1)
+}
+
+// Runs the command line, with the process's arguments, and exits with its status. It's public only for the binary to call,
+// and isn't part of the library's API.
+#[doc(hidden)]
+pub fn run() {
+    let args: Args = argh::from_env();
+    let output_format = args.output_format();
+    if let Err(e) = args.validate() {
+        exit_with_error(output_format, CodedError::new(ErrorCode::Usage, e));
+    }
+    if args.verbose {
+        log::set_max_level(log::Level::Debug);
+    } else if args.quiet {
+        log::set_max_level(log::Level::Error);
+    }
+
+    // Settled before anything is read or created, so that asking for usage leaves a fresh machine as it was.
+    if args.has_nothing_to_do() {
+        if output_format == OutputFormat::Json {
+            exit_with_error(output_format, CodedError::new(ErrorCode::Usage, "No books were given to open."));
+        }
+        eprintln!("{}", Args::usage());
+        exit(1);
+    }
+
+    if let Some(Subcommand::Check(check_args)) = &args.subcommand {
+        exit(check::run(check_args, output_format));
+    }
+
+    let project_dirs = ProjectDirs::from("", "", "rib");
+    let (default_config_path, default_library_dir) = match &project_dirs {
+        Some(project_dirs) => (project_dirs.config_dir().join("config.toml"), project_dirs.cache_dir().to_path_buf()),
+        None => (PathBuf::from(FALLBACK_CONFIG_PATH), PathBuf::from(FALLBACK_LIBRARY_DIR)),
+    };
+    let (config_path, config_path_source) = env_path(CONFIG_PATH_VAR, default_config_path);
+    if project_dirs.is_none() && config_path_source == ConfigSource::Default {
+        log::warning!("No home directory was found, so the config file is {} in the current directory. Set {} to use another.", FALLBACK_CONFIG_PATH, CONFIG_PATH_VAR);
+    }
+    if let Some(Subcommand::Config(config_args)) = &args.subcommand {
+        if output_format == OutputFormat::Json {
+            exit_with_error(output_format, CodedError::new(ErrorCode::Usage, "The config subcommands have no JSON output."));
+        }
+        exit(run_config_subcommand(config_args, (&config_path, config_path_source), args.library_dir.as_deref(), &default_library_dir));
+    }
+
+    let config = Config::load(&config_path, args.library_dir.as_deref()).unwrap_or_else(|e| {
+        exit_with_error(output_format, CodedError::new(ErrorCode::Config, e));
+    });
+    if project_dirs.is_none() && config.library_dir.is_none() && !args.ephemeral {
+        log::warning!("No home directory was found, so the library is {} in the current directory. Pass --library-dir or set library_dir to use another.", FALLBACK_LIBRARY_DIR);
+    }
+    let cache_dir = config.library_dir(&default_library_dir);
+
+    // The path subcommand runs before the config and cache are created, since it's meant to report on them.
+    if let Some(Subcommand::Library(LibraryArgs { subcommand: LibrarySubcommand::Path(path_args) })) = &args.subcommand {
+        exit(run_path_subcommand(&cache_dir, || create_dir_all(&cache_dir), path_args.create, output_format));
+    }
+
+    // Only opening books sets anything up; the library subcommands that just read it leave a fresh machine untouched.
+    let is_read_only = matches!(&args.subcommand, Some(Subcommand::Library(LibraryArgs { subcommand: LibrarySubcommand::Export(_) | LibrarySubcommand::Extraction(_) | LibrarySubcommand::History(_) | LibrarySubcommand::Info(_) | LibrarySubcommand::List(_) | LibrarySubcommand::Locate(_) | LibrarySubcommand::Search(_) | LibrarySubcommand::Verify(LibraryVerifyArgs { repair: false, .. }) })));
+    if args.subcommand.is_none() {
+        Config::create_if_missing(&config_path);
+        clean_up_ephemeral_dirs();
+    }
+
+    let mut destination = match args.ephemeral {
+        true => Destination::Ephemeral(create_ephemeral_root().unwrap_or_else(|e| {
+            exit_with_error(output_format, CodedError::new(ErrorCode::Io, e));
+        })),
+        false => {
+            let cache_path = cache_dir.join("cache_index.json");
+            let mut cache = match is_read_only {
+                true => Cache::open_readonly(cache_path, &config),
+                false => Cache::open(cache_path, &config),
+            };
+
+            match &args.subcommand {
+                Some(Subcommand::Library(LibraryArgs { subcommand: LibrarySubcommand::Prune(_) })) => (),
+                _ => {
+                    if !is_read_only {
+                        for temporary_dir_path in cache.remove_stale_temporary_dirs() {
+                            log::debug!("Removed leftover temporary dir {}", temporary_dir_path.display());
+                        }
+                    }
+                    let missing_book_count = cache.missing_book_dirs().len();
+                    if missing_book_count > 0 {
+                        log::warning!("{} library entries have no book directory. If the library is synced from elsewhere, it may not have finished syncing. Run 'rib library prune' to remove them.", missing_book_count);
+                    }
+                },
+            }
+
+            if let Some(Subcommand::Library(library_args)) = &args.subcommand {
+                exit(run_library_subcommand(library_args, &mut cache, &config, output_format));
+            }
+            Destination::Library(cache)
+        },
+    };
+
+    // Without books, this explains the stylesheet books open with when none has been chosen for them.
+    if args.explain_style && args.epubs.is_empty() {
+        let resolved_style = resolve_style(&args, &config, None).unwrap_or_else(|e| {
+            exit_with_error(output_format, CodedError::new(ErrorCode::Config, e));
+        });
+        let style_explanation = style_explanation::explain(&config, resolved_style.chosen, &resolved_style.stylesheet, None, &args);
+        match output_format {
+            OutputFormat::Plain => println!("{}", style_explanation),
+            OutputFormat::Json => output::print_envelope(&style_explanation.to_json(), &[], None),
+        }
+        exit(0);
+    }
+    let epub_paths = expand_book_paths(&args.epubs, args.recursive, args.strict).unwrap_or_else(|e| {
+        exit_with_error(output_format, CodedError::new(ErrorCode::NotFound, e));
+    });
+
+    if args.watch && !matches!(epub_paths.as_slice(), [epub_path] if epub_path.is_file()) {
+        exit_with_error(output_format, CodedError::new(ErrorCode::Usage, "--watch follows one book's file, so it needs exactly one book path, and not an unpacked epub directory."));
+    }
+
+    // Checked up front so a mistyped --stylesheet fails before any book is extracted.
+    if let Err(e) = config.get_stylesheet(args.stylesheet.as_deref()) {
+        exit_with_error(output_format, CodedError::new(ErrorCode::Config, e));
+    }
+    if let Some(stylesheet_name) = &args.stylesheet {
+        config.warn_if_empty_stylesheet(stylesheet_name);
+    }
+
+    let mut server = match args.serve {
+        true => Some(serve::Server::bind(args.port.unwrap_or(0)).unwrap_or_else(|e| {
+            exit_with_error(output_format, CodedError::new(ErrorCode::Io, e));
+        })),
+        false => None,
+    };
+    if args.watch {
+        if let Some(server) = &mut server {
+            server.enable_live_reload();
+        }
+    }
+    let status = open_books(&epub_paths, &args, &config, &mut destination, server.as_mut());
+    if args.watch && status == 0 {
+        let result = match server.filter(|server| !server.is_empty()) {
+            Some(server) => std::thread::scope(|scope| {
+                log::info!("Watching {} for changes. Press Ctrl-C to stop.", describe_book_path(&epub_paths[0]));
+                let server_thread = scope.spawn(|| server.run(args.idle_timeout.map(Duration::from_secs)));
+                watch_book(&epub_paths[0], &args, &config, &mut destination, Some(&server), || !server_thread.is_finished());
+                server_thread.join().unwrap()
+            }),
+            None => {
+                log::info!("Watching {} for changes. Press Ctrl-C to stop.", describe_book_path(&epub_paths[0]));
+                watch_book(&epub_paths[0], &args, &config, &mut destination, None, || true);
+                Ok(())
+            },
+        };
+        if let Err(e) = result {
+            log::error!("{}", e);
+            exit(1);
+        }
+        exit(0);
+    }
+    if let Some(server) = server.filter(|server| !server.is_empty()) {
+        log::info!("Press Ctrl-C to stop serving.");
+        if let Err(e) = server.run(args.idle_timeout.map(Duration::from_secs)) {
+            log::error!("{}", e);
+            exit(1);
+        }
+    }
+    exit(status);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    fn element_xml(element: &BytesStart) -> String {
+        let mut writer = quick_xml::Writer::new(Vec::new());
+        writer.write_event(Event::Empty(element.borrow())).unwrap();
+        String::from_utf8(writer.into_inner()).unwrap()
+    }
+
+    fn forcing_stylesheet() -> Stylesheet {
+        Stylesheet {
+            text_color: Some(StyleTextColor { value: String::from("#eee"), override_book: true }),
+            background_color: Some(StyleBackgroundColor { value: String::from("#111"), override_book: true }),
+            font: Some(StyleFont { value: String::from("Georgia"), override_book: false }),
+            force_styles: true,
+            ..Stylesheet::empty()
+        }
+    }
+
+    const DAY_SECS: u64 = 86_400;
+
+    fn day(day: u64) -> Timestamp {
+        Timestamp::from(UNIX_EPOCH + Duration::from_secs(day * DAY_SECS))
+    }
+
+    fn cached_book(id: &str, bytes: usize, last_opened_day: u64) -> CachedBook {
+        CachedBook {
+            id: String::from(id),
+            path: PathBuf::from(id),
+            bytes,
+            added_time: day(0),
+            last_opened_time: day(last_opened_day),
+            title: None,
+            creator: None,
+            cover: None,
+            source: None,
+            updated_time: None,
+            content_digest: None,
+            preferred_stylesheet: None,
+            exclude_from_limits: false,
+        }
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let scratch = std::env::temp_dir().join(format!("rib-test-{}-{}", std::process::id(), name));
+        let _ = remove_dir_all(&scratch);
+        create_dir_all(&scratch).unwrap();
+        scratch
+    }
+
+    fn cache_of(root: &Path, books: Vec<CachedBook>) -> Cache {
+        Cache {
+            path: root.join("cache_index.json"),
+            contents: VecDeque::from(books),
+            generation: 0,
+            shelf_generation: None,
+            max_books: None,
+            max_bytes: None,
+            truncation_policy: TruncationPolicy::Lru,
+        }
+    }
+
+    fn cache_in(root: &Path, books: Vec<CachedBook>, max_bytes: Option<usize>) -> Cache {
+        for cached_book in &books {
+            create_dir_all(root.join(&cached_book.path)).unwrap();
+        }
+        Cache {
+            max_bytes,
+            ..cache_of(root, books)
+        }
+    }
+
+    fn cached_ids(cache: &Cache) -> Vec<&str> {
+        cache.contents.iter().map(|cached_book| cached_book.id.as_str()).collect()
+    }
+
+    fn titled_book(id: &str, title: &str) -> CachedBook {
+        CachedBook {
+            title: Some(String::from(title)),
+            ..cached_book(id, 0, 0)
+        }
+    }
+
+    fn selector_cache() -> Cache {
+        cache_of(Path::new("library"), vec![
+            titled_book("urn:uuid:1234-aaaa", "The Left Hand of Darkness"),
+            titled_book("urn:uuid:1234-bbbb", "The Dispossessed"),
+            titled_book("urn:isbn:9780441478125", "The Lathe of Heaven"),
+            titled_book("urn:isbn:97804414781", "Rocannon's World"),
+        ])
+    }
+
+    #[test]
+    fn selects_exact_ids_over_longer_ones_they_start() {
+        assert_eq!(selector_cache().resolve_selector("urn:isbn:97804414781").unwrap(), ["urn:isbn:97804414781"]);
+    }
+
+    #[test]
+    fn selects_unique_id_prefixes() {
+        assert_eq!(selector_cache().resolve_selector("urn:uuid:1234-b").unwrap(), ["urn:uuid:1234-bbbb"]);
+    }
+
+    #[test]
+    fn refuses_ambiguous_id_prefixes() {
+        let e = selector_cache().resolve_selector("urn:uuid:1234").unwrap_err();
+        assert_eq!(e.code, ErrorCode::Usage);
+        assert!(e.message.contains("urn:uuid:1234-aaaa (The Left Hand of Darkness)") && e.message.contains("urn:uuid:1234-bbbb (The Dispossessed)"), "{}", e.message);
+    }
+
+    #[test]
+    fn selects_every_title_containing_the_text() {
+        assert_eq!(selector_cache().resolve_selector("title:the l").unwrap(), ["urn:uuid:1234-aaaa", "urn:isbn:9780441478125"]);
+        assert_eq!(selector_cache().resolve_selector("title:").unwrap_err().code, ErrorCode::Usage);
+        let e = selector_cache().resolve_single_selector("title:the").unwrap_err();
+        assert_eq!(e.code, ErrorCode::Usage);
+    }
+
+    #[test]
+    fn reports_selectors_matching_nothing() {
+        assert_eq!(selector_cache().resolve_selector("urn:doi").unwrap_err().code, ErrorCode::NotFound);
+        assert_eq!(selector_cache().resolve_selector("title:Earthsea").unwrap_err().code, ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn reopening_keeps_the_preferred_stylesheet() {
+        let scratch = scratch_dir("preferred");
+        let mut cache = cache_in(&scratch, vec![cached_book("a", 100, 1)], None);
+        cache.set_preferred_stylesheet("a", Some(String::from("dark")));
+        let details = BookDetails {
+            title: Some(String::from("A")),
+            creator: None,
+            cover: None,
+            source: None,
+            content_digest: None,
+            reimported: false,
+            exclude_from_limits: false,
+        };
+        cache.add(String::from("a"), String::from("a"), 120, details);
+        assert_eq!(cache.get("a").unwrap().preferred_stylesheet.as_deref(), Some("dark"));
+        cache.set_preferred_stylesheet("a", None);
+        assert_eq!(cache.get("a").unwrap().preferred_stylesheet, None);
+        remove_dir_all(&scratch).unwrap();
+    }
+
+    #[test]
+    fn oversized_new_book_leaves_the_rest_alone() {
+        let scratch = scratch_dir("oversized");
+        let books = vec![cached_book("a", 100, 1), cached_book("b", 100, 2), cached_book("c", 100, 3), cached_book("huge", 5000, 4)];
+        let mut cache = cache_in(&scratch, books, Some(1000));
+        cache.truncate(&[String::from("huge")]);
+        assert_eq!(cached_ids(&cache), ["a", "b", "c", "huge"]);
+        assert!(scratch.join("a").is_dir());
+        remove_dir_all(&scratch).unwrap();
+    }
+
+    #[test]
+    fn new_book_within_limits_still_makes_room() {
+        let scratch = scratch_dir("room");
+        let books = vec![cached_book("a", 300, 1), cached_book("b", 300, 2), cached_book("c", 300, 3), cached_book("new", 400, 4)];
+        let mut cache = cache_in(&scratch, books, Some(1000));
+        cache.truncate(&[String::from("new")]);
+        assert_eq!(cached_ids(&cache), ["b", "c", "new"]);
+        assert!(!scratch.join("a").exists());
+        remove_dir_all(&scratch).unwrap();
+    }
+
+    // Least recently opened first, as the library keeps them. Opened 9, 8, 7, and 6 days before day 10, their scores
+    // are 4050, 8000, 2800, and 3000 byte-days.
+    fn eviction_books() -> Vec<CachedBook> {
+        vec![
+            cached_book("a", 450, 1),
+            cached_book("b", 1000, 2),
+            cached_book("c", 400, 3),
+            cached_book("d", 500, 4),
+        ]
+    }
+
+    fn eviction_order(policy: TruncationPolicy) -> Vec<String> {
+        select_evictions(&eviction_books(), Some(0), None, policy, &[], day(10))
+    }
+
+    // Indexes as written before timestamps were stored as RFC 3339: a bare list of books, and then the current layout.
+    const LEGACY_LIST_INDEX: &str = r#"[
+        {"id": "urn:a", "path": "a", "bytes": 10, "added_time": {"secs_since_epoch": 1000000000, "nanos_since_epoch": 0}, "last_opened_time": {"secs_since_epoch": 1000086400, "nanos_since_epoch": 500000000}}
+    ]"#;
+    const SYSTEM_TIME_INDEX: &str = r#"{"generation": 3, "books": [
+        {"id": "urn:a", "path": "a", "bytes": 10, "added_time": {"secs_since_epoch": 1000000000, "nanos_since_epoch": 0}, "last_opened_time": {"secs_since_epoch": 1000086400, "nanos_since_epoch": 500000000}}
+    ]}"#;
+    const RFC3339_INDEX: &str = r#"{"generation": 3, "books": [
+        {"id": "urn:a", "path": "a", "bytes": 10, "added_time": "2001-09-09T01:46:40Z", "last_opened_time": "2001-09-10T01:46:40.5Z"}
+    ]}"#;
+
+    fn stored_books(index: &str) -> VecDeque<CachedBook> {
+        match serde_json::from_str(index).unwrap() {
+            StoredCacheIndex::Current(index) => index.books,
+            StoredCacheIndex::Legacy(books) => books,
+        }
+    }
+
+    #[test]
+    fn reads_timestamps_from_every_index_format() {
+        for index in [LEGACY_LIST_INDEX, SYSTEM_TIME_INDEX, RFC3339_INDEX] {
+            let books = stored_books(index);
+            assert_eq!(books[0].added_time.to_rfc3339(), "2001-09-09T01:46:40Z");
+            assert_eq!(books[0].last_opened_time.to_rfc3339(), "2001-09-10T01:46:40.500000000Z");
+        }
+    }
+
+    #[test]
+    fn saves_timestamps_as_rfc3339() {
+        let index = CacheIndex {
+            books: stored_books(SYSTEM_TIME_INDEX),
+            ..CacheIndex::default()
+        };
+        let saved = serde_json::to_string(&index).unwrap();
+        assert!(saved.contains("\"last_opened_time\":\"2001-09-10T01:46:40.500000000Z\""), "{}", saved);
+        assert_eq!(stored_books(&saved)[0].last_opened_time, stored_books(RFC3339_INDEX)[0].last_opened_time);
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_opened_first() {
+        assert_eq!(eviction_order(TruncationPolicy::Lru), ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn largest_first_evicts_by_size() {
+        assert_eq!(eviction_order(TruncationPolicy::LargestFirst), ["b", "d", "a", "c"]);
+    }
+
+    #[test]
+    fn score_weighs_age_by_size() {
+        assert_eq!(eviction_order(TruncationPolicy::Score), ["b", "a", "d", "c"]);
+    }
+
+    #[test]
+    fn evicts_only_until_within_limits() {
+        let books = eviction_books();
+        assert_eq!(select_evictions(&books, Some(3), None, TruncationPolicy::Lru, &[], day(10)), ["a"]);
+        assert_eq!(select_evictions(&books, None, Some(1500), TruncationPolicy::LargestFirst, &[], day(10)), ["b"]);
+        assert_eq!(select_evictions(&books, Some(4), Some(2350), TruncationPolicy::Score, &[], day(10)), Vec::<String>::new());
+    }
+
+    #[test]
+    fn spares_protected_and_excluded_books() {
+        let mut books = eviction_books();
+        books[1].exclude_from_limits = true;
+        // Without b counted, evicting a alone brings the bytes within 1000.
+        assert_eq!(select_evictions(&books, None, Some(1000), TruncationPolicy::Score, &[], day(10)), ["a"]);
+        assert_eq!(select_evictions(&books, None, Some(1000), TruncationPolicy::Score, &[String::from("a")], day(10)), ["d"]);
+    }
+
+    #[test]
+    fn ids_that_sanitize_to_nothing_get_hashed_dirnames() {
+        for id in ["///", "::", "", ".", "..", "CON", "NUL", "<>|?*"] {
+            let dirname = id_to_dirname(id);
+            assert!(dirname.starts_with("book-"), "{:?} became {:?}", id, dirname);
+            assert_eq!(dirname, id_to_dirname(id));
+        }
+        assert_ne!(id_to_dirname("CON"), id_to_dirname("NUL"));
+    }
+
+    #[test]
+    fn long_ids_are_truncated_apart() {
+        let first_id = format!("https://doi.org/10.1000/{}1", "x".repeat(300));
+        let second_id = format!("https://doi.org/10.1000/{}2", "x".repeat(300));
+        let first_dirname = id_to_dirname(&first_id);
+        assert!(first_dirname.len() <= MAX_DIRNAME_LENGTH);
+        assert_ne!(first_dirname, id_to_dirname(&second_id));
+        assert_eq!(first_dirname, id_to_dirname(&first_id));
+    }
+
+    #[test]
+    fn truncation_keeps_whole_characters() {
+        let id = "\u{1f4d6}".repeat(40);
+        assert_eq!(id_to_dirname("\u{1f4d6}\u{1f4da}"), "\u{1f4d6}\u{1f4da}");
+        let dirname = id_to_dirname(&id);
+        assert!(dirname.len() <= MAX_DIRNAME_LENGTH);
+        assert!(dirname.starts_with('\u{1f4d6}'));
+    }
+
+    #[test]
+    fn taken_dirnames_get_numbered() {
+        assert_eq!(unique_dirname("urn:isbn:123", |_dirname| false), "urnisbn123");
+        assert_eq!(unique_dirname("urn:isbn:123", |dirname| matches!(dirname, "urnisbn123" | "urnisbn123_2")), "urnisbn123_3");
+    }
+
+    const NAMES: [&str; 5] = ["Ursula", "Octavia", "Samuel", "Joanna", "Vonda"];
+
+    #[test]
+    fn joins_few_names_with_an_ampersand() {
+        assert_eq!(join_names(&[], false), None);
+        assert_eq!(join_names(&NAMES[..1], false).as_deref(), Some("Ursula"));
+        assert_eq!(join_names(&NAMES[..2], false).as_deref(), Some("Ursula & Octavia"));
+        assert_eq!(join_names(&NAMES[..2], true).as_deref(), Some("Ursula & Octavia"));
+    }
+
+    #[test]
+    fn joins_more_names_with_commas() {
+        assert_eq!(join_names(&NAMES[..3], false).as_deref(), Some("Ursula, Octavia & Samuel"));
+        assert_eq!(join_names(&NAMES, false).as_deref(), Some("Ursula, Octavia, Samuel, Joanna & Vonda"));
+        assert_eq!(join_names(&NAMES[..3], true).as_deref(), Some("Ursula, Octavia, & Samuel"));
+        assert_eq!(join_names(&NAMES, true).as_deref(), Some("Ursula, Octavia, Samuel, Joanna, & Vonda"));
+    }
+
+    #[test]
+    fn joins_only_creators_in_the_role() {
+        let creators = [("Ursula", CreatorRole::Author), ("Marta", CreatorRole::Translator), ("Octavia", CreatorRole::Author), ("Gene", CreatorRole::Translator), ("Ilse", CreatorRole::Illustrator)]
+            .map(|(name, role)| BookCreator { name: String::from(name), role });
+        assert_eq!(join_creator_names(&creators, CreatorRole::Author, false).as_deref(), Some("Ursula & Octavia"));
+        assert_eq!(join_creator_names(&creators, CreatorRole::Translator, false).as_deref(), Some("Marta & Gene"));
+        assert_eq!(join_creator_names(&creators, CreatorRole::Illustrator, false).as_deref(), Some("Ilse"));
+        assert_eq!(join_creator_names(&creators, CreatorRole::Editor, false), None);
+    }
+
+    #[test]
+    fn toc_labels_lose_markup_and_extra_whitespace() {
+        assert_eq!(normalize_toc_label("<i>Ship</i> name"), "Ship name");
+        assert_eq!(normalize_toc_label("  Chapter\n\t 1:\r\n  The   Start "), "Chapter 1: The Start");
+        assert_eq!(normalize_toc_label("<span class=\"num\">2</span><br/>Onward"), "2Onward");
+    }
+
+    #[test]
+    fn toc_labels_keep_text_that_only_looks_like_markup() {
+        assert_eq!(normalize_toc_label("x < y and y > z"), "x < y and y > z");
+        assert_eq!(normalize_toc_label("<3 <"), "<3 <");
+        assert_eq!(normalize_toc_label("Unclosed <b"), "Unclosed <b");
+    }
+
+    #[test]
+    fn empty_toc_labels_are_untitled() {
+        assert_eq!(normalize_toc_label(""), UNTITLED_TOC_LABEL);
+        assert_eq!(normalize_toc_label(" \n "), UNTITLED_TOC_LABEL);
+        assert_eq!(normalize_toc_label("<b></b>"), UNTITLED_TOC_LABEL);
+    }
+
+    #[test]
+    fn resource_paths_stay_inside_their_directory() {
+        assert_eq!(contained_resource_path(Path::new("OEBPS/./text/../images/a.png")), Some(PathBuf::from("OEBPS/images/a.png")));
+        assert_eq!(contained_resource_path(Path::new("OEBPS/../../a.png")), None);
+        assert_eq!(contained_resource_path(Path::new("/etc/passwd")), None);
+    }
+
+    // Symlinks already in the book's directory, as an earlier entry might have left them, are never written through.
+    #[cfg(unix)]
+    #[test]
+    fn writes_never_follow_symlinks_out_of_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let scratch = scratch_dir("symlinks");
+        let root = scratch.join("book");
+        let outside = scratch.join("outside");
+        create_dir_all(&root).unwrap();
+        create_dir_all(&outside).unwrap();
+        let root = canonicalize(&root).unwrap();
+        write(outside.join("secret.css"), "kept").unwrap();
+        symlink(&outside, root.join("escape")).unwrap();
+        symlink(outside.join("secret.css"), root.join("linked.css")).unwrap();
+
+        assert!(write_contained(&root, Path::new("escape/evil.css"), "evil").is_err());
+        write_contained(&root, Path::new("linked.css"), "replaced").unwrap();
+        assert!(!root.join("linked.css").symlink_metadata().unwrap().file_type().is_symlink());
+        assert_eq!(read_to_string(root.join("linked.css")).unwrap(), "replaced");
+        assert_eq!(read_to_string(outside.join("secret.css")).unwrap(), "kept");
+        assert!(!outside.join("evil.css").exists());
+        remove_dir_all(&scratch).unwrap();
+    }
+
+    #[test]
+    fn strips_only_the_given_properties_from_styles() {
+        assert_eq!(strip_style_properties("color: red; font-weight: bold", &["color"]), "font-weight: bold");
+        assert_eq!(strip_style_properties("COLOR:red;", &["color"]), "");
+        assert_eq!(strip_style_properties("background: url('a;b.png'); color: red", &["color"]), "background: url('a;b.png')");
+        assert_eq!(strip_style_properties("font-family: \"A;B\"; margin: 0", &["margin"]), "font-family: \"A;B\"");
+    }
+
+    #[test]
+    fn forces_only_overriding_styles_on_the_elements_they_select() {
+        let forced = forced_properties(&forcing_stylesheet());
+        assert!(forced.contains(&("color", ForcedOn::AllElements)));
+        assert!(forced.iter().any(|(property, forced_on)| *property == "background" && forced_on.includes("body") && !forced_on.includes("p")));
+        assert!(!forced.iter().any(|(property, _forced_on)| *property == "font-family"));
+    }
+
+    #[test]
+    fn forcing_strips_properties_from_style_attributes() {
+        let forced = forced_properties(&forcing_stylesheet());
+        let paragraph = BytesStart::new("p").with_attributes([("class", "x"), ("style", "color: red; background: blue; font-style: italic")]);
+        assert_eq!(element_xml(&strip_forced_properties(paragraph, &forced)), "<p class=\"x\" style=\"background: blue; font-style: italic\"/>");
+        let body = BytesStart::new("body").with_attributes([("style", "background-color: white; color: black")]);
+        assert_eq!(element_xml(&strip_forced_properties(body, &forced)), "<body/>");
+        let unstyled = BytesStart::new("p").with_attributes([("class", "x")]);
+        assert_eq!(element_xml(&strip_forced_properties(unstyled, &forced)), "<p class=\"x\"/>");
+    }
+
+    #[test]
+    fn forcing_needs_an_overriding_style() {
+        let stylesheet = Stylesheet {
+            font: Some(StyleFont { value: String::from("Georgia"), override_book: false }),
+            force_styles: true,
+            ..Stylesheet::empty()
+        };
+        assert_eq!(stylesheet.validate(), vec![String::from("force_styles has nothing to force, since no style sets override_book.")]);
+        assert!(forcing_stylesheet().validate().is_empty());
+    }
+
+    #[test]
+    fn stripped_stylesheet_links_become_alternates() {
+        let link = BytesStart::new("link").with_attributes([("rel", "stylesheet"), ("title", "Day"), ("href", "style.css")]);
+        assert!(is_stylesheet_link(&link));
+        assert_eq!(element_xml(&demote_stylesheet_link(link)), "<link href=\"style.css\" rel=\"alternate stylesheet\" title=\"Book styles\"/>");
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::fs::{File, create_dir_all, read_dir, remove_dir_all, remove_file};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{Cache, CachedBook, TEMPORARY_DIR_INFIX, log, publish_book_dir, unique_dirname};
+
+// Backups for moving a library between machines: a zip of the books' directories under books/, with their index
+// entries in a manifest. Only books in the index go in, so the shelf, the history, and leftover temporary dirs stay
+// behind.
+
+const MANIFEST_NAME: &str = "rib_library.json";
+const BOOKS_DIR_NAME: &str = "books";
+
+#[derive(Deserialize, Serialize)]
+struct ArchivedBook {
+    entry: CachedBook,
+    // What the book's files add up to, so a restored copy can be checked for anything lost on the way.
+    archived_bytes: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Manifest {
+    rib_version: String,
+    books: Vec<ArchivedBook>,
+}
+
+pub struct ImportSummary {
+    pub imported_ids: Vec<String>,
+    // Books the library already has a copy of that was opened at least as recently.
+    pub kept_ids: Vec<String>,
+    pub failures: Vec<(String, String)>,
+}
+
+fn add_book_dir(zip: &mut ZipWriter<File>, book_dir: &Path, dir: &Path, archive_dir: &str, options: SimpleFileOptions) -> Result<u64, String> {
+    let mut entry_paths = read_dir(dir).and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect::<std::io::Result<Vec<PathBuf>>>()).map_err(|e| format!("Couldn't read {}: {}", dir.display(), e))?;
+    entry_paths.sort();
+    let mut archived_bytes = 0;
+    for entry_path in entry_paths {
+        // Extraction never writes symlinks, so one in a book dir was put there by something else, and could point anywhere.
+        if entry_path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            log::warning!("Skipping symlink {} in the library.", entry_path.display());
+            continue
+        }
+        if entry_path.is_dir() {
+            archived_bytes += add_book_dir(zip, book_dir, &entry_path, archive_dir, options)?;
+            continue
+        }
+        // Zip entry names always use forward slashes, whatever the platform's separator.
+        let relative_name = entry_path.strip_prefix(book_dir).unwrap_or(&entry_path).components().map(|component| component.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+        zip.start_file(format!("{}/{}", archive_dir, relative_name), options).map_err(|e| format!("Couldn't add {} to the backup: {}", entry_path.display(), e))?;
+        let mut file = File::open(&entry_path).map_err(|e| format!("Couldn't read {}: {}", entry_path.display(), e))?;
+        archived_bytes += std::io::copy(&mut file, zip).map_err(|e| format!("Couldn't add {} to the backup: {}", entry_path.display(), e))?;
+    }
+    Ok(archived_bytes)
+}
+
+// Extensions of other archive formats, which a zip written under them would be mistaken for.
+const OTHER_ARCHIVE_EXTENSIONS: [&str; 7] = [".tar", ".tar.zst", ".tzst", ".zst", ".tar.gz", ".tgz", ".tar.xz"];
+
+pub fn export(cache: &Cache, ids: &[String], archive_path: &Path) -> Result<usize, String> {
+    let archive_name = archive_path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+    if let Some(extension) = OTHER_ARCHIVE_EXTENSIONS.iter().find(|extension| archive_name.ends_with(**extension)) {
+        return Err(format!("Backups are zip archives, so {} would be misnamed with {}. Name it with .zip instead.", archive_path.display(), extension))
+    }
+    let cached_books = cache.contents.iter().filter(|cached_book| ids.is_empty() || ids.contains(&cached_book.id)).collect::<Vec<&CachedBook>>();
+    let file = File::create(archive_path).map_err(|e| format!("Couldn't create {}: {}", archive_path.display(), e))?;
+    let mut zip = ZipWriter::new(file);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated).large_file(true);
+    let mut manifest = Manifest {
+        rib_version: String::from(env!("CARGO_PKG_VERSION")),
+        books: Vec::new(),
+    };
+    let written = (|| {
+        for (position, cached_book) in cached_books.iter().enumerate() {
+            let book_dir = cache.book_dir(cached_book);
+            if !book_dir.is_dir() {
+                log::warning!("Leaving {} out of the backup, since its directory {} is missing.", cached_book.id, book_dir.display());
+                continue
+            }
+            let archive_dir = format!("{}/{}", BOOKS_DIR_NAME, cached_book.path.to_string_lossy().replace('\\', "/"));
+            let archived_bytes = add_book_dir(&mut zip, &book_dir, &book_dir, &archive_dir, options)?;
+            log::info!("[{}/{}] Backed up {}", position + 1, cached_books.len(), cached_book.id);
+            manifest.books.push(ArchivedBook {
+                entry: (*cached_book).clone(),
+                archived_bytes,
+            });
+        }
+        zip.start_file(MANIFEST_NAME, options).map_err(|e| format!("Couldn't add the manifest to the backup: {}", e))?;
+        serde_json::to_writer_pretty(&mut zip, &manifest).map_err(|e| format!("Couldn't add the manifest to the backup: {}", e))?;
+        zip.finish().map_err(|e| format!("Couldn't finish writing the backup: {}", e))?;
+        Ok(manifest.books.len())
+    })();
+    // A backup that stopped partway would look complete to anyone who didn't see the error.
+    if written.is_err() {
+        let _ = remove_file(archive_path);
+    }
+    written
+}
+
+fn extract_book(archive: &mut ZipArchive<BufReader<File>>, entry_indexes: &[usize], archive_dir: &Path, book_dir: &Path) -> Result<u64, String> {
+    let mut extracted_bytes = 0;
+    for &index in entry_indexes {
+        let mut entry = archive.by_index(index).map_err(|e| format!("Couldn't read the backup: {}", e))?;
+        // Guards against zip-slip, as for books' own resources.
+        let Some(relative_path) = entry.enclosed_name().and_then(|path| path.strip_prefix(archive_dir).ok().map(Path::to_path_buf)) else {
+            return Err(format!("The backup's entry {} points outside the book's directory.", entry.name()))
+        };
+        if entry.is_dir() {
+            continue
+        }
+        let target_path = book_dir.join(&relative_path);
+        if let Some(target_dir) = target_path.parent() {
+            create_dir_all(target_dir).map_err(|e| format!("Couldn't create {}: {}", target_dir.display(), e))?;
+        }
+        let mut file = File::create(&target_path).map_err(|e| format!("Couldn't create {}: {}", target_path.display(), e))?;
+        extracted_bytes += std::io::copy(&mut entry, &mut file).map_err(|e| format!("Couldn't extract {}: {}", relative_path.display(), e))?;
+    }
+    Ok(extracted_bytes)
+}
+
+pub fn import(cache: &mut Cache, archive_path: &Path, ids: &[String]) -> Result<ImportSummary, String> {
+    let file = File::open(archive_path).map_err(|e| format!("Couldn't open {}: {}", archive_path.display(), e))?;
+    let mut archive = ZipArchive::new(BufReader::new(file)).map_err(|e| format!("{} isn't a library backup: {}", archive_path.display(), e))?;
+    let manifest: Manifest = {
+        let manifest_entry = archive.by_name(MANIFEST_NAME).map_err(|_| format!("{} isn't a library backup: it has no {}.", archive_path.display(), MANIFEST_NAME))?;
+        serde_json::from_reader(manifest_entry).map_err(|e| format!("The backup's {} is invalid: {}", MANIFEST_NAME, e))?
+    };
+    if let Some(missing_id) = ids.iter().find(|id| !manifest.books.iter().any(|archived_book| archived_book.entry.id == **id)) {
+        return Err(format!("No book with id {} is in the backup.", missing_id))
+    }
+
+    // Entries are grouped by book up front, rather than the whole archive being scanned once per book.
+    let mut entry_indexes_by_dir = HashMap::<String, Vec<usize>>::new();
+    for index in 0..archive.len() {
+        let Some(name) = archive.name_for_index(index) else {
+            continue
+        };
+        if let Some(book_dirname) = name.strip_prefix(&format!("{}/", BOOKS_DIR_NAME)).and_then(|rest| rest.split('/').next()) {
+            entry_indexes_by_dir.entry(book_dirname.to_string()).or_default().push(index);
+        }
+    }
+
+    let mut summary = ImportSummary {
+        imported_ids: Vec::new(),
+        kept_ids: Vec::new(),
+        failures: Vec::new(),
+    };
+    let archived_books = manifest.books.into_iter().filter(|archived_book| ids.is_empty() || ids.contains(&archived_book.entry.id)).collect::<Vec<ArchivedBook>>();
+    let book_count = archived_books.len();
+    for (position, archived_book) in archived_books.into_iter().enumerate() {
+        let ArchivedBook { mut entry, archived_bytes } = archived_book;
+        // Of two copies of a book, the one opened more recently is the one being read.
+        let local_dirname = match cache.get(&entry.id) {
+            Some(local_book) if cache.book_dir(local_book).is_dir() && local_book.last_opened_time >= entry.last_opened_time => {
+                summary.kept_ids.push(entry.id);
+                continue
+            },
+            Some(local_book) => local_book.path.file_name().map(|dirname| dirname.to_string_lossy().into_owned()),
+            None => None,
+        };
+        // The archived book's dirname may already belong to another book here, so it's only a starting point.
+        let dirname = local_dirname.unwrap_or_else(|| unique_dirname(&entry.id, |dirname| cache.contains_dirname(dirname) || cache.root().join(dirname).exists()));
+        let archive_dirname = entry.path.to_string_lossy().replace('\\', "/");
+        let archive_dir = Path::new(BOOKS_DIR_NAME).join(&archive_dirname);
+        let book_dir = cache.root().join(&dirname);
+        let temporary_dir = cache.root().join(format!("{}{}{}", dirname, TEMPORARY_DIR_INFIX, std::process::id()));
+        let _ = remove_dir_all(&temporary_dir);
+        let restored = extract_book(&mut archive, entry_indexes_by_dir.get(&archive_dirname).map(Vec::as_slice).unwrap_or_default(), &archive_dir, &temporary_dir)
+            .and_then(|extracted_bytes| match extracted_bytes == archived_bytes {
+                true => Ok(()),
+                false => Err(format!("Restored {} bytes of files where the backup recorded {}.", extracted_bytes, archived_bytes)),
+            })
+            .and_then(|()| publish_book_dir(&temporary_dir, &book_dir));
+        if let Err(e) = restored {
+            let _ = remove_dir_all(&temporary_dir);
+            summary.failures.push((entry.id, e));
+            continue
+        }
+        log::info!("[{}/{}] Restored {}", position + 1, book_count, entry.id);
+        entry.path = PathBuf::from(dirname);
+        summary.imported_ids.push(entry.id.clone());
+        cache.restore(entry);
+    }
+    Ok(summary)
+}
@@ -0,0 +1,123 @@
+use std::collections::HashSet;
+use std::fs::{read, read_dir};
+use std::path::{Path, PathBuf};
+
+use percent_encoding::percent_decode_str;
+use quick_xml::events::Event;
+
+use crate::{Cache, CachedBook, contained_resource_path, new_strict_xhtml_reader};
+
+// Checks for 'library verify' that a book's copy in the library still holds what its entry and index say it does,
+// for after a bad sync or a disk failure. Nothing here needs the book's source, so it works on books imported from
+// stdin or from files since moved.
+
+// Extensions of the extracted pages that are written out as XML.
+const XML_EXTENSIONS: [&str; 4] = ["xhtml", "html", "htm", "svg"];
+
+fn index_links(index: &[u8]) -> Vec<String> {
+    // The index is written as HTML, whose void elements never close, so end tags aren't matched up.
+    let mut reader = quick_xml::Reader::from_reader(index);
+    reader.config_mut().check_end_names = false;
+    let mut links = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e) | Event::Empty(e)) => {
+                for attribute in e.attributes().flatten().filter(|attribute| matches!(attribute.key.as_ref(), b"href" | b"src")) {
+                    links.push(attribute.unescape_value().map(|value| value.into_owned()).unwrap_or_default());
+                }
+            },
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(_) => (),
+        }
+    }
+    links
+}
+
+// The files the index links to, relative to the book's directory. Links the index makes outside the book, which it
+// never writes itself, are left to the browser.
+fn linked_paths(index: &[u8]) -> Vec<PathBuf> {
+    let mut seen_paths = HashSet::new();
+    index_links(index).into_iter().filter_map(|link| {
+        let link_path = link.split(['?', '#']).next().unwrap_or_default();
+        let first_segment = link_path.split('/').next().unwrap_or_default();
+        if link_path.is_empty() || link_path.starts_with('/') || first_segment.contains(':') {
+            return None
+        }
+        contained_resource_path(Path::new(percent_decode_str(link_path).decode_utf8_lossy().as_ref()))
+    }).filter(|path| seen_paths.insert(path.clone())).collect()
+}
+
+fn dir_bytes(dir: &Path) -> Result<u64, String> {
+    let mut bytes = 0;
+    for entry in read_dir(dir).map_err(|e| format!("Couldn't read {}: {}", dir.display(), e))? {
+        let entry_path = entry.map_err(|e| format!("Couldn't read {}: {}", dir.display(), e))?.path();
+        bytes += match entry_path.is_dir() {
+            true => dir_bytes(&entry_path)?,
+            false => entry_path.metadata().map_err(|e| format!("Couldn't read {}: {}", entry_path.display(), e))?.len(),
+        };
+    }
+    Ok(bytes)
+}
+
+fn xml_error(document: &[u8]) -> Option<String> {
+    let mut reader = new_strict_xhtml_reader(document);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) => return None,
+            Ok(_) => (),
+            Err(e) => return Some(format!("at byte {}: {}", reader.error_position(), e)),
+        }
+    }
+}
+
+// The problems found with the book, if any. The deep checks read every page the index links to, and add up every file
+// the book's extraction wrote.
+pub fn verify_book(cache: &Cache, cached_book: &CachedBook, deep: bool) -> Vec<String> {
+    let book_dir = cache.book_dir(cached_book);
+    if !book_dir.is_dir() {
+        return vec![format!("Its directory {} is missing.", book_dir.display())]
+    }
+    let mut problems = Vec::new();
+    if let Some(cover) = &cached_book.cover {
+        if !book_dir.join(cover).is_file() {
+            problems.push(format!("Its cover {} is missing.", cover.display()));
+        }
+    }
+    let index_path = book_dir.join("index.html");
+    let index = match read(&index_path) {
+        Ok(index) => index,
+        Err(e) => {
+            problems.push(format!("Its index {} can't be read: {}", index_path.display(), e));
+            return problems
+        },
+    };
+    for path in linked_paths(&index) {
+        let linked_path = book_dir.join(&path);
+        if !linked_path.is_file() {
+            problems.push(format!("{}, linked from its index, is missing.", path.display()));
+            continue
+        }
+        if deep && linked_path.extension().is_some_and(|extension| XML_EXTENSIONS.iter().any(|xml_extension| extension.eq_ignore_ascii_case(xml_extension))) {
+            match read(&linked_path) {
+                Ok(document) => if let Some(e) = xml_error(&document) {
+                    problems.push(format!("{} doesn't parse as XML, {}.", path.display(), e));
+                },
+                Err(e) => problems.push(format!("{} can't be read: {}", path.display(), e)),
+            }
+        }
+    }
+    if deep {
+        // The entry records the bytes of the book's own files and the styles written for them, not the index rib adds.
+        let extracted_bytes = ["epub", "styles"].iter()
+            .map(|dirname| book_dir.join(dirname))
+            .filter(|dir| dir.is_dir())
+            .map(|dir| dir_bytes(&dir))
+            .sum::<Result<u64, String>>();
+        match extracted_bytes {
+            Ok(extracted_bytes) if extracted_bytes != cached_book.bytes as u64 => problems.push(format!("Its files add up to {} bytes where {} were recorded.", extracted_bytes, cached_book.bytes)),
+            Ok(_) => (),
+            Err(e) => problems.push(e),
+        }
+    }
+    problems
+}
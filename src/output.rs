@@ -0,0 +1,76 @@
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::Value;
+
+// What `--format json` prints: one object per run, holding the command's result, any warnings, and on failure an error
+// whose code says what kind of failure it was, so scripts can act on it without matching messages. Plain output is
+// printed by each command as it goes, and never passes through here.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    // The arguments conflict, or name a command that has no JSON output.
+    Usage,
+    // The config file can't be read or loaded.
+    Config,
+    // A book path, or a library book selector, that matches nothing.
+    NotFound,
+    // A file that can't be read or written.
+    Io,
+    // A file that isn't a well-formed epub.
+    EpubParse,
+    Other,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CodedError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl CodedError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+// Errors that haven't been given a code yet are passed along as they are.
+impl From<String> for CodedError {
+    fn from(message: String) -> Self {
+        Self::new(ErrorCode::Other, message)
+    }
+}
+
+impl From<&str> for CodedError {
+    fn from(message: &str) -> Self {
+        Self::new(ErrorCode::Other, message)
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    ok: bool,
+    result: &'a Value,
+    warnings: &'a [Value],
+    error: Option<&'a CodedError>,
+}
+
+pub fn print_envelope(result: &Value, warnings: &[Value], error: Option<&CodedError>) {
+    let envelope = Envelope {
+        ok: error.is_none(),
+        result,
+        warnings,
+        error,
+    };
+    println!("{}", serde_json::to_string_pretty(&envelope).unwrap());
+}
@@ -0,0 +1,67 @@
+use std::borrow::Cow;
+use std::path::{Component, Path, PathBuf};
+
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+
+// Books are extracted under names every system accepts, even where their own names are legal, so a library synced between machines keeps working.
+// The renaming is a pure function of the name, so hrefs can be mapped the same way without keeping a table.
+
+const SEGMENT_ESCAPES: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}').add(b'/');
+
+fn is_windows_device_name(name: &str) -> bool {
+    // Windows reserves these with any extension, and ignores spaces before the extension when checking.
+    let stem = name.split('.').next().unwrap_or_default().trim_end_matches(' ').to_ascii_uppercase();
+    match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => stem.len() == 4 && (stem.starts_with("COM") || stem.starts_with("LPT")) && stem.ends_with(|character: char| character.is_ascii_digit()),
+    }
+}
+
+pub fn portable_file_name(name: &str) -> Cow<'_, str> {
+    if matches!(name, "" | "." | "..") {
+        return Cow::Borrowed(name)
+    }
+    let is_invalid = |character: char| character.is_control() || matches!(character, '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*');
+    let has_trailing_dot_or_space = name.ends_with(['.', ' ']);
+    if !name.contains(is_invalid) && !has_trailing_dot_or_space && !is_windows_device_name(name) {
+        return Cow::Borrowed(name)
+    }
+
+    let mut portable_name = name.replace(is_invalid, "_");
+    // Windows silently drops trailing dots and spaces, so they're replaced rather than trimmed to keep `a.` and `a` apart.
+    let kept_length = portable_name.trim_end_matches(['.', ' ']).len();
+    let trailing_length = portable_name.len() - kept_length;
+    portable_name.replace_range(kept_length.., &"_".repeat(trailing_length));
+    if is_windows_device_name(&portable_name) {
+        portable_name.insert(0, '_');
+    }
+    Cow::Owned(portable_name)
+}
+
+pub fn encode_href_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, SEGMENT_ESCAPES).to_string()
+}
+
+pub fn portable_resource_path(path: &Path) -> PathBuf {
+    path.components().map(|component| match component {
+        Component::Normal(name) => PathBuf::from(portable_file_name(&name.to_string_lossy()).as_ref()),
+        other => PathBuf::from(other.as_os_str()),
+    }).collect()
+}
+
+pub fn portable_href(href: &str) -> String {
+    // Absolute and external URLs don't point into the book, so only relative paths are mapped; queries and fragments pass through.
+    let first_segment = href.split(['/', '?', '#']).next().unwrap_or_default();
+    if href.starts_with('/') || first_segment.contains(':') {
+        return href.to_string()
+    }
+    let (path, suffix) = href.split_at(href.find(['?', '#']).unwrap_or(href.len()));
+    let portable_path = path.split('/').map(|segment| {
+        let decoded_segment = percent_decode_str(segment).decode_utf8_lossy();
+        match portable_file_name(&decoded_segment) {
+            Cow::Borrowed(_) => segment.to_string(),
+            Cow::Owned(portable_segment) => encode_href_segment(&portable_segment),
+        }
+    }).collect::<Vec<String>>().join("/");
+    portable_path + suffix
+}
@@ -0,0 +1,211 @@
+use std::fs::{read, read_dir};
+use std::path::{Path, PathBuf};
+
+use quick_xml::events::{BytesStart, Event};
+
+use crate::helpers::natural_cmp;
+
+// Searching the text of extracted books, one document at a time so matches can be shown as they're found, and counting
+// their words for the index's reading times.
+
+const CONTEXT_LENGTH: usize = 40;
+const DOCUMENT_EXTENSIONS: [&str; 3] = ["xhtml", "html", "htm"];
+// Elements that break the text around them, so words either side of one aren't run together.
+const BLOCK_ELEMENTS: [&str; 22] = ["address", "article", "aside", "blockquote", "br", "dd", "div", "dt", "figcaption", "footer", "h1", "h2", "h3", "h4", "h5", "h6", "header", "hr", "li", "p", "section", "td"];
+// Markup whose text isn't the book's: rib's own navigation sits in a template, and scripts and styles aren't read.
+const SKIPPED_ELEMENTS: [&str; 3] = ["template", "script", "style"];
+
+pub struct DocumentMatch {
+    // Of the nearest element with an id before the match, so a browser can be sent close to it.
+    pub anchor: Option<String>,
+    pub snippet: String,
+}
+
+pub struct DocumentMatches {
+    pub title: Option<String>,
+    pub matches: Vec<DocumentMatch>,
+}
+
+#[derive(Default)]
+struct DocumentText {
+    title: Option<String>,
+    text: Vec<char>,
+    anchors: Vec<(usize, String)>,
+}
+
+impl DocumentText {
+    fn push_text(&mut self, text: &str) {
+        // Whitespace is collapsed, so a query matches however the book happened to wrap its lines.
+        for character in text.chars() {
+            match character.is_whitespace() {
+                true => self.push_break(),
+                false => self.text.push(character),
+            }
+        }
+    }
+
+    fn push_break(&mut self) {
+        if self.text.last().is_some_and(|last| *last != ' ') {
+            self.text.push(' ');
+        }
+    }
+
+    fn anchor_at(&self, position: usize) -> Option<String> {
+        self.anchors.iter().rev().find(|(anchor_position, _id)| *anchor_position <= position).map(|(_position, id)| id.clone())
+    }
+}
+
+fn local_name(element: &BytesStart) -> String {
+    String::from_utf8_lossy(element.local_name().as_ref()).to_lowercase()
+}
+
+fn extract_text(xhtml: &[u8]) -> DocumentText {
+    let mut reader = quick_xml::Reader::from_reader(xhtml);
+    reader.config_mut().check_end_names = false;
+    let mut document = DocumentText::default();
+    let mut in_body = false;
+    let mut in_title = false;
+    let mut skipped_depth = 0;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => {
+                let name = local_name(&e);
+                match name.as_str() {
+                    _ if skipped_depth > 0 || SKIPPED_ELEMENTS.contains(&name.as_str()) => skipped_depth += 1,
+                    "body" => in_body = true,
+                    "title" if !in_body => in_title = true,
+                    _ => (),
+                }
+                if in_body && skipped_depth == 0 {
+                    if let Some(id) = e.try_get_attribute("id").ok().flatten() {
+                        document.anchors.push((document.text.len(), String::from_utf8_lossy(&id.value).into_owned()));
+                    }
+                    if BLOCK_ELEMENTS.contains(&name.as_str()) {
+                        document.push_break();
+                    }
+                }
+            },
+            Ok(Event::Empty(e)) if in_body && skipped_depth == 0 => {
+                if let Some(id) = e.try_get_attribute("id").ok().flatten() {
+                    document.anchors.push((document.text.len(), String::from_utf8_lossy(&id.value).into_owned()));
+                }
+                if BLOCK_ELEMENTS.contains(&local_name(&e).as_str()) {
+                    document.push_break();
+                }
+            },
+            Ok(Event::End(e)) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).to_lowercase();
+                match name.as_str() {
+                    _ if skipped_depth > 0 => skipped_depth -= 1,
+                    "body" => in_body = false,
+                    "title" => in_title = false,
+                    _ if in_body && BLOCK_ELEMENTS.contains(&name.as_str()) => document.push_break(),
+                    _ => (),
+                }
+            },
+            Ok(Event::Text(e)) if skipped_depth == 0 && (in_body || in_title) => {
+                let text = e.unescape().map(|text| text.into_owned()).unwrap_or_else(|_| String::from_utf8_lossy(&e).into_owned());
+                match in_title {
+                    true => document.title.get_or_insert_with(String::new).push_str(text.trim()),
+                    false => document.push_text(&text),
+                }
+            },
+            Ok(Event::CData(e)) if skipped_depth == 0 && in_body => document.push_text(&String::from_utf8_lossy(&e)),
+            // A document that stops parsing partway is still searched up to there.
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(_) => (),
+        }
+    }
+    document.title = document.title.filter(|title| !title.is_empty());
+    document
+}
+
+fn lowercase_char(character: char) -> char {
+    // Matching char for char keeps match positions in step with the original text, at the cost of the few letters whose lowercase is longer.
+    character.to_lowercase().next().unwrap_or(character)
+}
+
+fn snippet(text: &[char], start: usize, end: usize) -> String {
+    let context_start = start.saturating_sub(CONTEXT_LENGTH);
+    let context_end = (end + CONTEXT_LENGTH).min(text.len());
+    let mut snippet = text[context_start..context_end].iter().collect::<String>().trim().to_string();
+    if context_start > 0 {
+        snippet.insert(0, '…');
+    }
+    if context_end < text.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+fn is_unspaced_script(character: char) -> bool {
+    // Hiragana, katakana, and CJK ideographs, which are written without spaces between words.
+    matches!(character, '\u{3040}'..='\u{30FF}' | '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' | '\u{F900}'..='\u{FAFF}' | '\u{20000}'..='\u{2FA1F}')
+}
+
+pub fn count_words(xhtml: &[u8]) -> usize {
+    // Text without spaces between words is counted a character to a word, which is read at about the same pace.
+    let mut word_count = 0;
+    let mut in_word = false;
+    for character in extract_text(xhtml).text {
+        if is_unspaced_script(character) {
+            word_count += 1;
+            in_word = false;
+        } else if character.is_alphanumeric() {
+            if !in_word {
+                word_count += 1;
+                in_word = true;
+            }
+        } else if character.is_whitespace() {
+            in_word = false;
+        }
+    }
+    word_count
+}
+
+pub fn normalize_query(query: &str) -> Vec<char> {
+    query.split_whitespace().collect::<Vec<&str>>().join(" ").chars().map(lowercase_char).collect()
+}
+
+pub fn search_document(path: &Path, query: &[char]) -> Result<DocumentMatches, String> {
+    let xhtml = read(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    let document = extract_text(&xhtml);
+    let lowercase_text = document.text.iter().copied().map(lowercase_char).collect::<Vec<char>>();
+    let mut matches = Vec::new();
+    let mut position = 0;
+    while !query.is_empty() && position + query.len() <= lowercase_text.len() {
+        if lowercase_text[position..position + query.len()] == *query {
+            matches.push(DocumentMatch {
+                anchor: document.anchor_at(position),
+                snippet: snippet(&document.text, position, position + query.len()),
+            });
+            position += query.len();
+        } else {
+            position += 1;
+        }
+    }
+    Ok(DocumentMatches {
+        title: document.title,
+        matches,
+    })
+}
+
+pub fn searchable_documents(contents_dir: &Path) -> Vec<PathBuf> {
+    // Documents are searched in order of their paths, numbers compared by value, which follows the reading order of most books.
+    fn collect(dir: &Path, documents: &mut Vec<PathBuf>) {
+        let Ok(entries) = read_dir(dir) else {
+            return
+        };
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if path.is_dir() {
+                collect(&path, documents);
+            } else if path.extension().is_some_and(|extension| DOCUMENT_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str())) {
+                documents.push(path);
+            }
+        }
+    }
+    let mut documents = Vec::new();
+    collect(contents_dir, &mut documents);
+    documents.sort_by(|first, second| natural_cmp(&first.to_string_lossy(), &second.to_string_lossy()));
+    documents
+}
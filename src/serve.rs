@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fs::{canonicalize, read};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+
+use crate::log;
+
+const PATH_ESCAPES: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}');
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Served outside any book's mount, since no book dir is named with a leading dot.
+const VERSION_PATH: &str = "/.rib/version";
+const LIVE_RELOAD_PATH: &str = "/.rib/live-reload.js";
+// Pages reload once the version moves past the one they first saw. The script is loaded from the server rather than
+// written inline, so the relaxed content security policy lets it run; the strict one allows no scripts at all.
+const LIVE_RELOAD_SCRIPT: &str = r#"(function () {
+    var version = null;
+    setInterval(function () {
+        fetch("/.rib/version", { cache: "no-store" }).then(function (response) {
+            return response.text();
+        }).then(function (text) {
+            if (version !== null && text !== version) {
+                location.reload();
+            }
+            version = text;
+        }).catch(function () {});
+    }, 1000);
+})();
+"#;
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "xhtml" => "application/xhtml+xml",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "opf" => "application/oebps-package+xml",
+        "ncx" => "application/x-dtbncx+xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "txt" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+fn inject_live_reload(mut body: Vec<u8>) -> Vec<u8> {
+    // Pages without a closing body tag are served as they are, since anything added after their root element would make XHTML ill-formed.
+    let Some(position) = body.windows(b"</body>".len()).rposition(|window| window.eq_ignore_ascii_case(b"</body>")) else {
+        return body
+    };
+    let script_tag = format!("<script src=\"{}\"></script>", LIVE_RELOAD_PATH);
+    body.splice(position..position, script_tag.into_bytes());
+    body
+}
+
+// Serves each mounted book's rendition dir under its own prefix, and nothing else, so the rest of the library stays private.
+pub struct Server {
+    listener: TcpListener,
+    port: u16,
+    mounts: HashMap<String, PathBuf>,
+    live_reload: bool,
+    // Bumped whenever a mounted book is extracted again, for live-reloading pages to notice.
+    version: AtomicU64,
+}
+
+impl Server {
+    pub fn bind(port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).map_err(|e| format!("Couldn't listen on port {}: {}", port, e))?;
+        let port = listener.local_addr().map_err(|e| format!("Couldn't read the server's address: {}", e))?.port();
+        Ok(Self {
+            listener,
+            port,
+            mounts: HashMap::new(),
+            live_reload: false,
+            version: AtomicU64::new(0),
+        })
+    }
+
+    pub fn enable_live_reload(&mut self) {
+        self.live_reload = true;
+    }
+
+    pub fn bump_version(&self) {
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mounts.is_empty()
+    }
+
+    pub fn mount(&mut self, name: &str, root: &Path, relative_entry: &Path, fragment: Option<&str>) -> Result<String, String> {
+        let root = canonicalize(root).map_err(|e| format!("Couldn't resolve {}: {}", root.display(), e))?;
+        let mut url = format!("http://{}:{}/{}", Ipv4Addr::LOCALHOST, self.port, utf8_percent_encode(name, PATH_ESCAPES));
+        for component in relative_entry.components() {
+            url.push('/');
+            url.push_str(&utf8_percent_encode(&component.as_os_str().to_string_lossy(), PATH_ESCAPES).to_string());
+        }
+        if let Some(fragment) = fragment {
+            url.push('#');
+            url.push_str(fragment);
+        }
+        self.mounts.insert(name.to_string(), root);
+        Ok(url)
+    }
+
+    fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let request_path = request_path.split(['?', '#']).next().unwrap_or_default();
+        let request_path = percent_decode_str(request_path).decode_utf8().ok()?;
+        let mut segments = request_path.trim_start_matches('/').splitn(2, '/');
+        let root = self.mounts.get(segments.next()?)?;
+        let relative_path = Path::new(segments.next().unwrap_or_default());
+        // Anything but plain names could climb out of the book's dir.
+        if !relative_path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+            return None
+        }
+        let mut path = root.join(relative_path);
+        if path.is_dir() {
+            path = path.join("index.html");
+        }
+        // Symlinks inside the book could still point elsewhere, so check where the path really leads.
+        canonicalize(&path).ok().filter(|path| path.starts_with(root))
+    }
+
+    fn respond(&self, stream: &mut TcpStream) -> std::io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // Headers aren't needed, but they have to be read before the response, or some clients see a reset.
+        let mut header_line = String::new();
+        while reader.read_line(&mut header_line)? > 0 && !header_line.trim().is_empty() {
+            header_line.clear();
+        }
+
+        let mut request_words = request_line.split_whitespace();
+        let (method, request_path) = (request_words.next().unwrap_or_default(), request_words.next().unwrap_or_default());
+        let (status, content_type, body) = match method {
+            "GET" | "HEAD" if self.live_reload && request_path == VERSION_PATH => ("200 OK", "text/plain; charset=utf-8", self.version.load(Ordering::Relaxed).to_string().into_bytes()),
+            "GET" | "HEAD" if self.live_reload && request_path == LIVE_RELOAD_PATH => ("200 OK", "text/javascript", LIVE_RELOAD_SCRIPT.as_bytes().to_vec()),
+            "GET" | "HEAD" => match self.resolve(request_path).and_then(|path| read(&path).ok().map(|body| (path, body))) {
+                Some((path, body)) => {
+                    let content_type = content_type(&path);
+                    match self.live_reload && matches!(content_type, "text/html; charset=utf-8" | "application/xhtml+xml") {
+                        true => ("200 OK", content_type, inject_live_reload(body)),
+                        false => ("200 OK", content_type, body),
+                    }
+                },
+                None => ("404 Not Found", "text/plain; charset=utf-8", b"Not found".to_vec()),
+            },
+            _ => ("405 Method Not Allowed", "text/plain; charset=utf-8", b"Method not allowed".to_vec()),
+        };
+        log::debug!("{} {} -> {}", method, request_path, status);
+
+        write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, content_type, body.len())?;
+        if method != "HEAD" {
+            stream.write_all(&body)?;
+        }
+        stream.flush()
+    }
+
+    pub fn run(&self, idle_timeout: Option<Duration>) -> Result<(), String> {
+        // Polling lets the idle timeout fire even while no connection arrives.
+        self.listener.set_nonblocking(true).map_err(|e| format!("Couldn't configure the server: {}", e))?;
+        let mut last_request = Instant::now();
+        loop {
+            match self.listener.accept() {
+                Ok((mut stream, _address)) => {
+                    last_request = Instant::now();
+                    if let Err(e) = self.respond(&mut stream) {
+                        log::debug!("Couldn't answer a request: {}", e);
+                    }
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => match idle_timeout {
+                    Some(idle_timeout) if last_request.elapsed() >= idle_timeout => {
+                        log::info!("No requests for {} seconds; stopping the server.", idle_timeout.as_secs());
+                        return Ok(())
+                    },
+                    _ => std::thread::sleep(POLL_INTERVAL),
+                },
+                Err(e) => return Err(format!("Server stopped: {}", e)),
+            }
+        }
+    }
+}
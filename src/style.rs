@@ -0,0 +1,220 @@
+use crate::cli::Args;
+use crate::{Config, StyleMaxImageHeight, StyleMaxImageWidth, Stylesheet, log};
+
+// Every way of opening a book picks its styling here, so there's one order to know:
+//
+//   1. --stylesheet, when given.
+//   2. The stylesheet chosen for the book with 'library style set', unless --ignore-preferred is given, or it's since
+//      been removed from config, which is warned about.
+//   3. default_stylesheet in config.
+//   4. No stylesheet: the book is shown unstyled.
+//
+// Exactly one of these applies; stylesheets replace rather than merge with each other. --no-book-css,
+// --strip-inline-styles, and --force-styles are then switched on over whichever one it is, since they only ever add
+// stripping.
+//
+// Image size caps are the exception, layered per cap: default_max_image_height and default_max_image_width in config
+// fill in for a stylesheet that sets no cap of its own, and --max-image-height and --max-image-width replace either.
+
+// Which of the stylesheets that could apply to a book was used.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StylesheetChoice {
+    Flag,
+    Preferred,
+    Default,
+}
+
+impl StylesheetChoice {
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Flag => "--stylesheet",
+            Self::Preferred => "chosen for the book with 'library style set'",
+            Self::Default => "default_stylesheet in config",
+        }
+    }
+}
+
+// The name of the stylesheet used, and how it was chosen, or nothing when books are shown unstyled.
+pub type ChosenStylesheet<'a> = Option<(&'a str, StylesheetChoice)>;
+
+pub struct ResolvedStyle<'a> {
+    pub chosen: ChosenStylesheet<'a>,
+    // With the command-line flags applied.
+    pub stylesheet: Stylesheet,
+}
+
+// The preferred stylesheet is the book's, if it's in the library and has one.
+pub fn resolve_style<'a>(args: &'a Args, config: &'a Config, preferred: Option<&'a str>) -> Result<ResolvedStyle<'a>, String> {
+    let preferred = preferred.filter(|_| !args.ignore_preferred);
+    let mut chosen = match (args.stylesheet.as_deref(), preferred) {
+        (Some(requested), _) => Some((requested, StylesheetChoice::Flag)),
+        (None, Some(preferred)) => Some((preferred, StylesheetChoice::Preferred)),
+        (None, None) => None,
+    };
+    if let Some((preferred, StylesheetChoice::Preferred)) = chosen {
+        // A stylesheet since removed from config shouldn't stop the book opening.
+        if let Err(e) = config.get_stylesheet(Some(preferred)) {
+            log::warning!("{} Using the default stylesheet instead of the one chosen for this book.", e);
+            chosen = None;
+        }
+    }
+    let chosen = chosen.or_else(|| config.default_stylesheet.as_deref().map(|default_stylesheet| (default_stylesheet, StylesheetChoice::Default)));
+    let mut stylesheet = config.get_stylesheet(chosen.map(|(name, _choice)| name))?;
+    stylesheet.strip_book_css |= args.no_book_css;
+    stylesheet.strip_inline_styles |= args.strip_inline_styles;
+    stylesheet.force_styles |= args.force_styles;
+    if let Some(max_image_height) = args.max_image_height {
+        stylesheet.max_image_height = Some(StyleMaxImageHeight {
+            value: i64::from(max_image_height.get()),
+            override_book: true,
+        });
+    }
+    if let Some(max_image_width) = args.max_image_width {
+        stylesheet.max_image_width = Some(StyleMaxImageWidth {
+            value: i64::from(max_image_width.get()),
+            override_book: true,
+        });
+    }
+    Ok(ResolvedStyle {
+        chosen,
+        stylesheet,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use argh::FromArgs;
+
+    use super::*;
+
+    fn parse_args(flags: &[&str]) -> Args {
+        Args::from_args(&["rib"], &[flags, &["book.epub"]].concat()).unwrap()
+    }
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(&format!("max_cache_books = 0\nmax_cache_bytes = 0\n{}", toml)).unwrap()
+    }
+
+    fn image_caps(resolved: &ResolvedStyle) -> (Option<i64>, Option<i64>) {
+        (resolved.stylesheet.max_image_height.map(|cap| cap.value), resolved.stylesheet.max_image_width.map(|cap| cap.value))
+    }
+
+    const IMAGE_CAPS_CONFIG: &str = "
+        default_max_image_height = 1200
+        default_max_image_width = 900
+        default_stylesheet = 'plain'
+        [stylesheets.plain]
+        [stylesheets.tall]
+        max_image_height = { value = 2000, override_book = false }
+    ";
+
+    const CHOICE_CONFIG: &str = "
+        default_stylesheet = 'plain'
+        [stylesheets.plain]
+        [stylesheets.dark]
+        [stylesheets.sepia]
+    ";
+
+    fn chosen(flags: &[&str], config: &Config, preferred: Option<&str>) -> Option<(String, StylesheetChoice)> {
+        let args = parse_args(flags);
+        resolve_style(&args, config, preferred).unwrap().chosen.map(|(name, choice)| (String::from(name), choice))
+    }
+
+    #[test]
+    fn stylesheet_flag_comes_first() {
+        let config = config(CHOICE_CONFIG);
+        assert_eq!(chosen(&["--stylesheet", "dark"], &config, Some("sepia")), Some((String::from("dark"), StylesheetChoice::Flag)));
+        assert_eq!(chosen(&["--stylesheet", "dark", "--ignore-preferred"], &config, Some("sepia")), Some((String::from("dark"), StylesheetChoice::Flag)));
+    }
+
+    #[test]
+    fn preferred_stylesheet_comes_before_the_default() {
+        let config = config(CHOICE_CONFIG);
+        assert_eq!(chosen(&[], &config, Some("sepia")), Some((String::from("sepia"), StylesheetChoice::Preferred)));
+    }
+
+    #[test]
+    fn ignore_preferred_falls_back_to_the_default() {
+        let config = config(CHOICE_CONFIG);
+        assert_eq!(chosen(&["--ignore-preferred"], &config, Some("sepia")), Some((String::from("plain"), StylesheetChoice::Default)));
+    }
+
+    #[test]
+    fn removed_preferred_stylesheet_falls_back_to_the_default() {
+        let config = config(CHOICE_CONFIG);
+        assert_eq!(chosen(&[], &config, Some("removed")), Some((String::from("plain"), StylesheetChoice::Default)));
+    }
+
+    #[test]
+    fn default_stylesheet_applies_without_a_flag_or_preference() {
+        let config = config(CHOICE_CONFIG);
+        assert_eq!(chosen(&[], &config, None), Some((String::from("plain"), StylesheetChoice::Default)));
+    }
+
+    #[test]
+    fn books_are_unstyled_without_any_stylesheet() {
+        let config = config("[stylesheets.dark]");
+        assert_eq!(chosen(&[], &config, None), None);
+        assert_eq!(chosen(&["--ignore-preferred"], &config, Some("dark")), None);
+        assert_eq!(chosen(&[], &config, Some("removed")), None);
+    }
+
+    #[test]
+    fn unknown_stylesheet_flag_is_an_error() {
+        let config = config(CHOICE_CONFIG);
+        let args = parse_args(&["--stylesheet", "removed"]);
+        assert!(resolve_style(&args, &config, Some("sepia")).is_err());
+    }
+
+    #[test]
+    fn stripping_flags_apply_over_the_chosen_stylesheet() {
+        let config = config(CHOICE_CONFIG);
+        let args = parse_args(&["--no-book-css", "--strip-inline-styles"]);
+        let stylesheet = resolve_style(&args, &config, Some("sepia")).unwrap().stylesheet;
+        assert!(stylesheet.strip_book_css);
+        assert!(stylesheet.strip_inline_styles);
+        assert!(!stylesheet.force_styles);
+    }
+
+    #[test]
+    fn image_caps_come_from_config_without_a_stylesheet() {
+        let config = config("default_max_image_height = 1200");
+        let args = parse_args(&[]);
+        let resolved = resolve_style(&args, &config, None).unwrap();
+        assert_eq!(resolved.chosen, None);
+        assert_eq!(image_caps(&resolved), (Some(1200), None));
+        assert!(resolved.stylesheet.max_image_height.unwrap().override_book);
+    }
+
+    #[test]
+    fn image_caps_come_from_config_when_the_stylesheet_sets_none() {
+        let config = config(IMAGE_CAPS_CONFIG);
+        let args = parse_args(&[]);
+        assert_eq!(image_caps(&resolve_style(&args, &config, None).unwrap()), (Some(1200), Some(900)));
+    }
+
+    #[test]
+    fn stylesheet_image_caps_replace_config_defaults() {
+        let config = config(IMAGE_CAPS_CONFIG);
+        let args = parse_args(&[]);
+        let resolved = resolve_style(&args, &config, Some("tall")).unwrap();
+        assert_eq!(image_caps(&resolved), (Some(2000), Some(900)));
+        assert!(!resolved.stylesheet.max_image_height.unwrap().override_book);
+    }
+
+    #[test]
+    fn image_cap_flags_replace_stylesheets_and_config_defaults() {
+        let config = config(IMAGE_CAPS_CONFIG);
+        let args = parse_args(&["--stylesheet", "tall", "--max-image-height", "600"]);
+        let resolved = resolve_style(&args, &config, None).unwrap();
+        assert_eq!(image_caps(&resolved), (Some(600), Some(900)));
+        let width_args = parse_args(&["--max-image-width", "400"]);
+        assert_eq!(image_caps(&resolve_style(&width_args, &config, Some("tall")).unwrap()), (Some(2000), Some(400)));
+    }
+
+    #[test]
+    fn zero_image_caps_are_rejected() {
+        assert!(Args::from_args(&["rib"], &["--max-image-height", "0"]).is_err());
+        assert!(toml::from_str::<Config>("max_cache_books = 0\nmax_cache_bytes = 0\ndefault_max_image_width = 0").is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::cli::Args;
+use crate::config_check::STYLESHEET_KEYS;
+use crate::style::{ChosenStylesheet, StylesheetChoice};
+use crate::{Config, Stylesheet};
+
+// What --explain-style prints: each stylesheet property a book opens with, and which of config, the book's own choice,
+// and the command line it came from. Stylesheets replace each other whole rather than merging, so the ones passed over
+// are listed too, since a value set only in one of those is the usual reason a style doesn't apply.
+
+#[derive(Serialize)]
+struct PassedOver<'a> {
+    name: &'a str,
+    chosen_by: &'static str,
+    defined: bool,
+}
+
+#[derive(Serialize)]
+struct StyleProperty {
+    property: &'static str,
+    value: Value,
+    override_book: Option<bool>,
+    source: String,
+}
+
+#[derive(Serialize)]
+pub struct StyleExplanation<'a> {
+    stylesheet: Option<&'a str>,
+    chosen_by: Option<&'static str>,
+    passed_over: Vec<PassedOver<'a>>,
+    properties: Vec<StyleProperty>,
+}
+
+fn flag_for(property: &str, args: &Args) -> Option<&'static str> {
+    match property {
+        "strip_book_css" if args.no_book_css => Some("--no-book-css"),
+        "strip_inline_styles" if args.strip_inline_styles => Some("--strip-inline-styles"),
+        "force_styles" if args.force_styles => Some("--force-styles"),
+        "max_image_height" if args.max_image_height.is_some() => Some("--max-image-height"),
+        "max_image_width" if args.max_image_width.is_some() => Some("--max-image-width"),
+        _ => None,
+    }
+}
+
+fn config_default_for(property: &str, config: &Config) -> Option<&'static str> {
+    match property {
+        "max_image_height" if config.default_max_image_height.is_some() => Some("default_max_image_height in config"),
+        "max_image_width" if config.default_max_image_width.is_some() => Some("default_max_image_width in config"),
+        _ => None,
+    }
+}
+
+pub fn explain<'a>(config: &'a Config, chosen: ChosenStylesheet<'a>, stylesheet: &Stylesheet, preferred: Option<&'a str>, args: &'a Args) -> StyleExplanation<'a> {
+    let candidates = [(args.stylesheet.as_deref(), StylesheetChoice::Flag), (preferred, StylesheetChoice::Preferred), (config.default_stylesheet.as_deref(), StylesheetChoice::Default)];
+    let mut passed_over = Vec::<PassedOver>::new();
+    for (name, choice) in candidates.into_iter().filter_map(|(name, choice)| name.map(|name| (name, choice))) {
+        if chosen.is_some_and(|(chosen_name, chosen_choice)| chosen_name == name || chosen_choice == choice) || passed_over.iter().any(|passed_over| passed_over.name == name) {
+            continue
+        }
+        passed_over.push(PassedOver {
+            name,
+            chosen_by: match choice {
+                StylesheetChoice::Preferred if args.ignore_preferred => "chosen for the book with 'library style set', but --ignore-preferred was given",
+                choice => choice.describe(),
+            },
+            defined: config.stylesheets.contains_key(name),
+        });
+    }
+
+    // Going through the serialized form covers every property, including any added later, without a match arm per field.
+    let values = serde_json::to_value(stylesheet).unwrap();
+    let defaults = serde_json::to_value(Stylesheet::empty()).unwrap();
+    // The stylesheet as config defines it, before any flags, to tell what it set itself.
+    let chosen_values = chosen.and_then(|(name, _choice)| config.stylesheets.get(name)).map(|chosen_stylesheet| serde_json::to_value(chosen_stylesheet).unwrap()).unwrap_or_else(|| defaults.clone());
+    let properties = STYLESHEET_KEYS.iter().map(|&property| {
+        let (value, override_book) = match &values[property] {
+            Value::Object(style) => (style["value"].clone(), style["override_book"].as_bool()),
+            value => (value.clone(), None),
+        };
+        let set_by_stylesheet = !chosen_values[property].is_null() && chosen_values[property] != defaults[property];
+        let stylesheet_source = chosen.map(|(name, choice)| format!("stylesheet '{}' ({})", name, choice.describe()));
+        let source = match (flag_for(property, args), stylesheet_source) {
+            // Flags only ever switch a property on, so one that's given decides it, whatever the stylesheet says.
+            (Some(flag), Some(stylesheet_source)) if set_by_stylesheet => format!("{}; {} sets it too", flag, stylesheet_source),
+            (Some(flag), _) => String::from(flag),
+            (None, Some(stylesheet_source)) if set_by_stylesheet => stylesheet_source,
+            (None, _) if value.is_null() => String::from("unset"),
+            (None, _) => String::from(config_default_for(property, config).unwrap_or("default")),
+        };
+        StyleProperty {
+            property,
+            value,
+            override_book,
+            source,
+        }
+    }).collect();
+
+    StyleExplanation {
+        stylesheet: chosen.map(|(name, _choice)| name),
+        chosen_by: chosen.map(|(_name, choice)| choice.describe()),
+        passed_over,
+        properties,
+    }
+}
+
+impl StyleExplanation<'_> {
+    pub fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+
+}
+
+impl fmt::Display for StyleExplanation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.stylesheet, self.chosen_by) {
+            (Some(name), Some(chosen_by)) => writeln!(f, "Stylesheet: {} (from {})", name, chosen_by)?,
+            _ => writeln!(f, "Stylesheet: none")?,
+        }
+        for passed_over in &self.passed_over {
+            match passed_over.defined {
+                true => writeln!(f, "Passed over: {} ({})", passed_over.name, passed_over.chosen_by)?,
+                false => writeln!(f, "Passed over: {} ({}, but not defined in config)", passed_over.name, passed_over.chosen_by)?,
+            }
+        }
+        let rows = self.properties.iter().map(|property| {
+            let value = match &property.value {
+                Value::Null => String::from("-"),
+                // Freeform CSS can run over several lines, which would break up the table.
+                Value::String(value) => value.replace('\n', "\\n"),
+                value => value.to_string(),
+            };
+            let override_book = match property.override_book {
+                Some(true) => "yes",
+                Some(false) => "no",
+                None => "-",
+            };
+            [property.property.to_string(), value, override_book.to_string(), property.source.clone()]
+        }).collect::<Vec<[String; 4]>>();
+        let header = [String::from("property"), String::from("value"), String::from("override_book"), String::from("source")];
+        let widths = (0..3).map(|column| rows.iter().chain([&header]).map(|row| row[column].chars().count()).max().unwrap_or_default()).collect::<Vec<usize>>();
+        let lines = [&header].into_iter().chain(&rows).map(|row| format!("{:<width0$}  {:<width1$}  {:<width2$}  {}", row[0], row[1], row[2], row[3], width0 = widths[0], width1 = widths[1], width2 = widths[2])).collect::<Vec<String>>();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use argh::FromArgs;
+
+    use super::*;
+    use crate::style::resolve_style;
+
+    const CONFIG: &str = "
+        max_cache_books = 0
+        max_cache_bytes = 0
+        default_stylesheet = 'plain'
+        default_max_image_height = 1200
+        [stylesheets.plain]
+        font = { value = 'Georgia', override_book = false }
+        [stylesheets.dark]
+        text_color = { value = '#eee', override_book = true }
+        strip_book_css = true
+    ";
+
+    fn explanation_json(flags: &[&str], preferred: Option<&str>) -> Value {
+        let config = toml::from_str::<Config>(CONFIG).unwrap();
+        let args = Args::from_args(&["rib"], &[flags, &["book.epub"]].concat()).unwrap();
+        let resolved = resolve_style(&args, &config, preferred).unwrap();
+        explain(&config, resolved.chosen, &resolved.stylesheet, preferred, &args).to_json()
+    }
+
+    fn source<'a>(explanation: &'a Value, property: &str) -> &'a str {
+        explanation["properties"].as_array().unwrap().iter().find(|row| row["property"] == property).unwrap()["source"].as_str().unwrap()
+    }
+
+    #[test]
+    fn flag_stylesheet_shadows_preferred_and_default() {
+        let explanation = explanation_json(&["-s", "dark"], Some("plain"));
+        assert_eq!(explanation["stylesheet"], "dark");
+        assert_eq!(explanation["chosen_by"], "--stylesheet");
+        // The default is the same stylesheet as the preferred one, so it's only listed once.
+        assert_eq!(explanation["passed_over"], serde_json::json!([{"name": "plain", "chosen_by": "chosen for the book with 'library style set'", "defined": true}]));
+        assert_eq!(source(&explanation, "text_color"), "stylesheet 'dark' (--stylesheet)");
+        // Set only in the stylesheet passed over, so it doesn't apply.
+        assert_eq!(source(&explanation, "font"), "unset");
+    }
+
+    #[test]
+    fn ignored_and_undefined_preferences_are_passed_over() {
+        let ignored = explanation_json(&["--ignore-preferred"], Some("dark"));
+        assert_eq!(ignored["stylesheet"], "plain");
+        assert_eq!(ignored["passed_over"][0]["chosen_by"], "chosen for the book with 'library style set', but --ignore-preferred was given");
+        let undefined = explanation_json(&[], Some("removed"));
+        assert_eq!(undefined["stylesheet"], "plain");
+        assert_eq!(undefined["passed_over"][0]["defined"], false);
+    }
+
+    #[test]
+    fn flags_decide_the_properties_they_switch_on() {
+        let explanation = explanation_json(&["-s", "dark", "--no-book-css", "--strip-inline-styles"], None);
+        assert_eq!(source(&explanation, "strip_book_css"), "--no-book-css; stylesheet 'dark' (--stylesheet) sets it too");
+        assert_eq!(source(&explanation, "strip_inline_styles"), "--strip-inline-styles");
+        assert_eq!(source(&explanation, "force_styles"), "default");
+    }
+
+    #[test]
+    fn image_caps_name_their_layer() {
+        let from_config = explanation_json(&[], None);
+        assert_eq!(source(&from_config, "max_image_height"), "default_max_image_height in config");
+        assert_eq!(source(&from_config, "max_image_width"), "unset");
+        let from_flag = explanation_json(&["--max-image-height", "500"], None);
+        assert_eq!(source(&from_flag, "max_image_height"), "--max-image-height");
+    }
+}
@@ -0,0 +1,245 @@
+use std::fs::File;
+use std::io::Write;
+#[cfg(feature = "test-support")]
+use std::path::Path;
+
+use quick_xml::escape::escape;
+
+use zip::ZipWriter;
+use zip::result::ZipResult;
+use zip::write::SimpleFileOptions;
+
+use crate::book_paths::{TemporaryBook, write_temporary_epub};
+#[cfg(feature = "test-support")]
+use crate::book_paths::write_epub;
+
+// Formats other than epub are imported by building an epub from their contents, which then goes through the usual pipeline.
+
+const PACKAGE_PATH: &str = "content.opf";
+const NCX_PATH: &str = "toc.ncx";
+
+struct Page {
+    path: String,
+    title: String,
+    head: String,
+    body: String,
+    linear: bool,
+}
+
+struct Resource {
+    path: String,
+    media_type: String,
+    data: Vec<u8>,
+}
+
+struct TocEntry {
+    label: String,
+    href: String,
+    level: usize,
+}
+
+/// An EPUB 2 book built from pages of XHTML body markup, with a table of contents, resources, and a cover.
+pub struct SyntheticEpub {
+    identifier: String,
+    title: String,
+    creators: Vec<String>,
+    language: Option<String>,
+    series: Option<(String, Option<String>)>,
+    pages: Vec<Page>,
+    resources: Vec<Resource>,
+    toc: Vec<TocEntry>,
+    cover_path: Option<String>,
+}
+
+impl SyntheticEpub {
+    /// An empty book with the given unique identifier and title.
+    pub fn new(identifier: String, title: String) -> Self {
+        Self {
+            identifier,
+            title,
+            creators: Vec::new(),
+            language: None,
+            series: None,
+            pages: Vec::new(),
+            resources: Vec::new(),
+            toc: Vec::new(),
+            cover_path: None,
+        }
+    }
+
+    pub fn add_creator(&mut self, creator: String) {
+        self.creators.push(creator);
+    }
+
+    pub fn set_language(&mut self, language: String) {
+        self.language = Some(language);
+    }
+
+    pub fn set_series(&mut self, name: String, number: Option<String>) {
+        self.series = Some((name, number));
+    }
+
+    /// Adds a page to the end of the spine, with `body` as the markup inside its body element.
+    pub fn add_page(&mut self, path: String, title: String, body: String) {
+        self.add_page_with_head(path, title, String::new(), body);
+    }
+
+    /// Adds a page with `head` as markup to go in its head element, after its title.
+    pub fn add_page_with_head(&mut self, path: String, title: String, head: String, body: String) {
+        self.pages.push(Page {
+            path,
+            title,
+            head,
+            body,
+            linear: true,
+        });
+    }
+
+    /// Adds a page that's out of the reading order, like a footnote or an answer key.
+    #[cfg(feature = "test-support")]
+    pub fn add_nonlinear_page(&mut self, path: String, title: String, body: String) {
+        self.pages.push(Page {
+            path,
+            title,
+            head: String::new(),
+            body,
+            linear: false,
+        });
+    }
+
+    /// Adds a file pages can refer to, such as an image or a stylesheet.
+    pub fn add_resource(&mut self, path: String, media_type: &str, data: Vec<u8>) {
+        self.resources.push(Resource {
+            path,
+            media_type: media_type.to_string(),
+            data,
+        });
+    }
+
+    /// Makes the resource at `path` the book's cover image.
+    pub fn set_cover(&mut self, path: String) {
+        self.cover_path = Some(path);
+    }
+
+    /// Adds an entry to the end of the table of contents, nested under the last entry with a lower `level`, if any.
+    pub fn add_toc_entry(&mut self, label: String, href: String, level: usize) {
+        self.toc.push(TocEntry {
+            label,
+            href,
+            level,
+        });
+    }
+
+    fn ncx_document(&self) -> String {
+        // Entries are flat with levels, so nesting comes from closing each open navPoint at or below the next entry's level.
+        let mut nav_map = String::new();
+        let mut open_levels = Vec::new();
+        for (index, entry) in self.toc.iter().enumerate() {
+            while open_levels.last().is_some_and(|open_level| *open_level >= entry.level) {
+                open_levels.pop();
+                nav_map.push_str("</navPoint>\n");
+            }
+            nav_map.push_str(&format!(
+                "<navPoint id=\"navpoint{}\" playOrder=\"{}\"><navLabel><text>{}</text></navLabel><content src=\"{}\"/>\n",
+                index,
+                index + 1,
+                escape(entry.label.as_str()),
+                escape(entry.href.as_str()),
+            ));
+            open_levels.push(entry.level);
+        }
+        for _ in open_levels {
+            nav_map.push_str("</navPoint>\n");
+        }
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<ncx xmlns=\"http://www.daisy.org/z3986/2005/ncx/\" version=\"2005-1\">\n<head><meta name=\"dtb:uid\" content=\"{}\"/></head>\n<docTitle><text>{}</text></docTitle>\n<navMap>\n{}</navMap>\n</ncx>\n",
+            escape(self.identifier.as_str()),
+            escape(self.title.as_str()),
+            nav_map,
+        )
+    }
+
+    fn page_xhtml(page: &Page) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html>\n<html xmlns=\"http://www.w3.org/1999/xhtml\">\n<head><title>{}</title>{}</head>\n<body>\n{}\n</body>\n</html>\n",
+            escape(page.title.as_str()),
+            page.head,
+            page.body,
+        )
+    }
+
+    fn package_document(&self) -> String {
+        let mut manifest = String::new();
+        let mut spine = String::new();
+        for (index, page) in self.pages.iter().enumerate() {
+            manifest.push_str(&format!("    <item id=\"page{}\" href=\"{}\" media-type=\"application/xhtml+xml\"/>\n", index, escape(page.path.as_str())));
+            let linear = if page.linear { "" } else { " linear=\"no\"" };
+            spine.push_str(&format!("    <itemref idref=\"page{}\"{}/>\n", index, linear));
+        }
+        let mut metadata = String::new();
+        for creator in &self.creators {
+            metadata.push_str(&format!("    <dc:creator>{}</dc:creator>\n", escape(creator.as_str())));
+        }
+        metadata.push_str(&format!("    <dc:language>{}</dc:language>\n", escape(self.language.as_deref().unwrap_or("und"))));
+        // Series aren't part of EPUB 2 metadata; calibre's meta names are the convention readers understand.
+        if let Some((name, number)) = &self.series {
+            metadata.push_str(&format!("    <meta name=\"calibre:series\" content=\"{}\"/>\n", escape(name.as_str())));
+            if let Some(number) = number {
+                metadata.push_str(&format!("    <meta name=\"calibre:series_index\" content=\"{}\"/>\n", escape(number.as_str())));
+            }
+        }
+        for (index, resource) in self.resources.iter().enumerate() {
+            manifest.push_str(&format!("    <item id=\"resource{}\" href=\"{}\" media-type=\"{}\"/>\n", index, escape(resource.path.as_str()), escape(resource.media_type.as_str())));
+            if self.cover_path.as_ref() == Some(&resource.path) {
+                metadata.push_str(&format!("    <meta name=\"cover\" content=\"resource{}\"/>\n", index));
+            }
+        }
+        let spine_attributes = match self.toc.is_empty() {
+            true => "",
+            false => {
+                manifest.push_str(&format!("    <item id=\"ncx\" href=\"{}\" media-type=\"application/x-dtbncx+xml\"/>\n", NCX_PATH));
+                " toc=\"ncx\""
+            },
+        };
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<package xmlns=\"http://www.idpf.org/2007/opf\" version=\"2.0\" unique-identifier=\"book-id\">\n  <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n    <dc:identifier id=\"book-id\">{}</dc:identifier>\n    <dc:title>{}</dc:title>\n{}  </metadata>\n  <manifest>\n{}  </manifest>\n  <spine{}>\n{}  </spine>\n</package>\n",
+            escape(self.identifier.as_str()),
+            escape(self.title.as_str()),
+            metadata,
+            manifest,
+            spine_attributes,
+            spine,
+        )
+    }
+
+    fn add_contents(&self, zip: &mut ZipWriter<File>, options: SimpleFileOptions) -> ZipResult<()> {
+        let container = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n  <rootfiles>\n    <rootfile full-path=\"{}\" media-type=\"application/oebps-package+xml\"/>\n  </rootfiles>\n</container>\n", PACKAGE_PATH);
+        zip.start_file("META-INF/container.xml", options)?;
+        zip.write_all(container.as_bytes())?;
+        zip.start_file(PACKAGE_PATH, options)?;
+        zip.write_all(self.package_document().as_bytes())?;
+        if !self.toc.is_empty() {
+            zip.start_file(NCX_PATH, options)?;
+            zip.write_all(self.ncx_document().as_bytes())?;
+        }
+        for page in &self.pages {
+            zip.start_file(page.path.as_str(), options)?;
+            zip.write_all(Self::page_xhtml(page).as_bytes())?;
+        }
+        for resource in &self.resources {
+            zip.start_file(resource.path.as_str(), options)?;
+            zip.write_all(&resource.data)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write(&self, kind: &str) -> Result<TemporaryBook, String> {
+        write_temporary_epub(kind, |zip, options| self.add_contents(zip, options)).map_err(|e| format!("Couldn't write converted book: {}", e))
+    }
+
+    /// Writes the book as an epub file at `path`.
+    #[cfg(feature = "test-support")]
+    pub fn write_to(&self, path: &Path) -> Result<(), String> {
+        write_epub(path, |zip, options| self.add_contents(zip, options)).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))
+    }
+}
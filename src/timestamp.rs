@@ -0,0 +1,203 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// Timestamps are stored on disk as RFC 3339 UTC strings. Indexes written before that used serde's
+// native SystemTime representation, a `{secs_since_epoch, nanos_since_epoch}` struct, which still
+// deserializes.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    secs_since_epoch: i64,
+    nanos: u32,
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Self::from(SystemTime::now())
+    }
+
+    // Days since the Unix epoch, in UTC.
+    pub fn day(self) -> i64 {
+        self.secs_since_epoch.div_euclid(86_400)
+    }
+
+    pub fn year_and_month(self) -> (i64, u32) {
+        let (year, month, _day) = civil_from_days(self.day());
+        (year, month)
+    }
+
+    pub fn to_rfc3339(self) -> String {
+        let days = self.secs_since_epoch.div_euclid(86_400);
+        let secs_of_day = self.secs_since_epoch.rem_euclid(86_400);
+        let (year, month, day) = civil_from_days(days);
+        let time = format!("{:02}:{:02}:{:02}", secs_of_day / 3600, secs_of_day % 3600 / 60, secs_of_day % 60);
+        match self.nanos {
+            0 => format!("{:04}-{:02}-{:02}T{}Z", year, month, day, time),
+            nanos => format!("{:04}-{:02}-{:02}T{}.{:09}Z", year, month, day, time, nanos),
+        }
+    }
+
+    pub fn parse_rfc3339(text: &str) -> Option<Self> {
+        let (date, time) = text.split_once(['T', 't', ' '])?;
+        let mut date_parts = date.splitn(3, '-');
+        let year = date_parts.next()?.parse::<i64>().ok()?;
+        let month = date_parts.next()?.parse::<u32>().ok()?;
+        let day = date_parts.next()?.parse::<u32>().ok()?;
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return None
+        }
+
+        let (time, offset_secs) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+            (time, 0)
+        } else {
+            let offset_position = time.rfind(['+', '-'])?;
+            let (time, offset) = time.split_at(offset_position);
+            let (offset_hours, offset_minutes) = offset[1..].split_once(':')?;
+            let offset_magnitude = offset_hours.parse::<i64>().ok()? * 3600 + offset_minutes.parse::<i64>().ok()? * 60;
+            (time, if offset.starts_with('-') { -offset_magnitude } else { offset_magnitude })
+        };
+        let (time, fraction) = match time.split_once('.') {
+            Some((time, fraction)) => (time, fraction),
+            None => (time, ""),
+        };
+        let mut time_parts = time.splitn(3, ':');
+        let hours = time_parts.next()?.parse::<i64>().ok()?;
+        let minutes = time_parts.next()?.parse::<i64>().ok()?;
+        let seconds = time_parts.next()?.parse::<i64>().ok()?;
+        let nanos = match fraction {
+            "" => 0,
+            fraction if fraction.len() <= 9 && fraction.bytes().all(|byte| byte.is_ascii_digit()) => format!("{:0<9}", fraction).parse::<u32>().ok()?,
+            _ => return None,
+        };
+
+        Some(Self {
+            secs_since_epoch: days_from_civil(year, month, day) * 86_400 + hours * 3600 + minutes * 60 + seconds - offset_secs,
+            nanos,
+        })
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => Self {
+                secs_since_epoch: duration.as_secs() as i64,
+                nanos: duration.subsec_nanos(),
+            },
+            Err(e) => {
+                let duration = e.duration();
+                match duration.subsec_nanos() {
+                    0 => Self { secs_since_epoch: -(duration.as_secs() as i64), nanos: 0 },
+                    nanos => Self { secs_since_epoch: -(duration.as_secs() as i64) - 1, nanos: 1_000_000_000 - nanos },
+                }
+            },
+        }
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> Self {
+        match u64::try_from(timestamp.secs_since_epoch) {
+            Ok(secs) => UNIX_EPOCH + Duration::new(secs, timestamp.nanos),
+            Err(_) => UNIX_EPOCH - Duration::from_secs(timestamp.secs_since_epoch.unsigned_abs()) + Duration::from_nanos(timestamp.nanos as u64),
+        }
+    }
+}
+
+impl Default for Timestamp {
+    fn default() -> Self {
+        Self::from(UNIX_EPOCH)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_rfc3339())
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_rfc3339())
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum TimestampRepresentation {
+            Rfc3339(String),
+            SystemTime(SystemTime),
+        }
+
+        match TimestampRepresentation::deserialize(deserializer)? {
+            TimestampRepresentation::Rfc3339(text) => Self::parse_rfc3339(&text).ok_or_else(|| serde::de::Error::custom(format!("invalid RFC 3339 timestamp '{}'", text))),
+            TimestampRepresentation::SystemTime(time) => Ok(Self::from(time)),
+        }
+    }
+}
+
+// Conversions between days since the Unix epoch and proleptic Gregorian dates, after Howard Hinnant's
+// `days_from_civil`/`civil_from_days`.
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = (month as i64 + 9) % 12;
+    let day_of_year = (153 * shifted_month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_rfc3339() {
+        for text in ["2024-02-29T23:59:59Z", "1969-12-31T00:00:00.500000000Z", "2001-09-09T01:46:40.000000001Z"] {
+            assert_eq!(Timestamp::parse_rfc3339(text).unwrap().to_rfc3339(), text);
+        }
+    }
+
+    #[test]
+    fn normalizes_offsets_to_utc() {
+        assert_eq!(Timestamp::parse_rfc3339("2024-03-01T01:30:00+02:00").unwrap().to_rfc3339(), "2024-02-29T23:30:00Z");
+        assert_eq!(Timestamp::parse_rfc3339("2024-02-29 20:00:00.5-04:00").unwrap().to_rfc3339(), "2024-03-01T00:00:00.500000000Z");
+        assert_eq!(Timestamp::parse_rfc3339("2024-02-29T12:00:00"), None);
+        assert_eq!(Timestamp::parse_rfc3339("2024-13-01T00:00:00Z"), None);
+    }
+
+    #[test]
+    fn converts_to_and_from_system_time() {
+        let time = UNIX_EPOCH - Duration::from_millis(1500);
+        let timestamp = Timestamp::from(time);
+        assert_eq!(timestamp.to_rfc3339(), "1969-12-31T23:59:58.500000000Z");
+        assert_eq!(SystemTime::from(timestamp), time);
+    }
+
+    #[test]
+    fn deserializes_either_representation() {
+        let from_text = serde_json::from_str::<Timestamp>("\"2001-09-09T01:46:40Z\"").unwrap();
+        let from_struct = serde_json::from_str::<Timestamp>("{\"secs_since_epoch\": 1000000000, \"nanos_since_epoch\": 0}").unwrap();
+        assert_eq!(from_text, from_struct);
+        assert_eq!(serde_json::to_string(&from_struct).unwrap(), "\"2001-09-09T01:46:40Z\"");
+    }
+}
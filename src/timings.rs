@@ -0,0 +1,107 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Wall-clock time spent in each stage of opening a book, for --timings. Stages are listed in the order they first ran,
+// and a stage that runs more than once, like reading each resource, adds up.
+
+const SPINE_DOCUMENT_STAGE: &str = "rewrite spine documents";
+const SLOWEST_DOCUMENT_COUNT: usize = 5;
+
+struct Stage {
+    name: &'static str,
+    duration: Duration,
+    bytes: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct Timings {
+    stages: Vec<Stage>,
+    documents: Vec<(PathBuf, Duration)>,
+}
+
+impl Timings {
+    fn stage(&mut self, name: &'static str) -> &mut Stage {
+        let position = match self.stages.iter().position(|stage| stage.name == name) {
+            Some(position) => position,
+            None => {
+                self.stages.push(Stage {
+                    name,
+                    duration: Duration::ZERO,
+                    bytes: None,
+                });
+                self.stages.len() - 1
+            },
+        };
+        &mut self.stages[position]
+    }
+
+    pub fn add(&mut self, name: &'static str, duration: Duration) {
+        self.stage(name).duration += duration;
+    }
+
+    pub fn add_bytes(&mut self, name: &'static str, duration: Duration, bytes: usize) {
+        let stage = self.stage(name);
+        stage.duration += duration;
+        *stage.bytes.get_or_insert(0) += bytes;
+    }
+
+    pub fn time<T>(&mut self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(name, start.elapsed());
+        result
+    }
+
+    pub fn add_spine_document(&mut self, path: &Path, duration: Duration) {
+        self.add(SPINE_DOCUMENT_STAGE, duration);
+        self.documents.push((path.to_path_buf(), duration));
+    }
+
+    fn total(&self) -> Duration {
+        self.stages.iter().map(|stage| stage.duration).sum()
+    }
+
+    fn slowest_documents(&self) -> Vec<&(PathBuf, Duration)> {
+        let mut documents = self.documents.iter().collect::<Vec<&(PathBuf, Duration)>>();
+        documents.sort_by_key(|(_path, duration)| std::cmp::Reverse(*duration));
+        documents.truncate(SLOWEST_DOCUMENT_COUNT);
+        documents
+    }
+
+    pub fn table(&self) -> String {
+        let mut rows = self.stages.iter().map(|stage| {
+            let detail = match (stage.name, stage.bytes) {
+                (SPINE_DOCUMENT_STAGE, _) => format!("{} documents", self.documents.len()),
+                (_, Some(bytes)) => format!("{} bytes", bytes),
+                (_, None) => String::new(),
+            };
+            (String::from(stage.name), stage.duration, detail)
+        }).collect::<Vec<(String, Duration, String)>>();
+        rows.push((String::from("total"), self.total(), String::new()));
+        let slowest_rows = self.slowest_documents().into_iter().map(|(path, duration)| (format!("  {}", path.display()), *duration, String::new())).collect::<Vec<(String, Duration, String)>>();
+
+        let name_width = rows.iter().chain(&slowest_rows).map(|(name, _duration, _detail)| name.chars().count()).max().unwrap_or_default();
+        let format_row = |(name, duration, detail): &(String, Duration, String)| format!("  {:<name_width$}  {:>10}  {}", name, format!("{:.2?}", duration), detail).trim_end().to_string();
+        let mut lines = rows.iter().map(format_row).collect::<Vec<String>>();
+        if !slowest_rows.is_empty() {
+            lines.push(String::from("  slowest spine documents:"));
+            lines.extend(slowest_rows.iter().map(format_row));
+        }
+        lines.join("\n")
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "stages": self.stages.iter().map(|stage| serde_json::json!({
+                "stage": stage.name,
+                "seconds": stage.duration.as_secs_f64(),
+                "bytes": stage.bytes,
+            })).collect::<Vec<serde_json::Value>>(),
+            "total_seconds": self.total().as_secs_f64(),
+            "slowest_spine_documents": self.slowest_documents().into_iter().map(|(path, duration)| serde_json::json!({
+                "path": path,
+                "seconds": duration.as_secs_f64(),
+            })).collect::<Vec<serde_json::Value>>(),
+        })
+    }
+}
@@ -0,0 +1,77 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::log;
+
+// Follows a book's source file for --watch by polling its size and modification time, which works the same on every
+// platform and filesystem, synced and networked ones included.
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+// Generators often write a book in several steps, so a change only counts once the file has held still this long.
+const SETTLE_TIME: Duration = Duration::from_millis(750);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Snapshot {
+    bytes: u64,
+    modified_time: Option<SystemTime>,
+}
+
+impl Snapshot {
+    fn read(path: &Path) -> Option<Self> {
+        let metadata = path.metadata().ok()?;
+        Some(Self {
+            bytes: metadata.len(),
+            modified_time: metadata.modified().ok(),
+        })
+    }
+}
+
+pub struct SourceWatcher {
+    path: PathBuf,
+    last_seen: Option<Snapshot>,
+    pending: Option<(Snapshot, Instant)>,
+    is_missing: bool,
+}
+
+impl SourceWatcher {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            last_seen: Snapshot::read(path),
+            pending: None,
+            is_missing: false,
+        }
+    }
+
+    // Returns true once the file has changed and settled, or false once keep_waiting says to stop.
+    pub fn wait_for_change(&mut self, keep_waiting: impl Fn() -> bool) -> bool {
+        while keep_waiting() {
+            std::thread::sleep(POLL_INTERVAL);
+            // Generators that replace the file atomically leave it missing for a moment, which is waited out like any other change in progress.
+            let Some(snapshot) = Snapshot::read(&self.path) else {
+                if !self.is_missing {
+                    log::info!("{} is missing; waiting for it to come back.", self.path.display());
+                    self.is_missing = true;
+                }
+                self.pending = None;
+                continue
+            };
+            self.is_missing = false;
+            if Some(snapshot) == self.last_seen {
+                self.pending = None;
+                continue
+            }
+            match self.pending {
+                Some((pending_snapshot, since)) if pending_snapshot == snapshot => {
+                    if since.elapsed() >= SETTLE_TIME {
+                        self.last_seen = Some(snapshot);
+                        self.pending = None;
+                        return true
+                    }
+                },
+                _ => self.pending = Some((snapshot, Instant::now())),
+            }
+        }
+        false
+    }
+}
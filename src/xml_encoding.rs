@@ -0,0 +1,67 @@
+// Reading the encodings XML documents declare, for the few single-byte codepages books still turn up in.
+
+const WINDOWS_1251_HIGH_HALF: [char; 128] = [
+    '\u{0402}', '\u{0403}', '\u{201a}', '\u{0453}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{20ac}', '\u{2030}', '\u{0409}', '\u{2039}', '\u{040a}', '\u{040c}', '\u{040b}', '\u{040f}',
+    '\u{0452}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{fffd}', '\u{2122}', '\u{0459}', '\u{203a}', '\u{045a}', '\u{045c}', '\u{045b}', '\u{045f}',
+    '\u{00a0}', '\u{040e}', '\u{045e}', '\u{0408}', '\u{00a4}', '\u{0490}', '\u{00a6}', '\u{00a7}',
+    '\u{0401}', '\u{00a9}', '\u{0404}', '\u{00ab}', '\u{00ac}', '\u{00ad}', '\u{00ae}', '\u{0407}',
+    '\u{00b0}', '\u{00b1}', '\u{0406}', '\u{0456}', '\u{0491}', '\u{00b5}', '\u{00b6}', '\u{00b7}',
+    '\u{0451}', '\u{2116}', '\u{0454}', '\u{00bb}', '\u{0458}', '\u{0405}', '\u{0455}', '\u{0457}',
+    '\u{0410}', '\u{0411}', '\u{0412}', '\u{0413}', '\u{0414}', '\u{0415}', '\u{0416}', '\u{0417}',
+    '\u{0418}', '\u{0419}', '\u{041a}', '\u{041b}', '\u{041c}', '\u{041d}', '\u{041e}', '\u{041f}',
+    '\u{0420}', '\u{0421}', '\u{0422}', '\u{0423}', '\u{0424}', '\u{0425}', '\u{0426}', '\u{0427}',
+    '\u{0428}', '\u{0429}', '\u{042a}', '\u{042b}', '\u{042c}', '\u{042d}', '\u{042e}', '\u{042f}',
+    '\u{0430}', '\u{0431}', '\u{0432}', '\u{0433}', '\u{0434}', '\u{0435}', '\u{0436}', '\u{0437}',
+    '\u{0438}', '\u{0439}', '\u{043a}', '\u{043b}', '\u{043c}', '\u{043d}', '\u{043e}', '\u{043f}',
+    '\u{0440}', '\u{0441}', '\u{0442}', '\u{0443}', '\u{0444}', '\u{0445}', '\u{0446}', '\u{0447}',
+    '\u{0448}', '\u{0449}', '\u{044a}', '\u{044b}', '\u{044c}', '\u{044d}', '\u{044e}', '\u{044f}',
+];
+
+pub fn declared_encoding(xml: &[u8]) -> Option<String> {
+    let declaration_end = xml.iter().position(|byte| *byte == b'>')?;
+    let declaration = String::from_utf8_lossy(&xml[..declaration_end]).to_lowercase();
+    if !declaration.trim_start_matches('\u{feff}').starts_with("<?xml") {
+        return None
+    }
+    let value_start = declaration.find("encoding")? + "encoding".len();
+    let value = declaration[value_start..].trim_start().strip_prefix('=')?.trim_start();
+    // Anything else where the quote should be, like a stray multibyte character, means the declaration is malformed.
+    let quote = value.chars().next().filter(|quote| matches!(quote, '"' | '\''))?;
+    value[1..].split(quote).next().map(String::from)
+}
+
+pub fn decode_codepage(bytes: &[u8], encoding: &str) -> Option<String> {
+    match encoding {
+        "windows-1251" | "cp1251" | "cp-1251" => Some(bytes.iter().map(|byte| match byte {
+            0..=0x7f => char::from(*byte),
+            _ => WINDOWS_1251_HIGH_HALF[usize::from(byte - 0x80)],
+        }).collect()),
+        "iso-8859-1" | "latin1" | "latin-1" => Some(bytes.iter().map(|byte| char::from(*byte)).collect()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_either_quote() {
+        assert_eq!(declared_encoding(b"<?xml version=\"1.0\" encoding=\"Windows-1251\"?>"), Some(String::from("windows-1251")));
+        assert_eq!(declared_encoding(b"<?xml version='1.0' encoding = 'ISO-8859-1'?>"), Some(String::from("iso-8859-1")));
+    }
+
+    #[test]
+    fn ignores_missing_and_unquoted_encodings() {
+        assert_eq!(declared_encoding(b"<?xml version=\"1.0\"?>"), None);
+        assert_eq!(declared_encoding(b"<html><head/></html>"), None);
+        assert_eq!(declared_encoding(b"<?xml version=\"1.0\" encoding=utf-8?>"), None);
+    }
+
+    #[test]
+    fn rejects_multibyte_quote() {
+        assert_eq!(declared_encoding("<?xml version=\"1.0\" encoding=\u{201c}utf-8\u{201d}?>".as_bytes()), None);
+    }
+}
@@ -0,0 +1,62 @@
+mod support;
+
+use std::fs::read_dir;
+use std::process::{Command, Output};
+
+use support::ScratchDir;
+
+#[test]
+fn shows_usage_without_books() {
+    let scratch = ScratchDir::new("usage");
+    let output = Command::new(env!("CARGO_BIN_EXE_rib"))
+        .env("RIB_CONFIG", scratch.path().join("config.toml"))
+        .arg("--library-dir").arg(scratch.path().join("library"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Usage: rib"), "{}", stderr);
+    assert!(stderr.contains("--stylesheet"), "{}", stderr);
+    assert_eq!(read_dir(scratch.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn refuses_open_options_with_subcommands() {
+    let scratch = ScratchDir::new("subcommand-options");
+    let output = Command::new(env!("CARGO_BIN_EXE_rib"))
+        .env("RIB_CONFIG", scratch.path().join("config.toml"))
+        .args(["-s", "dark", "config", "path"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--stylesheet"));
+}
+
+fn run_in(scratch: &ScratchDir, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rib"))
+        .env("RIB_CONFIG", scratch.path().join("config").join("config.toml"))
+        .arg("--library-dir").arg(scratch.path().join("library"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn read_only_subcommands_leave_a_fresh_machine_untouched() {
+    let scratch = ScratchDir::new("read-only");
+    assert!(run_in(&scratch, &["library", "list"]).status.success());
+    assert_eq!(run_in(&scratch, &["library", "path"]).status.code(), Some(1));
+    assert_eq!(run_in(&scratch, &["config", "path"]).status.code(), Some(1));
+    assert!(!run_in(&scratch, &["library", "info", "urn:missing"]).status.success());
+    assert!(run_in(&scratch, &["config", "effective"]).status.success());
+    assert_eq!(read_dir(scratch.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn path_subcommands_create_only_when_asked() {
+    let scratch = ScratchDir::new("create");
+    assert!(run_in(&scratch, &["config", "path", "--create"]).status.success());
+    assert!(scratch.path().join("config").join("config.toml").is_file());
+    assert!(run_in(&scratch, &["library", "path", "--create"]).status.success());
+    assert!(scratch.path().join("library").is_dir());
+}
@@ -0,0 +1,268 @@
+mod support;
+
+use std::fs::{File, create_dir_all, read, read_dir, read_to_string};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use rib::{Stylesheet, extract_book};
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+use support::{ScratchDir, chapter_book, chapter_path, has_navigation, navigation_link, read_extracted, write_book, write_raw_epub};
+
+#[test]
+fn links_rib_stylesheets_from_every_page() {
+    let scratch = ScratchDir::new("stylesheets");
+    let epub_path = write_book(&chapter_book("Stylesheets", 2), &scratch, "book.epub");
+    let output_dir = scratch.path().join("out");
+    extract_book(&epub_path, &output_dir, &Stylesheet::empty()).unwrap();
+    for number in 1..=2 {
+        let page = read_extracted(&output_dir, &chapter_path(number));
+        assert!(page.contains("rib_no_override.css"), "{}", page);
+        assert!(page.contains("rib_override.css"), "{}", page);
+    }
+}
+
+#[test]
+fn navigates_linear_pages_in_order() {
+    let scratch = ScratchDir::new("navigation");
+    let epub_path = write_book(&chapter_book("Navigation", 3), &scratch, "book.epub");
+    let output_dir = scratch.path().join("out");
+    extract_book(&epub_path, &output_dir, &Stylesheet::empty()).unwrap();
+    let middle = read_extracted(&output_dir, &chapter_path(2));
+    assert!(has_navigation(&middle));
+    assert!(middle.contains("href=\"chapter1.xhtml\""), "{}", middle);
+    assert!(middle.contains("href=\"chapter3.xhtml\""), "{}", middle);
+}
+
+#[test]
+fn leaves_unlinked_nonlinear_pages_without_navigation() {
+    let scratch = ScratchDir::new("nonlinear");
+    let mut book = chapter_book("Nonlinear", 2);
+    book.add_nonlinear_page(String::from("answers.xhtml"), String::from("Answers"), String::from("<p>Nobody links here.</p>"));
+    book.add_nonlinear_page(String::from("notes.xhtml"), String::from("Notes"), String::from("<p id=\"note-1\">A note.</p>"));
+    book.add_page(String::from("epilogue.xhtml"), String::from("Epilogue"), String::from("<p>See <a href=\"notes.xhtml#note-1\">the note</a>.</p>"));
+    let epub_path = write_book(&book, &scratch, "book.epub");
+    let output_dir = scratch.path().join("out");
+    extract_book(&epub_path, &output_dir, &Stylesheet::empty()).unwrap();
+
+    assert!(!has_navigation(&read_extracted(&output_dir, "answers.xhtml")));
+    assert!(has_navigation(&read_extracted(&output_dir, "notes.xhtml")));
+    // The pages either side of the unlinked item navigate past it.
+    let last_chapter = read_extracted(&output_dir, &chapter_path(2));
+    assert!(!last_chapter.contains("href=\"answers.xhtml\""), "{}", last_chapter);
+}
+
+#[test]
+fn indexes_nested_contents_with_fragments() {
+    let scratch = ScratchDir::new("index");
+    let mut book = chapter_book("Index", 2);
+    book.add_nonlinear_page(String::from("notes.xhtml"), String::from("Notes"), String::from("<p>Notes.</p>"));
+    let epub_path = write_book(&book, &scratch, "book.epub");
+    let output_dir = scratch.path().join("out");
+    let extracted = extract_book(&epub_path, &output_dir, &Stylesheet::empty()).unwrap();
+    let index = read_to_string(&extracted.index_path).unwrap();
+    assert!(index.contains("<h1>Index</h1>"), "{}", index);
+    assert!(index.contains("Test Author"), "{}", index);
+    assert!(index.contains("epub/chapter2.xhtml#section-2"), "{}", index);
+    assert!(index.contains("Chapter 2, section 2"), "{}", index);
+    assert!(index.contains("(nonlinear)"), "{}", index);
+}
+
+fn open_into_library(scratch: &ScratchDir, epub_path: &Path, library_dirname: &str) -> PathBuf {
+    let config_path = scratch.path().join("config.toml");
+    std::fs::write(&config_path, "max_cache_books = 0\nmax_cache_bytes = 0\n").unwrap();
+    let library_dir = scratch.path().join(library_dirname);
+    let status = Command::new(env!("CARGO_BIN_EXE_rib"))
+        .env("RIB_CONFIG", &config_path)
+        .arg("--library-dir").arg(&library_dir)
+        .arg("-B")
+        .arg(epub_path)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    library_dir
+}
+
+fn cache_index(library_dir: &Path) -> serde_json::Value {
+    serde_json::from_str(&read_to_string(library_dir.join("cache_index.json")).unwrap()).unwrap()
+}
+
+// Every file under `dir`, by its path relative to it, in order.
+fn dir_files(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut files = Vec::new();
+    for entry in read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        match path.is_dir() {
+            true => files.extend(dir_files(&path).into_iter().map(|(relative_path, contents)| (Path::new(path.file_name().unwrap()).join(relative_path), contents))),
+            false => files.push((PathBuf::from(path.file_name().unwrap()), read(&path).unwrap())),
+        }
+    }
+    files.sort();
+    files
+}
+
+#[test]
+fn indexes_cleaned_toc_labels() {
+    let scratch = ScratchDir::new("labels");
+    let mut book = chapter_book("Labels", 0);
+    for (number, label) in ["<i>Ship</i>\n     name", "   ", "Chapter\t3:  <b>The</b>   End"].into_iter().enumerate() {
+        book.add_page(chapter_path(number + 1), format!("Page {}", number + 1), String::from("<p>Text.</p>"));
+        book.add_toc_entry(String::from(label), chapter_path(number + 1), 0);
+    }
+    let epub_path = write_book(&book, &scratch, "book.epub");
+    let output_dir = scratch.path().join("out");
+    let index = read_to_string(extract_book(&epub_path, &output_dir, &Stylesheet::empty()).unwrap().index_path).unwrap();
+    assert!(index.contains(">Ship name</a>"), "{}", index);
+    assert!(index.contains(">(untitled)</a>"), "{}", index);
+    assert!(index.contains(">Chapter 3: The End</a>"), "{}", index);
+}
+
+#[test]
+fn opens_into_library() {
+    let scratch = ScratchDir::new("pipeline");
+    let epub_path = write_book(&chapter_book("Pipeline", 2), &scratch, "book.epub");
+    let library_dir = open_into_library(&scratch, &epub_path, "library");
+
+    let cache_index = cache_index(&library_dir);
+    let books = cache_index["books"].as_array().unwrap();
+    assert_eq!(books.len(), 1);
+    assert_eq!(books[0]["title"], "Pipeline");
+    let book_dir = library_dir.join(books[0]["path"].as_str().unwrap());
+    assert!(book_dir.join("index.html").is_file());
+    assert!(book_dir.join("epub").join(chapter_path(1)).is_file());
+}
+
+// An epub whose archive holds a symlink to a file outside wherever it's extracted, and optionally one to a directory
+// outside it too, with manifest items that write to and through them.
+fn write_symlink_book(path: &Path, outside_dir: &Path, through_dir: bool) {
+    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let mut manifest = String::from(r#"<item id="page" href="page.xhtml" media-type="application/xhtml+xml"/><item id="linked" href="linked.css" media-type="text/css"/>"#);
+    if through_dir {
+        manifest.push_str(r#"<item id="escape" href="escape" media-type="text/plain"/><item id="evil" href="escape/evil.css" media-type="text/css"/>"#);
+    }
+    let mut zip = ZipWriter::new(File::create(path).unwrap());
+    zip.start_file("mimetype", options).unwrap();
+    zip.write_all(b"application/epub+zip").unwrap();
+    zip.start_file("META-INF/container.xml", options).unwrap();
+    zip.write_all(br#"<?xml version="1.0"?><container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container"><rootfiles><rootfile full-path="content.opf" media-type="application/oebps-package+xml"/></rootfiles></container>"#).unwrap();
+    zip.start_file("content.opf", options).unwrap();
+    write!(zip, r#"<?xml version="1.0"?><package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="id"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:identifier id="id">urn:rib-test:symlinks</dc:identifier><dc:title>Symlinks</dc:title></metadata><manifest>{}</manifest><spine><itemref idref="page"/></spine></package>"#, manifest).unwrap();
+    zip.start_file("page.xhtml", options).unwrap();
+    zip.write_all(br#"<?xml version="1.0"?><html xmlns="http://www.w3.org/1999/xhtml"><head><title>Page</title></head><body><p>Text.</p></body></html>"#).unwrap();
+    zip.add_symlink("linked.css", outside_dir.join("secret.css").to_string_lossy(), options).unwrap();
+    if through_dir {
+        zip.add_symlink("escape", outside_dir.to_string_lossy(), options).unwrap();
+        zip.start_file("escape/evil.css", options).unwrap();
+        zip.write_all(b"p { color: red; }").unwrap();
+    }
+    zip.finish().unwrap();
+}
+
+#[test]
+fn symlinks_in_archives_are_extracted_as_regular_files() {
+    let scratch = ScratchDir::new("symlink-file");
+    let outside_dir = scratch.path().join("outside");
+    create_dir_all(&outside_dir).unwrap();
+    let epub_path = scratch.path().join("book.epub");
+    write_symlink_book(&epub_path, &outside_dir, false);
+    let output_dir = scratch.path().join("out");
+    extract_book(&epub_path, &output_dir, &Stylesheet::empty()).unwrap();
+    let linked_path = output_dir.join("epub").join("linked.css");
+    assert!(linked_path.symlink_metadata().unwrap().file_type().is_file());
+    assert_eq!(read_dir(&outside_dir).unwrap().count(), 0);
+}
+
+#[test]
+fn writes_through_archived_directory_symlinks_stay_inside_the_book() {
+    let scratch = ScratchDir::new("symlink-dir");
+    let outside_dir = scratch.path().join("outside");
+    create_dir_all(&outside_dir).unwrap();
+    let epub_path = scratch.path().join("book.epub");
+    write_symlink_book(&epub_path, &outside_dir, true);
+    // The symlink's entry is extracted as a file, which can't also be the directory the other entry goes in, so the book fails instead.
+    assert!(extract_book(&epub_path, &scratch.path().join("out"), &Stylesheet::empty()).is_err());
+    assert_eq!(read_dir(&outside_dir).unwrap().count(), 0);
+}
+
+#[test]
+fn imports_identically_into_fresh_libraries() {
+    let scratch = ScratchDir::new("deterministic");
+    let mut book = chapter_book("Deterministic", 3);
+    book.add_creator(String::from("Second Author"));
+    book.add_creator(String::from("Another Author"));
+    for name in ["zeta", "alpha", "mu", "beta", "omega", "gamma"] {
+        book.add_resource(format!("styles/{}.css", name), "text/css", format!("p.{} {{ margin: 0; }}", name).into_bytes());
+    }
+    let epub_path = write_book(&book, &scratch, "book.epub");
+    let first_library_dir = open_into_library(&scratch, &epub_path, "first");
+    let second_library_dir = open_into_library(&scratch, &epub_path, "second");
+
+    // Opening and extraction times are the only things that should differ, so they're pinned before comparing.
+    let [mut first_index, mut second_index] = [&first_library_dir, &second_library_dir].map(|library_dir| cache_index(library_dir));
+    for index in [&mut first_index, &mut second_index] {
+        for book in index["books"].as_array_mut().unwrap() {
+            book["added_time"] = serde_json::Value::from("2001-09-09T01:46:40Z");
+            book["last_opened_time"] = serde_json::Value::from("2001-09-09T01:46:40Z");
+        }
+    }
+    assert_eq!(serde_json::to_string_pretty(&first_index).unwrap(), serde_json::to_string_pretty(&second_index).unwrap());
+    let book_dir = first_index["books"][0]["path"].as_str().unwrap();
+    let first_files = dir_files(&first_library_dir.join(book_dir));
+    let second_files = dir_files(&second_library_dir.join(book_dir));
+    assert_eq!(first_files.iter().map(|(path, _contents)| path).collect::<Vec<&PathBuf>>(), second_files.iter().map(|(path, _contents)| path).collect::<Vec<&PathBuf>>());
+    for ((path, first_contents), (_path, second_contents)) in first_files.iter().zip(&second_files) {
+        if path == Path::new("rib_extraction.json") {
+            let [mut first_record, mut second_record] = [first_contents, second_contents].map(|contents| serde_json::from_slice::<serde_json::Value>(contents).unwrap());
+            first_record["extracted_time"] = serde_json::Value::Null;
+            second_record["extracted_time"] = serde_json::Value::Null;
+            assert_eq!(first_record, second_record);
+            continue
+        }
+        assert!(first_contents == second_contents, "{} differs:\n{}\n{}", path.display(), String::from_utf8_lossy(first_contents), String::from_utf8_lossy(second_contents));
+    }
+}
+
+#[test]
+fn duplicate_spine_items_keep_navigation_in_order() {
+    let scratch = ScratchDir::new("duplicate-spine");
+    let epub_path = scratch.path().join("book.epub");
+    // The first chapter is listed again between the second and third, as a duplicated itemref.
+    write_raw_epub(&epub_path, r#"<?xml version="1.0"?><package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="id"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:identifier id="id">urn:rib-test:duplicate-spine</dc:identifier><dc:title>Duplicate Spine</dc:title></metadata><manifest><item id="c1" href="chapter1.xhtml" media-type="application/xhtml+xml"/><item id="c2" href="chapter2.xhtml" media-type="application/xhtml+xml"/><item id="c3" href="chapter3.xhtml" media-type="application/xhtml+xml"/></manifest><spine><itemref idref="c1"/><itemref idref="c2"/><itemref idref="c1"/><itemref idref="c3"/></spine></package>"#, &[("chapter1.xhtml", "One."), ("chapter2.xhtml", "Two."), ("chapter3.xhtml", "Three.")]);
+
+    let output_dir = scratch.path().join("out");
+    let extracted = extract_book(&epub_path, &output_dir, &Stylesheet::empty()).unwrap();
+    assert!(extracted.warnings.iter().any(|warning| warning.contains("chapter1.xhtml is listed 2 times")), "{:?}", extracted.warnings);
+    let links = (1..=3).map(|number| {
+        let page = read_extracted(&output_dir, &chapter_path(number));
+        (navigation_link(&page, "Previous"), navigation_link(&page, "Next"))
+    }).collect::<Vec<(Option<String>, Option<String>)>>();
+    assert_eq!(links, [
+        (None, Some(String::from("chapter2.xhtml"))),
+        (Some(String::from("chapter1.xhtml")), Some(String::from("chapter3.xhtml"))),
+        (Some(String::from("chapter2.xhtml")), None),
+    ]);
+}
+
+#[test]
+fn credits_secondary_roles_on_their_own_lines() {
+    let scratch = ScratchDir::new("creators");
+    let epub_path = scratch.path().join("book.epub");
+    // EPUB 3 refinements and EPUB 2 opf:role attributes, with a contributor that's only the tool that made the book.
+    write_raw_epub(&epub_path, r##"<?xml version="1.0"?><package xmlns="http://www.idpf.org/2007/opf" xmlns:opf="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:identifier id="id">urn:rib-test:creators</dc:identifier><dc:title>Creators</dc:title>
+        <dc:creator id="a1">Ursula</dc:creator><meta refines="#a1" property="role" scheme="marc:relators">aut</meta>
+        <dc:creator id="a2">Octavia</dc:creator>
+        <dc:creator id="t1">Marta</dc:creator><meta refines="#t1" property="role" scheme="marc:relators">trl</meta>
+        <dc:creator opf:role="trl">Gene</dc:creator>
+        <dc:contributor opf:role="ill">Ilse</dc:contributor>
+        <dc:creator>Samuel</dc:creator>
+        <dc:contributor>calibre (7.0)</dc:contributor>
+    </metadata><manifest><item id="c1" href="chapter1.xhtml" media-type="application/xhtml+xml"/></manifest><spine><itemref idref="c1"/></spine></package>"##, &[("chapter1.xhtml", "One.")]);
+    let output_dir = scratch.path().join("out");
+    let index = read_to_string(extract_book(&epub_path, &output_dir, &Stylesheet::empty()).unwrap().index_path).unwrap();
+    assert!(index.contains("<h3>Ursula, Octavia &amp; Samuel</h3>"), "{}", index);
+    assert!(index.contains("<p>Translated by Marta &amp; Gene</p>"), "{}", index);
+    assert!(index.contains("<p>Illustrated by Ilse</p>"), "{}", index);
+    assert!(!index.contains("calibre"), "{}", index);
+}
@@ -0,0 +1,109 @@
+mod support;
+
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use support::{ScratchDir, chapter_book, write_book};
+
+const CONFIG: &str = "
+max_cache_books = 0
+max_cache_bytes = 0
+[stylesheets.dark]
+text_color = { value = '#eee', override_book = true }
+background_color = { value = '#111', override_book = true }
+";
+
+fn run_rib(scratch: &ScratchDir, args: &[&str]) -> Output {
+    let config_path = scratch.path().join("config.toml");
+    if !config_path.exists() {
+        write(&config_path, CONFIG).unwrap();
+    }
+    Command::new(env!("CARGO_BIN_EXE_rib"))
+        .env("RIB_CONFIG", &config_path)
+        .arg("--library-dir").arg(scratch.path().join("library"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn open(scratch: &ScratchDir, epub_path: &Path, flags: &[&str]) {
+    let output = run_rib(scratch, &[flags, &["-B", epub_path.to_str().unwrap()]].concat());
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+fn stdout(output: Output) -> String {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn cache_index(scratch: &ScratchDir) -> serde_json::Value {
+    serde_json::from_str(&read_to_string(scratch.path().join("library").join("cache_index.json")).unwrap()).unwrap()
+}
+
+// The stylesheet the book was last extracted with, from its extraction record.
+fn extracted_stylesheet(scratch: &ScratchDir) -> Option<String> {
+    let cache_index = cache_index(scratch);
+    let book_dir = scratch.path().join("library").join(cache_index["books"][0]["path"].as_str().unwrap());
+    let record: serde_json::Value = serde_json::from_str(&read_to_string(book_dir.join("rib_extraction.json")).unwrap()).unwrap();
+    record["stylesheet_name"].as_str().map(String::from)
+}
+
+#[test]
+fn preferred_stylesheets_persist_until_unset() {
+    let scratch = ScratchDir::new("preferred");
+    let epub_path = write_book(&chapter_book("Preferred", 2), &scratch, "book.epub");
+    open(&scratch, &epub_path, &[]);
+    assert_eq!(extracted_stylesheet(&scratch), None);
+
+    stdout(run_rib(&scratch, &["library", "style", "set", "title:preferred", "dark"]));
+    assert!(stdout(run_rib(&scratch, &["library", "info", "title:preferred"])).contains("stylesheet: dark"));
+    open(&scratch, &epub_path, &[]);
+    assert_eq!(extracted_stylesheet(&scratch).as_deref(), Some("dark"));
+    open(&scratch, &epub_path, &["--ignore-preferred"]);
+    assert_eq!(extracted_stylesheet(&scratch), None);
+
+    stdout(run_rib(&scratch, &["library", "style", "unset", "title:preferred"]));
+    assert!(!stdout(run_rib(&scratch, &["library", "info", "title:preferred"])).contains("stylesheet:"));
+    open(&scratch, &epub_path, &[]);
+    assert_eq!(extracted_stylesheet(&scratch), None);
+}
+
+#[test]
+fn preferring_an_unknown_stylesheet_is_refused() {
+    let scratch = ScratchDir::new("unknown-preferred");
+    let epub_path = write_book(&chapter_book("Unknown", 1), &scratch, "book.epub");
+    open(&scratch, &epub_path, &[]);
+    assert!(!run_rib(&scratch, &["library", "style", "set", "title:unknown", "sepia"]).status.success());
+    assert!(!stdout(run_rib(&scratch, &["library", "info", "title:unknown"])).contains("stylesheet:"));
+}
+
+#[test]
+fn books_opened_together_write_the_index_once() {
+    let scratch = ScratchDir::new("together");
+    // Books of different lengths, so none has the same text as another.
+    let epub_paths = (1..=3).map(|chapters| write_book(&chapter_book(&format!("Together {}", chapters), chapters), &scratch, &format!("book{}.epub", chapters))).collect::<Vec<PathBuf>>();
+    let epub_args = epub_paths.iter().map(|epub_path| epub_path.to_str().unwrap()).collect::<Vec<&str>>();
+    stdout(run_rib(&scratch, &[&["-B"], epub_args.as_slice()].concat()));
+    let cache_index = cache_index(&scratch);
+    assert_eq!(cache_index["books"].as_array().unwrap().len(), 3);
+    assert_eq!(cache_index["generation"], 1);
+}
+
+#[test]
+fn duplicates_opened_alongside_their_replacement_are_kept() {
+    let scratch = ScratchDir::new("replace-opened");
+    let original_path = write_book(&chapter_book("Original", 2), &scratch, "original.epub");
+    let copy_path = write_book(&chapter_book("Copy", 2), &scratch, "copy.epub");
+    open(&scratch, &original_path, &[]);
+    let output = run_rib(&scratch, &["-B", "--dedupe", "replace", copy_path.to_str().unwrap(), original_path.to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    stdout(output);
+    assert!(stderr.contains("Kept urn:rib-test:original in the library"), "{}", stderr);
+    let cache_index = cache_index(&scratch);
+    let books = cache_index["books"].as_array().unwrap();
+    assert_eq!(books.len(), 2);
+    for book in books {
+        assert!(scratch.path().join("library").join(book["path"].as_str().unwrap()).join("index.html").is_file());
+    }
+}
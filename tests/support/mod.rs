@@ -0,0 +1,94 @@
+// Books built on the fly for the tests, and scratch directories to extract them into.
+
+#![allow(dead_code)]
+
+use std::fs::{File, create_dir_all, read_to_string, remove_dir_all};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use rib::SyntheticEpub;
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+// A directory under the system's temporary directory, removed along with everything in it when dropped.
+pub struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rib-test-{}-{}", std::process::id(), name));
+        let _ = remove_dir_all(&path);
+        create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.path);
+    }
+}
+
+pub fn chapter_path(number: usize) -> String {
+    format!("chapter{}.xhtml", number)
+}
+
+// A book of `chapters` chapters, each listed in the table of contents with a nested entry for its second section.
+pub fn chapter_book(title: &str, chapters: usize) -> SyntheticEpub {
+    let mut book = SyntheticEpub::new(format!("urn:rib-test:{}", title.to_lowercase().replace(' ', "-")), String::from(title));
+    book.add_creator(String::from("Test Author"));
+    for number in 1..=chapters {
+        book.add_page(chapter_path(number), format!("Chapter {}", number), format!("<h1>Chapter {}</h1>\n<p>The first section of chapter {}.</p>\n<h2 id=\"section-2\">Section 2</h2>\n<p>The second section.</p>", number, number));
+        book.add_toc_entry(format!("Chapter {}", number), chapter_path(number), 0);
+        book.add_toc_entry(format!("Chapter {}, section 2", number), format!("{}#section-2", chapter_path(number)), 1);
+    }
+    book
+}
+
+pub fn write_book(book: &SyntheticEpub, dir: &ScratchDir, filename: &str) -> PathBuf {
+    let path = dir.path().join(filename);
+    book.write_to(&path).unwrap();
+    path
+}
+
+// An epub with the package document and pages as given, for books SyntheticEpub can't make, like broken ones. Pages
+// get a minimal XHTML document with their text as a paragraph.
+pub fn write_raw_epub(path: &Path, package: &str, pages: &[(&str, &str)]) {
+    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let mut zip = ZipWriter::new(File::create(path).unwrap());
+    zip.start_file("mimetype", options).unwrap();
+    zip.write_all(b"application/epub+zip").unwrap();
+    zip.start_file("META-INF/container.xml", options).unwrap();
+    zip.write_all(br#"<?xml version="1.0"?><container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container"><rootfiles><rootfile full-path="content.opf" media-type="application/oebps-package+xml"/></rootfiles></container>"#).unwrap();
+    zip.start_file("content.opf", options).unwrap();
+    zip.write_all(package.as_bytes()).unwrap();
+    for (page_path, text) in pages {
+        zip.start_file(*page_path, options).unwrap();
+        write!(zip, r#"<?xml version="1.0"?><html xmlns="http://www.w3.org/1999/xhtml"><head><title>{}</title></head><body><p>{}</p></body></html>"#, page_path, text).unwrap();
+    }
+    zip.finish().unwrap();
+}
+
+pub fn read_extracted(output_dir: &Path, path: &str) -> String {
+    read_to_string(output_dir.join("epub").join(path)).unwrap()
+}
+
+// Whether the page carries rib's Previous/Index/Next navigation.
+pub fn has_navigation(page: &str) -> bool {
+    page.contains("aria-label=\"Book navigation\"")
+}
+
+// Where the page's navigation button labeled `label` (Previous, Index, or Next) links to, if it links anywhere.
+pub fn navigation_link(page: &str, label: &str) -> Option<String> {
+    let button = format!("><button type=\"button\" tabindex=\"-1\">{}</button>", label);
+    page.match_indices("<a href=\"").find_map(|(start, opening)| {
+        let rest = &page[start + opening.len()..];
+        let href_end = rest.find('"')?;
+        rest[href_end + 1..].starts_with(&button).then(|| rest[..href_end].to_string())
+    })
+}